@@ -0,0 +1,113 @@
+/*
+ * C interface to bader, the grid based Bader charge partitioning library.
+ *
+ * Declares the functions of src/ffi.rs, exported by the cdylib built with
+ * `cargo build --release` (target/release/libbader.so, .dylib or .dll).
+ *
+ * Lattice vectors and positions are cartesian, in any unit of length, and
+ * the density is per unit volume of that unit. The density is ordered with a
+ * varying fastest then b then c, ie. rho(na, nb, nc) in Fortran or
+ * rho[nc][nb][na] in C, as VASP writes it.
+ */
+#ifndef BADER_H
+#define BADER_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The call succeeded. */
+#define BADER_OK 0
+/* A required pointer was NULL. */
+#define BADER_ERROR_NULL -1
+/* The lattice, dimensions, positions or density were invalid. */
+#define BADER_ERROR_INPUT -2
+/* The results were queried before bader_partition succeeded. */
+#define BADER_ERROR_NOT_RUN -3
+/* The calculation failed unexpectedly. */
+#define BADER_ERROR_INTERNAL -4
+
+/*
+ * An opaque handle holding the cell, atoms and, once partitioned, the
+ * results of a calculation.
+ */
+typedef struct BaderGrid BaderGrid;
+
+/*
+ * Creates a handle for partitioning densities on a grid of a cell.
+ *
+ * lattice:   The 9 cartesian components of the lattice vectors, a then b
+ *            then c.
+ * dims:      The number of voxels along a, b and c.
+ * positions: The 3 cartesian components of each atom.
+ * atoms_len: The number of atoms.
+ *
+ * Returns the handle, or NULL if a pointer is NULL, a dimension is zero, the
+ * grid or positions are too large to address or the lattice is singular.
+ */
+BaderGrid *bader_grid_new(const double *lattice,
+                          const size_t *dims,
+                          const double *positions,
+                          size_t atoms_len);
+
+/* Releases a handle created by bader_grid_new. Passing NULL does nothing. */
+void bader_grid_free(BaderGrid *grid);
+
+/*
+ * Partitions a density, of na * nb * nc values, replacing the results of
+ * any previous partition. The density is copied and can be freed
+ * afterwards. Uses the default number of threads if threads is 0.
+ *
+ * Returns BADER_OK or an error code, see bader_error_message.
+ */
+int bader_partition(BaderGrid *grid, const double *density, size_t threads);
+
+/* The number of atoms of the handle, 0 if grid is NULL. */
+size_t bader_atoms_len(const BaderGrid *grid);
+
+/* The number of Bader volumes found by the last partition, 0 if none. */
+size_t bader_volumes_len(const BaderGrid *grid);
+
+/*
+ * Copies the charge of each atom into charge, which must have room for
+ * bader_atoms_len values.
+ *
+ * Returns BADER_OK or an error code, see bader_error_message.
+ */
+int bader_atoms_charge(BaderGrid *grid, double *charge);
+
+/*
+ * Copies the volume of each atom into volume, which must have room for
+ * bader_atoms_len values.
+ *
+ * Returns BADER_OK or an error code, see bader_error_message.
+ */
+int bader_atoms_volume(BaderGrid *grid, double *volume);
+
+/*
+ * Labels every voxel with the atom and Bader volume it belongs to, counting
+ * from 0 with -1 for vacuum, in the order of the density. Boundary voxels
+ * are labelled by the volume holding the largest part of them. Either array
+ * can be NULL, otherwise it must have room for na * nb * nc values.
+ *
+ * Returns BADER_OK or an error code, see bader_error_message.
+ */
+int bader_voxel_labels(BaderGrid *grid,
+                       int64_t *atom_labels,
+                       int64_t *volume_labels);
+
+/*
+ * The message of the last error raised with the handle, an empty string if
+ * there hasn't been one since the last successful partition. The string
+ * belongs to the handle and is valid until the next call with it.
+ */
+const char *bader_error_message(const BaderGrid *grid);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* BADER_H */
@@ -1,6 +1,8 @@
 use crate::atoms::Atoms;
+use crate::cancel::CancellationToken;
 use crate::grid::Grid;
-use crate::progress::Bar;
+use crate::precision::Real;
+use crate::progress::Progress;
 use crate::utils;
 use crate::voxel_map::{Voxel, VoxelMap};
 use rustc_hash::FxHashMap;
@@ -9,6 +11,9 @@ use rustc_hash::FxHashMap;
 pub enum AnalysisError {
     /// Not finding index for supplied maxima.
     NotMaxima,
+    /// The summation was stopped by its
+    /// [`CancellationToken`](crate::cancel::CancellationToken).
+    Cancelled,
 }
 
 /// Make Errors printable.
@@ -19,6 +24,9 @@ impl std::fmt::Display for AnalysisError {
             Self::NotMaxima => {
                 f.write_str("Error: Attempted to look up non-maxima in maxima index.")
             },
+            Self::Cancelled => {
+                f.write_str("Error: The summation was cancelled or passed its deadline.")
+            },
         }
     }
 }
@@ -31,6 +39,9 @@ impl std::fmt::Debug for AnalysisError {
             Self::NotMaxima => {
                 f.write_str("Error: Attempted to look up non-maxima in maxima index.")
             },
+            Self::Cancelled => {
+                f.write_str("Error: The summation was cancelled or passed its deadline.")
+            },
         }
     }
 }
@@ -57,6 +68,9 @@ pub struct Analysis {
     pub atoms_charge: Vec<Vec<f64>>,
     /// The volume associated with each atom.
     pub atoms_volume: Vec<f64>,
+    /// The net charge of each atom, its valence charge minus the partitioned
+    /// charge. Empty unless [`Analysis::net_charge`] has been called.
+    pub atoms_net_charge: Vec<f64>,
     /// The charge (and spin) assigned to the vacuum.
     pub vacuum_charge: Vec<f64>,
     /// The volume assigned to the vacuum.
@@ -103,6 +117,7 @@ impl Analysis {
         let bader_volume = Vec::with_capacity(0);
         let atoms_charge = vec![vec![0f64; atom_num]; densities_len];
         let atoms_volume = vec![0f64; atom_num];
+        let atoms_net_charge = Vec::with_capacity(0);
         let vacuum_charge = vec![0f64; densities_len];
         let vacuum_volume = 0f64;
         let total_charge = vec![0f64; densities_len];
@@ -115,6 +130,7 @@ impl Analysis {
                bader_volume,
                atoms_charge,
                atoms_volume,
+               atoms_net_charge,
                vacuum_charge,
                vacuum_volume,
                total_charge }
@@ -167,7 +183,10 @@ impl Analysis {
 
     /// Assigns each Bader maxima to an atom recording the distance between
     /// maxima and atom position.
-    pub fn assign_atoms(&mut self, atoms: &Atoms, grid: &Grid, pbar: Bar) {
+    pub fn assign_atoms(&mut self,
+                        atoms: &Atoms,
+                        grid: &Grid,
+                        pbar: Box<dyn Progress>) {
         let mut assigned_atom = Vec::with_capacity(atoms.positions.len());
         let mut minimum_distance = Vec::with_capacity(atoms.positions.len());
         for maxima in self.bader_maxima.iter() {
@@ -214,34 +233,41 @@ impl Analysis {
         self.minimum_distance = minimum_distance;
     }
 
-    /// Sums the densities for each bader volume.
-    pub fn charge_sum(&mut self,
-                      atoms: &Atoms,
-                      densities: &[Vec<f64>],
-                      grid: &Grid,
-                      voxel_map: &VoxelMap,
-                      pbar: Bar)
-                      -> Result<(), AnalysisError> {
+    /// Sums the densities for each bader volume, returning
+    /// [`AnalysisError::Cancelled`] if `cancel` is cancelled part way.
+    pub fn charge_sum<T, D>(&mut self,
+                            atoms: &Atoms,
+                            densities: &[D],
+                            grid: &Grid,
+                            voxel_map: &VoxelMap,
+                            cancel: &CancellationToken,
+                            pbar: Box<dyn Progress>)
+                            -> Result<(), AnalysisError>
+        where T: Real,
+              D: AsRef<[T]> {
         let mut minimum_distance = vec![f64::INFINITY; atoms.positions.len()];
         let mut bader_charge =
             vec![vec![0.; self.bader_maxima.len()]; self.bader_charge.len()];
         let mut bader_volume = vec![0.; self.bader_maxima.len()];
         let volume = grid.voxel_lattice.volume;
         for p in 0..grid.size.total {
+            // checking the clock every voxel would slow the sum noticeably
+            if p % 1024 == 0 && cancel.is_cancelled() {
+                return Err(AnalysisError::Cancelled);
+            }
             match voxel_map.voxel_get(p as isize) {
                 Voxel::Weight(weights) => {
-                    let atom_num = self.atom_get(weights[0] as usize)?;
+                    let atom_num = self.atom_get(weights.maxima())?;
                     let mut is_atom_boundary = false;
-                    for maxima_weight in weights.iter() {
-                        let maxima = *maxima_weight as usize;
-                        let weight = maxima_weight - maxima as f64;
+                    for (maxima, weight) in weights.iter() {
                         if atom_num != self.atom_get(maxima)? {
                             is_atom_boundary = true
                         }
                         let i = self.index_get(maxima)?;
                         bader_volume[i] += weight;
                         for (j, charge) in densities.iter().enumerate() {
-                            bader_charge[j][i] += weight * charge[p];
+                            bader_charge[j][i] +=
+                                weight * charge.as_ref()[p].to_f64();
                         }
                     }
                     if is_atom_boundary {
@@ -279,13 +305,14 @@ impl Analysis {
                     let i = self.index_get(maxima)?;
                     bader_volume[i] += 1.;
                     for (j, charge) in densities.iter().enumerate() {
-                        bader_charge[j][i] += charge[p];
+                        bader_charge[j][i] += charge.as_ref()[p].to_f64();
                     }
                 }
                 Voxel::Vacuum => {
                     self.vacuum_volume += volume;
                     for (j, charge) in densities.iter().enumerate() {
-                        self.vacuum_charge[j] += volume * charge[p];
+                        self.vacuum_charge[j] +=
+                            volume * charge.as_ref()[p].to_f64();
                     }
                 }
             }
@@ -322,19 +349,43 @@ impl Analysis {
         }
     }
 
+    /// Calculates the net charge of each atom from its valence charge. This
+    /// should be called after [`Analysis::atoms_charge_sum`].
+    ///
+    /// * `valence`: The valence charge of each atom.
+    ///
+    /// ### Examples
+    /// ```
+    /// use bader::analysis::Analysis;
+    /// use bader::voxel_map::VoxelMap;
+    ///
+    /// let voxel_map = VoxelMap::new(10);
+    /// let mut analysis = Analysis::new(&voxel_map, 1, 2);
+    /// analysis.atoms_charge = vec![vec![7.5, 0.5]];
+    /// analysis.net_charge(&[6., 1.]);
+    /// assert_eq!(analysis.atoms_net_charge, vec![-1.5, 0.5])
+    /// ```
+    pub fn net_charge(&mut self, valence: &[f64]) {
+        self.atoms_net_charge = valence.iter()
+                                       .zip(&self.atoms_charge[0])
+                                       .map(|(z, charge)| z - charge)
+                                       .collect();
+    }
+
     /// Creates a voxel map for a specific atom.
+    ///
+    /// Returns [`AnalysisError::NotMaxima`] if a voxel belongs to a maxima
+    /// that isn't in [`self.bader_maxima`].
     pub fn output_atom_map(&self,
                            grid: &Grid,
                            voxel_map: &VoxelMap,
                            atom_num: usize,
-                           pbar: Bar)
-                           -> Vec<Option<f64>> {
-        (0..grid.size.total).map(|p| {
+                           pbar: Box<dyn Progress>)
+                           -> Result<Vec<Option<f64>>, AnalysisError> {
+        (0..grid.size.total).map(|p| -> Result<Option<f64>, AnalysisError> {
                                 let w = match voxel_map.voxel_get(p as isize) {
                                     Voxel::Maxima(maxima) => {
-                                        if self.atom_get(maxima).unwrap()
-                                           == atom_num
-                                        {
+                                        if self.atom_get(maxima)? == atom_num {
                                             Some(1f64)
                                         } else {
                                             None
@@ -342,12 +393,9 @@ impl Analysis {
                                     }
                                     Voxel::Weight(weights) => {
                                         let mut w = None;
-                                        for maxima_weight in weights {
-                                            let maxima =
-                                                *maxima_weight as usize;
-                                            let weight =
-                                                maxima_weight - maxima as f64;
-                                            if self.atom_get(maxima).unwrap()
+                                        for (maxima, weight) in weights.iter()
+                                        {
+                                            if self.atom_get(maxima)?
                                                == atom_num
                                             {
                                                 w = Some(weight);
@@ -359,7 +407,101 @@ impl Analysis {
                                     Voxel::Vacuum => None,
                                 };
                                 pbar.tick();
-                                w
+                                Ok(w)
+                            })
+                            .collect()
+    }
+
+    /// Creates a map of the Bader volume each voxel belongs to, as an index of
+    /// [`self.bader_maxima`], and the fraction of the voxel in that volume.
+    /// Boundary voxels are labelled by the volume with the largest weight and
+    /// vacuum voxels are None.
+    ///
+    /// Returns [`AnalysisError::NotMaxima`] if a voxel belongs to a maxima
+    /// that isn't in [`self.bader_maxima`].
+    pub fn output_label_map(&self,
+                            grid: &Grid,
+                            voxel_map: &VoxelMap,
+                            pbar: Box<dyn Progress>)
+                            -> Result<Vec<Option<(usize, f64)>>, AnalysisError>
+    {
+        (0..grid.size.total).map(|p| {
+                                let l = match voxel_map.voxel_get(p as isize) {
+                                    Voxel::Maxima(maxima) => {
+                                        Some((self.index_get(maxima)?, 1.))
+                                    }
+                                    Voxel::Weight(weights) => {
+                                        let mut l = (0, 0.);
+                                        for (maxima, weight) in weights.iter()
+                                        {
+                                            if weight > l.1 {
+                                                l = (self.index_get(maxima)?,
+                                                     weight);
+                                            }
+                                        }
+                                        Some(l)
+                                    }
+                                    Voxel::Vacuum => None,
+                                };
+                                pbar.tick();
+                                Ok(l)
+                            })
+                            .collect()
+    }
+
+    /// The Bader volumes, as indices of [`self.bader_maxima`], assigned to any
+    /// of the atoms.
+    pub fn atoms_volumes(&self, atoms: &[usize]) -> Vec<usize> {
+        self.assigned_atom
+            .iter()
+            .enumerate()
+            .filter(|(_, atom)| atoms.contains(atom))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Creates a single voxel map for a set of volumes, as indices of
+    /// [`self.bader_maxima`], in one pass over the [`VoxelMap`]. Boundary
+    /// voxels take the sum of the weights of the volumes in the set.
+    ///
+    /// Returns [`AnalysisError::NotMaxima`] if a voxel belongs to a maxima
+    /// that isn't in [`self.bader_maxima`].
+    pub fn output_sum_map(&self,
+                          grid: &Grid,
+                          voxel_map: &VoxelMap,
+                          volumes: &[usize],
+                          pbar: Box<dyn Progress>)
+                          -> Result<Vec<Option<f64>>, AnalysisError> {
+        let mut selected = vec![false; self.bader_maxima.len()];
+        volumes.iter().for_each(|v| selected[*v] = true);
+        (0..grid.size.total).map(|p| -> Result<Option<f64>, AnalysisError> {
+                                let w = match voxel_map.voxel_get(p as isize) {
+                                    Voxel::Maxima(maxima) => {
+                                        if selected[self.index_get(maxima)?] {
+                                            Some(1f64)
+                                        } else {
+                                            None
+                                        }
+                                    }
+                                    Voxel::Weight(weights) => {
+                                        let mut w = 0f64;
+                                        for (maxima, weight) in weights.iter()
+                                        {
+                                            if selected[self.index_get(maxima)?]
+                                            {
+                                                w += weight;
+                                            }
+                                        }
+                                        if w > 0. {
+                                            Some(w)
+                                        } else {
+                                            None
+                                        }
+                                    }
+                                    Voxel::Vacuum => None,
+                                };
+                                pbar.tick();
+                                Ok(w)
                             })
                             .collect()
     }
@@ -369,7 +511,7 @@ impl Analysis {
                              grid: &Grid,
                              voxel_map: &VoxelMap,
                              maxima_out: usize,
-                             pbar: Bar)
+                             pbar: Box<dyn Progress>)
                              -> Vec<Option<f64>> {
         (0..grid.size.total).map(|p| {
                                 let w = match voxel_map.voxel_get(p as isize) {
@@ -382,11 +524,8 @@ impl Analysis {
                                     }
                                     Voxel::Weight(weights) => {
                                         let mut w = None;
-                                        for maxima_weight in weights {
-                                            let maxima =
-                                                *maxima_weight as usize;
-                                            let weight =
-                                                maxima_weight - maxima as f64;
+                                        for (maxima, weight) in weights.iter()
+                                        {
                                             if maxima == maxima_out {
                                                 w = Some(weight);
                                                 break;
@@ -406,6 +545,7 @@ impl Analysis {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::progress::Silent;
 
     #[test]
     fn analysis_new_all_vacuum() {
@@ -420,4 +560,99 @@ mod test {
         let analysis = Analysis::new(&voxel_map, 0, 1);
         assert!(analysis.bader_maxima.is_empty())
     }
+
+    #[test]
+    fn analysis_output_label_map() {
+        let grid = Grid::new([2, 2, 2],
+                             [[2., 0., 0.], [0., 2., 0.], [0., 0., 2.]],
+                             1E-8,
+                             1E-6,
+                             None,
+                             [0.; 3]);
+        let voxel_map = VoxelMap::new(8);
+        voxel_map.maxima_store(0, 0);
+        voxel_map.maxima_store(7, 7);
+        let i = voxel_map.lock().push(vec![0.25, 7.75]);
+        voxel_map.weight_store(1, i);
+        let analysis = Analysis::new(&voxel_map, 1, 1);
+        let pbar = Box::new(Silent);
+        let labels =
+            analysis.output_label_map(&grid, &voxel_map, pbar).unwrap();
+        assert_eq!(labels[0], Some((0, 1.)));
+        assert_eq!(labels[1], Some((1, 0.75)));
+        assert_eq!(labels[2], None);
+        assert_eq!(labels[7], Some((1, 1.)));
+    }
+
+    #[test]
+    fn analysis_output_sum_map() {
+        let grid = Grid::new([2, 2, 2],
+                             [[2., 0., 0.], [0., 2., 0.], [0., 0., 2.]],
+                             1E-8,
+                             1E-6,
+                             None,
+                             [0.; 3]);
+        let voxel_map = VoxelMap::new(8);
+        voxel_map.maxima_store(0, 0);
+        voxel_map.maxima_store(6, 6);
+        voxel_map.maxima_store(7, 7);
+        let i = voxel_map.lock().push(vec![0.25, 6.25, 7.5]);
+        voxel_map.weight_store(1, i);
+        let mut analysis = Analysis::new(&voxel_map, 1, 2);
+        analysis.assigned_atom = vec![0, 1, 1];
+        let volumes = analysis.atoms_volumes(&[1]);
+        assert_eq!(volumes, vec![1, 2]);
+        let pbar = Box::new(Silent);
+        let map = analysis.output_sum_map(&grid, &voxel_map, &volumes, pbar)
+                          .unwrap();
+        assert_eq!(map[0], None);
+        assert_eq!(map[1], Some(0.75));
+        assert_eq!(map[2], None);
+        assert_eq!(map[6], Some(1.));
+        assert_eq!(map[7], Some(1.));
+    }
+
+    #[test]
+    fn analysis_output_sum_map_not_maxima() {
+        let grid = Grid::new([2, 2, 2],
+                             [[2., 0., 0.], [0., 2., 0.], [0., 0., 2.]],
+                             1E-8,
+                             1E-6,
+                             None,
+                             [0.; 3]);
+        let voxel_map = VoxelMap::new(8);
+        voxel_map.maxima_store(0, 0);
+        let mut analysis = Analysis::new(&voxel_map, 1, 1);
+        analysis.assigned_atom = vec![0];
+        // a voxel belonging to a maxima the analysis doesn't know of
+        voxel_map.maxima_store(1, 2);
+        let map =
+            analysis.output_sum_map(&grid, &voxel_map, &[0], Box::new(Silent));
+        let flag = matches!(map, Err(AnalysisError::NotMaxima));
+        assert!(flag);
+        let map =
+            analysis.output_atom_map(&grid, &voxel_map, 0, Box::new(Silent));
+        let flag = matches!(map, Err(AnalysisError::NotMaxima));
+        assert!(flag);
+    }
+
+    #[test]
+    fn analysis_output_label_map_not_maxima() {
+        let grid = Grid::new([2, 2, 2],
+                             [[2., 0., 0.], [0., 2., 0.], [0., 0., 2.]],
+                             1E-8,
+                             1E-6,
+                             None,
+                             [0.; 3]);
+        let voxel_map = VoxelMap::new(8);
+        voxel_map.maxima_store(0, 0);
+        let analysis = Analysis::new(&voxel_map, 1, 1);
+        // a boundary voxel partly belonging to an unknown maxima
+        let i = voxel_map.lock().push(vec![2.75, 0.25]);
+        voxel_map.weight_store(1, i);
+        let labels =
+            analysis.output_label_map(&grid, &voxel_map, Box::new(Silent));
+        let flag = matches!(labels, Err(AnalysisError::NotMaxima));
+        assert!(flag);
+    }
 }
@@ -1,5 +1,11 @@
-use crate::io::{FileType, WriteType};
-use clap::{crate_authors, App, Arg, ArgMatches};
+use crate::errors::Error;
+use crate::io::output::OutputPath;
+use crate::io::{FileType, OutputFormat, WriteType};
+use crate::progress::{Indicatif, JsonLines, Reporter, Silent};
+use crate::storage::Storage;
+use crate::upsample::Interpolation;
+use clap::{crate_authors, App, Arg, ArgMatches, ArgSettings};
+use std::path::PathBuf;
 
 /// Indicates how many reference files are passed
 #[derive(Clone)]
@@ -8,10 +14,34 @@ pub enum Reference {
     One(String),
     /// Two files as a reference, these files will be summed together.
     Two(String, String),
+    /// One of the multiple values per voxel in the density file, such as an
+    /// orbital in a cube file.
+    Column(usize),
     /// No reference, just use the density file.
     None,
 }
 
+/// How progress and messages are reported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Progress bars on stderr and messages on stdout.
+    Text,
+    /// A line of JSON on stderr for every event.
+    Json,
+    /// Nothing but errors.
+    Quiet,
+}
+
+/// The precision the densities and the weights of the boundary voxels are
+/// stored in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Precision {
+    /// f32, halving the memory of the densities.
+    Single,
+    /// f64.
+    Double,
+}
+
 /// Create a container for dealing with clap and being able to test arg parsing.
 pub enum ClapApp {}
 
@@ -22,7 +52,7 @@ impl<'a> ClapApp {
             .author(crate_authors!())
             .version("0.3.2")
             .arg(Arg::new("file")
-                .required(true)
+                .required_unless_present_any(&["config", "dump config"])
                 .index(1)
                 .about("The file to analyse."))
             .arg(Arg::new("output")
@@ -31,13 +61,64 @@ impl<'a> ClapApp {
                 .takes_value(true)
                 .possible_value("atoms")
                 .possible_value("volumes")
+                .possible_value("sum-atoms")
+                .possible_value("sum-volumes")
+                .possible_value("labels")
+                .possible_value("labels-vtk")
+                .possible_value("vtk")
                 .case_insensitive(false)
                 .about("Output the Bader atoms or volumes.")
                 .long_about(
 "Output the Bader atoms or the Bader volumes in the same file formtat as the
 input density. This can be used in conjunction with the index flag to specify a
 specific atoms or volumes. Without the index flag it will print all the atoms or
-volumes."))
+volumes. \"sum-atoms\" and \"sum-volumes\" instead write the atoms or volumes
+summed into a single density, such as that of an adsorbed molecule.
+\"labels\" writes the Bader volume and atom that each voxel belongs to, starting
+at 1 with 0 being vacuum, to volume_labels and atom_labels and the fraction of
+the voxel in that volume to boundary_weight, in the format of the input file
+without the units of a density. \"labels-vtk\" writes the same to
+bader_labels.vts. Passing \"vtk\" writes the density with the Bader volume, atom
+and boundary weight of each voxel to bader.vts and the atoms to bader_atoms.vtp
+for viewing in ParaView."))
+            .arg(Arg::new("format")
+                .short('f')
+                .long("format")
+                .multiple(true)
+                .number_of_values(1)
+                .possible_value("dat")
+                .possible_value("json")
+                .possible_value("csv")
+                .possible_value("xyz")
+                .possible_value("cif")
+                .default_value("dat")
+                .case_insensitive(false)
+                .about("Format(s) of the charge analysis files.")
+                .long_about(
+"The formats to write the results of the analysis in. \"dat\" writes the ACF.dat
+and BCF.dat tables, \"json\" writes every result with the metadata of the run to
+bader.json and \"csv\" writes ACF.csv and BCF.csv with the metadata as comments
+starting with \"#\". \"xyz\" and \"cif\" write the structure to bader.xyz, as
+extended XYZ, and bader.cif with the population of each density, the net charge
+and the volume of every atom. Multiple formats can be written by repeating the
+flag ie. bca CHGCAR -f dat -f json. By default only \"dat\" is written."))
+            .arg(Arg::new("directory")
+                .short('d')
+                .long("dir")
+                .takes_value(true)
+                .about("Directory to write the output files to.")
+                .long_about(
+"The directory to write every output file to, it is created if it doesn't
+exist before the density is read, so that one that can't be made fails early. By
+default files are written to the current directory."))
+            .arg(Arg::new("prefix")
+                .long("prefix")
+                .takes_value(true)
+                .about("Prefix added to the name of every output file.")
+                .long_about(
+"A prefix added to the start of the name of every output file, such that
+bca CHGCAR --prefix run_1_ writes run_1_ACF.dat and run_1_BCF.dat. Combined with
+the directory flag this stops runs in the same directory overwriting each other."))
             .arg(Arg::new("index")
                 .short('i')
                 .long("index")
@@ -55,11 +136,17 @@ repeating the flag ie. bca CHGCAR -o atoms -i 1 -i 2."))
                 .takes_value(true)
                 .possible_value("cube")
                 .possible_value("vasp")
+                .possible_value("xsf")
+                .possible_value("qe")
+                .possible_value("castep")
+                .possible_value("abinit")
+                .possible_value("siesta")
                 .case_insensitive(false)
                 .about("The file type of the charge density.")
                 .long_about(
 "The file type of the input file. If this is not supplied the type will attempt
-to be infered from the filename."))
+to be infered from the filename. Quantum ESPRESSO pp.x (filplot) files have no
+standard naming and so always require \"--type qe\"."))
             .arg(Arg::new("reference")
                 .short('r')
                 .long("ref")
@@ -71,6 +158,17 @@ to be infered from the filename."))
 "A reference charge to do the partitioning upon. Two files can be passed
 by using multiple flags (bca CHGCAR -r AECCAR0 -r AECCAR2). If two files are
 passed they are summed together."))
+            .arg(Arg::new("column")
+                .short('c')
+                .long("column")
+                .takes_value(true)
+                .conflicts_with_all(&["reference", "all electron"])
+                .about("Which value per voxel to partition upon.")
+                .long_about(
+"For files containing multiple values per voxel, such as a cube file of several
+orbitals, the index of the value to do the partitioning upon, starting at 1.
+Every value is then summed over the same Bader volumes. By default the first
+value is used."))
             .arg(Arg::new("spin")
                 .short('s')
                 .long("spin")
@@ -88,6 +186,29 @@ contain a single density (ie. the original file has been split)."))
                 .takes_value(false)
                 .multiple(false)
                 .conflicts_with("reference"))
+            .arg(Arg::new("potcar")
+                .short('p')
+                .long("potcar")
+                .takes_value(true)
+                .about("POTCAR to read the valence charges from.")
+                .long_about(
+"A POTCAR from which the valence charge (ZVAL) of each species is read. This is
+used to report the net charge of each atom, the valence charge minus the
+partitioned charge, in ACF.dat along with the average for each species. The
+species of the POTCAR are matched in order to those of the POSCAR, so it must
+have one entry for each species, as VASP requires."))
+            .arg(Arg::new("zval")
+                .short('z')
+                .long("zval")
+                .multiple(true)
+                .number_of_values(1)
+                .about("Valence charge of a species, as SYMBOL=VALUE.")
+                .long_about(
+"The valence charge of a species used to calculate net charges, passed as
+SYMBOL=VALUE (bca CHGCAR -z Ti=10 -z O=6). This takes precedence over the POTCAR
+and the nuclear charges in a cube file. When any valence charges are known
+every atom needs one. There is no built-in table, as the valence charge of an
+element depends on the pseudopotential used (Ti has 4, 10 or 12)."))
             .arg(Arg::new("vacuum tolerance")
                 .short('v')
                 .long("vac")
@@ -100,6 +221,7 @@ included in the calculation. A value of \"auto\" can be passed to use 1E-6 C*m^-
                 .short('m')
                 .long("maxima")
                 .takes_value(true)
+                .default_value("1E-6")
                 .about("Cut-off for charge at which a maxima is not printed.")
                 .long_about(
 "Values of charge for the Bader maxima below the supplied value are not written
@@ -108,6 +230,7 @@ to the Bader charge file (BCF.dat). A default value of 1E-6 is used."))
                 .short('w')
                 .long("weight")
                 .takes_value(true)
+                .default_value("1E-6")
                 .about("Cut-off at which contributions to the weighting will be ignored.")
                 .long_about(
 "Values of density below the supplied value are ignored from the weighting and
@@ -124,6 +247,440 @@ unaccounted for in the final partitions. Be sure to test this!"))
 "The number of threads to be used by the program. A default value of 0 is used
 to allow the program to best decide how to use the available hardware. It does
 this by using the minimum value out of the number cores available and 12."))
+            .arg(Arg::new("precision")
+                .long("precision")
+                .takes_value(true)
+                .possible_value("single")
+                .possible_value("double")
+                .default_value("double")
+                .case_insensitive(false)
+                .about("The precision to store the densities and weights in.")
+                .long_about(
+"The precision to store the densities and the weights of the boundary voxels
+in. \"single\" halves the memory of the densities, at the cost of rounding each
+value to 7 significant figures, and keeps each weight as a f32 beside the index
+of its maxima, whilst the gradients and charges are still calculated in double
+precision. On the test files the charge of each atom moves by less than 1E-8 of
+the total charge. The sorted index and voxel map stay at 8 bytes per voxel, so
+the peak memory of a single density falls by about a fifth."))
+            .arg(Arg::new("coarse")
+                .long("coarse")
+                .takes_value(true)
+                .about("Partition a grid downsampled by this factor first.")
+                .long_about(
+"Partition a grid downsampled by the supplied factor, which must divide every
+dimension of the grid, first. Voxels whose coarse neighbours all belong to one
+maxima inherit it and the weight method is only re-run at full resolution near
+the coarse boundaries. This is faster for large grids but maxima smaller than
+the coarse voxels can be missed, check the charges against a full run."))
+            .arg(Arg::new("upsample")
+                .long("upsample")
+                .takes_value(true)
+                .about("Upsample the densities onto a grid this much finer.")
+                .long_about(
+"Upsample the reference and every density onto a grid finer by the supplied
+factor along each lattice vector of the periodic cell before partitioning. The
+original voxels keep their values and the charge is conserved. The output
+densities are only written as VTK files (-o vtk or labels-vtk) as the header of
+the density file describes the original grid."))
+            .arg(Arg::new("interpolation")
+                .long("interpolation")
+                .takes_value(true)
+                .possible_value("fourier")
+                .possible_value("tricubic")
+                .default_value("fourier")
+                .case_insensitive(false)
+                .about("How to interpolate the densities with --upsample.")
+                .long_about(
+"How to interpolate the densities with --upsample. \"fourier\" zero-pads the
+Fourier transform of the density, which is exact for a plane-wave density but
+rings near sharp features, whilst \"tricubic\" fits a periodic cubic along each
+lattice vector that only reaches two voxels either side."))
+            .arg(Arg::new("write upsampled")
+                .long("write-upsampled")
+                .takes_value(false)
+                .requires("upsample")
+                .about("Write the upsampled densities to upsampled.vts.")
+                .long_about(
+"Write the upsampled reference and densities to a VTK XML StructuredGrid,
+upsampled.vts, in the output directory for inspection."))
+            .arg(Arg::new("scratch")
+                .long("scratch")
+                .takes_value(true)
+                .about("Memory-map the large arrays to files in a directory.")
+                .long_about(
+"Memory-map the densities, the sorted index and the voxel map to scratch files
+in the supplied directory, so that grids larger than the memory available can be
+partitioned. The densities are read straight into their files. The voxels are
+partitioned in bands of similar density, each swept through in order of
+position, so the reference and voxel map are read forwards through their files.
+The files are deleted at the end of the run. Use a fast local disk."))
+            .arg(Arg::new("max resident")
+                .long("max-resident")
+                .takes_value(true)
+                .requires("scratch")
+                .about("Cap on the resident memory with --scratch.")
+                .long_about(
+"The resident memory above which the scratch files of --scratch are written back
+and released during partitioning, in bytes or with a suffix of K, M, G or T
+(powers of 1024), eg. 8G. Only read where the operating system reports the
+resident memory of a process (Linux)."))
+            .arg(Arg::new("max memory")
+                .long("max-memory")
+                .takes_value(true)
+                .about("The memory the calculation may use.")
+                .long_about(
+"The memory the calculation may use, in bytes or with a suffix of K, M, G or T
+(powers of 1024), eg. 8G. The expected peak memory is printed from the header of
+the input, before any density is read, and the calculation stops if it is above
+this or the memory available."))
+            .arg(Arg::new("checkpoint")
+                .long("checkpoint")
+                .takes_value(true)
+                .conflicts_with("load")
+                .about("Save the partition to a checkpoint file.")
+                .long_about(
+"Save the partitioned voxels, the grid and the atoms to a binary checkpoint file
+after partitioning. The file can be loaded with --load to write different
+outputs without partitioning again."))
+            .arg(Arg::new("load")
+                .long("load")
+                .takes_value(true)
+                .about("Load the partition from a checkpoint file.")
+                .long_about(
+"Load the partition from a checkpoint file written with --checkpoint instead of
+partitioning the density. The input files must still be passed, to sum their
+charge, and are refused if they differ from those that were partitioned. The
+weight and vacuum tolerances have to match those of the checkpoint, any other
+flag such as the output, index, format or maxima tolerance can be changed."))
+            .arg(Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .takes_value(false)
+                .about("Print nothing but errors.")
+                .long_about(
+"Print nothing but errors, without progress bars or messages. This takes
+precedence over the log format."))
+            .arg(Arg::new("log format")
+                .long("log-format")
+                .takes_value(true)
+                .possible_value("text")
+                .possible_value("json")
+                .default_value("text")
+                .case_insensitive(false)
+                .about("How to report progress and messages.")
+                .long_about(
+"How to report progress and messages. \"text\" draws progress bars on stderr and
+prints messages to stdout. \"json\" writes a line of JSON to stderr for every
+message and at the start, end and every tenth of each stage, with the stage,
+the fraction done and the seconds elapsed, for log collectors."))
+            .arg(Arg::new("config")
+                .long("config")
+                .takes_value(true)
+                .about("TOML or JSON file of settings.")
+                .long_about(
+"A file of settings, read as JSON if it ends in .json and as TOML otherwise.
+Every flag can be set using its name, with spaces replaced by underscores, or
+its long form, ie. file = \"CHGCAR\", vacuum_tolerance = 1E-4,
+format = [\"dat\", \"json\"] or all_electron = true. Flags passed on the
+command-line replace the values in the file, but a switch set to true in the
+file can't be turned off from the command-line, set it to false or leave it
+out of the file instead. Paths are relative to the working directory."))
+            .arg(Arg::new("dump config")
+                .long("dump-config")
+                .takes_value(false)
+                .about("Print the settings as TOML and exit.")
+                .long_about(
+"Print the settings that would be used, combining the config file, the
+command-line and the defaults, as TOML and exit without reading any files. The
+output can be saved and passed back with --config."))
+    }
+}
+
+/// The values of each argument read from a config file. Flags set to true
+/// have no values and flags set to false are left out, as if unset.
+pub type ConfigValues = Vec<(String, Vec<String>)>;
+
+/// The name of an argument as used in a config file, spaces replaced by
+/// underscores.
+fn config_key(name: &str) -> String {
+    name.replace(' ', "_")
+}
+
+/// Converts a single TOML value into the text of a command-line value.
+fn toml_value(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(i) => Some(i.to_string()),
+        toml::Value::Float(f) => Some(f.to_string()),
+        _ => None,
+    }
+}
+
+/// Converts a single JSON value into the text of a command-line value.
+fn json_value(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// Parses the text of a config file into the values of each key, sorted by
+/// key for both JSON and TOML rather than in the order they appear.
+///
+/// * `text`: The contents of the config file.
+/// * `json`: Whether the text is JSON rather than TOML.
+///
+/// ### Examples
+/// ```
+/// use bader::arguments::parse_config;
+///
+/// let text = "file = \"CHGCAR\"\nformat = [\"dat\", \"json\"]\naec = true";
+/// let values = parse_config(text, false).unwrap();
+/// assert_eq!(values,
+///            vec![(String::from("aec"), vec![]),
+///                 (String::from("file"), vec![String::from("CHGCAR")]),
+///                 (String::from("format"),
+///                  vec![String::from("dat"), String::from("json")])]);
+/// ```
+pub fn parse_config(text: &str, json: bool) -> Result<ConfigValues, Error> {
+    let mut values = Vec::new();
+    // each value is converted to text, with booleans kept apart as flags
+    let mut push = |key: &str,
+                    flag: Option<bool>,
+                    value: Vec<Option<String>>|
+     -> Result<(), Error> {
+        match flag {
+            Some(true) => values.push((String::from(key), vec![])),
+            Some(false) => (),
+            None => {
+                let value = value.into_iter()
+                                 .collect::<Option<Vec<String>>>()
+                                 .ok_or_else(|| {
+                                     Error::parse(0,
+                                                  format!("Unsupported value \
+                                                           for {}.",
+                                                          key))
+                                 })?;
+                values.push((String::from(key), value));
+            }
+        }
+        Ok(())
+    };
+    if json {
+        let table = match serde_json::from_str(text) {
+            Ok(serde_json::Value::Object(table)) => table,
+            Ok(_) => {
+                return Err(Error::parse(1, "Expected a JSON object."))
+            }
+            Err(e) => return Err(Error::parse(e.line(), e.to_string())),
+        };
+        for (key, value) in table.iter() {
+            match value {
+                serde_json::Value::Null => Ok(()),
+                serde_json::Value::Bool(b) => push(key, Some(*b), vec![]),
+                serde_json::Value::Array(a) => {
+                    push(key, None, a.iter().map(json_value).collect())
+                }
+                v => push(key, None, vec![json_value(v)]),
+            }?;
+        }
+    } else {
+        let table = match text.parse::<toml::Value>() {
+            Ok(toml::Value::Table(table)) => table,
+            Ok(_) => return Err(Error::parse(1, "Expected a TOML table.")),
+            Err(e) => {
+                let line = e.line_col().map_or(0, |(l, _)| l + 1);
+                let message = e.to_string();
+                let message = match message.find(" at line ") {
+                    Some(i) => &message[..i],
+                    None => &message,
+                };
+                return Err(Error::parse(line, message));
+            }
+        };
+        for (key, value) in table.iter() {
+            match value {
+                toml::Value::Boolean(b) => push(key, Some(*b), vec![]),
+                toml::Value::Array(a) => {
+                    push(key, None, a.iter().map(toml_value).collect())
+                }
+                v => push(key, None, vec![toml_value(v)]),
+            }?;
+        }
+    }
+    Ok(values)
+}
+
+/// Reads a config file, as JSON if the name ends in .json and TOML otherwise.
+///
+/// * `filename`: The config file to read.
+pub fn read_config(filename: &str) -> Result<ConfigValues, Error> {
+    let text = std::fs::read_to_string(filename).map_err(|e| {
+                                                    Error::from(e)
+                                                    .in_file(filename)
+                                                })?;
+    let json = filename.to_lowercase().ends_with(".json");
+    parse_config(&text, json).map_err(|e| e.in_file(filename))
+}
+
+/// Fills every argument not passed on the command-line with the value from
+/// the config file, if one was passed with --config.
+///
+/// Switches have no negated form, so one set to true in the file stays on
+/// whatever the command-line.
+///
+/// * `matches`: The arguments parsed from `argv`.
+/// * `argv`: The command-line, starting with the name of the program.
+///
+/// ### Returns:
+/// `Result<ArgMatches, Error>`: The combined arguments, or an
+/// [`Error::Argument`] if a key of the file isn't an argument, or a value is
+/// invalid, and an [`Error::Parse`] if the file can't be read.
+pub fn apply_config(matches: ArgMatches,
+                    argv: Vec<String>)
+                    -> Result<ArgMatches, Error> {
+    let filename = match matches.value_of("config") {
+        Some(f) => String::from(f),
+        None => return Ok(matches),
+    };
+    let app = ClapApp::get();
+    let mut config_argv = vec![argv.first().cloned().unwrap_or_default()];
+    for (key, values) in read_config(&filename)? {
+        let name = key.replace('-', "_");
+        let arg = app.get_arguments().find(|a| {
+                                         config_key(a.get_name()) == name
+                                         || a.get_long()
+                                             .map(config_key)
+                                             .map_or(false, |l| l == name)
+                                     });
+        let arg = match arg {
+            Some(a) if !["config", "dump config"].contains(&a.get_name()) => a,
+            _ => {
+                return Err(Error::Argument(format!("Unknown setting, ({}), \
+                                                    in {}.",
+                                                   key, filename)))
+            }
+        };
+        // the command-line takes precedence
+        if matches.occurrences_of(arg.get_name()) > 0 {
+            continue;
+        }
+        let takes_value =
+            arg.get_index().is_some() || arg.is_set(ArgSettings::TakesValue);
+        if takes_value == values.is_empty() {
+            return Err(Error::Argument(format!("Setting, ({}), in {} {}.",
+                                               key,
+                                               filename,
+                                               if takes_value {
+                                                   "requires a value"
+                                               } else {
+                                                   "must be true or false"
+                                               })));
+        }
+        match arg.get_long() {
+            Some(long) if takes_value => {
+                for v in values {
+                    config_argv.push(format!("--{}={}", long, v));
+                }
+            }
+            Some(long) => config_argv.push(format!("--{}", long)),
+            None => config_argv.extend(values),
+        }
+    }
+    config_argv.extend(argv.into_iter().skip(1));
+    app.try_get_matches_from(config_argv).map_err(|e| {
+        // drop the usage that follows the message
+        let message = e.to_string();
+        let message = message.split("\n\n")
+                             .next()
+                             .unwrap_or("")
+                             .trim_start_matches("error: ");
+        Error::Argument(format!("Invalid setting in {}: {}", filename, message))
+    })
+}
+
+/// Writes the value of every argument as TOML that can be read back with
+/// --config, including the defaults.
+///
+/// * `matches`: The parsed arguments.
+///
+/// ### Examples
+/// ```
+/// use bader::arguments::{dump_config, ClapApp};
+///
+/// let app = ClapApp::get();
+/// let matches = app.get_matches_from(vec!["bca", "CHGCAR", "-v", "auto"]);
+/// let dump = dump_config(&matches);
+/// assert!(dump.starts_with("file = \"CHGCAR\"\n"));
+/// assert!(dump.contains("vacuum_tolerance = \"auto\"\n"));
+/// assert!(dump.contains("threads = 0\n"));
+/// ```
+pub fn dump_config(matches: &ArgMatches) -> String {
+    let value = |v: &str| {
+        // keep text such as "01" that wouldn't read back the same
+        match (v.parse::<i64>(), v.parse::<f64>()) {
+            (Ok(i), _) if i.to_string() == v => toml::Value::Integer(i),
+            (Err(_), Ok(f)) if f.is_finite() => toml::Value::Float(f),
+            _ => toml::Value::String(String::from(v)),
+        }
+    };
+    let mut dump = String::new();
+    for arg in ClapApp::get().get_arguments() {
+        let name = arg.get_name();
+        if ["config", "dump config"].contains(&name)
+           || !matches.is_present(name)
+        {
+            continue;
+        }
+        let toml_value = if arg.get_index().is_none()
+                            && !arg.is_set(ArgSettings::TakesValue)
+        {
+            toml::Value::Boolean(true)
+        } else if arg.is_set(ArgSettings::MultipleOccurrences) {
+            let values = matches.values_of(name)
+                                .map_or(vec![], |v| v.map(value).collect());
+            toml::Value::Array(values)
+        } else {
+            value(matches.value_of(name).unwrap_or(""))
+        };
+        dump.push_str(&format!("{} = {}\n", config_key(name), toml_value));
+    }
+    dump
+}
+
+/// Parses an amount of memory, in bytes or with a suffix of K, M, G or T for
+/// powers of 1024.
+///
+/// ### Examples
+/// ```
+/// use bader::arguments::parse_size;
+///
+/// assert_eq!(parse_size("512").unwrap(), 512);
+/// assert_eq!(parse_size("1.5K").unwrap(), 1536);
+/// assert_eq!(parse_size("8g").unwrap(), 8 << 30);
+/// assert!(parse_size("lots").is_err());
+/// ```
+pub fn parse_size(size: &str) -> Result<u64, Error> {
+    let size = size.trim();
+    let (number, power) = match size.chars().last().map(|c| {
+                                      c.to_ascii_uppercase()
+                                  }) {
+        Some('K') => (&size[..size.len() - 1], 1),
+        Some('M') => (&size[..size.len() - 1], 2),
+        Some('G') => (&size[..size.len() - 1], 3),
+        Some('T') => (&size[..size.len() - 1], 4),
+        _ => (size, 0),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(x) if x.is_finite() && x >= 0. => {
+            Ok((x * 1024f64.powi(power)).round() as u64)
+        }
+        _ => Err(Error::Argument(format!("Unable to parse size, ({}), as \
+                                          bytes with an optional K, M, G or \
+                                          T.",
+                                         size))),
     }
 }
 
@@ -139,19 +696,48 @@ pub struct Args {
     pub maxima_tolerance: f64,
     /// Output Writing
     pub output: WriteType,
+    /// The formats of the charge analysis files.
+    pub formats: Vec<OutputFormat>,
+    /// Where to write the output files.
+    pub output_path: OutputPath,
     /// Is there a reference file.
     pub reference: Reference,
     /// Is there a spin density to include as well.
     pub spin: Option<String>,
+    /// A POTCAR to read valence charges from.
+    pub potcar: Option<String>,
+    /// User supplied valence charges for each species.
+    pub zval: Vec<(String, f64)>,
     /// How many threads to use in the calculation.
     pub threads: usize,
     /// Is there a tolerance to consider a density vacuum.
     pub vacuum_tolerance: Option<f64>,
+    /// How to report progress and messages.
+    pub log_format: LogFormat,
+    /// The precision to store the densities in.
+    pub precision: Precision,
+    /// Where to keep the densities, index and voxel map.
+    pub storage: Storage,
+    /// The factor to downsample the grid by for a coarse partition first.
+    pub coarse: Option<usize>,
+    /// The factor to upsample the densities by before partitioning.
+    pub upsample: Option<usize>,
+    /// How to interpolate the upsampled densities.
+    pub interpolation: Interpolation,
+    /// Whether to write the upsampled densities.
+    pub write_upsampled: bool,
+    /// The memory the calculation may use, if limited.
+    pub max_memory: Option<u64>,
+    /// A file to save the partition to.
+    pub checkpoint: Option<String>,
+    /// A file to load the partition from instead of partitioning.
+    pub load: Option<String>,
 }
 
 impl Args {
-    /// Initialises the structure from the command-line arguments.
-    pub fn new(arguments: ArgMatches) -> Self {
+    /// Initialises the structure from the command-line arguments, returning
+    /// an [`Error::Argument`] if any can't be parsed.
+    pub fn new(arguments: ArgMatches) -> Result<Self, Error> {
         // Collect file
         let file = match arguments.value_of("file") {
             Some(f) => String::from(f),
@@ -159,82 +745,86 @@ impl Args {
         };
 
         // Collect write charge info
+        let index = match arguments.values_of("index") {
+            Some(vec) => vec.map(|s| match s.parse::<usize>() {
+                               Ok(u) => match u.checked_sub(1) {
+                                   Some(u) => Ok(u),
+                                   None => Err(Error::Argument(String::from(
+                                       "Counting for index starts at 1.",
+                                   ))),
+                               },
+                               Err(_) => {
+                                   Err(Error::Argument(format!(
+                                       "Unable to parse index, ({}) to usize.",
+                                       s
+                                   )))
+                               }
+                           })
+                           .collect::<Result<Vec<usize>, Error>>()?,
+            None => Vec::with_capacity(0),
+        };
         let output = match arguments.value_of("output") {
-            Some("atoms") => {
-                let atoms = match arguments.values_of("index") {
-                    Some(vec) => {
-                        vec.map(|s| match s.parse::<usize>() {
-                            Ok(u) => match u.checked_sub(1) {
-                                Some(u) => u,
-                                None => {
-                                    panic!("Counting for index starts at 1.")
-                                }
-                            },
-                            Err(_) => {
-                                panic!("Unable to parse index, ({}) to usize.",
-                                       s)
-                            }
-                        })
-                        .collect::<Vec<usize>>()
-                    }
-                    None => Vec::with_capacity(0),
-                };
-                WriteType::Atom(atoms)
-            }
-            Some("volumes") => {
-                let volumes = match arguments.values_of("index") {
-                    Some(vec) => {
-                        vec.map(|s| match s.parse::<usize>() {
-                            Ok(u) => match u.checked_sub(1) {
-                                Some(u) => u,
-                                None => {
-                                    panic!("Counting for index starts at 1.")
-                                }
-                            },
-                            Err(_) => {
-                                panic!("Unable to parse index, ({}) to usize.",
-                                       s)
-                            }
-                        })
-                        .collect::<Vec<usize>>()
-                    }
-                    None => Vec::with_capacity(0),
-                };
-                WriteType::Volume(volumes)
-            }
+            Some("atoms") => WriteType::Atom(index),
+            Some("volumes") => WriteType::Volume(index),
+            Some("sum-atoms") => WriteType::SumAtoms(index),
+            Some("sum-volumes") => WriteType::SumVolumes(index),
+            Some("labels") => WriteType::Labels,
+            Some("labels-vtk") => WriteType::LabelsVtk,
+            Some("vtk") => WriteType::Vtk,
             _ => WriteType::None,
         };
 
-        // Collect file type
-        let file_type = match arguments.value_of("file type") {
-            Some(f) => Some(String::from(f)),
-            None => None,
+        // Collect charge analysis formats
+        let formats = match arguments.values_of("format") {
+            Some(vec) => vec.map(|s| match s {
+                               "json" => OutputFormat::Json,
+                               "csv" => OutputFormat::Csv,
+                               "xyz" => OutputFormat::Xyz,
+                               "cif" => OutputFormat::Cif,
+                               _ => OutputFormat::Dat,
+                           })
+                           .collect::<Vec<OutputFormat>>(),
+            None => vec![OutputFormat::Dat],
         };
+
+        // Collect output directory and prefix
+        let output_path =
+            OutputPath::new(arguments.value_of("directory").unwrap_or(""),
+                            String::from(arguments.value_of("prefix")
+                                                  .unwrap_or("")));
+
+        // Collect file type
+        let file_type = arguments.value_of("file type").map(String::from);
         let file_type = match file_type {
             Some(ftype) => {
                 if ftype.eq("cube") {
                     FileType::Cube
+                } else if ftype.eq("xsf") {
+                    FileType::Xsf
+                } else if ftype.eq("qe") {
+                    FileType::Qe
+                } else if ftype.eq("castep") {
+                    FileType::Castep
+                } else if ftype.eq("abinit") {
+                    FileType::Abinit
+                } else if ftype.eq("siesta") {
+                    FileType::Siesta
                 } else {
                     FileType::Vasp
                 }
             }
-            None => {
-                if file.to_lowercase().contains("cube") {
-                    FileType::Cube
-                } else if file.to_lowercase().contains("car") {
-                    FileType::Vasp
-                } else {
-                    println!("Error: File-type cannot be infered, attempting to read as VASP");
-                    FileType::Vasp
-                }
-            }
+            // the caller warns when this falls back to VASP
+            None => FileType::infer(&file).unwrap_or(FileType::Vasp),
         };
         // Collect weight tolerance
         let weight_tolerance = match arguments.value_of("weight tolerance") {
             Some(x) => match x.parse::<f64>() {
                 Ok(x) => x,
                 Err(e) => {
-                    panic!("Couldn't parse weight tolerance into float:\n{}", e)
+                    return Err(Error::Argument(format!(
+                        "Couldn't parse weight tolerance into float:\n{}",
+                        e
+                    )))
                 }
             },
             _ => 1E-6,
@@ -244,7 +834,10 @@ impl Args {
             Some(x) => match x.parse::<f64>() {
                 Ok(x) => x,
                 Err(e) => {
-                    panic!("Couldn't parse maxima tolerance into float:\n{}", e)
+                    return Err(Error::Argument(format!(
+                        "Couldn't parse maxima tolerance into float:\n{}",
+                        e
+                    )))
                 }
             },
             _ => 1E-6,
@@ -255,7 +848,12 @@ impl Args {
             match arguments.value_of("threads").unwrap().parse::<usize>() {
                 Ok(0) => num_cpus::get().min(12),
                 Ok(x) => x,
-                Err(e) => panic!("Couldn't parse threads into integer:\n{}", e),
+                Err(e) => {
+                    return Err(Error::Argument(format!(
+                        "Couldn't parse threads into integer:\n{}",
+                        e
+                    )))
+                }
             }
         };
         // Collect vacuum tolerance
@@ -266,10 +864,13 @@ impl Args {
                 } else {
                     match s.parse::<f64>() {
                         Ok(x) => Some(x),
-                        Err(e) => panic!(
-                            "Couldn't parse vacuum tolerance into float:\n{}",
-                            e
-                        ),
+                        Err(e) => {
+                            return Err(Error::Argument(format!(
+                                "Couldn't parse vacuum tolerance into \
+                                 float:\n{}",
+                                e
+                            )))
+                        }
                     }
                 }
             }
@@ -280,7 +881,9 @@ impl Args {
             if let FileType::Vasp = file_type {
                 vec!["AECCAR0", "AECCAR2"]
             } else {
-                panic!("Error: Cannot use AECCAR flag for non VASP file-types.")
+                return Err(Error::Argument(String::from(
+                    "Cannot use AECCAR flag for non VASP file-types.",
+                )));
             }
         } else {
             match arguments.values_of("reference") {
@@ -292,21 +895,157 @@ impl Args {
             2 => Reference::Two(String::from(references[0]),
                                 String::from(references[1])),
             1 => Reference::One(String::from(references[0])),
-            _ => Reference::None,
+            _ => match arguments.value_of("column") {
+                Some(s) => match s.parse::<usize>() {
+                    Ok(u) => match u.checked_sub(1) {
+                        Some(u) => Reference::Column(u),
+                        None => {
+                            return Err(Error::Argument(String::from(
+                                "Counting for column starts at 1.",
+                            )))
+                        }
+                    },
+                    Err(_) => {
+                        return Err(Error::Argument(format!(
+                            "Unable to parse column, ({}) to usize.",
+                            s
+                        )))
+                    }
+                },
+                None => Reference::None,
+            },
+        };
+        let spin = arguments.value_of("spin").map(String::from);
+        // Collect valence charges
+        let potcar = arguments.value_of("potcar").map(String::from);
+        let zval = match arguments.values_of("zval") {
+            Some(vec) => vec.map(|s| {
+                               let mut split = s.splitn(2, '=');
+                               let label = split.next().unwrap().trim();
+                               match split.next()
+                                          .and_then(|z| z.trim().parse().ok())
+                               {
+                                   Some(z) => Ok((String::from(label), z)),
+                                   None => Err(Error::Argument(format!(
+                                       "Unable to parse zval, ({}) as \
+                                        SYMBOL=VALUE.",
+                                       s
+                                   ))),
+                               }
+                           })
+                           .collect::<Result<Vec<(String, f64)>, Error>>()?,
+            None => Vec::with_capacity(0),
         };
-        let spin = match arguments.value_of("spin") {
-            Some(x) => Some(String::from(x)),
+        // Collect logging
+        let log_format = if arguments.is_present("quiet") {
+            LogFormat::Quiet
+        } else if let Some("json") = arguments.value_of("log format") {
+            LogFormat::Json
+        } else {
+            LogFormat::Text
+        };
+        // Collect precision
+        let precision = match arguments.value_of("precision") {
+            Some("single") => Precision::Single,
+            _ => Precision::Double,
+        };
+        // Collect coarse factor
+        let coarse = match arguments.value_of("coarse") {
+            Some(s) => match s.parse::<usize>() {
+                Ok(f) if f > 1 => Some(f),
+                _ => {
+                    return Err(Error::Argument(format!(
+                        "Unable to parse coarse factor, ({}), as an integer \
+                         above 1.",
+                        s
+                    )))
+                }
+            },
             None => None,
         };
-        Self { file,
-               file_type,
-               weight_tolerance,
-               maxima_tolerance,
-               output,
-               reference,
-               threads,
-               spin,
-               vacuum_tolerance }
+        // Collect upsampling
+        let upsample = match arguments.value_of("upsample") {
+            Some(s) => match s.parse::<usize>() {
+                Ok(f) if f > 1 => Some(f),
+                _ => {
+                    return Err(Error::Argument(format!(
+                        "Unable to parse upsample factor, ({}), as an integer \
+                         above 1.",
+                        s
+                    )))
+                }
+            },
+            None => None,
+        };
+        // the headers of the density file describe the original grid
+        let native = !matches!(output,
+                               WriteType::None
+                               | WriteType::Vtk
+                               | WriteType::LabelsVtk);
+        if upsample.is_some() && native {
+            return Err(Error::Argument(String::from(
+                "Upsampled densities can only be written as VTK, use -o vtk \
+                 or -o labels-vtk.",
+            )));
+        }
+        let interpolation = match arguments.value_of("interpolation") {
+            Some("tricubic") => Interpolation::Tricubic,
+            _ => Interpolation::Fourier,
+        };
+        let write_upsampled = arguments.is_present("write upsampled");
+        // Collect storage
+        let storage = match arguments.value_of("scratch") {
+            Some(directory) => {
+                let max_resident = match arguments.value_of("max resident") {
+                    Some(s) => Some(parse_size(s)?),
+                    None => None,
+                };
+                Storage::Mapped { directory: PathBuf::from(directory),
+                                  max_resident }
+            }
+            None => Storage::Memory,
+        };
+        let max_memory = match arguments.value_of("max memory") {
+            Some(s) => Some(parse_size(s)?),
+            None => None,
+        };
+        // Collect checkpoint files
+        let checkpoint = arguments.value_of("checkpoint").map(String::from);
+        let load = arguments.value_of("load").map(String::from);
+        Ok(Self { file,
+                  file_type,
+                  weight_tolerance,
+                  maxima_tolerance,
+                  output,
+                  formats,
+                  output_path,
+                  reference,
+                  threads,
+                  spin,
+                  potcar,
+                  zval,
+                  vacuum_tolerance,
+                  log_format,
+                  precision,
+                  storage,
+                  coarse,
+                  upsample,
+                  interpolation,
+                  write_upsampled,
+                  max_memory,
+                  checkpoint,
+                  load })
+    }
+
+    /// Creates the [`Reporter`] for the log format.
+    pub fn reporter(&self) -> Box<dyn Reporter> {
+        match self.log_format {
+            LogFormat::Text => Box::new(Indicatif),
+            LogFormat::Json => {
+                Box::new(JsonLines::new(Box::new(std::io::stderr())))
+            }
+            LogFormat::Quiet => Box::new(Silent),
+        }
     }
 }
 
@@ -324,7 +1063,7 @@ mod tests {
     fn argument_file() {
         let app = ClapApp::get();
         let matches = app.get_matches_from(vec!["bca", "CHGCAR"]);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         assert_eq!(args.file, String::from("CHGCAR"));
     }
 
@@ -340,7 +1079,7 @@ mod tests {
     fn argument_file_type_default_vasp() {
         let app = ClapApp::get();
         let matches = app.get_matches_from(vec!["bca", "CHGCAR"]);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         let flag = matches!(args.file_type, FileType::Vasp);
         assert!(flag);
     }
@@ -349,7 +1088,7 @@ mod tests {
     fn argument_file_type_default_unknown() {
         let app = ClapApp::get();
         let matches = app.get_matches_from(vec!["bca", "CHG"]);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         let flag = matches!(args.file_type, FileType::Vasp);
         assert!(flag);
     }
@@ -358,7 +1097,7 @@ mod tests {
     fn argument_file_type_vasp() {
         let app = ClapApp::get();
         let matches = app.get_matches_from(vec!["bca", "CHGCAR", "-t", "vasp"]);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         let flag = matches!(args.file_type, FileType::Vasp);
         assert!(flag);
     }
@@ -367,7 +1106,7 @@ mod tests {
     fn argument_file_type_default_cube() {
         let app = ClapApp::get();
         let matches = app.get_matches_from(vec!["bca", "charge.cube"]);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         let flag = matches!(args.file_type, FileType::Cube);
         assert!(flag);
     }
@@ -377,11 +1116,49 @@ mod tests {
         let app = ClapApp::get();
         let matches =
             app.get_matches_from(vec!["bca", "charge.cube", "--type", "cube",]);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         let flag = matches!(args.file_type, FileType::Cube);
         assert!(flag);
     }
 
+    #[test]
+    fn argument_file_type_default_xsf() {
+        let app = ClapApp::get();
+        let matches = app.get_matches_from(vec!["bca", "charge.xsf"]);
+        let args = Args::new(matches).unwrap();
+        let flag = matches!(args.file_type, FileType::Xsf);
+        assert!(flag);
+    }
+
+    #[test]
+    fn argument_file_type_xsf() {
+        let app = ClapApp::get();
+        let matches =
+            app.get_matches_from(vec!["bca", "charge.dat", "--type", "xsf",]);
+        let args = Args::new(matches).unwrap();
+        let flag = matches!(args.file_type, FileType::Xsf);
+        assert!(flag);
+    }
+
+    #[test]
+    fn argument_file_type_default_castep() {
+        let app = ClapApp::get();
+        let matches = app.get_matches_from(vec!["bca", "seed.den_fmt"]);
+        let args = Args::new(matches).unwrap();
+        let flag = matches!(args.file_type, FileType::Castep);
+        assert!(flag);
+    }
+
+    #[test]
+    fn argument_file_type_qe() {
+        let app = ClapApp::get();
+        let matches =
+            app.get_matches_from(vec!["bca", "charge.pp", "--type", "qe",]);
+        let args = Args::new(matches).unwrap();
+        let flag = matches!(args.file_type, FileType::Qe);
+        assert!(flag);
+    }
+
     #[test]
     #[should_panic]
     fn argument_file_type_not_type() {
@@ -395,7 +1172,7 @@ mod tests {
         let app = ClapApp::get();
         let matches =
             app.get_matches_from(vec!["bca", "CHGCAR", "-o", "atoms"]);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         match args.output {
             WriteType::Atom(v) => assert!(v.is_empty()),
             _ => panic!(),
@@ -407,13 +1184,119 @@ mod tests {
         let app = ClapApp::get();
         let matches =
             app.get_matches_from(vec!["bca", "CHGCAR", "-o", "volumes"]);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         match args.output {
             WriteType::Volume(v) => assert!(v.is_empty()),
             _ => panic!(),
         }
     }
 
+    #[test]
+    fn argument_output_sum_atoms() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "-o", "sum-atoms", "-i", "1", "-i", "3"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        match args.output {
+            WriteType::SumAtoms(v) => assert_eq!(v, vec![0, 2]),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn argument_output_sum_volumes() {
+        let app = ClapApp::get();
+        let matches =
+            app.get_matches_from(vec!["bca", "CHGCAR", "-o", "sum-volumes"]);
+        let args = Args::new(matches).unwrap();
+        match args.output {
+            WriteType::SumVolumes(v) => assert!(v.is_empty()),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn argument_output_labels() {
+        let app = ClapApp::get();
+        let matches =
+            app.get_matches_from(vec!["bca", "CHGCAR", "-o", "labels"]);
+        let args = Args::new(matches).unwrap();
+        let flag = matches!(args.output, WriteType::Labels);
+        assert!(flag)
+    }
+
+    #[test]
+    fn argument_output_labels_vtk() {
+        let app = ClapApp::get();
+        let matches =
+            app.get_matches_from(vec!["bca", "CHGCAR", "-o", "labels-vtk"]);
+        let args = Args::new(matches).unwrap();
+        let flag = matches!(args.output, WriteType::LabelsVtk);
+        assert!(flag)
+    }
+
+    #[test]
+    fn argument_output_vtk() {
+        let app = ClapApp::get();
+        let matches = app.get_matches_from(vec!["bca", "CHGCAR", "-o", "vtk"]);
+        let args = Args::new(matches).unwrap();
+        let flag = matches!(args.output, WriteType::Vtk);
+        assert!(flag);
+    }
+
+    #[test]
+    fn argument_format_default() {
+        let app = ClapApp::get();
+        let matches = app.get_matches_from(vec!["bca", "CHGCAR"]);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.formats, vec![OutputFormat::Dat])
+    }
+
+    #[test]
+    fn argument_format_multiple() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "-f", "json", "--format", "csv"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.formats, vec![OutputFormat::Json, OutputFormat::Csv])
+    }
+
+    #[test]
+    fn argument_format_structure() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "-f", "xyz", "-f", "cif"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.formats, vec![OutputFormat::Xyz, OutputFormat::Cif])
+    }
+
+    #[test]
+    fn argument_output_path_default() {
+        let app = ClapApp::get();
+        let matches = app.get_matches_from(vec!["bca", "CHGCAR"]);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.output_path.join("ACF.dat"),
+                   std::path::PathBuf::from("ACF.dat"))
+    }
+
+    #[test]
+    fn argument_output_path() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "-d", "results", "--prefix", "run_1_"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.output_path,
+                   OutputPath::new("results", String::from("run_1_")))
+    }
+
+    #[test]
+    #[should_panic]
+    fn argument_format_not_format() {
+        let app = ClapApp::get();
+        let _ = app.try_get_matches_from(vec!["bca", "CHGCAR", "-f", "xml"])
+                   .unwrap_or_else(|e| panic!("An error occurs: {}", e));
+    }
+
     #[test]
     #[should_panic]
     fn argument_output_not_output() {
@@ -427,7 +1310,7 @@ mod tests {
         let app = ClapApp::get();
         let matches = app.get_matches_from(vec!["bca", "CHGCAR", "-o",
                                                 "volumes", "-i", "1"]);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         match args.output {
             WriteType::Volume(v) => assert_eq!(v, vec![0]),
             _ => panic!(),
@@ -440,7 +1323,7 @@ mod tests {
         let matches = app.get_matches_from(vec!["bca", "CHGCAR", "-o",
                                                 "atoms", "--index", "1",
                                                 "-i", "3"]);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         match args.output {
             WriteType::Atom(v) => assert_eq!(v, vec![0, 2]),
             _ => panic!(),
@@ -448,12 +1331,11 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn argument_index_zero() {
         let app = ClapApp::get();
         let matches = app.get_matches_from(vec!["bca", "CHGCAR", "-o",
                                                 "atoms", "-i", "0"]);
-        let _ = Args::new(matches);
+        assert!(Args::new(matches).is_err())
     }
 
     #[test]
@@ -479,7 +1361,7 @@ mod tests {
                                                 "density.cube",
                                                 "-s",
                                                 "spin.cube",]);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         assert_eq!(args.spin, Some(String::from("spin.cube")))
     }
 
@@ -488,7 +1370,7 @@ mod tests {
         let app = ClapApp::get();
         let matches =
             app.get_matches_from(vec!["bca", "CHGCAR", "-r", "CHGCAR_sum"]);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         let flag = matches!(args.reference, Reference::One(_));
         assert!(flag)
     }
@@ -498,7 +1380,7 @@ mod tests {
         let app = ClapApp::get();
         let v = vec!["bca", "CHGCAR", "-r", "AECCAR0", "--ref", "AECCAR2"];
         let matches = app.get_matches_from(v);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         let flag = matches!(args.reference, Reference::Two(_, _));
         assert!(flag)
     }
@@ -508,17 +1390,63 @@ mod tests {
         let app = ClapApp::get();
         let v = vec!["bca", "CHGCAR"];
         let matches = app.get_matches_from(v);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         let flag = matches!(args.reference, Reference::None);
         assert!(flag)
     }
 
+    #[test]
+    fn argument_reference_column() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "orbitals.cube", "-c", "2"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        let flag = matches!(args.reference, Reference::Column(1));
+        assert!(flag)
+    }
+
+    #[test]
+    #[should_panic]
+    fn argument_reference_column_and_reference() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "orbitals.cube", "-c", "2", "-r", "ref.cube"];
+        let _ = app.try_get_matches_from(v)
+                   .unwrap_or_else(|e| panic!("An error occurs: {}", e));
+    }
+
+    #[test]
+    fn argument_potcar() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "-p", "POTCAR"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.potcar, Some(String::from("POTCAR")))
+    }
+
+    #[test]
+    fn argument_zval() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "-z", "Ti=10", "--zval", "O=6"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.zval,
+                   vec![(String::from("Ti"), 10.), (String::from("O"), 6.)])
+    }
+
+    #[test]
+    fn argument_zval_not_parse() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "-z", "Ti"];
+        let matches = app.get_matches_from(v);
+        assert!(Args::new(matches).is_err())
+    }
+
     #[test]
     fn argument_aeccar() {
         let app = ClapApp::get();
         let v = vec!["bca", "CHGCAR", "-a"];
         let matches = app.get_matches_from(v);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         let flag = match args.reference {
             Reference::Two(x, y) => (x == *"AECCAR0") && (y == *"AECCAR2"),
             _ => false,
@@ -527,12 +1455,11 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn argument_aeccar_cube() {
         let app = ClapApp::get();
         let v = vec!["bca", "charge.cube", "-a"];
         let matches = app.get_matches_from(v);
-        let _ = Args::new(matches);
+        assert!(Args::new(matches).is_err())
     }
 
     #[test]
@@ -540,7 +1467,7 @@ mod tests {
         let app = ClapApp::get();
         let v = vec!["bca", "CHGCAR", "-v", "auto"];
         let matches = app.get_matches_from(v);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         assert_eq!(args.vacuum_tolerance, Some(1E-6))
     }
 
@@ -549,17 +1476,16 @@ mod tests {
         let app = ClapApp::get();
         let v = vec!["bca", "CHGCAR", "--vac", "1E-4"];
         let matches = app.get_matches_from(v);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         assert_eq!(args.vacuum_tolerance, Some(1E-4))
     }
 
     #[test]
-    #[should_panic]
     fn argument_vacuum_tolerance_not_float() {
         let app = ClapApp::get();
         let v = vec!["bca", "CHGCAR", "-v", "0.00.1"];
         let matches = app.get_matches_from(v);
-        let _ = Args::new(matches);
+        assert!(Args::new(matches).is_err())
     }
 
     #[test]
@@ -567,17 +1493,16 @@ mod tests {
         let app = ClapApp::get();
         let v = vec!["bca", "CHGCAR", "--weight", "1E-4"];
         let matches = app.get_matches_from(v);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         assert_eq!(args.weight_tolerance, 1E-4)
     }
 
     #[test]
-    #[should_panic]
     fn argument_weight_tolerance_not_float() {
         let app = ClapApp::get();
         let v = vec!["bca", "CHGCAR", "-w", "0.00.1"];
         let matches = app.get_matches_from(v);
-        let _ = Args::new(matches);
+        assert!(Args::new(matches).is_err())
     }
 
     #[test]
@@ -585,17 +1510,16 @@ mod tests {
         let app = ClapApp::get();
         let v = vec!["bca", "CHGCAR", "--maxima", "1E-4"];
         let matches = app.get_matches_from(v);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         assert_eq!(args.maxima_tolerance, 1E-4)
     }
 
     #[test]
-    #[should_panic]
     fn argument_maxima_tolerance_not_float() {
         let app = ClapApp::get();
         let v = vec!["bca", "CHGCAR", "-m", "0.00.1"];
         let matches = app.get_matches_from(v);
-        let _ = Args::new(matches);
+        assert!(Args::new(matches).is_err())
     }
 
     #[test]
@@ -603,7 +1527,7 @@ mod tests {
         let app = ClapApp::get();
         let v = vec!["bca", "CHGCAR"];
         let matches = app.get_matches_from(v);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         let threads = num_cpus::get().min(12);
         assert_eq!(args.threads, threads)
     }
@@ -613,16 +1537,242 @@ mod tests {
         let app = ClapApp::get();
         let v = vec!["bca", "CHGCAR", "--threads", "1"];
         let matches = app.get_matches_from(v);
-        let args = Args::new(matches);
+        let args = Args::new(matches).unwrap();
         assert_eq!(args.threads, 1)
     }
 
     #[test]
-    #[should_panic]
     fn argument_threads_not_int() {
         let app = ClapApp::get();
         let v = vec!["bca", "CHGCAR", "-J", "0.1"];
         let matches = app.get_matches_from(v);
-        let _ = Args::new(matches);
+        assert!(Args::new(matches).is_err())
+    }
+
+    #[test]
+    fn argument_precision_default() {
+        let app = ClapApp::get();
+        let matches = app.get_matches_from(vec!["bca", "CHGCAR"]);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.precision, Precision::Double)
+    }
+
+    #[test]
+    fn argument_precision_single() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--precision", "single"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.precision, Precision::Single)
+    }
+
+    #[test]
+    fn argument_coarse() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--coarse", "2"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.coarse, Some(2))
+    }
+
+    #[test]
+    fn argument_coarse_one() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--coarse", "1"];
+        let matches = app.get_matches_from(v);
+        assert!(Args::new(matches).is_err())
+    }
+
+    #[test]
+    fn argument_upsample() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--upsample", "2", "--interpolation",
+                     "tricubic", "--write-upsampled"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.upsample, Some(2));
+        assert_eq!(args.interpolation, Interpolation::Tricubic);
+        assert!(args.write_upsampled)
+    }
+
+    #[test]
+    fn argument_upsample_native_output() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--upsample", "2", "-o", "atoms"];
+        let matches = app.get_matches_from(v);
+        assert!(Args::new(matches).is_err())
+    }
+
+    #[test]
+    fn argument_scratch() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--scratch", "/tmp", "--max-resident",
+                     "2G"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.storage,
+                   Storage::Mapped { directory: PathBuf::from("/tmp"),
+                                     max_resident: Some(2 << 30) })
+    }
+
+    #[test]
+    #[should_panic]
+    fn argument_max_resident_no_scratch() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--max-resident", "2G"];
+        let _ = app.try_get_matches_from(v)
+                   .unwrap_or_else(|e| panic!("An error occurs: {}", e));
+    }
+
+    #[test]
+    fn argument_max_resident_bad() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--scratch", ".", "--max-resident", "2Q"];
+        let matches = app.get_matches_from(v);
+        assert!(Args::new(matches).is_err())
+    }
+
+    #[test]
+    fn argument_max_memory() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--max-memory", "512M"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.max_memory, Some(512 << 20))
+    }
+
+    #[test]
+    fn argument_checkpoint() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--checkpoint", "bader.chk"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.checkpoint, Some(String::from("bader.chk")));
+        assert_eq!(args.load, None)
+    }
+
+    #[test]
+    fn argument_load() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--load", "bader.chk"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.load, Some(String::from("bader.chk")))
+    }
+
+    #[test]
+    #[should_panic]
+    fn argument_checkpoint_and_load() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--checkpoint", "a", "--load", "b"];
+        let _ = app.try_get_matches_from(v)
+                   .unwrap_or_else(|e| panic!("An error occurs: {}", e));
+    }
+
+    #[test]
+    fn argument_log_format_default() {
+        let app = ClapApp::get();
+        let matches = app.get_matches_from(vec!["bca", "CHGCAR"]);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.log_format, LogFormat::Text)
+    }
+
+    #[test]
+    fn argument_log_format_json() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--log-format", "json"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.log_format, LogFormat::Json)
+    }
+
+    #[test]
+    fn argument_quiet() {
+        let app = ClapApp::get();
+        let v = vec!["bca", "CHGCAR", "--log-format", "json", "-q"];
+        let matches = app.get_matches_from(v);
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.log_format, LogFormat::Quiet)
+    }
+
+    #[test]
+    fn argument_config() {
+        let v = vec!["bca", "--config", "tests/config/bader.toml"];
+        let argv = v.iter().map(|s| String::from(*s)).collect::<Vec<_>>();
+        let matches = ClapApp::get().get_matches_from(v);
+        let matches = apply_config(matches, argv).unwrap();
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.file, String::from("CHGCAR"));
+        assert_eq!(args.formats, vec![OutputFormat::Dat, OutputFormat::Json]);
+        assert_eq!(args.vacuum_tolerance, Some(1E-4));
+        assert_eq!(args.threads, 2);
+        let flag = matches!(args.reference, Reference::Two(_, _));
+        assert!(flag)
+    }
+
+    #[test]
+    fn argument_config_overridden() {
+        let v = vec!["bca",
+                     "CHG",
+                     "--config",
+                     "tests/config/bader.toml",
+                     "-f",
+                     "csv",
+                     "-J",
+                     "1"];
+        let argv = v.iter().map(|s| String::from(*s)).collect::<Vec<_>>();
+        let matches = ClapApp::get().get_matches_from(v);
+        let matches = apply_config(matches, argv).unwrap();
+        let args = Args::new(matches).unwrap();
+        assert_eq!(args.file, String::from("CHG"));
+        assert_eq!(args.formats, vec![OutputFormat::Csv]);
+        assert_eq!(args.vacuum_tolerance, Some(1E-4));
+        assert_eq!(args.threads, 1)
+    }
+
+    #[test]
+    fn argument_config_json() {
+        let text = "{\"ref\": [\"AECCAR0\", \"AECCAR2\"], \"aec\": true}";
+        let values = parse_config(text, true).unwrap();
+        assert_eq!(values,
+                   vec![(String::from("aec"), vec![]),
+                        (String::from("ref"),
+                         vec![String::from("AECCAR0"),
+                              String::from("AECCAR2")])]);
+    }
+
+    #[test]
+    fn argument_config_false() {
+        let text = "aec = false\nquiet = true";
+        let values = parse_config(text, false).unwrap();
+        assert_eq!(values, vec![(String::from("quiet"), vec![])]);
+    }
+
+    #[test]
+    fn argument_config_bad_json() {
+        let text = "{\"ref\": [\"AECCAR0\",\n \"AECCAR2\"}";
+        let flag = matches!(parse_config(text, true),
+                            Err(Error::Parse { line: 2, .. }));
+        assert!(flag)
+    }
+
+    #[test]
+    fn argument_dump_config() {
+        let v = vec!["bca", "--config", "tests/config/bader.toml"];
+        let argv = v.iter().map(|s| String::from(*s)).collect::<Vec<_>>();
+        let matches = ClapApp::get().get_matches_from(v);
+        let matches = apply_config(matches, argv).unwrap();
+        assert_eq!(dump_config(&matches),
+                   "file = \"CHGCAR\"
+format = [\"dat\", \"json\"]
+all_electron = true
+vacuum_tolerance = 0.0001
+maxima_tolerance = 0.000001
+weight_tolerance = 0.000001
+threads = 2
+precision = \"double\"
+interpolation = \"fourier\"
+log_format = \"text\"
+");
     }
 }
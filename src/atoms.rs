@@ -1,13 +1,30 @@
+use crate::elements;
 use crate::utils;
 
+/// The records of a binary (Fortran unformatted) header, kept when it is read
+/// so that the writer of the format can copy them.
+#[derive(Default)]
+pub struct Header {
+    /// Whether the records are big-endian.
+    pub big_endian: bool,
+    /// The records, without their length markers.
+    pub records: Vec<Vec<u8>>,
+}
+
 /// struct for containing the information about the atoms.
 pub struct Atoms {
     /// The lattice of the structure.
     pub lattice: Lattice,
     /// The positions of the atoms in cartesian coordinates.
     pub positions: Vec<[f64; 3]>,
+    /// The atomic number of each atom, 0 if unknown.
+    pub atomic_numbers: Vec<usize>,
+    /// The element symbol of each atom, "X" if unknown.
+    pub symbols: Vec<String>,
     /// Text representation from the input file.
     pub text: String,
+    /// The header of a binary input file, empty for text formats.
+    pub header: Header,
     /// The LLL-reduced lattice for the structure.
     pub reduced_lattice: ReducedLattice,
     /// The positions of the atoms in the LLL-reduced basis.
@@ -15,11 +32,17 @@ pub struct Atoms {
 }
 
 impl Atoms {
-    /// Initialises the structure.
+    /// Initialises the structure with an empty [`Header`]. Missing atomic
+    /// numbers are treated as unknown.
     pub fn new(lattice: Lattice,
                positions: Vec<[f64; 3]>,
+               mut atomic_numbers: Vec<usize>,
                text: String)
                -> Self {
+        atomic_numbers.resize(positions.len(), 0);
+        let symbols = atomic_numbers.iter()
+                                    .map(|z| String::from(elements::symbol(*z)))
+                                    .collect::<Vec<String>>();
         let reduced_lattice = ReducedLattice::from_lattice(&lattice);
         let reduced_positions =
             positions.iter()
@@ -34,9 +57,12 @@ impl Atoms {
                      .collect::<Vec<[f64; 3]>>();
         Self { lattice,
                positions,
+               atomic_numbers,
+               symbols,
                reduced_lattice,
                reduced_positions,
-               text }
+               text,
+               header: Header::default() }
     }
 }
 
@@ -86,7 +112,30 @@ impl Lattice {
     ///     [cx, cy, cz],
     /// ]
     /// </pre>
+    ///
+    /// # Panics
+    /// If the lattice doesn't span 3D space, use [`Lattice::try_new`] for a
+    /// lattice read from a file.
     pub fn new(lattice: [[f64; 3]; 3]) -> Self {
+        match Self::try_new(lattice) {
+            Ok(lattice) => lattice,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Initialises the structure as [`Lattice::new`] does, returning an error
+    /// message rather than panicking if the lattice doesn't span 3D space.
+    ///
+    /// # Examples
+    /// ```
+    /// use bader::atoms::Lattice;
+    ///
+    /// assert!(Lattice::try_new([[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]])
+    ///         .is_ok());
+    /// assert!(Lattice::try_new([[1., 0., 0.], [1., 0., 0.], [0., 0., 1.]])
+    ///         .is_err());
+    /// ```
+    pub fn try_new(lattice: [[f64; 3]; 3]) -> Result<Self, String> {
         let a_vector = utils::norm(lattice[0]);
         let b_vector = utils::norm(lattice[1]);
         let c_vector = utils::norm(lattice[2]);
@@ -133,10 +182,7 @@ impl Lattice {
         for i in 0..26 {
             distance_matrix[i] = utils::norm(shift_matrix[i]);
         }
-        let to_fractional = match utils::invert_lattice(&lattice) {
-            Ok(inv) => inv,
-            Err(e) => panic!("{}", e),
-        };
+        let to_fractional = utils::invert_lattice(&lattice)?;
         let to_cartesian = lattice;
         let volume = {
             (lattice[0][0]
@@ -150,15 +196,15 @@ impl Lattice {
                                                    .abs()
         };
         let gradient_transform = utils::transpose_square(to_fractional);
-        Self { a: a_vector,
-               b: b_vector,
-               c: c_vector,
-               distance_matrix,
-               shift_matrix,
-               gradient_transform,
-               to_fractional,
-               to_cartesian,
-               volume }
+        Ok(Self { a: a_vector,
+                  b: b_vector,
+                  c: c_vector,
+                  distance_matrix,
+                  shift_matrix,
+                  gradient_transform,
+                  to_fractional,
+                  to_cartesian,
+                  volume })
     }
 }
 
@@ -228,8 +274,9 @@ impl ReducedLattice {
                 match mu[i][j] {
                     q if q.abs() <= 0.5 => (),
                     q => {
-                        for k in 0..3 {
-                            a[i][k] -= q.round() * a[j][k];
+                        let a_j = a[j];
+                        for (a_ik, a_jk) in a[i].iter_mut().zip(&a_j) {
+                            *a_ik -= q.round() * a_jk;
                         }
                         let (b_temp, mu_temp) =
                             ReducedLattice::gram_schmidt(&a);
@@ -244,11 +291,7 @@ impl ReducedLattice {
             {
                 i += 1;
             } else {
-                for j in 0..3 {
-                    b[0][0] = a[i][j];
-                    a[i][j] = a[i - 1][j];
-                    a[i - 1][j] = b[0][0];
-                }
+                a.swap(i, i - 1);
                 let (b_temp, mu_temp) = ReducedLattice::gram_schmidt(&a);
                 b = b_temp;
                 mu = mu_temp;
@@ -285,12 +328,14 @@ mod tests {
         let positions = vec![[0.; 3]];
         let lattice = Lattice::new([[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]]);
         let text = String::new();
-        let atoms = Atoms::new(lattice, positions, text);
+        let atoms = Atoms::new(lattice, positions, vec![], text);
         let positions = vec![[0.; 3]];
         let lattice = Lattice::new([[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]]);
         let text = String::new();
         assert_eq!(atoms.lattice.to_cartesian, lattice.to_cartesian);
         assert_eq!(atoms.positions, positions);
+        assert_eq!(atoms.atomic_numbers, vec![0]);
+        assert_eq!(atoms.symbols, vec![String::from("X")]);
         assert_eq!(atoms.text, text);
     }
 
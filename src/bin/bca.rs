@@ -1,104 +1,413 @@
-use atomic_counter::{AtomicCounter, RelaxedCounter};
-use bader::analysis::Analysis;
-use bader::arguments::{Args, ClapApp};
-use bader::grid::Grid;
-use bader::io::{self, FileFormat, FileType};
-use bader::methods::weight;
-use bader::progress::Bar;
-use bader::utils::vacuum_tolerance;
-use bader::voxel_map::VoxelMap;
-use crossbeam_utils::thread;
+use bader::arguments::{apply_config, dump_config, Args, ClapApp, Precision};
+use bader::errors::Error;
+use bader::atoms::Atoms;
+use bader::grid::{Grid, Size};
+use bader::io::{self, checkpoint, FileFormat, FileType, OutputFormat};
+use bader::memory::{available_memory, Estimate};
+use bader::pipeline::analyse;
+use bader::precision::{single, Real};
+use bader::progress::Reporter;
+use bader::storage::{Buffer, Plain};
+use bader::upsample::upsample;
+use bader::{Config, Results};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+/// Prints the error and exits with a non-zero status.
+fn exit(e: Error) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(1)
+}
+
+/// Upsamples the densities and reference onto a grid `factor` times finer,
+/// writing them for inspection if asked, and returns them with the finer grid
+/// and its voxel origin, as the original voxels sit on every `factor`th voxel
+/// of the finer grid.
+#[allow(clippy::borrowed_box, clippy::too_many_arguments,
+        clippy::type_complexity)]
+fn upsample_all(args: &Args,
+                factor: usize,
+                file_type: &Box<dyn FileFormat>,
+                reporter: &dyn Reporter,
+                densities: Vec<Buffer<f64>>,
+                rho: Buffer<f64>,
+                atoms: &Atoms,
+                grid: [usize; 3],
+                voxel_origin: [f64; 3])
+                -> Result<(Vec<Buffer<f64>>, Buffer<f64>, [usize; 3], [f64; 3]),
+                          Error> {
+    let pbar = reporter.stage("Upsampling", densities.len() + 1);
+    let mut fine = grid;
+    // each density is dropped once its finer copy is stored
+    let mut refine = |density: Buffer<f64>| {
+        pbar.tick();
+        if density.is_empty() {
+            return Ok(density);
+        }
+        let (density, g) =
+            upsample(&density, grid, factor, args.interpolation);
+        fine = g;
+        Buffer::from_vec(density, &args.storage)
+    };
+    let densities =
+        densities.into_iter()
+                 .map(&mut refine)
+                 .collect::<std::io::Result<Vec<Buffer<f64>>>>()?;
+    let rho = refine(rho)?;
+    drop(pbar);
+    let voxel_origin = [voxel_origin[0] * factor as f64,
+                        voxel_origin[1] * factor as f64,
+                        voxel_origin[2] * factor as f64];
+    reporter.message(&format!("Upsampled the densities by {} onto {} \
+                               voxels.",
+                              factor,
+                              fine.iter().product::<usize>()));
+    if args.write_upsampled {
+        let path = &args.output_path;
+        let mut names = file_type.density_names(atoms, densities.len())
+                                 .iter()
+                                 .map(|n| n.to_lowercase().replace(' ', "_"))
+                                 .collect::<Vec<String>>();
+        let mut written = densities.iter().collect::<Vec<&Buffer<f64>>>();
+        if !rho.is_empty() {
+            names.push(String::from("reference"));
+            written.push(&rho);
+        }
+        // the tolerances don't matter for writing the points of the grid
+        let grid = Grid::new(fine,
+                             atoms.lattice.to_cartesian,
+                             args.weight_tolerance,
+                             args.maxima_tolerance,
+                             args.vacuum_tolerance,
+                             voxel_origin);
+        let filename = path.join("upsampled");
+        io::vtk::write_densities(&written,
+                                 &names,
+                                 &grid,
+                                 filename.display().to_string(),
+                                 file_type)?;
+        reporter.message(&format!("{}.vts written successfully.",
+                                  filename.display()));
+    }
+    Ok((densities, rho, fine, voxel_origin))
+}
+
+/// Restores the partition saved in a checkpoint and sums the densities over
+/// it, refusing the checkpoint if it was made from a different input or with
+/// different weight or vacuum tolerances.
+fn load<T>(filename: &str,
+           checksum: u64,
+           config: &Config,
+           densities: &[Buffer<T>],
+           atoms: &Atoms,
+           reporter: &dyn Reporter)
+           -> Result<Results, Error>
+    where T: Real + Plain {
+    reporter.message(&format!("Loading the partition from {}.", filename));
+    let named = |e: Error| e.in_file(filename);
+    let mut file = File::open(filename).map(BufReader::new)
+                                       .map_err(|e| named(e.into()))?;
+    let pbar = reporter.stage("Loading Checkpoint", 0);
+    let saved =
+        checkpoint::read(&mut file, &config.storage, T::PRECISION, pbar)
+        .map_err(named)?;
+    if saved.checksum != checksum {
+        return Err(Error::Input(format!("{} was made from a different input \
+                                         and cannot be loaded.",
+                                        filename)));
+    }
+    // the tolerances change the partition itself so have to match
+    let mismatch = |name: &str, saved: String, given: String, fix: String| {
+        Err(Error::Argument(format!("{} was partitioned with a {} \
+                                     tolerance of {} but {} was given, pass \
+                                     {} or partition again.",
+                                    filename, name, saved, given, fix)))
+    };
+    let vacuum = |t: Option<f64>| {
+        t.map_or(String::from("none"), |t| format!("{:E}", t))
+    };
+    let (weight, saved_weight) =
+        (config.weight_tolerance, saved.grid.weight_tolerance);
+    if saved_weight != weight {
+        return mismatch("weight",
+                        format!("{:E}", saved_weight),
+                        format!("{:E}", weight),
+                        format!("-w {:E}", saved_weight));
+    }
+    let saved_vacuum = saved.grid.vacuum_tolerance;
+    if saved_vacuum != config.vacuum_tolerance {
+        let fix = saved_vacuum.map_or(String::from("no -v"), |v| {
+                                  format!("-v {:E}", v)
+                              });
+        return mismatch("vacuum",
+                        vacuum(saved_vacuum),
+                        vacuum(config.vacuum_tolerance),
+                        fix);
+    }
+    // the maxima tolerance only filters the output so can be changed
+    let mut grid = saved.grid;
+    grid.maxima_tolerance = config.maxima_tolerance;
+    analyse(config, densities, atoms, grid, saved.voxel_map, reporter)
+}
 
 fn main() {
     // argument parsing
-    let app = ClapApp::get();
-    let args = Args::new(app.get_matches());
+    let argv = std::env::args().collect::<Vec<String>>();
+    let matches = ClapApp::get().get_matches_from(argv.clone());
+    let matches = apply_config(matches, argv).unwrap_or_else(|e| exit(e));
+    if matches.is_present("dump config") {
+        print!("{}", dump_config(&matches));
+        return;
+    }
+    if !matches.is_present("file") {
+        exit(Error::Argument(String::from("No file to analyse was passed on \
+                                           the command-line or in the \
+                                           config file.")));
+    }
+    let known_type = matches.is_present("file type")
+                     || FileType::infer(matches.value_of("file").unwrap_or(""))
+                        .is_some();
+    let args = Args::new(matches).unwrap_or_else(|e| exit(e));
+    let reporter = args.reporter();
+    if !known_type {
+        reporter.message("Error: File-type cannot be infered, attempting to \
+                          read as VASP");
+    }
     // print splash
-    println!("Multi-threaded Bader Charge Analysis ({})",
-             env!("CARGO_PKG_VERSION"));
+    reporter.message(&format!("Multi-threaded Bader Charge Analysis ({})",
+                              env!("CARGO_PKG_VERSION")));
     // read the input files into a densities vector and a Grid struct
     let file_type: Box<dyn FileFormat> = match args.file_type {
         FileType::Vasp => Box::new(io::vasp::Vasp {}),
         FileType::Cube => Box::new(io::cube::Cube {}),
+        FileType::Xsf => Box::new(io::xsf::Xsf {}),
+        FileType::Qe => Box::new(io::qe::Qe {}),
+        FileType::Castep => Box::new(io::castep::Castep {}),
+        FileType::Abinit => Box::new(io::abinit::Abinit {}),
+        FileType::Siesta => Box::new(io::siesta::Siesta {}),
     };
 
-    let (densities, rho, atoms, grid, voxel_origin) = file_type.init(&args);
-    let reference = if rho.is_empty() { &densities[0] } else { &rho };
-    let grid = Grid::new(grid,
-                         atoms.lattice.to_cartesian,
-                         args.weight_tolerance,
-                         args.maxima_tolerance,
-                         args.vacuum_tolerance,
-                         voxel_origin);
-    let voxel_map = VoxelMap::new(grid.size.total);
-    {
-        let mut index: Vec<usize> = (0..grid.size.total).collect();
-        // Start a thread-safe progress bar and run the main calculation
-        println!("Sorting density.");
-        index.sort_unstable_by(|a, b| {
-                 reference[*b].partial_cmp(&reference[*a]).unwrap()
-             });
-        let counter = RelaxedCounter::new(0);
-        let vacuum_index =
-            vacuum_tolerance(&reference, &index, grid.vacuum_tolerance);
-        let pbar = Bar::visible(vacuum_index as u64,
-                                100,
-                                String::from("Bader Partitioning: "));
-        thread::scope(|s| {
-            for _ in 0..args.threads {
-                s.spawn(|_| loop {
-                     let p = {
-                         let i = counter.inc();
-                         if i >= vacuum_index {
-                             break;
-                         };
-                         index[i]
-                     };
-                     weight(p, &grid, &reference, &voxel_map);
-                     pbar.tick();
-                 });
-            }
-        }).unwrap();
+    // check the memory from the header before any density is allocated, a
+    // spin density adds one to those of the file
+    let dimensions = file_type.dimensions(args.file.clone())
+                              .unwrap_or_else(|e| exit(e));
+    let grid = dimensions.grid;
+    let estimate = Estimate::new(&args,
+                                 &Size::new(grid[0], grid[1], grid[2]),
+                                 dimensions.densities
+                                 + args.spin.is_some() as usize,
+                                 dimensions.atoms);
+    reporter.message(&estimate.to_string());
+    if let Err(e) = estimate.check(args.max_memory, available_memory()) {
+        exit(e);
     }
-    {
-        let mut weights = voxel_map.lock();
-        weights.shrink_to_fit();
+    // the output directory is made before the densities are read and
+    // partitioned, so that one that can't be made fails before the work
+    if let Err(e) = args.output_path.create_dir() {
+        let directory = args.output_path.directory.display().to_string();
+        exit(Error::from(e).in_file(&directory));
     }
-    let mut analysis =
-        Analysis::new(&voxel_map, densities.len(), atoms.positions.len());
-    // find the nearest atom to each Bader maxima
-    let pbar = Bar::visible(analysis.bader_maxima.len() as u64,
-                            100,
-                            String::from("Assigning to Atoms: "));
-    analysis.assign_atoms(&atoms, &grid, pbar);
-    let pbar = Bar::visible(grid.size.total as u64,
-                            100,
-                            String::from("Summing Charge: "));
-    // Sum the charge in each volume
-    if let Err(e) =
-        analysis.charge_sum(&atoms, &densities, &grid, &voxel_map, pbar)
-    {
-        println!("{}", e);
+    // a missing POTCAR or valence charge is reported before the densities
+    // are read and partitioned rather than once they are written
+    let potcar = args.potcar
+                     .as_ref()
+                     .map(|f| io::vasp::Vasp::read_potcar(f))
+                     .transpose()
+                     .unwrap_or_else(|e| exit(e));
+    let (densities, rho, atoms, grid, voxel_origin) =
+        file_type.init(&args, reporter.as_ref())
+                 .unwrap_or_else(|e| exit(e));
+    let valence = io::valence(&args, potcar.as_deref(), &atoms, &file_type)
+        .unwrap_or_else(|e| exit(e));
+    let (densities, rho, grid, voxel_origin) = match args.upsample {
+        Some(factor) => upsample_all(&args,
+                                     factor,
+                                     &file_type,
+                                     reporter.as_ref(),
+                                     densities,
+                                     rho,
+                                     &atoms,
+                                     grid,
+                                     voxel_origin).unwrap_or_else(|e| exit(e)),
+        None => (densities, rho, grid, voxel_origin),
+    };
+    match args.precision {
+        Precision::Double => write_results(args,
+                                           &file_type,
+                                           reporter.as_ref(),
+                                           densities,
+                                           rho,
+                                           atoms,
+                                           valence,
+                                           grid,
+                                           voxel_origin),
+        Precision::Single => {
+            let rho = single(vec![rho], &args.storage)
+                .map(|mut rho| rho.remove(0))
+                .unwrap_or_else(|e| exit(e.into()));
+            let densities = single(densities, &args.storage)
+                .unwrap_or_else(|e| exit(e.into()));
+            write_results(args,
+                          &file_type,
+                          reporter.as_ref(),
+                          densities,
+                          rho,
+                          atoms,
+                          valence,
+                          grid,
+                          voxel_origin)
+        }
+    }
+}
+
+/// Partitions the densities, or loads their partition, and writes every
+/// output, with the densities stored in the precision of `T` and the net
+/// charges calculated from `valence`, if known.
+#[allow(clippy::borrowed_box, clippy::too_many_arguments)]
+fn write_results<T>(args: Args,
+                    file_type: &Box<dyn FileFormat>,
+                    reporter: &dyn Reporter,
+                    densities: Vec<Buffer<T>>,
+                    rho: Buffer<T>,
+                    atoms: Atoms,
+                    valence: Option<Vec<f64>>,
+                    grid: [usize; 3],
+                    voxel_origin: [f64; 3])
+    where T: Real + Plain {
+    // the densities were read into scratch files if asked, start with none
+    // of them resident as the partitioning only reads them, forwards
+    let store = |density: &Buffer<T>| {
+        density.release()?;
+        density.advise_sequential(true);
+        Ok(())
+    };
+    densities.iter()
+             .chain(Some(&rho))
+             .try_for_each(store)
+             .unwrap_or_else(|e: std::io::Error| exit(e.into()));
+    // the checksum of the input is only needed for checkpoints
+    let checksum = if args.checkpoint.is_some() || args.load.is_some() {
+        let reference = if rho.is_empty() { &densities[0] } else { &rho };
+        checkpoint::checksum(reference, grid, &atoms)
+    } else {
+        0
+    };
+    // partition the density and sum the charge in each volume and atom
+    let config = Config::from(&args);
+    let results = match &args.load {
+        Some(filename) => load(filename,
+                               checksum,
+                               &config,
+                               &densities,
+                               &atoms,
+                               reporter),
+        None => bader::run(&config,
+                           &densities,
+                           &rho,
+                           &atoms,
+                           grid,
+                           voxel_origin,
+                           reporter),
+    };
+    let Results { mut analysis,
+                  grid,
+                  voxel_map, } = results.unwrap_or_else(|e| exit(e));
+    if let Some(filename) = &args.checkpoint {
+        let pbar = reporter.stage("Writing Checkpoint", grid.size.total);
+        let result = File::create(filename).and_then(|f| {
+                         checkpoint::write(checksum,
+                                           &atoms,
+                                           &grid,
+                                           &voxel_map,
+                                           &mut BufWriter::new(f),
+                                           pbar)
+                     });
+        if let Err(e) = result {
+            exit(Error::from(e).in_file(filename));
+        }
+        reporter.message(&format!("{} written successfully.", filename));
+    }
+    // Calculate the net charge if the valence charges are known
+    if let Some(valence) = valence {
+        analysis.net_charge(&valence);
     }
-    // Sum the charge in each atom
-    analysis.atoms_charge_sum();
     // build the results
-    println!("Writing output files:");
-    let (atoms_charge_file, bader_charge_file) =
-        io::output::charge_files(&analysis, &atoms, &grid, &file_type);
-    // check that the write was successfull
-    if let Err(e) = io::output::write(atoms_charge_file, bader_charge_file) {
-        panic!("Error occured: {}", e);
+    let path = &args.output_path;
+    reporter.message("Writing output files:");
+    for format in args.formats.iter() {
+        let (result, names) = match format {
+            OutputFormat::Dat => {
+                let (atoms_charge_file, bader_charge_file) =
+                    io::output::charge_files(&analysis,
+                                             &atoms,
+                                             &grid,
+                                             file_type);
+                (io::output::write(atoms_charge_file, bader_charge_file, path),
+                 vec!["ACF.dat", "BCF.dat"])
+            }
+            OutputFormat::Json => {
+                (path.create("bader.json").and_then(|mut buffer| {
+                        io::json::write(&analysis,
+                                        &atoms,
+                                        &grid,
+                                        &args,
+                                        file_type,
+                                        &mut buffer)
+                    }),
+                 vec!["bader.json"])
+            }
+            OutputFormat::Csv => {
+                (path.create("ACF.csv").and_then(|mut atoms_buffer| {
+                        let mut bader_buffer = path.create("BCF.csv")?;
+                        io::csv::write(&analysis,
+                                       &atoms,
+                                       &grid,
+                                       &args,
+                                       file_type,
+                                       &mut atoms_buffer,
+                                       &mut bader_buffer)
+                    }),
+                 vec!["ACF.csv", "BCF.csv"])
+            }
+            OutputFormat::Xyz => {
+                (path.create("bader.xyz").and_then(|mut buffer| {
+                        io::structure::write_extxyz(&atoms,
+                                                    &analysis,
+                                                    file_type,
+                                                    &mut buffer)
+                    }),
+                 vec!["bader.xyz"])
+            }
+            OutputFormat::Cif => {
+                (path.create("bader.cif").and_then(|mut buffer| {
+                        io::structure::write_cif(&atoms,
+                                                 &analysis,
+                                                 file_type,
+                                                 &mut buffer)
+                    }),
+                 vec!["bader.cif"])
+            }
+        };
+        // check that the write was successfull
+        if let Err(e) = result {
+            exit(e.into());
+        }
+        let names = names.iter()
+                         .map(|name| path.join(name).display().to_string())
+                         .collect::<Vec<String>>();
+        reporter.message(&format!("{} written successfully.",
+                                  names.join(" and ")));
     }
-    println!("ACF.dat and BCF.dat written successfully.");
     if let Err(e) = io::output::write_densities(&atoms,
                                                 &analysis,
                                                 densities,
                                                 &grid,
                                                 args.output,
                                                 &voxel_map,
-                                                &file_type)
+                                                file_type,
+                                                path,
+                                                reporter)
     {
-        panic!("Error occured: {}", e);
+        exit(e);
     }
 }
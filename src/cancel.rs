@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A token for stopping a calculation early, either by calling
+/// [`CancellationToken::cancel`] from another thread or by passing a deadline.
+/// Clones share the same state, so one can be kept to cancel the calculation
+/// that the other is given to.
+///
+/// The partitioning checks the token before starting each voxel and lets the
+/// voxels already started finish, so no thread is left waiting on a voxel
+/// that will never be written.
+///
+/// # Examples
+/// ```
+/// use bader::cancel::CancellationToken;
+/// use std::time::Duration;
+///
+/// let token = CancellationToken::new();
+/// let handle = token.clone();
+/// assert!(!token.is_cancelled());
+/// handle.cancel();
+/// assert!(token.is_cancelled());
+/// let token = CancellationToken::with_timeout(Duration::from_secs(0));
+/// assert!(token.is_cancelled());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    /// Set once the token has been cancelled.
+    cancelled: Arc<AtomicBool>,
+    /// When the calculation should stop, if ever.
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    /// Creates a token that is only cancelled by calling
+    /// [`CancellationToken::cancel`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a token that is also cancelled once `deadline` has passed.
+    pub fn with_deadline(deadline: Instant) -> Self {
+        Self { cancelled: Arc::new(AtomicBool::new(false)),
+               deadline: Some(deadline) }
+    }
+
+    /// Creates a token that is also cancelled once `timeout` has passed.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self::with_deadline(Instant::now() + timeout)
+    }
+
+    /// Cancels the token, and every clone of it.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the token has been cancelled or its deadline has passed.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+        || self.deadline.map_or(false, |d| Instant::now() >= d)
+    }
+}
+
+/// Tokens are equal if they are clones of each other with the same deadline.
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.cancelled, &other.cancelled)
+        && self.deadline == other.deadline
+    }
+}
@@ -0,0 +1,227 @@
+use crate::errors::Error;
+use crate::grid::Grid;
+use crate::pipeline::{partition as weight_partition, sorted_index, Config};
+use crate::precision::Real;
+use crate::progress::Reporter;
+use crate::storage::Storage;
+use crate::voxel_map::{Voxel, VoxelMap};
+use std::collections::HashMap;
+
+/// Samples every `factor`th voxel along each lattice vector of a density, in
+/// the flipped order of the grid, returning the coarse density and its grid.
+///
+/// Returns an [`Error::Input`] if the factor doesn't divide every dimension
+/// of the grid, as the coarse voxels must sit on the fine ones for the
+/// periodic cell to be kept.
+///
+/// # Examples
+/// ```
+/// use bader::coarse::downsample;
+///
+/// let density = (0..64).map(|p| p as f64).collect::<Vec<f64>>();
+/// let (coarse, grid) = downsample(&density, [4, 4, 4], 2).unwrap();
+/// assert_eq!(grid, [2, 2, 2]);
+/// assert_eq!(coarse, vec![0., 2., 8., 10., 32., 34., 40., 42.]);
+/// assert!(downsample(&density, [4, 4, 4], 3).is_err());
+/// ```
+pub fn downsample<T: Real>(density: &[T],
+                           grid: [usize; 3],
+                           factor: usize)
+                           -> Result<(Vec<T>, [usize; 3]), Error> {
+    if factor == 0 || grid.iter().any(|g| g % factor != 0) {
+        return Err(Error::Input(format!("Grid, {:?}, is not divisible by \
+                                         the coarse factor, {}.",
+                                        grid, factor)));
+    }
+    let coarse = [grid[0] / factor, grid[1] / factor, grid[2] / factor];
+    let mut sampled = Vec::with_capacity(coarse.iter().product());
+    for x in 0..coarse[0] {
+        for y in 0..coarse[1] {
+            for z in 0..coarse[2] {
+                let p = ((x * grid[1] + y) * grid[2] + z) * factor;
+                sampled.push(density[p]);
+            }
+        }
+    }
+    Ok((sampled, coarse))
+}
+
+/// Follows the steepest ascent of `reference` from voxel `p` to the maxima
+/// that the weight method would find, stepping between Voronoi neighbours.
+fn climb<T: Real>(p: isize, grid: &Grid, reference: &[T]) -> isize {
+    let mut p = p;
+    loop {
+        let mut next = p;
+        for shift in grid.voronoi.vectors.iter() {
+            let pt = grid.voronoi_shift(p, shift);
+            if reference[pt as usize] > reference[next as usize] {
+                next = pt;
+            }
+        }
+        if next == p {
+            break p;
+        }
+        p = next;
+    }
+}
+
+/// Partitions `reference` on a grid downsampled by `factor` and then fills
+/// `voxel_map` at full resolution.
+///
+/// A coarse voxel whose 26 neighbours all belong to the same maxima as it
+/// does is interior, and every fine voxel nearest to it inherits that
+/// maxima, found by climbing the full density from the coarse maxima. The
+/// weight method is re-run at full resolution, from the highest density
+/// down, only for the remaining voxels near the coarse boundaries, so the
+/// boundary weights are those of the full grid. Maxima too small to survive
+/// the downsampling are only found if they lie near a coarse boundary.
+///
+/// * `config`: The [`Config`] of the calculation.
+/// * `factor`: How many fine voxels each coarse voxel spans along each
+///   lattice vector.
+/// * `reference`: The density to partition.
+/// * `lattice`: The lattice of the periodic cell, as cartesian vectors.
+/// * `grid`: The [`Grid`] of `reference`.
+/// * `voxel_map`: An empty [`VoxelMap`] the size of `grid`.
+/// * `reporter`: The [`Reporter`] of each stage and of how many voxels
+///   were re-run.
+///
+/// ### Returns:
+/// `Result<usize, Error>`: How many voxels the weight method was re-run
+/// for, an [`Error::Input`] if `factor` doesn't divide the grid, an
+/// [`Error::Io`] if a scratch file can't be made or [`Error::Cancelled`] if
+/// `config.cancel` was cancelled before the end.
+pub fn partition<T: Real>(config: &Config,
+                          factor: usize,
+                          reference: &[T],
+                          lattice: [[f64; 3]; 3],
+                          grid: &Grid,
+                          voxel_map: &VoxelMap,
+                          reporter: &dyn Reporter)
+                          -> Result<usize, Error> {
+    let size =
+        [grid.size.x as usize, grid.size.y as usize, grid.size.z as usize];
+    // the position of voxel p in a grid of size g
+    let position = |p: usize, g: [usize; 3]| {
+        [p / (g[1] * g[2]), p / g[2] % g[1], p % g[2]]
+    };
+    let (coarse_reference, coarse_size) =
+        downsample(reference, size, factor)?;
+    let coarse_grid = Grid::new(coarse_size,
+                                lattice,
+                                grid.weight_tolerance,
+                                grid.maxima_tolerance,
+                                grid.vacuum_tolerance,
+                                grid.voxel_origin);
+    // the coarse grid is a fraction of the fine one so is kept on the heap
+    let coarse_map = VoxelMap::new(coarse_grid.size.total);
+    let index = sorted_index(&coarse_reference,
+                             0..coarse_grid.size.total,
+                             &Storage::Memory)?;
+    weight_partition(config,
+                     &coarse_grid,
+                     &coarse_reference,
+                     &coarse_map,
+                     &index,
+                     "Coarse Partitioning",
+                     reporter)?;
+    drop(index);
+    // the fine voxel of each coarse maxima climbs to its own maxima
+    let fine_maxima = coarse_map.maxima_list()
+                                .into_iter()
+                                .map(|m| {
+                                    let [x, y, z] = position(m, coarse_size);
+                                    let p = ((x * size[1] + y) * size[2] + z)
+                                            * factor;
+                                    (m, climb(p as isize, grid, reference))
+                                })
+                                .collect::<HashMap<usize, isize>>();
+    // the fine maxima inherited by each coarse voxel, -1 if it isn't interior
+    let interior = (0..coarse_grid.size.total as isize)
+        .map(|c| match coarse_map.voxel_get(c) {
+            Voxel::Maxima(m) => {
+                let same = coarse_grid.full_shift(c).iter().all(|shift| {
+                    match coarse_map.voxel_get(c + shift) {
+                        Voxel::Maxima(n) => n == m,
+                        _ => false,
+                    }
+                });
+                if same {
+                    fine_maxima[&m]
+                } else {
+                    -1
+                }
+            }
+            _ => -1,
+        })
+        .collect::<Vec<isize>>();
+    drop(coarse_map);
+    let is_vacuum = |p: usize| match grid.vacuum_tolerance {
+        Some(tolerance) => reference[p].to_f64() < tolerance,
+        None => false,
+    };
+    // nearest coarse voxel along one axis, wrapping around the cell
+    let nearest = |i: usize, n: usize| (i + factor / 2) / factor % n;
+    let mut refine = Vec::new();
+    let pbar = reporter.stage("Inheriting Interiors", grid.size.total);
+    for p in 0..grid.size.total {
+        let [x, y, z] = position(p, size);
+        let c = (nearest(x, coarse_size[0]) * coarse_size[1]
+                 + nearest(y, coarse_size[1]))
+                * coarse_size[2]
+                + nearest(z, coarse_size[2]);
+        match interior[c] {
+            -1 => refine.push(p),
+            _ if is_vacuum(p) => (),
+            maxima => voxel_map.maxima_store(p as isize, maxima),
+        }
+        pbar.tick();
+    }
+    drop(pbar);
+    // a maxima may sit in a voxel nearest another coarse maxima
+    for maxima in fine_maxima.values() {
+        if !is_vacuum(*maxima as usize) {
+            voxel_map.maxima_store(*maxima, *maxima);
+        }
+    }
+    let refined = refine.len();
+    reporter.message(&format!("Refining {} of {} voxels near the coarse \
+                               boundaries.",
+                              refined,
+                              grid.size.total));
+    let index = sorted_index(reference, refine.into_iter(), &config.storage)?;
+    weight_partition(config,
+                     grid,
+                     reference,
+                     voxel_map,
+                     &index,
+                     "Refining Boundaries",
+                     reporter)?;
+    Ok(refined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atoms::Lattice;
+
+    #[test]
+    fn coarse_climb() {
+        let density = (0..64).map(|p| p as f64).collect::<Vec<f64>>();
+        let lattice = Lattice::new([[4., 0., 0.], [0., 4., 0.], [0., 0., 4.]]);
+        let grid = Grid::new([4, 4, 4],
+                             lattice.to_cartesian,
+                             1E-6,
+                             1E-6,
+                             None,
+                             [0.; 3]);
+        assert_eq!(climb(0, &grid, &density), 63)
+    }
+
+    #[test]
+    fn coarse_downsample_not_divisible() {
+        let flag = matches!(downsample(&[0f64; 12], [2, 2, 3], 2),
+                            Err(Error::Input(_)));
+        assert!(flag)
+    }
+}
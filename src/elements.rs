@@ -0,0 +1,56 @@
+/// The element symbols indexed by atomic number, with "X" for an unknown or
+/// dummy atom at 0.
+pub const SYMBOLS: [&str; 119] =
+    ["X", "H", "He", "Li", "Be", "B", "C", "N", "O", "F", "Ne", "Na", "Mg",
+     "Al", "Si", "P", "S", "Cl", "Ar", "K", "Ca", "Sc", "Ti", "V", "Cr", "Mn",
+     "Fe", "Co", "Ni", "Cu", "Zn", "Ga", "Ge", "As", "Se", "Br", "Kr", "Rb",
+     "Sr", "Y", "Zr", "Nb", "Mo", "Tc", "Ru", "Rh", "Pd", "Ag", "Cd", "In",
+     "Sn", "Sb", "Te", "I", "Xe", "Cs", "Ba", "La", "Ce", "Pr", "Nd", "Pm",
+     "Sm", "Eu", "Gd", "Tb", "Dy", "Ho", "Er", "Tm", "Yb", "Lu", "Hf", "Ta",
+     "W", "Re", "Os", "Ir", "Pt", "Au", "Hg", "Tl", "Pb", "Bi", "Po", "At",
+     "Rn", "Fr", "Ra", "Ac", "Th", "Pa", "U", "Np", "Pu", "Am", "Cm", "Bk",
+     "Cf", "Es", "Fm", "Md", "No", "Lr", "Rf", "Db", "Sg", "Bh", "Hs", "Mt",
+     "Ds", "Rg", "Cn", "Nh", "Fl", "Mc", "Lv", "Ts", "Og"];
+
+/// Returns the element symbol for an atomic number, "X" if it is out of range.
+///
+/// ### Examples
+/// ```
+/// use bader::elements::symbol;
+///
+/// assert_eq!(symbol(22), "Ti");
+/// assert_eq!(symbol(200), "X");
+/// ```
+pub fn symbol(atomic_number: usize) -> &'static str {
+    SYMBOLS.get(atomic_number).unwrap_or(&"X")
+}
+
+/// Returns the atomic number of a species label, 0 if it is not recognised.
+///
+/// The label is matched on its leading letters so that labels such as
+/// "Ti_pv", "O1" or "Fe/up" are recognised, and is case insensitive.
+///
+/// ### Examples
+/// ```
+/// use bader::elements::atomic_number;
+///
+/// assert_eq!(atomic_number("Ti_pv"), 22);
+/// assert_eq!(atomic_number("O1"), 8);
+/// assert_eq!(atomic_number("Xy"), 0);
+/// ```
+pub fn atomic_number(label: &str) -> usize {
+    let label = label.trim()
+                     .chars()
+                     .take_while(|c| c.is_ascii_alphabetic())
+                     .collect::<String>()
+                     .to_lowercase();
+    SYMBOLS.iter()
+           .skip(1)
+           .position(|s| s.to_lowercase() == label)
+           .map_or(0, |z| z + 1)
+}
+
+/// Returns whether a label is an element symbol, without any decoration.
+pub fn is_symbol(label: &str) -> bool {
+    SYMBOLS.iter().skip(1).any(|s| *s == label)
+}
@@ -0,0 +1,235 @@
+use crate::analysis::AnalysisError;
+use std::str::FromStr;
+
+/// The Errors returned by the crate.
+pub enum Error {
+    /// An I/O error, with the file it occured in if known.
+    Io {
+        /// The file being read or written.
+        file: String,
+        /// The underlying error.
+        source: std::io::Error,
+    },
+    /// A malformed file, with the line of the problem. A line of 0 means the
+    /// position isn't known or the file isn't line based.
+    Parse {
+        /// The file being read.
+        file: String,
+        /// The line, counting from 1, that could not be parsed.
+        line: usize,
+        /// What went wrong.
+        message: String,
+    },
+    /// An invalid argument.
+    Argument(String),
+    /// Inputs that don't fit together, ie. densities on different grids.
+    Input(String),
+    /// An error raised by the [`Analysis`](crate::analysis::Analysis).
+    Analysis(AnalysisError),
+    /// The calculation was stopped by its
+    /// [`CancellationToken`](crate::cancel::CancellationToken).
+    Cancelled,
+}
+
+impl Error {
+    /// Creates a [`Error::Parse`] for a line of a file that has not been named
+    /// yet, see [`Error::in_file`].
+    ///
+    /// * `line`: The line, counting from 1, of the problem.
+    /// * `message`: What went wrong.
+    pub fn parse<S: Into<String>>(line: usize, message: S) -> Self {
+        Self::Parse { file: String::new(),
+                      line,
+                      message: message.into() }
+    }
+
+    /// Names the file of an I/O or parse error if it hasn't been already.
+    ///
+    /// ### Examples
+    /// ```
+    /// use bader::errors::Error;
+    ///
+    /// let e = Error::parse(3, "expected a float").in_file("CHGCAR");
+    /// assert_eq!(e.to_string(),
+    ///            "Error: Cannot read CHGCAR, line 3: expected a float");
+    /// ```
+    pub fn in_file(self, name: &str) -> Self {
+        match self {
+            Self::Io { file, source } if file.is_empty() => {
+                Self::Io { file: String::from(name),
+                           source }
+            }
+            Self::Parse { file,
+                          line,
+                          message, }
+                if file.is_empty() =>
+            {
+                Self::Parse { file: String::from(name),
+                              line,
+                              message }
+            }
+            e => e,
+        }
+    }
+}
+
+/// Make Errors printable.
+impl std::fmt::Display for Error {
+    /// Match the error and write the text associated with matched error.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io { file, source } if file.is_empty() => {
+                write!(f, "Error: {}", source)
+            }
+            Self::Io { file, source } => write!(f, "Error: {}: {}", file, source),
+            Self::Parse { file,
+                          line,
+                          message, } => {
+                let file = if file.is_empty() { "file" } else { file };
+                if *line == 0 {
+                    write!(f, "Error: Cannot read {}: {}", file, message)
+                } else {
+                    write!(f,
+                           "Error: Cannot read {}, line {}: {}",
+                           file, line, message)
+                }
+            }
+            Self::Argument(message) => write!(f, "Error: {}", message),
+            Self::Input(message) => write!(f, "Error: {}", message),
+            Self::Analysis(e) => write!(f, "{}", e),
+            Self::Cancelled => {
+                f.write_str("Error: The calculation was cancelled or passed \
+                             its deadline.")
+            }
+        }
+    }
+}
+
+/// Make errors unwrapable
+impl std::fmt::Debug for Error {
+    /// Write the same text as [`std::fmt::Display`].
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for Error {
+    /// The underlying I/O error, if any.
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(source: std::io::Error) -> Self {
+        Self::Io { file: String::new(),
+                   source }
+    }
+}
+
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(_: std::string::FromUtf8Error) -> Self {
+        Self::parse(0, "File contains invalid UTF-8.")
+    }
+}
+
+impl From<AnalysisError> for Error {
+    fn from(e: AnalysisError) -> Self {
+        match e {
+            AnalysisError::Cancelled => Self::Cancelled,
+            e => Self::Analysis(e),
+        }
+    }
+}
+
+/// Parses a single value, naming the line it is on if it fails.
+///
+/// * `value`: The text to parse, None if the value is missing.
+/// * `line`: The line, counting from 1, the value is on.
+/// * `name`: What the value is, used in the error message.
+///
+/// ### Examples
+/// ```
+/// use bader::errors::parse_value;
+///
+/// assert_eq!(parse_value::<usize>(Some("3"), 2, "grid").unwrap(), 3);
+/// assert!(parse_value::<usize>(Some("3.0"), 2, "grid").is_err());
+/// assert!(parse_value::<usize>(None, 2, "grid").is_err());
+/// ```
+pub fn parse_value<T: FromStr>(value: Option<&str>,
+                               line: usize,
+                               name: &str)
+                               -> Result<T, Error> {
+    match value {
+        Some(x) => x.parse::<T>().map_err(|_| {
+                                      Error::parse(line,
+                                                   format!("Unable to parse \
+                                                            {}, ({}).",
+                                                           name, x))
+                                  }),
+        None => Err(Error::parse(line, format!("Missing {}.", name))),
+    }
+}
+
+/// Parses every whitespace separated value of a line, naming the line if any
+/// fails or if there are fewer than `len` values.
+///
+/// * `text`: The line to parse, None if the file has ended.
+/// * `line`: The line, counting from 1, of the text.
+/// * `len`: The fewest values the line should have.
+/// * `name`: What the line holds, used in the error message.
+pub fn parse_line<T: FromStr>(text: Option<&str>,
+                              line: usize,
+                              len: usize,
+                              name: &str)
+                              -> Result<Vec<T>, Error> {
+    let text = match text {
+        Some(text) => text,
+        None => return Err(Error::parse(line, format!("Missing {}.", name))),
+    };
+    let values = text.split_whitespace()
+                     .map(|x| parse_value(Some(x), line, name))
+                     .collect::<Result<Vec<T>, Error>>()?;
+    if values.len() < len {
+        return Err(Error::parse(line,
+                                format!("Expected {} values for {}, found \
+                                         {}.",
+                                        len,
+                                        name,
+                                        values.len())));
+    }
+    Ok(values)
+}
+
+/// Parses every whitespace separated value of a block of text, naming the
+/// line of the first value that fails.
+///
+/// * `text`: The text to parse.
+/// * `first_line`: The line, counting from 1, the text starts on.
+/// * `name`: What the text holds, used in the error message.
+///
+/// ### Examples
+/// ```
+/// use bader::errors::parse_values;
+///
+/// let values = parse_values::<f64>("1 2\n3\n", 10, "density").unwrap();
+/// assert_eq!(values, vec![1., 2., 3.]);
+/// let e = parse_values::<f64>("1 2\n3 x\n", 10, "density").unwrap_err();
+/// assert_eq!(e.to_string(),
+///            "Error: Cannot read file, line 11: Unable to parse density, (x).");
+/// ```
+pub fn parse_values<T: FromStr>(text: &str,
+                                first_line: usize,
+                                name: &str)
+                                -> Result<Vec<T>, Error> {
+    let mut values = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        for x in line.split_whitespace() {
+            values.push(parse_value(Some(x), first_line + i, name)?);
+        }
+    }
+    Ok(values)
+}
@@ -0,0 +1,337 @@
+use crate::atoms::{Atoms, Lattice};
+use crate::errors::Error;
+use crate::pipeline::{run, Config, Results};
+use crate::progress::Silent;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// The call succeeded.
+pub const BADER_OK: c_int = 0;
+/// A required pointer was NULL.
+pub const BADER_ERROR_NULL: c_int = -1;
+/// The lattice, dimensions, positions or density were invalid.
+pub const BADER_ERROR_INPUT: c_int = -2;
+/// The results were queried before [`bader_partition`] succeeded.
+pub const BADER_ERROR_NOT_RUN: c_int = -3;
+/// The calculation failed unexpectedly.
+pub const BADER_ERROR_INTERNAL: c_int = -4;
+
+/// An opaque handle holding the cell, atoms and, once partitioned, the
+/// results of a calculation. Created with [`bader_grid_new`] and released
+/// with [`bader_grid_free`].
+pub struct BaderGrid {
+    /// The atoms in the flipped order used by the rest of the crate.
+    atoms: Atoms,
+    /// The number of voxels along c, b and a.
+    grid: [usize; 3],
+    /// The results of the last successful partition.
+    results: Option<Results>,
+    /// The message of the last error, empty if there hasn't been one.
+    error: CString,
+}
+
+impl BaderGrid {
+    /// Stores the message of an error and returns its code.
+    fn fail(&mut self, code: c_int, message: String) -> c_int {
+        self.error = CString::new(message).unwrap_or_default();
+        code
+    }
+
+    /// The results, or the error code for querying them too early.
+    fn results(&mut self) -> Result<&Results, c_int> {
+        if self.results.is_none() {
+            return Err(self.fail(BADER_ERROR_NOT_RUN,
+                                 String::from("Error: No partition has \
+                                               been run.")));
+        }
+        Ok(self.results.as_ref().unwrap())
+    }
+}
+
+/// The number of `f64`s in `counts` of `len` values each, or None if they
+/// couldn't be addressed as a single slice.
+fn values_len(len: usize, counts: &[usize]) -> Option<usize> {
+    let total = counts.iter()
+                      .try_fold(len, |total, &n| total.checked_mul(n))?;
+    total.checked_mul(std::mem::size_of::<f64>())
+         .filter(|&bytes| bytes <= isize::MAX as usize)
+         .map(|_| total)
+}
+
+/// Creates a handle for partitioning densities on a grid of a cell.
+///
+/// * `lattice`: The 9 cartesian components of the lattice vectors, a then b
+///   then c.
+/// * `dims`: The number of voxels along a, b and c.
+/// * `positions`: The 3 cartesian components of each atom.
+/// * `atoms_len`: The number of atoms.
+///
+/// ### Returns:
+/// `*mut BaderGrid`: The handle, or NULL if a pointer is NULL, a dimension is
+/// zero, the grid or positions are too large to address or the lattice is
+/// singular.
+///
+/// # Safety
+/// `lattice` must point to 9 values, `dims` to 3 and `positions` to
+/// 3 * `atoms_len`.
+#[no_mangle]
+pub unsafe extern "C" fn bader_grid_new(lattice: *const f64,
+                                        dims: *const usize,
+                                        positions: *const f64,
+                                        atoms_len: usize)
+                                        -> *mut BaderGrid {
+    if lattice.is_null() || dims.is_null() || positions.is_null() {
+        return std::ptr::null_mut();
+    }
+    let l = std::slice::from_raw_parts(lattice, 9);
+    let d = std::slice::from_raw_parts(dims, 3);
+    let positions_len = match (values_len(1, d), values_len(3, &[atoms_len])) {
+        (Some(_), Some(len)) => len,
+        _ => return std::ptr::null_mut(),
+    };
+    let pos = std::slice::from_raw_parts(positions, positions_len);
+    let volume = l[0] * (l[4] * l[8] - l[5] * l[7])
+                 + l[1] * (l[5] * l[6] - l[3] * l[8])
+                 + l[2] * (l[3] * l[7] - l[4] * l[6]);
+    if d.contains(&0)
+       || !volume.is_normal()
+       || pos.iter().any(|x| !x.is_finite())
+    {
+        return std::ptr::null_mut();
+    }
+    // density[c, b, a] so swap the c and a, as the file readers do
+    let handle = catch_unwind(|| {
+        let lattice = Lattice::new([[l[8], l[7], l[6]],
+                                    [l[5], l[4], l[3]],
+                                    [l[2], l[1], l[0]]]);
+        let positions = pos.chunks_exact(3)
+                           .map(|p| [p[2], p[1], p[0]])
+                           .collect::<Vec<[f64; 3]>>();
+        let atoms = Atoms::new(lattice, positions, vec![], String::new());
+        BaderGrid { atoms,
+                    grid: [d[2], d[1], d[0]],
+                    results: None,
+                    error: CString::default() }
+    });
+    match handle {
+        Ok(handle) => Box::into_raw(Box::new(handle)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Releases a handle created by [`bader_grid_new`]. Passing NULL does nothing.
+///
+/// # Safety
+/// `grid` must have come from [`bader_grid_new`] and not already be freed.
+#[no_mangle]
+pub unsafe extern "C" fn bader_grid_free(grid: *mut BaderGrid) {
+    if !grid.is_null() {
+        drop(Box::from_raw(grid));
+    }
+}
+
+/// Partitions a density, replacing the results of any previous partition.
+///
+/// * `grid`: The handle from [`bader_grid_new`].
+/// * `density`: The density, per unit volume, of every voxel with a varying
+///   fastest then b then c, ie. `rho(na, nb, nc)` in Fortran or
+///   `rho[nc][nb][na]` in C. It is copied and can be freed afterwards.
+/// * `threads`: How many threads to use, 0 for the default.
+///
+/// ### Returns:
+/// `int`: [`BADER_OK`] or an error code, see [`bader_error_message`].
+///
+/// # Safety
+/// `grid` must be a live handle and `density` must point to na * nb * nc
+/// values.
+#[no_mangle]
+pub unsafe extern "C" fn bader_partition(grid: *mut BaderGrid,
+                                         density: *const f64,
+                                         threads: usize)
+                                         -> c_int {
+    let grid = match grid.as_mut() {
+        Some(grid) => grid,
+        None => return BADER_ERROR_NULL,
+    };
+    grid.results = None;
+    if density.is_null() {
+        return grid.fail(BADER_ERROR_NULL,
+                         String::from("Error: Density is NULL."));
+    }
+    let total = match values_len(1, &grid.grid) {
+        Some(total) => total,
+        None => {
+            return grid.fail(BADER_ERROR_INPUT,
+                             String::from("Error: The grid is too large."))
+        }
+    };
+    let density = std::slice::from_raw_parts(density, total).to_vec();
+    let mut config = Config::default();
+    if threads > 0 {
+        config.threads = threads;
+    }
+    let results = catch_unwind(AssertUnwindSafe(|| {
+                      run(&config,
+                          &[density],
+                          &[],
+                          &grid.atoms,
+                          grid.grid,
+                          [0.; 3],
+                          &Silent)
+                  }));
+    match results {
+        Ok(Ok(results)) => {
+            grid.results = Some(results);
+            grid.error = CString::default();
+            BADER_OK
+        }
+        Ok(Err(e @ Error::Input(_))) => {
+            grid.fail(BADER_ERROR_INPUT, e.to_string())
+        }
+        Ok(Err(e)) => grid.fail(BADER_ERROR_INTERNAL, e.to_string()),
+        Err(_) => grid.fail(BADER_ERROR_INTERNAL,
+                            String::from("Error: Partitioning panicked.")),
+    }
+}
+
+/// The number of atoms of the handle, 0 if `grid` is NULL.
+///
+/// # Safety
+/// `grid` must be a live handle or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn bader_atoms_len(grid: *const BaderGrid) -> usize {
+    grid.as_ref().map_or(0, |g| g.atoms.positions.len())
+}
+
+/// The number of Bader volumes found by the last partition, 0 if there isn't
+/// one or `grid` is NULL.
+///
+/// # Safety
+/// `grid` must be a live handle or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn bader_volumes_len(grid: *const BaderGrid) -> usize {
+    grid.as_ref()
+        .and_then(|g| g.results.as_ref())
+        .map_or(0, |r| r.analysis.bader_maxima.len())
+}
+
+/// Copies the charge of each atom into `charge`.
+///
+/// ### Returns:
+/// `int`: [`BADER_OK`] or an error code, see [`bader_error_message`].
+///
+/// # Safety
+/// `grid` must be a live handle and `charge` must have room for
+/// [`bader_atoms_len`] values.
+#[no_mangle]
+pub unsafe extern "C" fn bader_atoms_charge(grid: *mut BaderGrid,
+                                            charge: *mut f64)
+                                            -> c_int {
+    copy_atoms(grid, charge, |r| &r.analysis.atoms_charge[0])
+}
+
+/// Copies the volume of each atom into `volume`.
+///
+/// ### Returns:
+/// `int`: [`BADER_OK`] or an error code, see [`bader_error_message`].
+///
+/// # Safety
+/// `grid` must be a live handle and `volume` must have room for
+/// [`bader_atoms_len`] values.
+#[no_mangle]
+pub unsafe extern "C" fn bader_atoms_volume(grid: *mut BaderGrid,
+                                            volume: *mut f64)
+                                            -> c_int {
+    copy_atoms(grid, volume, |r| &r.analysis.atoms_volume)
+}
+
+/// Copies a per-atom result of the last partition into a C array.
+unsafe fn copy_atoms<F>(grid: *mut BaderGrid, out: *mut f64, field: F) -> c_int
+    where F: Fn(&Results) -> &Vec<f64> {
+    let grid = match grid.as_mut() {
+        Some(grid) => grid,
+        None => return BADER_ERROR_NULL,
+    };
+    if out.is_null() {
+        return grid.fail(BADER_ERROR_NULL,
+                         String::from("Error: Output array is NULL."));
+    }
+    let values = match grid.results() {
+        Ok(results) => field(results),
+        Err(code) => return code,
+    };
+    std::ptr::copy_nonoverlapping(values.as_ptr(), out, values.len());
+    BADER_OK
+}
+
+/// Labels every voxel with the atom and Bader volume it belongs to, counting
+/// from 0 with -1 for vacuum, in the order of the density. Boundary voxels
+/// are labelled by the volume holding the largest part of them.
+///
+/// * `grid`: The handle from [`bader_grid_new`].
+/// * `atom_labels`: Where to write the atom of each voxel, can be NULL.
+/// * `volume_labels`: Where to write the Bader volume of each voxel, can be
+///   NULL.
+///
+/// ### Returns:
+/// `int`: [`BADER_OK`] or an error code, see [`bader_error_message`].
+///
+/// # Safety
+/// `grid` must be a live handle and each non-NULL array must have room for
+/// na * nb * nc values.
+#[no_mangle]
+pub unsafe extern "C" fn bader_voxel_labels(grid: *mut BaderGrid,
+                                            atom_labels: *mut i64,
+                                            volume_labels: *mut i64)
+                                            -> c_int {
+    let grid = match grid.as_mut() {
+        Some(grid) => grid,
+        None => return BADER_ERROR_NULL,
+    };
+    let results = match grid.results() {
+        Ok(results) => results,
+        Err(code) => return code,
+    };
+    let labelled = catch_unwind(AssertUnwindSafe(|| -> Result<(), Error> {
+        let labels = results.analysis.output_label_map(&results.grid,
+                                                       &results.voxel_map,
+                                                       Box::new(Silent))?;
+        for (p, label) in labels.iter().enumerate() {
+            let (atom, volume) = match label {
+                Some((v, _)) => {
+                    (results.analysis.assigned_atom[*v] as i64, *v as i64)
+                }
+                None => (-1, -1),
+            };
+            if !atom_labels.is_null() {
+                *atom_labels.add(p) = atom;
+            }
+            if !volume_labels.is_null() {
+                *volume_labels.add(p) = volume;
+            }
+        }
+        Ok(())
+    }));
+    match labelled {
+        Ok(Ok(())) => BADER_OK,
+        Ok(Err(e)) => grid.fail(BADER_ERROR_INTERNAL, e.to_string()),
+        Err(_) => grid.fail(BADER_ERROR_INTERNAL,
+                            String::from("Error: Labelling panicked.")),
+    }
+}
+
+/// The message of the last error raised with the handle, an empty string if
+/// there hasn't been one since the last successful partition. The string
+/// belongs to the handle and is valid until the next call with it.
+///
+/// # Safety
+/// `grid` must be a live handle or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn bader_error_message(grid: *const BaderGrid)
+                                             -> *const c_char {
+    match grid.as_ref() {
+        Some(grid) => grid.error.as_ptr(),
+        None => "Error: Handle is NULL.\0".as_ptr() as *const c_char,
+    }
+}
@@ -1019,7 +1019,7 @@ pub struct Size {
 
 impl Size {
     /// The length of the flattened array for the density data in 3d
-    fn new(x: usize, y: usize, z: usize) -> Self {
+    pub fn new(x: usize, y: usize, z: usize) -> Self {
         let x = x as isize;
         let y = y as isize;
         let z = z as isize;
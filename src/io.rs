@@ -1,15 +1,38 @@
 use crate::arguments::{Args, Reference};
 use crate::atoms::Atoms;
-use crate::progress::Bar;
+use crate::elements;
+use crate::errors::Error;
+use crate::progress::{Progress, Reporter};
+use crate::storage::{Buffer, Storage};
 
+/// File I/O for the ABINIT binary density format.
+pub mod abinit;
+/// File I/O for the CASTEP formatted density format.
+pub mod castep;
+/// Saving and restoring a partition, with a checksum of its input.
+pub mod checkpoint;
+/// Output for comma-separated values.
+pub mod csv;
 /// File I/O for the gaussian cube format.
 pub mod cube;
+/// Output for JSON.
+pub mod json;
 /// Write analysis files.
 pub mod output;
-/// Custom BufReader.
+/// File I/O for the Quantum ESPRESSO pp.x filplot format.
+pub mod qe;
+/// Custom BufReader and streaming parsers of densities.
 pub mod reader;
+/// File I/O for the SIESTA binary density format.
+pub mod siesta;
+/// Output of the structure, as extended XYZ and CIF, with the Bader charges.
+pub mod structure;
 /// File I/O for the VASP file format.
 pub mod vasp;
+/// Output for the VTK XML file formats.
+pub mod vtk;
+/// File I/O for the XCrySDen XSF and BXSF file formats.
+pub mod xsf;
 
 /// Indicates the available file types of the density file.
 pub enum FileType {
@@ -17,6 +40,90 @@ pub enum FileType {
     Vasp,
     /// Guassian, CP2K etc.
     Cube,
+    /// XCrySDen XSF and BXSF.
+    Xsf,
+    /// Quantum ESPRESSO pp.x filplot.
+    Qe,
+    /// CASTEP .den_fmt (with .cell).
+    Castep,
+    /// ABINIT _DEN.
+    Abinit,
+    /// SIESTA .RHO (with .XV).
+    Siesta,
+}
+
+/// Write the file type as it is passed on the command line.
+impl std::fmt::Display for FileType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Vasp => "vasp",
+            Self::Cube => "cube",
+            Self::Xsf => "xsf",
+            Self::Qe => "qe",
+            Self::Castep => "castep",
+            Self::Abinit => "abinit",
+            Self::Siesta => "siesta",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FileType {
+    /// Guesses the file type from the name of the file, None if it can't be.
+    ///
+    /// ### Examples
+    /// ```
+    /// use bader::io::FileType;
+    ///
+    /// assert!(matches!(FileType::infer("CHGCAR"), Some(FileType::Vasp)));
+    /// assert!(FileType::infer("density.dat").is_none());
+    /// ```
+    pub fn infer(filename: &str) -> Option<Self> {
+        let file = filename.to_lowercase();
+        if file.contains("cube") {
+            Some(Self::Cube)
+        } else if file.contains("car") {
+            Some(Self::Vasp)
+        } else if file.contains("xsf") {
+            Some(Self::Xsf)
+        } else if file.contains("den_fmt") {
+            Some(Self::Castep)
+        } else if file.ends_with("_den") {
+            Some(Self::Abinit)
+        } else if file.ends_with(".rho") {
+            Some(Self::Siesta)
+        } else {
+            None
+        }
+    }
+
+    /// The name of the format for reporting.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Vasp => "VASP",
+            Self::Cube => "cube",
+            Self::Xsf => "XSF",
+            Self::Qe => "Quantum ESPRESSO filplot",
+            Self::Castep => "CASTEP",
+            Self::Abinit => "ABINIT",
+            Self::Siesta => "SIESTA",
+        }
+    }
+}
+
+/// The formats to write the results of the analysis in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// The ACF.dat and BCF.dat text tables.
+    Dat,
+    /// A single JSON file of every result and the run metadata.
+    Json,
+    /// ACF.csv and BCF.csv with the run metadata as comments.
+    Csv,
+    /// The structure as extended XYZ with the Bader properties as columns.
+    Xyz,
+    /// The structure as a CIF with the Bader properties in a custom loop.
+    Cif,
 }
 
 /// What type of density to write.
@@ -25,6 +132,18 @@ pub enum WriteType {
     Atom(Vec<usize>),
     /// Write a Bader Volume.
     Volume(Vec<usize>),
+    /// Write the sum of several Bader Atoms as one density.
+    SumAtoms(Vec<usize>),
+    /// Write the sum of several Bader Volumes as one density.
+    SumVolumes(Vec<usize>),
+    /// Write the density and partitioning as VTK files.
+    Vtk,
+    /// Write the Bader volume and atom of each voxel, and the boundary
+    /// weights, in the format of the density file.
+    Labels,
+    /// Write the Bader volume and atom of each voxel, and the boundary
+    /// weights, as a VTK file.
+    LabelsVtk,
     /// Don't write anything.
     None,
 }
@@ -41,20 +160,18 @@ pub struct FortranFormat {
 impl std::fmt::Display for FortranFormat {
     /// Format the structure into a fortran style exponential.
     fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let prec = if let Some(prec) = formatter.precision() {
-            prec
-        } else {
-            6
-        };
+        let prec = formatter.precision().unwrap_or(6);
         match self.float {
-            None => {
-                write!(formatter, " 0.{:0<width$}E{:+03}", 0, 0, width = prec)
-            }
-            Some(f) => {
+            Some(f) if f != 0. => {
                 let float = f * self.mult;
-                let exponant = float.log10() as i32 + 1;
+                let mut exponant = float.abs().log10().floor() as i32 + 1;
                 let decimals = float.abs() * 10f64.powi(prec as i32 - exponant);
-                let decimals = decimals.round() as usize;
+                let mut decimals = decimals.round() as usize;
+                // rounding can carry into an extra digit, eg. 0.9999999
+                if decimals >= 10usize.pow(prec as u32) {
+                    decimals /= 10;
+                    exponant += 1;
+                }
                 if float.is_sign_negative() {
                     write!(formatter,
                            "-0.{:0<width$}E{:+03}",
@@ -69,116 +186,368 @@ impl std::fmt::Display for FortranFormat {
                            width = prec)
                 }
             }
+            _ => {
+                write!(formatter, " 0.{:0<width$}E{:+03}", 0, 0, width = prec)
+            }
         }
     }
 }
 
+/// The size of what [`FileFormat::read`] returns, known from the header of
+/// the file before any density is read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dimensions {
+    /// The number of voxels along each lattice vector, in the order returned
+    /// by [`FileFormat::read`].
+    pub grid: [usize; 3],
+    /// The number of densities in the file.
+    pub densities: usize,
+    /// The number of atoms in the file.
+    pub atoms: usize,
+}
+
 /// Return type of the read function in FileFormat.
 pub type ReadFunction =
-    std::io::Result<([f64; 3], [usize; 3], Atoms, Vec<Vec<f64>>)>;
+    Result<([f64; 3], [usize; 3], Atoms, Vec<Buffer<f64>>), Error>;
 /// Return type of the init function in FileFormat.
-type InitReturn = (Vec<Vec<f64>>, Vec<f64>, Atoms, [usize; 3], [f64; 3]);
+type InitReturn =
+    Result<(Vec<Buffer<f64>>, Buffer<f64>, Atoms, [usize; 3], [f64; 3]),
+           Error>;
 
 /// FileFormat trait. Used for handling input from a file.
 pub trait FileFormat {
     /// Returns the parts required to build [`Grid`] and [`Atoms`] structures.
+    /// The densities are read straight into the [`Storage`] of `args`, an
+    /// empty reference means the first density is the reference.
     ///
     /// * `args`: [`Args`] parsed from the command line.
-    fn init(&self, args: &Args) -> InitReturn {
-        let (voxel_origin, grid, atoms, mut densities) =
-            match self.read(args.file.clone()) {
-                Ok(x) => x,
-                Err(e) => panic!("Error: Problem reading file.\n{}", e),
-            };
+    /// * `reporter`: The [`Reporter`] told of each file read.
+    fn init(&self, args: &Args, reporter: &dyn Reporter) -> InitReturn {
+        // read a file naming it in any error
+        let read = |filename: &str| -> ReadFunction {
+            reporter.message(&format!("Reading {} as {} format:",
+                                      filename,
+                                      args.file_type.name()));
+            let r = self.read(String::from(filename), &args.storage)
+                        .map_err(|e| e.in_file(filename))?;
+            reporter.message("File read successfully.");
+            Ok(r)
+        };
+        let (voxel_origin, grid, atoms, mut densities) = read(&args.file)?;
         if let Some(x) = args.spin.clone() {
             match densities.len() {
                 1 => {
-                    let (_, g, _, d) = match self.read(x.clone()) {
-                        Ok(r) => r,
-                        Err(e) => panic!("{}", e),
-                    };
+                    let (_, g, _, mut d) = read(&x)?;
                     if 1 != d.len() {
-                        panic!(
-                               "Number of densities in original file is not 1.
+                        return Err(Error::Input(format!(
+                            "Number of densities in original file is not 1.
 Ambiguous how to handle spin density when {} contains {} densities.",
-                               x,
-                               d.len()
-                        );
+                            x,
+                            d.len()
+                        )));
+                    }
+                    if g != grid {
+                        return Err(Error::Input(String::from(
+                            "Spin density has different grid size.",
+                        )));
                     }
-                    assert_eq!(g, grid,
-                               "Error: Spin density has different grid size.");
-                    densities.push(d[0].clone());
+                    densities.push(d.remove(0));
                 }
-                x => panic!(
-                            "Number of densities in original file is not 1.
+                x => {
+                    return Err(Error::Input(format!(
+                        "Number of densities in original file is not 1.
 Ambiguous how to handle new spin when {} already has {} spin densities.",
-                            args.file,
-                            x - 1
-                ),
+                        args.file,
+                        x - 1
+                    )))
+                }
             }
         }
+        let reference_grid = |g: [usize; 3]| {
+            if g == grid {
+                Ok(())
+            } else {
+                Err(Error::Input(String::from("Reference density has \
+                                               different grid size.")))
+            }
+        };
         let rho = match args.reference.clone() {
-            Reference::None => Vec::with_capacity(0),
+            Reference::None => Buffer::from_vec(Vec::new(), &Storage::Memory)?,
+            Reference::Column(i) => match densities.get(i) {
+                Some(density) => {
+                    Buffer::from_fn(density.len(), &args.storage, |p| {
+                        density[p]
+                    })?
+                }
+                None => {
+                    return Err(Error::Input(format!(
+                        "Column {} requested but {} only has {} values per \
+                         voxel.",
+                        i + 1,
+                        args.file,
+                        densities.len()
+                    )))
+                }
+            },
             Reference::One(f) => {
-                let (_, g, _, densities) = match self.read(f) {
-                    Ok(r) => r,
-                    Err(e) => panic!("{}", e),
-                };
-                assert_eq!(g, grid,
-                           "Error: Reference density has different grid size.");
-                densities[0].clone()
+                let (_, g, _, mut densities) = read(&f)?;
+                reference_grid(g)?;
+                densities.remove(0)
             }
             Reference::Two(f1, f2) => {
-                let (_, g, _, densities) = match self.read(f1) {
-                    Ok(r) => r,
-                    Err(e) => panic!("{}", e),
-                };
-                assert_eq!(g, grid,
-                           "Error: Reference density has different grid size.");
-                let (_, g2, _, densities2) = match self.read(f2) {
-                    Ok(r) => r,
-                    Err(e) => panic!("{}", e),
-                };
-
-                assert_eq!(g2, grid,
-                           "Error: Reference density has different grid size.");
-                densities[0].iter()
-                            .zip(&densities2[0])
-                            .map(|(a, b)| a + b)
-                            .collect::<Vec<f64>>()
+                let (_, g, _, mut densities) = read(&f1)?;
+                reference_grid(g)?;
+                let mut rho = densities.remove(0);
+                drop(densities);
+                let (_, g2, _, densities2) = read(&f2)?;
+                reference_grid(g2)?;
+                rho.iter_mut()
+                   .zip(&densities2[0])
+                   .for_each(|(a, b)| *a += b);
+                rho
             }
         };
-        (densities, rho, atoms, grid, voxel_origin)
+        Ok((densities, rho, atoms, grid, voxel_origin))
     }
 
     /// Reads the file into a [`ReadFunction`] containing the information
-    /// needed from the file to build a [`Grid`].
+    /// needed from the file to build a [`Grid`]. Each density is parsed
+    /// straight into a [`Buffer`] kept in `storage`, so that a density larger
+    /// than the memory is never held on the heap.
+    ///
+    /// * `filename`: The name of the file to read.
+    /// * `storage`: Where to keep the densities read.
+    fn read(&self, filename: String, storage: &Storage) -> ReadFunction;
+
+    /// Reads just enough of the file for the [`Dimensions`] of what
+    /// [`FileFormat::read`] would return, so that the memory needed can be
+    /// checked before any density is allocated.
     ///
     /// * `filename`: The name of the file to read.
-    fn read(&self, filename: String) -> ReadFunction;
+    fn dimensions(&self, filename: String) -> Result<Dimensions, Error>;
 
     /// Reads the non-density section of the file into an [`Atoms`] object.
     ///
     /// * `atom_text`: The full string of non-density information from the
-    /// density file.
-    fn to_atoms(&self, atom_text: String) -> Atoms;
+    ///   density file. Binary formats supply a text summary of their header
+    ///   and, once read, keep its records in the
+    ///   [`Header`](crate::atoms::Header) of the atoms for their writer.
+    fn to_atoms(&self, atom_text: String) -> Result<Atoms, Error>;
 
-    /// Writes a specific density, data, to tile in the correct format.
+    /// Writes the values of a grid, data, to file in the correct format
+    /// exactly as they are given, without the units of a density, such as the
+    /// labels of each voxel.
     ///
     /// * `atoms`: The associated &[`Atoms`] object for the density file.
-    /// * `data`: The density to write to file wrapped in options with None representing 0.
+    /// * `data`: The values to write to file wrapped in options with None
+    ///   representing 0.
     /// * `filename`: Where to save the file, minus any suffix as this should
-    /// be applied in the function.
+    ///   be applied in the function.
+    /// * `pbar`: A progress bar for monitoring the write.
+    fn write_values(&self,
+                    atoms: &Atoms,
+                    data: Vec<Option<f64>>,
+                    filename: String,
+                    pbar: Box<dyn Progress>)
+                    -> std::io::Result<()>;
+
+    /// The factor a density is multiplied by when it is written, undoing the
+    /// conversion made when it was read. By default there is none.
+    ///
+    /// * `atoms`: The associated &[`Atoms`] object for the density file.
+    fn scale(&self, _atoms: &Atoms) -> f64 {
+        1.
+    }
+
+    /// Writes a specific density, data, to file in the correct format,
+    /// multiplied by the [`FileFormat::scale`] of the format.
+    ///
+    /// * `atoms`: The associated &[`Atoms`] object for the density file.
+    /// * `data`: The density to write to file wrapped in options with None
+    ///   representing 0.
+    /// * `filename`: Where to save the file, minus any suffix as this should
+    ///   be applied in the function.
     /// * `pbar`: A progress bar for monitoring the write.
     fn write(&self,
              atoms: &Atoms,
-             data: Vec<Option<f64>>,
+             mut data: Vec<Option<f64>>,
              filename: String,
-             pbar: Bar)
-             -> std::io::Result<()>;
+             pbar: Box<dyn Progress>)
+             -> std::io::Result<()> {
+        let scale = self.scale(atoms);
+        data.iter_mut().flatten().for_each(|d| *d *= scale);
+        self.write_values(atoms, data, filename, pbar)
+    }
+
+    /// The names of the densities read from the file, used to label the
+    /// output. By default these are the charge followed by the spin.
+    ///
+    /// * `atoms`: The [`Atoms`] read from the density file.
+    /// * `densities_len`: How many densities have been read.
+    fn density_names(&self,
+                     _atoms: &Atoms,
+                     densities_len: usize)
+                     -> Vec<String> {
+        output::density_names(densities_len)
+    }
+
+    /// The valence charge of each atom if the file records it, used for
+    /// calculating net charges. By default nothing is known.
+    ///
+    /// * `atoms`: The [`Atoms`] read from the density file.
+    fn valence(&self, atoms: &Atoms) -> Vec<Option<f64>> {
+        vec![None; atoms.positions.len()]
+    }
+
+    /// How many atoms there are of each species, in the order the species are
+    /// listed, used to match the species of a POTCAR to the atoms. By default
+    /// each run of atoms with the same symbol is a species.
+    ///
+    /// * `atoms`: The [`Atoms`] read from the density file.
+    fn species_counts(&self, atoms: &Atoms) -> Vec<usize> {
+        let mut counts: Vec<usize> = Vec::new();
+        for (i, symbol) in atoms.symbols.iter().enumerate() {
+            match counts.last_mut() {
+                Some(n) if i > 0 && atoms.symbols[i - 1] == *symbol => *n += 1,
+                _ => counts.push(1),
+            }
+        }
+        counts
+    }
+
+    /// Whether the lattice vectors, and the components of each vector and
+    /// position, are stored in the reverse of the order of the file, ie. the
+    /// lattice as [c, b, a] with each vector as [z, y, x]. By default they are,
+    /// as the densities of most formats are written with the first index
+    /// fastest.
+    fn reversed(&self) -> bool {
+        true
+    }
 
     /// How the format the positions of maxima and atoms
     ///
     /// * `coords`: The 3d representation of the position.
     fn coordinate_format(&self, coords: [f64; 3]) -> (String, String, String);
 }
+
+/// Collects the valence charge of each atom for calculating net charges.
+///
+/// Each atom takes its value from, in order of preference, the user supplied
+/// table, the ZVAL of its species in the POTCAR and the density file (the
+/// nuclear charge of a cube file). The species of the POTCAR are matched in
+/// order to the [`FileFormat::species_counts`], so a POTCAR must have one
+/// entry for each species, as it does for VASP. If none of these supply a
+/// value for any atom then None is returned and no net charges are
+/// calculated, otherwise every atom needs a value.
+///
+/// There is no built-in table to fall back on: the valence charge of an
+/// element depends on the pseudopotential the density was calculated with
+/// (Ti has 4, 10 or 12), so a guess would silently give wrong net charges.
+///
+/// * `args`: [`Args`] parsed from the command line.
+/// * `potcar`: The species of the POTCAR passed in `args`, as returned by
+///   [`Vasp::read_potcar`](vasp::Vasp::read_potcar), if any.
+/// * `atoms`: The [`Atoms`] read from the density file.
+/// * `file_type`: The [`FileFormat`] of the density file.
+#[allow(clippy::borrowed_box)]
+pub fn valence(args: &Args,
+               potcar: Option<&[(usize, f64)]>,
+               atoms: &Atoms,
+               file_type: &Box<dyn FileFormat>)
+               -> Result<Option<Vec<f64>>, Error> {
+    let potcar = match potcar {
+        Some(potcar) => {
+            let counts = file_type.species_counts(atoms);
+            if potcar.len() != counts.len() {
+                return Err(Error::Input(format!("The POTCAR has {} species \
+                                                 but the structure has {}.",
+                                                potcar.len(),
+                                                counts.len())));
+            }
+            let mut start = 0;
+            let mut zval = Vec::with_capacity(atoms.positions.len());
+            for ((n, v), count) in potcar.iter().zip(counts) {
+                let end = start + count;
+                let z = atoms.atomic_numbers[start..end].iter()
+                                                        .find(|z| **z != 0);
+                if let Some(z) = z.filter(|z| *n != 0 && **z != *n) {
+                    return Err(Error::Input(format!("The POTCAR species \
+                                                     {} doesn't match the \
+                                                     structure's {}.",
+                                                    elements::symbol(*n),
+                                                    elements::symbol(*z))));
+                }
+                zval.extend(vec![*v; count]);
+                start = end;
+            }
+            zval
+        }
+        None => Vec::with_capacity(0),
+    };
+    let mut valence = file_type.valence(atoms);
+    for (i, v) in valence.iter_mut().enumerate() {
+        let z = atoms.atomic_numbers[i];
+        let from_table = args.zval.iter().find(|(label, _)| {
+                                             if z == 0 {
+                                                 *label == atoms.symbols[i]
+                                             } else {
+                                                 elements::atomic_number(label)
+                                                 == z
+                                             }
+                                         });
+        if let Some((_, zval)) = from_table {
+            *v = Some(*zval);
+        } else if let Some(zval) = potcar.get(i) {
+            *v = Some(*zval);
+        }
+    }
+    if valence.iter().all(|v| v.is_none()) {
+        return Ok(None);
+    }
+    valence.iter()
+           .enumerate()
+           .map(|(i, v)| {
+               v.ok_or_else(|| {
+                    Error::Argument(format!("No valence charge for atom {} \
+                                             ({}), pass one with -z {}=VALUE.",
+                                            i + 1,
+                                            atoms.symbols[i],
+                                            atoms.symbols[i]))
+                })
+           })
+           .collect::<Result<Vec<f64>, Error>>()
+           .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fortran_format_positive() {
+        let f = FortranFormat { float: Some(152.46),
+                                mult: 1. };
+        assert_eq!(format!("{:.5}", f), " 0.15246E+03");
+    }
+
+    #[test]
+    fn fortran_format_small_negative() {
+        let f = FortranFormat { float: Some(-0.05),
+                                mult: 1. };
+        assert_eq!(format!("{:.5}", f), "-0.50000E-01");
+    }
+
+    #[test]
+    fn fortran_format_zero() {
+        let f = FortranFormat { float: Some(0.),
+                                mult: 1. };
+        assert_eq!(format!("{:.5}", f), " 0.00000E+00");
+    }
+
+    #[test]
+    fn fortran_format_round_up() {
+        let f = FortranFormat { float: Some(0.9999999),
+                                mult: 1. };
+        assert_eq!(format!("{:.5}", f), " 0.10000E+01");
+    }
+}
@@ -0,0 +1,307 @@
+use crate::atoms::{Atoms, Header, Lattice};
+use crate::errors::{parse_line, parse_value, Error};
+use crate::io::reader::{FortranReader, FortranWriter};
+use crate::io::{Dimensions, FileFormat, ReadFunction};
+use crate::progress::Progress;
+use crate::storage::{Buffer, Storage};
+use crate::utils;
+use std::fs::File;
+use std::io::BufWriter;
+
+/// Convert from Bohr.
+const LENGTH_UNITS: f64 = 0.52917721067;
+/// Convert from Bohr^3.
+const VOLUME_UNITS: f64 = LENGTH_UNITS * LENGTH_UNITS * LENGTH_UNITS;
+
+/// The ABINIT binary density (_DEN) file format.
+///
+/// The file is Fortran unformatted, starting with the ABINIT header and
+/// finishing with a double precision record of the density for each spin
+/// component, in electrons per Bohr^3. Only the parts of the header common to
+/// header forms 57 and above are read: the grid, number of spin components and
+/// lattice from the second record and the reduced positions from the fourth.
+/// Spin-polarised densities are stored as charge and spin up and so are
+/// converted to charge and spin, non-collinear densities are stored as charge
+/// and the x, y and z spin.
+///
+/// As the header is binary the [`Atoms`] text is a summary of it and the
+/// records of the header are kept in the [`Header`] of the atoms, to be copied
+/// on writing.
+pub struct Abinit {}
+
+/// Counts the records of a file without reading them.
+fn count_records(filename: &str) -> std::io::Result<usize> {
+    let mut file = FortranReader::open(filename)?;
+    let mut count = 0;
+    while let Some(record) = file.skip_record() {
+        record?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Reads and checks the version and first header records, returning them and
+/// the integers of the header record.
+fn read_header(file: &mut FortranReader)
+               -> Result<(Vec<Vec<u8>>, Vec<i32>), Error> {
+    let invalid = |msg: &str| Error::parse(0, msg);
+    let mut next_record = || match file.read_record() {
+        Some(record) => record.map_err(Error::from),
+        None => Err(invalid("Unexpected end of file.")),
+    };
+    let records = vec![next_record()?, next_record()?];
+    // codvsn (6 or 8 characters), headform, fform
+    let version = &records[0];
+    if version.len() != 14 && version.len() != 16 {
+        return Err(invalid("Bad version record."));
+    }
+    let headform = file.to_i32(&version[(version.len() - 8)..])[0];
+    if headform < 57 {
+        return Err(invalid("Unsupported header form."));
+    }
+    // bantot, date, intxc, ixc, natom, ngfft(3), nkpt, nspden, nspinor,
+    // nsppol, nsym, npsp, ntypat, occopt, pertcase, usepaw followed by
+    // ecut, ecutdg, ecutsm, ecut_eff, qptn(3), rprimd(3, 3), ...
+    if records[1].len() < 224 {
+        return Err(invalid("Bad header record."));
+    }
+    let ints = file.to_i32(&records[1][..72]);
+    if ints[4] < 1 || ints[5..8].iter().any(|n| *n < 1) {
+        return Err(invalid("Bad header record."));
+    }
+    let nspden = ints[9];
+    if nspden != 1 && nspden != 2 && nspden != 4 {
+        return Err(invalid("Unsupported number of spin components."));
+    }
+    Ok((records, ints))
+}
+
+impl FileFormat for Abinit {
+    /// Read a _DEN file.
+    fn read(&self, filename: String, storage: &Storage) -> ReadFunction {
+        // the voxel origin in ABINIT is (0, 0, 0)
+        let voxel_origin = [0f64; 3];
+        // name the file in any error
+        let named = |e: Error| e.in_file(&filename);
+        let invalid = |msg: &str| named(Error::parse(0, msg));
+        let count = count_records(&filename).map_err(|e| named(e.into()))?;
+        if count < 5 {
+            return Err(invalid("Incomplete header."));
+        }
+        let mut file =
+            FortranReader::open(&filename).map_err(|e| named(e.into()))?;
+        let next_record = |file: &mut FortranReader| match file.read_record() {
+            Some(record) => record.map_err(|e| named(e.into())),
+            None => Err(invalid("Unexpected end of file.")),
+        };
+        // only the header is read into memory, the densities are streamed
+        let (mut records, ints) = read_header(&mut file).map_err(named)?;
+        let doubles = file.to_f64(&records[1][72..224]);
+        let natom = ints[4] as usize;
+        let grid = [ints[5] as usize, ints[6] as usize, ints[7] as usize];
+        let nspden = ints[9] as usize;
+        // the density records are the last nspden records
+        if count < 5 + nspden {
+            return Err(invalid("Missing density records."));
+        }
+        while records.len() < count - nspden {
+            records.push(next_record(&mut file)?);
+        }
+        // istwfk(nkpt), nband(nkpt * nsppol), npwarr(nkpt), so_psp(npsp),
+        // symafm(nsym), symrel(3, 3, nsym), typat(natom), ... finishing with
+        // znucltypat(ntypat), wtk(nkpt)
+        let (nkpt, nsppol, nsym, npsp, ntypat) = (ints[8] as usize,
+                                                  ints[11] as usize,
+                                                  ints[12] as usize,
+                                                  ints[13] as usize,
+                                                  ints[14] as usize);
+        let typat_start = 4 * (nkpt * (2 + nsppol) + npsp + 10 * nsym);
+        let znucl_start =
+            records[2].len().saturating_sub(8 * (ntypat + nkpt));
+        let atomic_numbers = if typat_start + 4 * natom <= znucl_start {
+            let typat = file.to_i32(&records[2][typat_start
+                                                ..(typat_start + 4 * natom)]);
+            let znucl = file.to_f64(&records[2][znucl_start
+                                                ..(znucl_start + 8 * ntypat)]);
+            typat.iter()
+                 .map(|t| {
+                     (*t as usize).checked_sub(1)
+                                  .and_then(|t| znucl.get(t))
+                                  .map_or(0, |z| z.round() as usize)
+                 })
+                 .collect::<Vec<usize>>()
+        } else {
+            vec![0; natom]
+        };
+        // residm, xred(3, natom), ...
+        if records[3].len() < 8 + 24 * natom {
+            return Err(invalid("Bad positions record."));
+        }
+        let xred = file.to_f64(&records[3][8..(8 + 24 * natom)]);
+        let mut text = format!(" ABINIT density header from {}\n", filename);
+        text.push_str(&format!(" ngfft: {} {} {}\n",
+                               grid[0], grid[1], grid[2]));
+        text.push_str(" rprimd (Bohr):\n");
+        for v in doubles[7..16].chunks(3) {
+            text.push_str(&format!(" {:20.12} {:20.12} {:20.12}\n",
+                                   v[0], v[1], v[2]));
+        }
+        text.push_str(&format!(" natom: {}\n", natom));
+        text.push_str(" xred, Z:\n");
+        for (p, z) in xred.chunks(3).zip(&atomic_numbers) {
+            text.push_str(&format!(" {:20.12} {:20.12} {:20.12} {:4}\n",
+                                   p[0], p[1], p[2], z));
+        }
+        let mut atoms = self.to_atoms(text).map_err(named)?;
+        atoms.header = Header { big_endian: file.big_endian(),
+                                records };
+        let total = grid.iter().product::<usize>();
+        let mut spins = Vec::with_capacity(nspden);
+        for _ in 0..nspden {
+            let mut density =
+                Buffer::zeroed(total, storage).map_err(|e| named(e.into()))?;
+            let len = match file.read_floats(8, |i, x| {
+                                    if i < total {
+                                        density[i] = x / VOLUME_UNITS
+                                    }
+                                }) {
+                Some(len) => len.map_err(|e| named(e.into()))?,
+                None => return Err(invalid("Missing density records.")),
+            };
+            if len != total {
+                return Err(invalid("Bad density record."));
+            }
+            spins.push(density);
+        }
+        if nspden == 2 {
+            // spin = up - down = 2 * up - charge
+            let (charge, up) = spins.split_at_mut(1);
+            up[0].iter_mut()
+                 .zip(charge[0].iter())
+                 .for_each(|(u, c)| *u = 2. * *u - c);
+        }
+        let densities = spins;
+        let grid_pts = [grid[2], grid[1], grid[0]];
+        Ok((voxel_origin, grid_pts, atoms, densities))
+    }
+
+    /// Reads the grid and the numbers of spin components and atoms from the
+    /// header record.
+    fn dimensions(&self, filename: String) -> Result<Dimensions, Error> {
+        let named = |e: Error| e.in_file(&filename);
+        let mut file =
+            FortranReader::open(&filename).map_err(|e| named(e.into()))?;
+        let (_, ints) = read_header(&mut file).map_err(named)?;
+        Ok(Dimensions { grid: [ints[7] as usize,
+                               ints[6] as usize,
+                               ints[5] as usize],
+                        densities: ints[9] as usize,
+                        atoms: ints[4] as usize })
+    }
+
+    /// Read the lattice and reduced positions from the header summary.
+    fn to_atoms(&self, atoms_text: String) -> Result<Atoms, Error> {
+        let mut lines = atoms_text.lines();
+        for line in lines.by_ref() {
+            if line.contains("rprimd") {
+                break;
+            }
+        }
+        let mut cell = [[0f64; 3]; 3];
+        for v in cell.iter_mut() {
+            let line = parse_line::<f64>(lines.next(), 0, 3, "rprimd")?;
+            v.copy_from_slice(&line[..3]);
+        }
+        let [a, b, c] = cell;
+        // density[c, b, a] so lets swap the c and a
+        let lattice = Lattice::try_new([[c[2] * LENGTH_UNITS,
+                                         c[1] * LENGTH_UNITS,
+                                         c[0] * LENGTH_UNITS],
+                                        [b[2] * LENGTH_UNITS,
+                                         b[1] * LENGTH_UNITS,
+                                         b[0] * LENGTH_UNITS],
+                                        [a[2] * LENGTH_UNITS,
+                                         a[1] * LENGTH_UNITS,
+                                         a[0] * LENGTH_UNITS]])
+            .map_err(|e| Error::parse(0, e))?;
+        let natom = parse_value::<usize>(lines.next()
+                                              .and_then(|l| {
+                                                  l.split_whitespace().nth(1)
+                                              }),
+                                         0,
+                                         "natom")?;
+        lines.next();
+        let mut positions: Vec<[f64; 3]> = Vec::with_capacity(natom);
+        let mut atomic_numbers: Vec<usize> = Vec::with_capacity(natom);
+        for _ in 0..natom {
+            let p = parse_line::<f64>(lines.next(), 0, 3, "xred")?;
+            atomic_numbers.push(p.get(3).map_or(0, |z| *z as usize));
+            positions.push(utils::dot([p[2].rem_euclid(1f64),
+                                       p[1].rem_euclid(1f64),
+                                       p[0].rem_euclid(1f64)],
+                                      lattice.to_cartesian));
+        }
+        Ok(Atoms::new(lattice, positions, atomic_numbers, atoms_text))
+    }
+
+    /// The density is stored per cubic Bohr.
+    fn scale(&self, _atoms: &Atoms) -> f64 {
+        VOLUME_UNITS
+    }
+
+    /// Write a _DEN from a vector of options where None will be written as
+    /// zero. The header that was read is copied with the number of spin
+    /// components set to 1.
+    fn write_values(&self,
+                    atoms: &Atoms,
+                    data: Vec<Option<f64>>,
+                    filename: String,
+                    pbar: Box<dyn Progress>)
+                    -> std::io::Result<()> {
+        let header = &atoms.header;
+        if header.records.len() < 2 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                                           "No ABINIT header to write."));
+        }
+        let filename = format!("{}_DEN", filename);
+        let mut buffer =
+            FortranWriter::new(BufWriter::new(File::create(filename)?),
+                               header.big_endian);
+        let nx = atoms.text
+                      .lines()
+                      .find(|l| l.starts_with(" ngfft:"))
+                      .and_then(|l| l.split_whitespace().nth(1))
+                      .and_then(|x| x.parse::<usize>().ok())
+                      .unwrap_or(1);
+        // nspden is in the second record
+        for (i, record) in header.records.iter().enumerate() {
+            if i == 1 {
+                let mut record = record.clone();
+                record[36..40].copy_from_slice(&buffer.i32_bytes(&[1]));
+                buffer.write_record(&record)?;
+            } else {
+                buffer.write_record(record)?;
+            }
+        }
+        pbar.set_length(data.len() / nx);
+        // the grid is one record, written a line at a time
+        buffer.write_record_with(data.len() * 8, |record| {
+            for line in data.chunks(nx) {
+                let line = line.iter()
+                               .map(|d| d.unwrap_or(0.))
+                               .collect::<Vec<f64>>();
+                record.write_f64(&line)?;
+                pbar.tick();
+            }
+            Ok(())
+        })
+    }
+
+    /// Deals with the flipped grid.
+    fn coordinate_format(&self, coords: [f64; 3]) -> (String, String, String) {
+        let z = format!("{:.6}", coords[0]);
+        let y = format!("{:.6}", coords[1]);
+        let x = format!("{:.6}", coords[2]);
+        (x, y, z)
+    }
+}
@@ -0,0 +1,387 @@
+use crate::atoms::{Atoms, Lattice};
+use crate::elements;
+use crate::errors::{parse_line, parse_value, Error};
+use crate::io::{Dimensions, FileFormat, ReadFunction};
+use crate::progress::Progress;
+use crate::storage::{Buffer, Storage};
+use crate::utils;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Convert from Bohr.
+const LENGTH_UNITS: f64 = 0.52917721067;
+
+/// Reads the header of a .den_fmt, which finishes with "END header: data is
+/// ..." and a blank line, returning its lines, the fine FFT grid and the
+/// number of lines read.
+fn read_header<R: BufRead>(file: &mut R)
+                           -> Result<(Vec<String>, [usize; 3], usize), Error> {
+    let mut header = Vec::new();
+    let mut header_line = 0;
+    let mut grid = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if file.read_line(&mut line)? == 0 {
+            break;
+        }
+        header_line += 1;
+        if line.contains("fine FFT grid") {
+            let g = line.split_whitespace()
+                        .take(3)
+                        .map(|x| parse_value(Some(x), header_line, "grid"))
+                        .collect::<Result<Vec<usize>, Error>>()?;
+            if g.len() != 3 {
+                return Err(Error::parse(header_line, "Bad grid line."));
+            }
+            grid = Some([g[0], g[1], g[2]]);
+        }
+        header.push(line.trim_end_matches(&['\n', '\r'][..]).to_string());
+        if line.trim_start().starts_with("END header") {
+            break;
+        }
+    }
+    let complete = header.last()
+                         .map_or(false, |l| {
+                             l.trim_start().starts_with("END header")
+                         });
+    match grid {
+        Some(g) if complete => Ok((header, g, header_line)),
+        _ => Err(Error::parse(0, "Incomplete header.")),
+    }
+}
+
+/// The CASTEP formatted density (.den_fmt) file format.
+///
+/// The density file only contains the lattice so the atoms are read from the
+/// .cell file with the same seed name. Each data line is "i j k" followed by
+/// the charge and, for spin-polarised or non-collinear calculations, the spin
+/// (or the x, y and z spin). Values are in electrons per grid point times the
+/// number of grid points and so, like VASP, are divided by the cell volume.
+/// The .cell file that was read is written back alongside each density.
+pub struct Castep {}
+
+impl FileFormat for Castep {
+    /// Read a .den_fmt and its .cell file.
+    fn read(&self, filename: String, storage: &Storage) -> ReadFunction {
+        // the voxel origin in CASTEP is (0, 0, 0)
+        let voxel_origin = [0f64; 3];
+        // name the file in any error
+        let named = |e: Error| e.in_file(&filename);
+        let invalid = |line: usize, msg: &str| named(Error::parse(line, msg));
+        let mut file = File::open(&filename).map(io::BufReader::new)
+                                            .map_err(|e| named(e.into()))?;
+        let (header, grid, header_line) =
+            read_header(&mut file).map_err(named)?;
+        let atoms = self.header_atoms(&filename, &header)?;
+        // place each value by its index as the order isn't guaranteed
+        let total = grid.iter().product::<usize>();
+        let mut densities: Vec<Buffer<f64>> = Vec::with_capacity(4);
+        let mut count = 0;
+        let mut line_number = header_line;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if file.read_line(&mut line).map_err(|e| named(e.into()))? == 0 {
+                break;
+            }
+            line_number += 1;
+            let values = line.split_whitespace().collect::<Vec<&str>>();
+            if values.is_empty() {
+                continue;
+            }
+            let bad_line = || invalid(line_number, "Bad data line.");
+            if values.len() < 4 {
+                return Err(bad_line());
+            }
+            if densities.is_empty() {
+                match values.len() - 3 {
+                    1 | 2 | 4 => {
+                        for _ in 0..(values.len() - 3) {
+                            densities.push(Buffer::zeroed(total, storage)
+                                .map_err(|e| named(e.into()))?);
+                        }
+                    }
+                    _ => {
+                        return Err(invalid(line_number,
+                                           "Bad number of columns."))
+                    }
+                }
+            }
+            if values.len() != densities.len() + 3 {
+                return Err(bad_line());
+            }
+            let mut index = [0usize; 3];
+            for (i, x) in index.iter_mut().zip(&values[..3]) {
+                *i = match x.parse::<usize>() {
+                    Ok(x) if x > 0 => x - 1,
+                    _ => return Err(bad_line()),
+                };
+            }
+            if index.iter().zip(&grid).any(|(i, g)| i >= g) {
+                return Err(invalid(line_number, "Index out of grid."));
+            }
+            // flip the index as the density is stored density[c, b, a]
+            let p = (index[2] * grid[1] + index[1]) * grid[0] + index[0];
+            for (density, x) in densities.iter_mut().zip(&values[3..]) {
+                density[p] = match x.parse::<f64>() {
+                    Ok(x) => x / atoms.lattice.volume,
+                    Err(_) => return Err(bad_line()),
+                };
+            }
+            count += 1;
+        }
+        if count != total {
+            return Err(invalid(0, "Number of values does not match the grid."));
+        }
+        let grid_pts = [grid[2], grid[1], grid[0]];
+        Ok((voxel_origin, grid_pts, atoms, densities))
+    }
+
+    /// Reads the grid from the header, the atoms from the .cell file and the
+    /// number of densities from the columns of the first data line.
+    fn dimensions(&self, filename: String) -> Result<Dimensions, Error> {
+        let named = |e: Error| e.in_file(&filename);
+        let mut file = File::open(&filename).map(io::BufReader::new)
+                                            .map_err(|e| named(e.into()))?;
+        let (header, grid, mut line_number) =
+            read_header(&mut file).map_err(named)?;
+        let atoms = self.header_atoms(&filename, &header)?;
+        let mut line = String::new();
+        let columns = loop {
+            line.clear();
+            if file.read_line(&mut line).map_err(|e| named(e.into()))? == 0 {
+                break 0;
+            }
+            line_number += 1;
+            match line.split_whitespace().count() {
+                0 => continue,
+                n => break n,
+            }
+        };
+        match columns {
+            4 | 5 | 7 => Ok(Dimensions { grid: [grid[2], grid[1], grid[0]],
+                                         densities: columns - 3,
+                                         atoms: atoms.positions.len() }),
+            _ => Err(named(Error::parse(line_number,
+                                        "Bad number of columns."))),
+        }
+    }
+
+    /// Read the lattice from the density header and the atoms from the cell,
+    /// errors are reported against the lines of atoms_text.
+    fn to_atoms(&self, atoms_text: String) -> Result<Atoms, Error> {
+        // number the lines from 1 for reporting errors
+        let mut lines = atoms_text.lines()
+                                  .enumerate()
+                                  .map(|(i, l)| (i + 1, l));
+        // the three lines after "Real Lattice(A)" hold the lattice vectors
+        let mut cell = [[0f64; 3]; 3];
+        let mut first = 0;
+        for (i, line) in lines.by_ref() {
+            if line.contains("Real Lattice") {
+                first = i + 1;
+                break;
+            }
+        }
+        if first == 0 {
+            return Err(Error::parse(0, "No lattice found in the header."));
+        }
+        for (k, v) in cell.iter_mut().enumerate() {
+            let line = lines.next().map(|(_, l)| {
+                                       l.split_whitespace()
+                                        .take(3)
+                                        .collect::<Vec<&str>>()
+                                        .join(" ")
+                                   });
+            let line = parse_line::<f64>(line.as_deref(),
+                                         first + k,
+                                         3,
+                                         "lattice")?;
+            v.copy_from_slice(&line[..3]);
+        }
+        let [a, b, c] = cell;
+        // density[c, b, a] so lets swap the c and a
+        let lattice = Lattice::try_new([[c[2], c[1], c[0]],
+                                        [b[2], b[1], b[0]],
+                                        [a[2], a[1], a[0]]])
+            .map_err(|e| Error::parse(first, e))?;
+        // now find the positions block in the cell file
+        for (_, line) in lines.by_ref() {
+            if line.trim_start().starts_with("END header") {
+                break;
+            }
+        }
+        let mut positions: Vec<[f64; 3]> = vec![];
+        let mut atomic_numbers: Vec<usize> = vec![];
+        let mut block = None;
+        let mut scale = 1f64;
+        for (i, line) in lines {
+            // remove the comments
+            let line = line.split(|c| c == '!' || c == '#').next().unwrap_or("");
+            let keyword = line.trim().to_lowercase();
+            if keyword.is_empty() {
+                continue;
+            }
+            if keyword.starts_with("%block") {
+                let name = keyword.split_whitespace().nth(1).unwrap_or("");
+                block = match name {
+                    "positions_frac" => Some(true),
+                    "positions_abs" => Some(false),
+                    _ => None,
+                };
+                continue;
+            } else if keyword.starts_with("%endblock") {
+                block = None;
+                continue;
+            }
+            let fractional = match block {
+                Some(f) => f,
+                None => continue,
+            };
+            let split = keyword.split_whitespace().collect::<Vec<&str>>();
+            // absolute positions can start with a units line
+            if split.len() == 1 {
+                scale = match split[0] {
+                    "bohr" | "a0" => LENGTH_UNITS,
+                    "nm" => 10.,
+                    _ => 1.,
+                };
+                continue;
+            }
+            // the species can carry a label, eg. "Ti:1"
+            atomic_numbers.push(elements::atomic_number(split[0]));
+            let pos = parse_line::<f64>(Some(&split[1..split.len().min(4)]
+                                                 .join(" ")),
+                                        i,
+                                        3,
+                                        "position")?;
+            let p = if fractional {
+                [pos[2], pos[1], pos[0]]
+            } else {
+                utils::dot([pos[2] * scale, pos[1] * scale, pos[0] * scale],
+                           lattice.to_fractional)
+            };
+            positions.push(utils::dot([p[0].rem_euclid(1f64),
+                                       p[1].rem_euclid(1f64),
+                                       p[2].rem_euclid(1f64)],
+                                      lattice.to_cartesian));
+        }
+        Ok(Atoms::new(lattice, positions, atomic_numbers, atoms_text))
+    }
+
+    /// A den_fmt stores the density multiplied by the volume of the cell.
+    fn scale(&self, atoms: &Atoms) -> f64 {
+        atoms.lattice.volume
+    }
+
+    /// Write a .den_fmt, and the .cell that was read alongside it, from a
+    /// vector of options where None will be written as zero.
+    fn write_values(&self,
+                    atoms: &Atoms,
+                    data: Vec<Option<f64>>,
+                    filename: String,
+                    pbar: Box<dyn Progress>)
+                    -> std::io::Result<()> {
+        let mut header = String::new();
+        let mut grid = None;
+        let mut lines = atoms.text.lines();
+        for line in lines.by_ref() {
+            header.push_str(line);
+            header.push('\n');
+            if line.contains("fine FFT grid") {
+                grid = line.split_whitespace()
+                           .take(3)
+                           .map(|x| x.parse::<usize>().ok().filter(|n| *n > 0))
+                           .collect::<Option<Vec<usize>>>()
+                           .filter(|g| g.len() == 3);
+            } else if line.trim_start().starts_with("END header") {
+                header.push('\n');
+                break;
+            }
+        }
+        let grid = grid.ok_or_else(|| {
+                           io::Error::new(io::ErrorKind::InvalidData,
+                                          "Unable to read the fine FFT grid \
+                                           from the header.")
+                       })?;
+        // the .cell follows the blank line after the header
+        let mut cell = String::new();
+        for line in lines.skip(1) {
+            cell.push_str(line);
+            cell.push('\n');
+        }
+        std::fs::write(format!("{}.cell", filename), cell)?;
+        let filename = format!("{}.den_fmt", filename);
+        let mut buffer = BufWriter::new(File::create(filename)?);
+        pbar.set_length(data.len() / grid[0]);
+        buffer.write_all(header.as_bytes())?;
+        for (p, d) in data.iter().enumerate() {
+            let i = p % grid[0];
+            let j = (p / grid[0]) % grid[1];
+            let k = p / (grid[0] * grid[1]);
+            writeln!(buffer,
+                     "{:6}{:6}{:6}{:20.10}",
+                     i + 1,
+                     j + 1,
+                     k + 1,
+                     d.unwrap_or(0.))?;
+            if i == 0 {
+                pbar.tick();
+            }
+        }
+        Ok(())
+    }
+
+    /// Deals with the flipped grid.
+    fn coordinate_format(&self, coords: [f64; 3]) -> (String, String, String) {
+        let z = format!("{:.6}", coords[0]);
+        let y = format!("{:.6}", coords[1]);
+        let x = format!("{:.6}", coords[2]);
+        (x, y, z)
+    }
+}
+
+impl Castep {
+    /// Reads the atoms from the .cell file with the same seed as filename,
+    /// and the lattice from the header of the density, naming the file, and
+    /// the line within it, of any error.
+    ///
+    /// * `filename`: The path to the .den_fmt.
+    /// * `header`: The lines of the header of the .den_fmt.
+    fn header_atoms(&self, filename: &str, header: &[String])
+                    -> Result<Atoms, Error> {
+        let cell_file = Path::new(filename).with_extension("cell");
+        let cell_name = cell_file.display().to_string();
+        let mut cell = String::new();
+        File::open(&cell_file).and_then(|mut f| f.read_to_string(&mut cell))
+                              .map_err(|e| Error::from(e).in_file(&cell_name))?;
+        // only a single density is written back out
+        let mut text = String::new();
+        for line in header.iter() {
+            if line.contains("nspins") {
+                text.push_str("   1                            ! nspins\n");
+            } else if line.trim_start().starts_with("END header") {
+                text.push_str(" END header: data is \"<a b c> charge\" in \
+                               units of electrons/grid_point * number of \
+                               grid_points\n");
+            } else {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+        text.push('\n');
+        text.push_str(&cell);
+        // the header lines match the density, the .cell follows a blank line
+        self.to_atoms(text).map_err(|e| match e {
+                               Error::Parse { line, message, .. }
+                                   if line > header.len() + 1 =>
+                               {
+                                   Error::parse(line - header.len() - 1,
+                                                message).in_file(&cell_name)
+                               }
+                               e => e.in_file(filename),
+                           })
+    }
+}
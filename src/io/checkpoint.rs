@@ -0,0 +1,528 @@
+use crate::arguments::Precision;
+use crate::atoms::{Atoms, Header, Lattice};
+use crate::errors::Error;
+use crate::grid::Grid;
+use crate::precision::Real;
+use crate::progress::Progress;
+use crate::storage::Storage;
+use crate::voxel_map::{Voxel, VoxelMap, MAX_WEIGHTS};
+use std::io::{Read, Write};
+
+/// The first bytes of a checkpoint, naming the version of the layout.
+const MAGIC: &[u8; 8] = b"BCACHK02";
+
+/// A partitioned density restored by [`read`]: the [`VoxelMap`], the [`Grid`]
+/// it was partitioned on and the [`Atoms`] of the input.
+///
+/// The file is little-endian and laid out as the magic bytes, the
+/// [`checksum`] of the input, the grid dimensions, the lattice, voxel origin
+/// and tolerances, the positions, atomic numbers, text and binary header of
+/// the atoms, then an i64 for each voxel (the maxima, -1 for vacuum or -2
+/// minus the index of its weights) followed by the weights of each boundary
+/// voxel.
+pub struct Checkpoint {
+    /// The [`checksum`] of the input that was partitioned.
+    pub checksum: u64,
+    /// The atoms of the input.
+    pub atoms: Atoms,
+    /// The grid the density was partitioned on.
+    pub grid: Grid,
+    /// The maxima, or weights, of every voxel.
+    pub voxel_map: VoxelMap,
+}
+
+/// Adds bytes to a 64 bit FNV-1a hash.
+fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |h, b| {
+                    (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+                })
+}
+
+/// A checksum of the input to a partition: the reference density, the grid
+/// and the lattice and positions of the atoms. A checkpoint is only loaded
+/// for an input with the same checksum.
+///
+/// ### Examples
+/// ```
+/// use bader::atoms::{Atoms, Lattice};
+/// use bader::io::checkpoint::checksum;
+///
+/// let lattice = Lattice::new([[2., 0., 0.], [0., 2., 0.], [0., 0., 2.]]);
+/// let atoms = Atoms::new(lattice, vec![[0.; 3]], vec![1], String::new());
+/// let mut density = vec![1.; 8];
+/// let before = checksum(&density, [2, 2, 2], &atoms);
+/// density[7] = 2.;
+/// assert_ne!(before, checksum(&density, [2, 2, 2], &atoms));
+/// ```
+pub fn checksum<T: Real>(reference: &[T],
+                         grid: [usize; 3],
+                         atoms: &Atoms)
+                         -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    for g in grid.iter() {
+        hash = fnv(hash, &(*g as u64).to_le_bytes());
+    }
+    let lattice = atoms.lattice.to_cartesian.iter().flatten();
+    for x in lattice.chain(atoms.positions.iter().flatten()) {
+        hash = fnv(hash, &x.to_le_bytes());
+    }
+    reference.iter().fold(hash, |h, x| fnv(h, &x.to_f64().to_le_bytes()))
+}
+
+/// Writes a partition so that it can be restored with [`read`].
+///
+/// * `checksum`: The [`checksum`] of the input.
+/// * `atoms`: The [`Atoms`] of the input.
+/// * `grid`: The [`Grid`] the density was partitioned on.
+/// * `voxel_map`: The fully partitioned [`VoxelMap`].
+/// * `writer`: Where to write the checkpoint.
+/// * `pbar`: A progress bar for monitoring the write.
+pub fn write<W: Write>(checksum: u64,
+                       atoms: &Atoms,
+                       grid: &Grid,
+                       voxel_map: &VoxelMap,
+                       writer: &mut W,
+                       pbar: Box<dyn Progress>)
+                       -> std::io::Result<()> {
+    let floats = |values: &[f64]| {
+        values.iter()
+              .flat_map(|v| v.to_le_bytes().to_vec())
+              .collect::<Vec<u8>>()
+    };
+    let size = [grid.size.x as u64, grid.size.y as u64, grid.size.z as u64];
+    writer.write_all(MAGIC)?;
+    writer.write_all(&checksum.to_le_bytes())?;
+    for s in size.iter() {
+        writer.write_all(&s.to_le_bytes())?;
+    }
+    for v in atoms.lattice.to_cartesian.iter() {
+        writer.write_all(&floats(v))?;
+    }
+    writer.write_all(&floats(&grid.voxel_origin))?;
+    // a NaN vacuum tolerance stands for none
+    writer.write_all(&floats(&[grid.weight_tolerance,
+                               grid.maxima_tolerance,
+                               grid.vacuum_tolerance.unwrap_or(f64::NAN)]))?;
+    writer.write_all(&(atoms.positions.len() as u64).to_le_bytes())?;
+    for p in atoms.positions.iter() {
+        writer.write_all(&floats(p))?;
+    }
+    for z in atoms.atomic_numbers.iter() {
+        writer.write_all(&(*z as u64).to_le_bytes())?;
+    }
+    writer.write_all(&(atoms.text.len() as u64).to_le_bytes())?;
+    writer.write_all(atoms.text.as_bytes())?;
+    writer.write_all(&[atoms.header.big_endian as u8])?;
+    writer.write_all(&(atoms.header.records.len() as u64).to_le_bytes())?;
+    for record in atoms.header.records.iter() {
+        writer.write_all(&(record.len() as u64).to_le_bytes())?;
+        writer.write_all(record)?;
+    }
+    // renumber the weights in the order of the voxels
+    let mut weights = Vec::with_capacity(voxel_map.boundary_voxels());
+    for p in 0..grid.size.total {
+        let entry = match voxel_map.voxel_get(p as isize) {
+            Voxel::Maxima(m) => m as i64,
+            Voxel::Weight(w) => {
+                weights.push(w);
+                -1 - weights.len() as i64
+            }
+            Voxel::Vacuum => -1,
+        };
+        writer.write_all(&entry.to_le_bytes())?;
+        pbar.tick();
+    }
+    writer.write_all(&(weights.len() as u64).to_le_bytes())?;
+    for w in weights {
+        let w = w.iter()
+                 .map(|(maxima, weight)| maxima as f64 + weight)
+                 .collect::<Vec<f64>>();
+        writer.write_all(&(w.len() as u32).to_le_bytes())?;
+        writer.write_all(&floats(&w))?;
+    }
+    writer.flush()
+}
+
+/// Reads the little-endian values of a checkpoint.
+struct Values<'a, R: Read> {
+    reader: &'a mut R,
+}
+
+impl<'a, R: Read> Values<'a, R> {
+    /// Fills the buffer, treating the end of the file as malformed.
+    fn bytes(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let eof = std::io::ErrorKind::UnexpectedEof;
+        self.reader.read_exact(buffer).map_err(|e| {
+                                           if e.kind() == eof {
+                                               Error::parse(0,
+                                                            "Checkpoint is \
+                                                             truncated.")
+                                           } else {
+                                               e.into()
+                                           }
+                                       })
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let mut buffer = [0u8; 8];
+        self.bytes(&mut buffer)?;
+        Ok(u64::from_le_bytes(buffer))
+    }
+
+    fn i64(&mut self) -> Result<i64, Error> {
+        Ok(self.u64()? as i64)
+    }
+
+    fn f64(&mut self) -> Result<f64, Error> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    fn vector(&mut self) -> Result<[f64; 3], Error> {
+        Ok([self.f64()?, self.f64()?, self.f64()?])
+    }
+
+    /// Reads bytes of a length given first, without trusting the length for
+    /// the allocation.
+    fn block(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.u64()?;
+        let mut block = Vec::new();
+        (&mut self.reader).take(len).read_to_end(&mut block)?;
+        if (block.len() as u64) < len {
+            return Err(Error::parse(0, "Checkpoint is truncated."));
+        }
+        Ok(block)
+    }
+
+    fn text(&mut self) -> Result<String, Error> {
+        Ok(String::from_utf8(self.block()?)?)
+    }
+}
+
+/// Reads a checkpoint written by [`write`].
+///
+/// * `reader`: The checkpoint.
+/// * `storage`: Where to keep the maxima of each voxel of the [`VoxelMap`].
+/// * `precision`: The precision to keep the weights of the boundary voxels
+///   in, see [`VoxelMap::with_precision`].
+/// * `pbar`: A progress bar for monitoring the read.
+///
+/// ### Returns:
+/// `Result<Checkpoint, Error>`: The [`Checkpoint`], or an [`Error::Parse`] if
+/// the file isn't a checkpoint or is malformed.
+pub fn read<R: Read>(reader: &mut R,
+                     storage: &Storage,
+                     precision: Precision,
+                     pbar: Box<dyn Progress>)
+                     -> Result<Checkpoint, Error> {
+    let invalid = |message: &str| Err(Error::parse(0, message));
+    let mut values = Values { reader };
+    let mut magic = [0u8; 8];
+    values.bytes(&mut magic)?;
+    if &magic != MAGIC {
+        return invalid("Not a checkpoint or from another version of bca.");
+    }
+    let checksum = values.u64()?;
+    let mut size = [0usize; 3];
+    for s in size.iter_mut() {
+        *s = values.u64()? as usize;
+    }
+    let total = size.iter().try_fold(1usize, |t, s| t.checked_mul(*s));
+    let total = match total {
+        Some(t) if t > 0 && t < isize::MAX as usize - 1 => t,
+        _ => return invalid("Grid size is invalid."),
+    };
+    pbar.set_length(total);
+    let lattice = [values.vector()?, values.vector()?, values.vector()?];
+    let lattice = match Lattice::try_new(lattice) {
+        Ok(lattice) => lattice,
+        Err(e) => return invalid(&e),
+    };
+    let voxel_origin = values.vector()?;
+    let weight_tolerance = values.f64()?;
+    let maxima_tolerance = values.f64()?;
+    let vacuum_tolerance = Some(values.f64()?).filter(|v| !v.is_nan());
+    let atoms_len = values.u64()? as usize;
+    if atoms_len > total {
+        return invalid("Number of atoms is invalid.");
+    }
+    let positions = (0..atoms_len).map(|_| values.vector())
+                                  .collect::<Result<Vec<[f64; 3]>, Error>>()?;
+    let atomic_numbers = (0..atoms_len).map(|_| Ok(values.u64()? as usize))
+                                       .collect::<Result<Vec<usize>, Error>>()?;
+    let text = values.text()?;
+    let mut big_endian = [0u8];
+    values.bytes(&mut big_endian)?;
+    let records_len = values.u64()?;
+    let records = (0..records_len).map(|_| values.block())
+                                  .collect::<Result<Vec<Vec<u8>>, Error>>()?;
+    let header = Header { big_endian: big_endian[0] != 0,
+                          records };
+    let voxel_map = VoxelMap::with_precision(total, storage, precision)?;
+    let mut weights_len = 0;
+    for p in 0..total as isize {
+        match values.i64()? {
+            m if m >= total as i64 => return invalid("Maxima is off the grid."),
+            -1 => (),
+            m if m >= 0 => voxel_map.maxima_store(p, m as isize),
+            w => {
+                weights_len = weights_len.max(-1 - w as isize);
+                voxel_map.maxima_store(p, w as isize);
+            }
+        }
+        pbar.tick();
+    }
+    let len = values.u64()? as usize;
+    if len > total || (len as isize) < weights_len {
+        return invalid("Number of boundary voxels is invalid.");
+    }
+    let mut indices = Vec::with_capacity(len);
+    {
+        let t = total as f64;
+        let mut weights = voxel_map.lock();
+        for _ in 0..len {
+            let mut buffer = [0u8; 4];
+            values.bytes(&mut buffer)?;
+            let w_len = u32::from_le_bytes(buffer) as usize;
+            if w_len == 0 || w_len > MAX_WEIGHTS {
+                return invalid("Boundary voxel weights are invalid.");
+            }
+            let w = (0..w_len).map(|_| values.f64())
+                              .collect::<Result<Vec<f64>, Error>>()?;
+            // the whole part of each weight is the maxima it belongs to
+            if w.iter().any(|x| !(*x >= 0. && *x < t)) {
+                return invalid("Boundary voxel weights are invalid.");
+            }
+            indices.push(weights.push(w));
+        }
+    }
+    voxel_map.renumber_weights(&indices);
+    // the analysis looks up every maxima referred to, so each has to be one
+    let is_maxima = |m: usize| match voxel_map.voxel_get(m as isize) {
+        Voxel::Maxima(n) => n == m,
+        _ => false,
+    };
+    for p in 0..total as isize {
+        let valid = match voxel_map.voxel_get(p) {
+            Voxel::Maxima(m) => is_maxima(m),
+            Voxel::Weight(w) => w.iter().all(|(m, _)| is_maxima(m)),
+            Voxel::Vacuum => true,
+        };
+        if !valid {
+            return invalid("Voxel belongs to a maxima that isn't one.");
+        }
+    }
+    let mut atoms = Atoms::new(lattice, positions, atomic_numbers, text);
+    atoms.header = header;
+    let grid = Grid::new(size,
+                         atoms.lattice.to_cartesian,
+                         weight_tolerance,
+                         maxima_tolerance,
+                         vacuum_tolerance,
+                         voxel_origin);
+    Ok(Checkpoint { checksum,
+                    atoms,
+                    grid,
+                    voxel_map })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{run, Config};
+    use crate::progress::Silent;
+
+    fn partition() -> (Atoms, crate::pipeline::Results) {
+        let lattice =
+            Lattice::new([[4., 0., 0.], [0., 4., 0.], [0., 0., 4.]]);
+        let mut atoms = Atoms::new(lattice,
+                                   vec![[0.; 3], [0., 0., 2.]],
+                                   vec![1, 8],
+                                   String::from("test"));
+        atoms.header = Header { big_endian: true,
+                                records: vec![vec![1, 2], vec![], vec![3]] };
+        let density = (0..64usize).map(|p| {
+                                      let x = (p % 4) as f64;
+                                      (-4. * x.min(4. - x).powi(2)).exp()
+                                      + 0.5 * (-4. * (x - 2.).powi(2)).exp()
+                                  })
+                                  .collect::<Vec<f64>>();
+        let config = Config { threads: 1,
+                              vacuum_tolerance: Some(1E-3),
+                              ..Config::default() };
+        let results =
+            run(&config, &[density], &[], &atoms, [4, 4, 4], [0.; 3], &Silent)
+            .unwrap();
+        (atoms, results)
+    }
+
+    fn same_partition(a: &VoxelMap, b: &VoxelMap) {
+        for p in 0..64 {
+            let flag = match (a.voxel_get(p), b.voxel_get(p)) {
+                (Voxel::Maxima(a), Voxel::Maxima(b)) => a == b,
+                (Voxel::Weight(a), Voxel::Weight(b)) => a == b,
+                (Voxel::Vacuum, Voxel::Vacuum) => true,
+                _ => false,
+            };
+            assert!(flag)
+        }
+    }
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let (atoms, results) = partition();
+        let mut buffer = Vec::new();
+        write(42,
+              &atoms,
+              &results.grid,
+              &results.voxel_map,
+              &mut buffer,
+              Box::new(Silent)).unwrap();
+        let saved = read(&mut &buffer[..],
+                         &Storage::Memory,
+                         Precision::Double,
+                         Box::new(Silent)).unwrap();
+        assert_eq!(saved.checksum, 42);
+        assert_eq!(saved.atoms.positions, atoms.positions);
+        assert_eq!(saved.atoms.atomic_numbers, atoms.atomic_numbers);
+        assert_eq!(saved.atoms.text, atoms.text);
+        assert!(saved.atoms.header.big_endian);
+        assert_eq!(saved.atoms.header.records, atoms.header.records);
+        assert_eq!(saved.grid.vacuum_tolerance, Some(1E-3));
+        assert!(results.voxel_map.boundary_voxels() > 0);
+        same_partition(&results.voxel_map, &saved.voxel_map);
+    }
+
+    #[test]
+    fn checkpoint_single() {
+        let (atoms, results) = partition();
+        let mut buffer = Vec::new();
+        write(42,
+              &atoms,
+              &results.grid,
+              &results.voxel_map,
+              &mut buffer,
+              Box::new(Silent)).unwrap();
+        let saved = read(&mut &buffer[..],
+                         &Storage::Memory,
+                         Precision::Single,
+                         Box::new(Silent)).unwrap();
+        assert_eq!(saved.voxel_map.boundary_voxels(),
+                   results.voxel_map.boundary_voxels());
+        for p in 0..64 {
+            match (results.voxel_map.voxel_get(p), saved.voxel_map.voxel_get(p))
+            {
+                (Voxel::Weight(a), Voxel::Weight(b)) => {
+                    for ((m, w), (n, x)) in a.iter().zip(b.iter()) {
+                        assert_eq!(m, n);
+                        assert!((w - x).abs() < 1E-7);
+                    }
+                    assert_eq!(a.iter().count(), b.iter().count());
+                }
+                (Voxel::Maxima(a), Voxel::Maxima(b)) => assert_eq!(a, b),
+                (Voxel::Vacuum, Voxel::Vacuum) => (),
+                _ => panic!("voxel {} differs", p),
+            }
+        }
+    }
+
+    #[test]
+    fn checkpoint_truncated() {
+        let (atoms, results) = partition();
+        let mut buffer = Vec::new();
+        write(42,
+              &atoms,
+              &results.grid,
+              &results.voxel_map,
+              &mut buffer,
+              Box::new(Silent)).unwrap();
+        buffer.truncate(buffer.len() - 4);
+        let result = read(&mut &buffer[..],
+                          &Storage::Memory,
+                          Precision::Double,
+                          Box::new(Silent));
+        let flag = matches!(result, Err(Error::Parse { .. }));
+        assert!(flag)
+    }
+
+    /// Whether a checkpoint of the partition, altered by `alter`, is refused.
+    fn refused<F: Fn(&VoxelMap)>(alter: F) -> bool {
+        let (atoms, results) = partition();
+        alter(&results.voxel_map);
+        let mut buffer = Vec::new();
+        write(42,
+              &atoms,
+              &results.grid,
+              &results.voxel_map,
+              &mut buffer,
+              Box::new(Silent)).unwrap();
+        let result = read(&mut &buffer[..],
+                          &Storage::Memory,
+                          Precision::Double,
+                          Box::new(Silent));
+        matches!(result, Err(Error::Parse { .. }))
+    }
+
+    #[test]
+    fn checkpoint_not_maxima() {
+        // voxels 0 and 2 are maxima of the density, voxel 1 is neither
+        assert!(refused(|voxel_map| {
+            let flag = matches!(voxel_map.voxel_get(0), Voxel::Maxima(0));
+            assert!(flag);
+            voxel_map.maxima_store(0, 1)
+        }));
+        assert!(refused(|voxel_map| voxel_map.maxima_store(1, 5)));
+    }
+
+    #[test]
+    fn checkpoint_weight_not_maxima() {
+        assert!(refused(|voxel_map| {
+            let i = voxel_map.lock().push(vec![0.5, 1.5]);
+            voxel_map.weight_store(1, i);
+        }));
+        assert!(!refused(|voxel_map| {
+            let i = voxel_map.lock().push(vec![0.5, 2.5]);
+            voxel_map.weight_store(1, i);
+        }));
+    }
+
+    #[test]
+    fn checkpoint_singular_lattice() {
+        let (atoms, results) = partition();
+        let mut buffer = Vec::new();
+        write(42,
+              &atoms,
+              &results.grid,
+              &results.voxel_map,
+              &mut buffer,
+              Box::new(Silent)).unwrap();
+        // the lattice follows the magic bytes, checksum and grid
+        for b in buffer[40..112].iter_mut() {
+            *b = 0;
+        }
+        let result = read(&mut &buffer[..],
+                          &Storage::Memory,
+                          Precision::Double,
+                          Box::new(Silent));
+        let flag = matches!(result, Err(Error::Parse { .. }));
+        assert!(flag)
+    }
+
+    #[test]
+    fn checkpoint_mapped() {
+        let (atoms, results) = partition();
+        let mut buffer = Vec::new();
+        write(42,
+              &atoms,
+              &results.grid,
+              &results.voxel_map,
+              &mut buffer,
+              Box::new(Silent)).unwrap();
+        let storage = Storage::Mapped { directory: std::env::temp_dir(),
+                                        max_resident: None };
+        let saved = read(&mut &buffer[..],
+                         &storage,
+                         Precision::Double,
+                         Box::new(Silent)).unwrap();
+        same_partition(&results.voxel_map, &saved.voxel_map);
+    }
+}
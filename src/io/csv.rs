@@ -0,0 +1,238 @@
+use crate::analysis::Analysis;
+use crate::arguments::Args;
+use crate::atoms::Atoms;
+use crate::grid::Grid;
+use crate::io::{json, FileFormat};
+use crate::utils;
+use serde_json::Value;
+use std::io::Write;
+
+/// Writes the metadata of the run as comment lines, "# key: value", with the
+/// value formatted as JSON.
+fn write_metadata<W: Write>(buffer: &mut W,
+                            metadata: &[(&str, Value)])
+                            -> std::io::Result<()> {
+    for (key, value) in metadata.iter() {
+        writeln!(buffer, "# {}: {}", key, value)?;
+    }
+    Ok(())
+}
+
+/// Writes every field of the [`Analysis`] to two CSV files, or any other
+/// sinks, one with a row for each atom and one with a row for each Bader
+/// maxima.
+///
+/// Both files start with the metadata of the run as comment lines beginning
+/// with "#". The atoms file also records the vacuum and total values in these
+/// comments. Columns of charge and spin are named by the lowercase name of the
+/// density, ie. "charge" and "spin".
+///
+/// * `analysis`: The [`Analysis`] to write.
+/// * `atoms`: The [`Atoms`] of the density file.
+/// * `grid`: The [`Grid`] of the density.
+/// * `args`: The [`Args`] of the run.
+/// * `file_type`: [`FileFormat`] for writing the correct coordinates.
+/// * `atoms_buffer`: Where to write the atoms.
+/// * `bader_buffer`: Where to write the Bader maxima.
+#[allow(clippy::borrowed_box)]
+pub fn write<W, V>(analysis: &Analysis,
+                   atoms: &Atoms,
+                   grid: &Grid,
+                   args: &Args,
+                   file_type: &Box<dyn FileFormat>,
+                   atoms_buffer: &mut W,
+                   bader_buffer: &mut V)
+                   -> std::io::Result<()>
+    where W: Write,
+          V: Write
+{
+    let density_names =
+        file_type.density_names(atoms, analysis.bader_charge.len());
+    let keys = density_names.iter()
+                            .map(|name| name.to_lowercase().replace(' ', "_"))
+                            .collect::<Vec<String>>();
+    let metadata = json::metadata(args, atoms, grid, &density_names);
+    let net_charge = !analysis.atoms_net_charge.is_empty();
+    // the atoms
+    let buffer = atoms_buffer;
+    write_metadata(buffer, &metadata)?;
+    for (key, charge) in keys.iter().zip(&analysis.vacuum_charge) {
+        writeln!(buffer, "# vacuum_{}: {}", key, json::number(*charge))?;
+    }
+    writeln!(buffer,
+             "# vacuum_volume: {}",
+             json::number(analysis.vacuum_volume))?;
+    for (key, charge) in keys.iter().zip(&analysis.total_charge) {
+        writeln!(buffer, "# total_{}: {}", key, json::number(*charge))?;
+    }
+    let mut header = vec![String::from("index"),
+                          String::from("element"),
+                          String::from("atomic_number"),
+                          String::from("x"),
+                          String::from("y"),
+                          String::from("z")];
+    header.extend(keys.iter().cloned());
+    if net_charge {
+        header.push(String::from("net_charge"));
+    }
+    header.push(String::from("volume"));
+    header.push(String::from("surface_distance"));
+    writeln!(buffer, "{}", header.join(","))?;
+    for i in 0..atoms.positions.len() {
+        let (x, y, z) = file_type.coordinate_format(atoms.positions[i]);
+        let mut row = vec![(i + 1).to_string(),
+                           atoms.symbols[i].clone(),
+                           atoms.atomic_numbers[i].to_string(),
+                           x,
+                           y,
+                           z];
+        analysis.atoms_charge
+                .iter()
+                .for_each(|charge| row.push(json::number(charge[i])));
+        if let Some(charge) = analysis.atoms_net_charge.get(i) {
+            row.push(json::number(*charge));
+        }
+        row.push(json::number(analysis.atoms_volume[i]));
+        row.push(analysis.surface_distance
+                         .get(i)
+                         .map_or(String::new(), |d| json::number(*d)));
+        writeln!(buffer, "{}", row.join(","))?;
+    }
+    buffer.flush()?;
+    // the maxima
+    let buffer = bader_buffer;
+    write_metadata(buffer, &metadata)?;
+    let mut header = vec![String::from("index"),
+                          String::from("atom"),
+                          String::from("x"),
+                          String::from("y"),
+                          String::from("z")];
+    header.extend(keys.iter().cloned());
+    header.push(String::from("volume"));
+    header.push(String::from("minimum_distance"));
+    writeln!(buffer, "{}", header.join(","))?;
+    for (i, maxima) in analysis.bader_maxima.iter().enumerate() {
+        let p = grid.to_cartesian(*maxima as isize);
+        let p = utils::dot(p, grid.voxel_lattice.to_cartesian);
+        let (x, y, z) = file_type.coordinate_format(p);
+        let mut row = vec![(i + 1).to_string(),
+                           (analysis.assigned_atom[i] + 1).to_string(),
+                           x,
+                           y,
+                           z];
+        analysis.bader_charge
+                .iter()
+                .for_each(|charge| row.push(json::number(charge[i])));
+        row.push(json::number(analysis.bader_volume[i]));
+        row.push(json::number(analysis.minimum_distance[i]));
+        writeln!(buffer, "{}", row.join(","))?;
+    }
+    buffer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::json::tests::results;
+    use crate::io::vasp::Vasp;
+
+    /// The metadata comments and the rows, including the header, of a file.
+    type Table = (Vec<String>, Vec<Vec<String>>);
+
+    /// Writes the [`results`] as CSV and parses them back into a [`Table`]
+    /// of the atoms and of the maxima.
+    fn parse() -> (Table, Table) {
+        let (analysis, atoms, grid, args) = results();
+        let file_type: Box<dyn FileFormat> = Box::new(Vasp {});
+        let (mut atoms_buffer, mut bader_buffer) = (Vec::new(), Vec::new());
+        write(&analysis,
+              &atoms,
+              &grid,
+              &args,
+              &file_type,
+              &mut atoms_buffer,
+              &mut bader_buffer).unwrap();
+        let split = |buffer: Vec<u8>| {
+            let text = String::from_utf8(buffer).unwrap();
+            let (comments, rows): (Vec<&str>, Vec<&str>) =
+                text.lines().partition(|l| l.starts_with('#'));
+            (comments.iter().map(|c| c.to_string()).collect(),
+             rows.iter()
+                 .map(|r| r.split(',').map(String::from).collect())
+                 .collect())
+        };
+        (split(atoms_buffer), split(bader_buffer))
+    }
+
+    /// The value of the metadata comment, key, parsed as JSON.
+    fn comment(comments: &[String], key: &str) -> Value {
+        let prefix = format!("# {}: ", key);
+        let line = comments.iter().find(|c| c.starts_with(&prefix)).unwrap();
+        serde_json::from_str(&line[prefix.len()..]).unwrap()
+    }
+
+    #[test]
+    fn csv_metadata() {
+        let ((atoms_comments, _), (bader_comments, _)) = parse();
+        for comments in [atoms_comments, bader_comments].iter() {
+            assert_eq!(comment(comments, "file"), "CHGCAR");
+            assert_eq!(comment(comments, "grid"),
+                       serde_json::json!([2, 2, 2]));
+            assert_eq!(comment(comments, "vacuum_tolerance"), 1E-4);
+        }
+    }
+
+    #[test]
+    fn csv_atoms() {
+        let ((comments, rows), _) = parse();
+        assert_eq!(comment(&comments, "vacuum_charge"), 0.125);
+        assert_eq!(comment(&comments, "vacuum_volume"), 0.5);
+        assert_eq!(comment(&comments, "total_spin"), 0.25);
+        assert_eq!(rows[0],
+                   vec!["index",
+                        "element",
+                        "atomic_number",
+                        "x",
+                        "y",
+                        "z",
+                        "charge",
+                        "spin",
+                        "net_charge",
+                        "volume",
+                        "surface_distance"]);
+        assert_eq!(rows.len(), 3);
+        let values = rows[2][3..].iter()
+                                 .map(|v| v.parse::<f64>().ok())
+                                 .collect::<Vec<Option<f64>>>();
+        assert_eq!(rows[2][..3], ["2", "H", "1"]);
+        assert_eq!(values,
+                   vec![Some(0.5),
+                        Some(1.),
+                        Some(1.5),
+                        Some(1.5),
+                        Some(-0.25),
+                        None,
+                        Some(5.),
+                        Some(1.25)]);
+    }
+
+    #[test]
+    fn csv_maxima() {
+        let (_, (_, rows)) = parse();
+        assert_eq!(rows[0],
+                   vec!["index",
+                        "atom",
+                        "x",
+                        "y",
+                        "z",
+                        "charge",
+                        "spin",
+                        "volume",
+                        "minimum_distance"]);
+        assert_eq!(rows.len(), 3);
+        let values = rows[1].iter()
+                            .map(|v| v.parse::<f64>().unwrap())
+                            .collect::<Vec<f64>>();
+        assert_eq!(values, vec![1., 1., 0., 0., 0., 6.5, 0.5, 3., 0.25]);
+    }
+}
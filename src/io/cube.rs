@@ -1,193 +1,232 @@
 use crate::atoms::{Atoms, Lattice};
-use crate::io::reader::BufReader;
-use crate::io::{FileFormat, FortranFormat, ReadFunction};
-use crate::progress::Bar;
+use crate::errors::{parse_line, parse_value, Error};
+use crate::io::reader::{read_values, BufReader};
+use crate::io::{output, Dimensions, FileFormat, FortranFormat,
+                ReadFunction};
+use crate::progress::Progress;
+use crate::storage::{Buffer, Storage};
 use crate::utils;
 use std::fs::File;
-use std::io::{BufWriter, Read, Write};
+use std::io::{self, BufWriter, Read, Write};
 
 /// Convert from chemists.
 const LENGTH_UNITS: f64 = 0.52917721067;
 /// Convert from chemists.
 const VOLUME_UNITS: f64 = LENGTH_UNITS * LENGTH_UNITS * LENGTH_UNITS;
 
+/// Rewrites a cube header with multiple values per voxel, either from the
+/// fifth field of the third line or from a negative number of atoms and the
+/// orbital indices, as a header for a single value per voxel.
+fn single_value_header(text: &str) -> String {
+    let mut lines = text.lines();
+    let mut header = String::new();
+    for _ in 0..2 {
+        header.push_str(lines.next().unwrap_or(""));
+        header.push('\n');
+    }
+    let line = lines.next().unwrap_or("");
+    let split = line.split_whitespace().collect::<Vec<&str>>();
+    match split.first().map(|x| x.parse::<f64>()) {
+        Some(Ok(natoms)) if natoms < 0. || split.len() > 4 => {
+            header.push_str(&format!("{:5}", natoms.abs() as usize));
+            split[1..4].iter().for_each(|x| {
+                                   header.push_str(&format!(" {:>11}", x))
+                               });
+            header.push('\n');
+            for line in lines.take(3 + natoms.abs() as usize) {
+                header.push_str(line);
+                header.push('\n');
+            }
+            header
+        }
+        _ => String::from(text),
+    }
+}
+
+/// Where the density starts in a cube file and what the header describes.
+struct Layout {
+    /// The byte the density starts at.
+    start: usize,
+    /// The line the density starts on, for reporting parse errors.
+    lines: usize,
+    /// The number of voxels along each lattice vector.
+    grid: [usize; 3],
+    /// The number of values per voxel.
+    values: usize,
+    /// The origin of the grid.
+    origin: [f64; 3],
+    /// The number of atoms.
+    atoms: usize,
+}
+
+/// Reads the header of the cube file, filename, without the density.
+fn layout(filename: &str) -> Result<Layout, Error> {
+    let mut reader = BufReader::open(filename)?;
+    let mut buffer = String::new();
+    let mut pos = 0;
+    let mut line_number = 0;
+    // read the next line counting its size and number
+    let mut next_line = || -> Result<Option<String>, Error> {
+        match reader.read_line(&mut buffer) {
+            Some(line) => {
+                let (text, size) = line?;
+                pos += size;
+                line_number += 1;
+                Ok(Some(text.clone()))
+            }
+            None => Ok(None),
+        }
+    };
+    // first two lines are comments
+    for _ in 0..2 {
+        let _ = next_line()?;
+    }
+    // lets start trying to match
+    let text = next_line()?;
+    let split = parse_line::<f64>(text.as_deref(), 3, 4, "origin")?;
+    // an optional fifth field is the number of values per voxel
+    let values = match split.get(4) {
+        Some(x) if *x >= 1. => *x as usize,
+        Some(_) => {
+            return Err(Error::parse(3,
+                                    "Number of values per voxel must be \
+                                     positive."))
+        }
+        None => 1,
+    };
+    let natoms = split[0] as isize;
+    let origin = [split[1], split[2], split[3]];
+    let mut grid = [0usize; 3];
+    for (i, gp) in grid.iter_mut().enumerate() {
+        let text = next_line()?;
+        *gp = parse_value(text.as_deref()
+                              .and_then(|t| t.split_whitespace().next()),
+                          4 + i,
+                          "grid")?;
+    }
+    for i in 0..natoms.abs() as usize {
+        if next_line()?.is_none() {
+            return Err(Error::parse(7 + i, "Missing atom."));
+        }
+    }
+    // negative natoms is followed by the number of orbitals and their
+    // indices, which can span multiple lines
+    let values = if natoms < 0 {
+        let mut orbitals = Vec::new();
+        loop {
+            let text = next_line()?;
+            let line = 7 + natoms.abs() as usize + orbitals.len();
+            if text.is_none() {
+                return Err(Error::parse(line, "Missing orbitals."));
+            }
+            orbitals.extend(parse_line::<usize>(text.as_deref(),
+                                                line,
+                                                0,
+                                                "orbitals")?);
+            if !orbitals.is_empty() && orbitals.len() > orbitals[0] {
+                break;
+            }
+        }
+        match orbitals[0] {
+            0 => {
+                return Err(Error::parse(7 + natoms.abs() as usize,
+                                        "Number of orbitals must be \
+                                         positive."))
+            }
+            n => n,
+        }
+    } else {
+        values
+    };
+    Ok(Layout { start: pos,
+                lines: line_number + 1,
+                grid,
+                values,
+                origin,
+                atoms: natoms.abs() as usize })
+}
+
 /// Structure for reading/writing a cube file.
 pub struct Cube {}
 
 impl FileFormat for Cube {
     /// reads a cube file from filename.
-    fn read(&self, filename: String) -> ReadFunction {
+    fn read(&self, filename: String, storage: &Storage) -> ReadFunction {
         // the voxel origin in cube files is (0.5, 0.5, 0.5)
         let mut voxel_origin = [0.5f64; 3];
 
-        println!("Reading {} as cube format:", filename);
+        // name the file in any error
+        let named = |e: Error| e.in_file(&filename);
         // find the start and end points of the density as well as the total file size
-        let (start, grid_pts) = {
-            // open the file in a buffer reader
-            let mut reader = BufReader::open(filename.clone())?;
-
-            let mut buffer = String::new();
-            let mut pos = 0;
-            // first two lines are comments
-            for _ in 0..2 {
-                let size = match reader.read_line(&mut buffer) {
-                    Some(line) => {
-                        let (_, size) = line?;
-                        size
-                    }
-                    None => 0,
-                };
-                pos += size;
-            }
-            // lets start trying to match
-            let natoms = match reader.read_line(&mut buffer) {
-                Some(line) => {
-                    let (text, size) = line?;
-                    pos += size;
-                    let split = text
-                        .trim()
-                        .split_whitespace()
-                        .map(|x| x.parse::<f64>())
-                        .collect::<Vec<Result<f64, std::num::ParseFloatError>>>(
-                        );
-                    if split.len() == 5 && split[4] != Ok(1.) {
-                        panic!(
-                            "Error(Unsuppoerted): Multiple values per voxel."
-                        );
-                    }
-                    let natoms = match split[0] {
-                        Ok(x) => x as isize,
-                        Err(_) => panic!("Error: Cannot read {} as cube file.",
-                                         filename),
-                    };
-                    for i in 0..3 {
-                        voxel_origin[i] += match split[i + 1] {
-                            Ok(x) => x,
-                            Err(_) => {
-                                panic!("Error: Cannot read {} as cube file.",
-                                       filename)
-                            }
-                        };
-                    }
-                    natoms
-                }
-                None => panic!("Error: Cannot read {} as cube file.", filename),
-            };
-            if natoms < 0 {
-                panic!("Error(Unsuppoerted): Multiple values per voxel.");
-            }
-            let mut grid_pts = [0usize; 3];
-            for gp in &mut grid_pts {
-                *gp = match reader.read_line(&mut buffer) {
-                    Some(line) => {
-                        let (text, size) = line?;
-                        pos += size;
-                        match text.trim().split_whitespace().next() {
-                            Some(x) => match x.parse::<usize>() {
-                                Ok(x) => x,
-                                Err(_) => panic!(
-                                    "Error: Cannot read {} as cube file.",
-                                    filename
-                                ),
-                            },
-                            None => {
-                                panic!("Error: Cannot read {} as cube file.",
-                                       filename)
-                            }
-                        }
-                    }
-                    None => {
-                        panic!("Error: Cannot read {} as cube file.", filename)
-                    }
-                }
-            }
-            for _ in 0..natoms.abs() {
-                match reader.read_line(&mut buffer) {
-                    Some(line) => {
-                        let (_, size) = line?;
-                        pos += size;
-                    }
-                    None => {
-                        panic!("Error: Cannot read {} as cube file.", filename)
-                    }
-                }
-            }
-            (pos, grid_pts)
-        };
+        let Layout { start,
+                     lines,
+                     grid: grid_pts,
+                     values,
+                     origin,
+                     .. } = layout(&filename).map_err(named)?;
+        for i in 0..3 {
+            voxel_origin[i] += origin[i];
+        }
         // Now we know where everything is so let's work out what to do
-        // Start by making vector of start and end points of the densities
-        let mut file = File::open(filename)?;
-        let total = file.metadata()?.len();
-        // assign Vectos with the capacity of what it is to hold
-        let mut xyz_b = Vec::with_capacity(start);
-        let mut density_b = Vec::with_capacity(total as usize - start);
+        let mut file = File::open(&filename).map(io::BufReader::new)
+                                            .map_err(|e| named(e.into()))?;
         // read the xyz information into xyz_b
-        let _ = <File as Read>::by_ref(&mut file).take(start as u64)
-                                                 .read_to_end(&mut xyz_b)?;
-        // read the total charge density into density_b
-        let _ = <File as Read>::by_ref(&mut file).take(total - start as u64)
-                                                 .read_to_end(&mut density_b)?;
+        let mut xyz_b = Vec::with_capacity(start);
+        let _ = file.by_ref().take(start as u64).read_to_end(&mut xyz_b)?;
         // convert the bytes we have read into a String and an Atoms struct
-        let xyz = String::from_utf8(xyz_b).unwrap();
-        let atoms = self.to_atoms(xyz);
-        // convert out of Bohr
-        let density = String::from_utf8(density_b).unwrap()
-                                                  .split_whitespace()
-                                                  .map(|x| {
-                                                      x.parse::<f64>().unwrap()
-                                                      / VOLUME_UNITS
-                                                  })
-                                                  .collect::<Vec<f64>>();
-        println!("File read successfully.");
-        Ok((voxel_origin, grid_pts, atoms, vec![density]))
+        let xyz = String::from_utf8(xyz_b).map_err(|e| named(e.into()))?;
+        let atoms = self.to_atoms(xyz).map_err(named)?;
+        // convert out of Bohr, multiple values per voxel are interleaved
+        let total = grid_pts.iter().product::<usize>();
+        let mut densities = (0..values).map(|_| Buffer::zeroed(total, storage))
+                                       .collect::<std::io::Result<Vec<_>>>()
+                                       .map_err(|e| named(e.into()))?;
+        read_values(&mut file, lines, total * values, "density", |i, x| {
+            densities[i % values][i / values] = x / VOLUME_UNITS
+        }).map_err(named)?;
+        Ok((voxel_origin, grid_pts, atoms, densities))
+    }
+
+    /// Reads the grid and the numbers of values and atoms from the header.
+    fn dimensions(&self, filename: String) -> Result<Dimensions, Error> {
+        let layout = layout(&filename).map_err(|e| e.in_file(&filename))?;
+        Ok(Dimensions { grid: layout.grid,
+                        densities: layout.values,
+                        atoms: layout.atoms })
     }
 
     /// Read atoms information from file header.
-    fn to_atoms(&self, atoms_text: String) -> Atoms {
+    fn to_atoms(&self, atoms_text: String) -> Result<Atoms, Error> {
         let mut lines = atoms_text.lines();
         // skip the 2 comment lines + voxel info and then read the lattice information
         let _ = lines.next();
         let _ = lines.next();
-        let _ = lines.next();
-        let mut a = {
-            lines.next()
-                 .unwrap()
-                 .to_string()
-                 .split_whitespace()
-                 .map(|x| x.parse::<f64>().unwrap())
-                 .collect::<Vec<f64>>()
-        };
+        let natoms = parse_line::<f64>(lines.next(), 3, 4, "origin")?[0].abs()
+                     as usize;
+        let mut a = parse_line::<f64>(lines.next(), 4, 4, "lattice")?;
         // density[z, y, x] so lets swap the c and a
-        let mut b = {
-            lines.next()
-                 .unwrap()
-                 .to_string()
-                 .split_whitespace()
-                 .map(|x| x.parse::<f64>().unwrap())
-                 .collect::<Vec<f64>>()
-        };
-        let mut c = {
-            lines.next()
-                 .unwrap()
-                 .to_string()
-                 .split_whitespace()
-                 .map(|x| x.parse::<f64>().unwrap())
-                 .collect::<Vec<f64>>()
-        };
+        let mut b = parse_line::<f64>(lines.next(), 5, 4, "lattice")?;
+        let mut c = parse_line::<f64>(lines.next(), 6, 4, "lattice")?;
         for i in 1..4 {
             c[i] *= c[0] * LENGTH_UNITS;
             b[i] *= b[0] * LENGTH_UNITS;
             a[i] *= a[0] * LENGTH_UNITS;
         }
-        let lattice = Lattice::new([[a[1], a[2], a[3]],
-                                    [b[1], b[2], b[3]],
-                                    [c[1], c[2], c[3]]]);
+        let lattice = Lattice::try_new([[a[1], a[2], a[3]],
+                                        [b[1], b[2], b[3]],
+                                        [c[1], c[2], c[3]]])
+            .map_err(|e| Error::parse(4, e))?;
         let mut positions: Vec<[f64; 3]> = vec![];
-        // make the positions fractional and swap c and a
-        for line in lines {
-            let pos = line.split_whitespace()
-                          .map(|x| x.parse::<f64>().unwrap() * LENGTH_UNITS)
+        let mut atomic_numbers: Vec<usize> = vec![];
+        // make the positions fractional and swap c and a, any orbital indices
+        // follow the atoms
+        for i in 0..natoms {
+            let pos = parse_line::<f64>(lines.next(), 7 + i, 5, "atom")?
+                          .into_iter()
+                          .map(|x| x * LENGTH_UNITS)
                           .collect::<Vec<f64>>();
+            // the first column is the atomic number
+            atomic_numbers.push((pos[0] / LENGTH_UNITS).round() as usize);
             let pos_frac =
                 utils::dot([pos[2], pos[3], pos[4]], lattice.to_fractional)
                     .iter()
@@ -199,31 +238,86 @@ impl FileFormat for Cube {
             };
             positions.push(pos_cart);
         }
-        Atoms::new(lattice, positions, atoms_text)
+        Ok(Atoms::new(lattice, positions, atomic_numbers, atoms_text))
+    }
+
+    /// The density is stored per cubic Bohr.
+    fn scale(&self, _atoms: &Atoms) -> f64 {
+        VOLUME_UNITS
     }
 
     /// Write a cube file from a vector of options where None will be written as
     /// zero.
-    fn write(&self,
-             atoms: &Atoms,
-             data: Vec<Option<f64>>,
-             filename: String,
-             pbar: Bar)
-             -> std::io::Result<()> {
+    fn write_values(&self,
+                    atoms: &Atoms,
+                    data: Vec<Option<f64>>,
+                    filename: String,
+                    pbar: Box<dyn Progress>)
+                    -> std::io::Result<()> {
         let filename = format!("{}.cube", filename);
         let mut buffer = BufWriter::new(File::create(filename)?);
         pbar.set_length(data.len() / 6 + (data.len() % 6 != 0) as usize);
-        buffer.write_all(atoms.text.as_bytes())?;
-        data.chunks(6).for_each(|line| {
-            if let Err(e) = line.iter().try_for_each(|f| write!(buffer, " {:.5}", FortranFormat{ float: *f, mult: VOLUME_UNITS })) {
-                panic!("Error occured during write: {}", e)
-            };
-            if let Err(e) = writeln!(buffer) {
-                panic!("Error occured during write: {}", e)
-            };
+        buffer.write_all(single_value_header(&atoms.text).as_bytes())?;
+        data.chunks(6).try_for_each(|line| {
+            line.iter()
+                .try_for_each(|f| write!(buffer, " {:.5}", FortranFormat{ float: *f, mult: 1. }))?;
+            writeln!(buffer)?;
             pbar.tick();
-        });
-        Ok(())
+            Ok(())
+        })
+    }
+
+    /// Orbitals are named by their index and multiple values per voxel by their
+    /// column, otherwise the charge and spin.
+    fn density_names(&self,
+                     atoms: &Atoms,
+                     densities_len: usize)
+                     -> Vec<String> {
+        let mut lines = atoms.text.lines().skip(2);
+        let split = lines.next()
+                         .unwrap_or("")
+                         .split_whitespace()
+                         .map(String::from)
+                         .collect::<Vec<String>>();
+        let natoms = split.first()
+                          .and_then(|x| x.parse::<f64>().ok())
+                          .unwrap_or(0.) as isize;
+        if natoms < 0 {
+            lines.skip(3 + natoms.abs() as usize)
+                 .flat_map(|line| line.split_whitespace())
+                 .skip(1)
+                 .take(densities_len)
+                 .map(|x| format!("MO {}", x))
+                 .collect()
+        } else if split.get(4)
+                       .and_then(|x| x.parse::<f64>().ok())
+                       .map_or(false, |x| x > 1.)
+        {
+            (1..=densities_len).map(|i| format!("Value {}", i)).collect()
+        } else {
+            output::density_names(densities_len)
+        }
+    }
+
+    /// The nuclear charge, the second column of each atom line. Values of 0
+    /// are treated as missing.
+    fn valence(&self, atoms: &Atoms) -> Vec<Option<f64>> {
+        atoms.text
+             .lines()
+             .skip(6)
+             .take(atoms.positions.len())
+             .map(|line| {
+                 line.split_whitespace()
+                     .nth(1)
+                     .and_then(|x| x.parse::<f64>().ok())
+                     .filter(|z| *z > 0.)
+             })
+             .collect()
+    }
+
+    /// The last index is the fastest in a cube file so nothing is reversed.
+    fn reversed(&self) -> bool {
+        false
     }
 
     /// Coordinate format for dealing with fortran indexing (doesn't affect cube).
@@ -0,0 +1,274 @@
+use crate::analysis::Analysis;
+use crate::arguments::{Args, Reference};
+use crate::atoms::Atoms;
+use crate::grid::Grid;
+use crate::io::FileFormat;
+use crate::utils;
+use serde_json::{json, Map, Value};
+use std::io::Write;
+
+/// Escapes a string and wraps it in quotes.
+///
+/// ### Examples
+/// ```
+/// use bader::io::json::string;
+///
+/// assert_eq!(string("a \"b\"\\c"), String::from("\"a \\\"b\\\"\\\\c\""));
+/// ```
+pub fn string(s: &str) -> String {
+    Value::from(s).to_string()
+}
+
+/// Formats a float as a JSON number, null if it is not finite.
+///
+/// ### Examples
+/// ```
+/// use bader::io::json::number;
+///
+/// assert_eq!(number(1.5), String::from("1.5"));
+/// assert_eq!(number(f64::NAN), String::from("null"));
+/// ```
+pub fn number(f: f64) -> String {
+    Value::from(f).to_string()
+}
+
+/// The metadata of the run as pairs of keys and JSON values: the version,
+/// input files, tolerances and the grid.
+///
+/// * `args`: The [`Args`] of the run.
+/// * `atoms`: The [`Atoms`] of the density file.
+/// * `grid`: The [`Grid`] of the density.
+/// * `density_names`: The names of the densities.
+pub fn metadata(args: &Args,
+                atoms: &Atoms,
+                grid: &Grid,
+                density_names: &[String])
+                -> Vec<(&'static str, Value)> {
+    let (reference, column) = match &args.reference {
+        Reference::One(f) => (json!([f]), None),
+        Reference::Two(f1, f2) => (json!([f1, f2]), None),
+        Reference::Column(i) => (json!([]), Some(i + 1)),
+        Reference::None => (json!([]), None),
+    };
+    // the grid and lattice are stored as [c, b, a] each as [z, y, x]
+    let l = atoms.lattice.to_cartesian;
+    let lattice = [[l[2][2], l[2][1], l[2][0]],
+                   [l[1][2], l[1][1], l[1][0]],
+                   [l[0][2], l[0][1], l[0][0]]];
+    vec![("version", json!(env!("CARGO_PKG_VERSION"))),
+         ("file", json!(args.file)),
+         ("file_type", json!(args.file_type.to_string())),
+         ("spin", json!(args.spin)),
+         ("reference", reference),
+         ("column", json!(column)),
+         ("potcar", json!(args.potcar)),
+         ("weight_tolerance", json!(grid.weight_tolerance)),
+         ("maxima_tolerance", json!(grid.maxima_tolerance)),
+         ("vacuum_tolerance", json!(grid.vacuum_tolerance)),
+         ("threads", json!(args.threads)),
+         ("grid", json!([grid.size.z, grid.size.y, grid.size.x])),
+         ("lattice", json!(lattice)),
+         ("density_names", json!(density_names))]
+}
+
+/// A position, as written by the [`FileFormat`], as a JSON array.
+#[allow(clippy::borrowed_box)]
+fn position(p: [f64; 3], file_type: &Box<dyn FileFormat>) -> Value {
+    let (x, y, z) = file_type.coordinate_format(p);
+    [x, y, z].iter()
+             .map(|c| c.parse::<f64>().map_or(Value::Null, Value::from))
+             .collect()
+}
+
+/// Writes every field of the [`Analysis`] along with metadata about the run
+/// to a JSON file, or any other sink.
+///
+/// The file has a "metadata" object, an "atoms" and a "maxima" array with an
+/// object for each atom or Bader maxima, and the "vacuum" and "total" values.
+/// The charge and spin of each atom and maxima are keyed by the lowercase
+/// name of the density, ie. "charge" and "spin". The keys of every object are
+/// sorted.
+///
+/// * `analysis`: The [`Analysis`] to write.
+/// * `atoms`: The [`Atoms`] of the density file.
+/// * `grid`: The [`Grid`] of the density.
+/// * `args`: The [`Args`] of the run.
+/// * `file_type`: [`FileFormat`] for writing the correct coordinates.
+/// * `buffer`: Where to write the JSON.
+#[allow(clippy::borrowed_box)]
+pub fn write<W: Write>(analysis: &Analysis,
+                       atoms: &Atoms,
+                       grid: &Grid,
+                       args: &Args,
+                       file_type: &Box<dyn FileFormat>,
+                       buffer: &mut W)
+                       -> std::io::Result<()> {
+    let density_names =
+        file_type.density_names(atoms, analysis.bader_charge.len());
+    let keys = density_names.iter()
+                            .map(|name| name.to_lowercase().replace(' ', "_"))
+                            .collect::<Vec<String>>();
+    // an object of the value of each density at i
+    let densities = |values: &[Vec<f64>], i: usize| {
+        keys.iter()
+            .zip(values)
+            .map(|(key, v)| (key.clone(), json!(v[i])))
+            .collect::<Map<String, Value>>()
+    };
+    let metadata = metadata(args, atoms, grid, &density_names)
+                       .into_iter()
+                       .map(|(key, value)| (String::from(key), value))
+                       .collect::<Map<String, Value>>();
+    let atoms_rows = (0..atoms.positions.len()).map(|i| {
+        let mut row = densities(&analysis.atoms_charge, i);
+        row.insert(String::from("index"), json!(i + 1));
+        row.insert(String::from("element"), json!(atoms.symbols[i]));
+        row.insert(String::from("atomic_number"),
+                   json!(atoms.atomic_numbers[i]));
+        row.insert(String::from("position"),
+                   position(atoms.positions[i], file_type));
+        if let Some(net_charge) = analysis.atoms_net_charge.get(i) {
+            row.insert(String::from("net_charge"), json!(net_charge));
+        }
+        row.insert(String::from("volume"), json!(analysis.atoms_volume[i]));
+        if let Some(d) = analysis.surface_distance.get(i) {
+            row.insert(String::from("surface_distance"), json!(d));
+        }
+        Value::Object(row)
+    })
+    .collect::<Vec<Value>>();
+    let maxima_rows = analysis.bader_maxima.iter().enumerate().map(|(i, m)| {
+        let p = grid.to_cartesian(*m as isize);
+        let p = utils::dot(p, grid.voxel_lattice.to_cartesian);
+        let mut row = densities(&analysis.bader_charge, i);
+        row.insert(String::from("index"), json!(i + 1));
+        row.insert(String::from("atom"), json!(analysis.assigned_atom[i] + 1));
+        row.insert(String::from("position"), position(p, file_type));
+        row.insert(String::from("volume"), json!(analysis.bader_volume[i]));
+        row.insert(String::from("minimum_distance"),
+                   json!(analysis.minimum_distance[i]));
+        Value::Object(row)
+    })
+    .collect::<Vec<Value>>();
+    let total = |values: &[f64]| {
+        keys.iter()
+            .zip(values)
+            .map(|(key, v)| (key.clone(), json!(v)))
+            .collect::<Map<String, Value>>()
+    };
+    let mut vacuum = total(&analysis.vacuum_charge);
+    vacuum.insert(String::from("volume"), json!(analysis.vacuum_volume));
+    let document = json!({
+        "metadata": metadata,
+        "atoms": atoms_rows,
+        "maxima": maxima_rows,
+        "vacuum": vacuum,
+        "total": total(&analysis.total_charge),
+    });
+    serde_json::to_writer_pretty(&mut *buffer, &document)?;
+    writeln!(buffer)?;
+    buffer.flush()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::arguments::ClapApp;
+    use crate::atoms::Lattice;
+    use crate::io::vasp::Vasp;
+    use crate::voxel_map::VoxelMap;
+
+    /// An [`Analysis`] of two atoms, each with a maxima, on a 2x2x2 grid of
+    /// charge and spin, with the [`Atoms`], [`Grid`] and [`Args`] of the run.
+    pub(crate) fn results() -> (Analysis, Atoms, Grid, Args) {
+        let lattice =
+            Lattice::new([[2., 0., 0.], [0., 2., 0.], [0., 0., 2.]]);
+        let atoms = Atoms::new(lattice,
+                               vec![[0.; 3], [1.5, 1., 0.5]],
+                               vec![8, 1],
+                               String::new());
+        let grid = Grid::new([2, 2, 2],
+                             atoms.lattice.to_cartesian,
+                             1E-3,
+                             1E-6,
+                             Some(1E-4),
+                             [0.; 3]);
+        let voxel_map = VoxelMap::new(8);
+        voxel_map.maxima_store(0, 0);
+        voxel_map.maxima_store(7, 7);
+        let mut analysis = Analysis::new(&voxel_map, 2, 2);
+        analysis.assigned_atom = vec![0, 1];
+        analysis.minimum_distance = vec![0.25, 0.5];
+        analysis.surface_distance = vec![0.75, 1.25];
+        analysis.bader_charge = vec![vec![6.5, 1.5], vec![0.5, -0.25]];
+        analysis.bader_volume = vec![3., 5.];
+        analysis.atoms_charge = vec![vec![6.5, 1.5], vec![0.5, -0.25]];
+        analysis.atoms_volume = vec![3., 5.];
+        analysis.atoms_net_charge = vec![-0.5, f64::NAN];
+        analysis.vacuum_charge = vec![0.125, 0.];
+        analysis.vacuum_volume = 0.5;
+        analysis.total_charge = vec![8.125, 0.25];
+        let argv = vec!["bca", "CHGCAR", "-r", "AECCAR0", "-r", "AECCAR2"];
+        let args = Args::new(ClapApp::get().get_matches_from(argv)).unwrap();
+        (analysis, atoms, grid, args)
+    }
+
+    /// Writes the [`results`] as JSON and parses them back.
+    fn parse() -> Value {
+        let (analysis, atoms, grid, args) = results();
+        let file_type: Box<dyn FileFormat> = Box::new(Vasp {});
+        let mut buffer = Vec::new();
+        write(&analysis, &atoms, &grid, &args, &file_type, &mut buffer)
+            .unwrap();
+        serde_json::from_slice(&buffer).unwrap()
+    }
+
+    #[test]
+    fn json_metadata() {
+        let metadata = &parse()["metadata"];
+        assert_eq!(metadata["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(metadata["file"], "CHGCAR");
+        assert_eq!(metadata["spin"], Value::Null);
+        assert_eq!(metadata["reference"], json!(["AECCAR0", "AECCAR2"]));
+        assert_eq!(metadata["column"], Value::Null);
+        assert_eq!(metadata["weight_tolerance"], 1E-3);
+        assert_eq!(metadata["vacuum_tolerance"], 1E-4);
+        assert_eq!(metadata["grid"], json!([2, 2, 2]));
+        assert_eq!(metadata["lattice"][0], json!([2., 0., 0.]));
+        assert_eq!(metadata["density_names"], json!(["Charge", "Spin"]));
+    }
+
+    #[test]
+    fn json_atoms() {
+        let document = parse();
+        let atoms = document["atoms"].as_array().unwrap();
+        assert_eq!(atoms.len(), 2);
+        assert_eq!(atoms[1],
+                   json!({"index": 2,
+                          "element": "H",
+                          "atomic_number": 1,
+                          "position": [0.5, 1., 1.5],
+                          "charge": 1.5,
+                          "spin": -0.25,
+                          "net_charge": null,
+                          "volume": 5.,
+                          "surface_distance": 1.25}));
+        assert_eq!(atoms[0]["net_charge"], -0.5);
+    }
+
+    #[test]
+    fn json_maxima_vacuum_total() {
+        let document = parse();
+        assert_eq!(document["maxima"][1],
+                   json!({"index": 2,
+                          "atom": 2,
+                          "position": [1., 1., 1.],
+                          "charge": 1.5,
+                          "spin": -0.25,
+                          "volume": 5.,
+                          "minimum_distance": 0.5}));
+        assert_eq!(document["vacuum"],
+                   json!({"charge": 0.125, "spin": 0., "volume": 0.5}));
+        assert_eq!(document["total"], json!({"charge": 8.125, "spin": 0.25}));
+    }
+}
@@ -1,12 +1,64 @@
 use crate::analysis::Analysis;
 use crate::atoms::Atoms;
+use crate::errors::Error;
 use crate::grid::Grid;
-use crate::io::{FileFormat, WriteType};
-use crate::progress::Bar;
+use crate::io::{vtk, FileFormat, WriteType};
+use crate::precision::Real;
+use crate::progress::Reporter;
 use crate::utils;
 use crate::voxel_map::VoxelMap;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+/// Where the output files are written: a directory and a prefix added to the
+/// start of every file name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OutputPath {
+    /// The directory to write into, empty for the current directory.
+    pub directory: PathBuf,
+    /// Added to the start of every file name.
+    pub prefix: String,
+}
+
+impl OutputPath {
+    /// Creates a new OutputPath.
+    ///
+    /// * `directory`: The directory to write into.
+    /// * `prefix`: Added to the start of every file name.
+    pub fn new<P: Into<PathBuf>>(directory: P, prefix: String) -> Self {
+        Self { directory: directory.into(),
+               prefix }
+    }
+
+    /// The path of a file in the output directory with the prefix added.
+    ///
+    /// ### Examples
+    /// ```
+    /// use bader::io::output::OutputPath;
+    /// use std::path::PathBuf;
+    ///
+    /// let path = OutputPath::new("results", String::from("run_1_"));
+    /// assert_eq!(path.join("ACF.dat"), PathBuf::from("results/run_1_ACF.dat"));
+    /// ```
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.directory.join(format!("{}{}", self.prefix, name))
+    }
+
+    /// Creates the output directory, and any parents, if it doesn't exist.
+    pub fn create_dir(&self) -> std::io::Result<()> {
+        if self.directory.as_os_str().is_empty() {
+            Ok(())
+        } else {
+            std::fs::create_dir_all(&self.directory)
+        }
+    }
+
+    /// Creates a buffered file in the output directory with the prefix added.
+    pub fn create(&self, name: &str) -> std::io::Result<BufWriter<File>> {
+        Ok(BufWriter::new(File::create(self.join(name))?))
+    }
+}
 
 /// Enum of available tables.
 enum TableType {
@@ -20,57 +72,76 @@ enum TableType {
 struct Table {
     /// How wide each column is.
     column_width: Vec<usize>,
+    /// The names of the charge and spin densities.
+    density_names: Vec<String>,
     /// The number of charge and spin densities.
     density_num: usize,
+    /// Whether there is a net charge column.
+    net_charge: bool,
     /// The rows of the table as a vector of strings.
     rows: Vec<Vec<String>>,
+    /// The average net charge of each species for the footer.
+    species_net_charge: Vec<(String, f64)>,
     /// What type of table the structure is.
     table_type: TableType,
 }
 
 impl Table {
     /// Creates a new structure and sets the minimum widths of each.
-    fn new(table_type: TableType, density_num: usize) -> Self {
+    fn new(table_type: TableType,
+           density_names: Vec<String>,
+           net_charge: bool)
+           -> Self {
         let rows = vec![Vec::with_capacity(0)];
-        let mut column_width = Vec::with_capacity(6 + density_num);
+        let density_num = density_names.len();
+        let mut column_width = Vec::with_capacity(8 + density_num);
         column_width.push(1);
+        if let TableType::AtomsCharge = table_type {
+            column_width.push(7);
+        }
         column_width.push(1);
         column_width.push(1);
         column_width.push(1);
-        column_width.push(6);
-        match density_num.cmp(&2) {
-            std::cmp::Ordering::Greater => {
-                column_width.push(6);
-                column_width.push(6);
-                column_width.push(6);
-            }
-            std::cmp::Ordering::Equal => column_width.push(6),
-            std::cmp::Ordering::Less => (),
-        };
+        density_names.iter()
+                     .for_each(|name| column_width.push(name.len().max(6)));
+        if net_charge {
+            column_width.push(10);
+        }
         column_width.push(6);
         column_width.push(8);
         Self { column_width,
+               density_names,
                density_num,
+               net_charge,
                rows,
+               species_net_charge: Vec::with_capacity(0),
                table_type }
     }
 
     /// Adds a row the table.
-    #[allow(clippy::borrowed_box)]
+    #[allow(clippy::borrowed_box, clippy::too_many_arguments)]
     fn add_row(&mut self,
                index: usize,
+               element: Option<&str>,
                p: [f64; 3],
                density: &[f64],
+               net_charge: Option<f64>,
                volume: f64,
                distance: f64,
                file_type: &Box<dyn FileFormat>) {
-        let mut row: Vec<String> = Vec::with_capacity(6 + self.density_num);
+        let mut row: Vec<String> = Vec::with_capacity(8 + self.density_num);
         row.push(format!("{}", index));
+        if let Some(element) = element {
+            row.push(String::from(element));
+        }
         let coord = file_type.coordinate_format(p);
         row.push(coord.0);
         row.push(coord.1);
         row.push(coord.2);
         density.iter().for_each(|d| row.push(format!("{:.6}", d)));
+        if let Some(net_charge) = net_charge {
+            row.push(format!("{:.6}", net_charge));
+        }
         row.push(format!("{:.6}", volume));
         row.push(format!("{:.6}", distance));
         for (i, col) in row.iter().enumerate() {
@@ -89,33 +160,30 @@ impl Table {
         match self.table_type {
             TableType::AtomsCharge => {
                 let mut separator = self.format_separator(0);
-                let footer = match self.density_num.cmp(&2) {
-                        std::cmp::Ordering::Less => format!(
-                            "\n  Vacuum Charge: {:>18.4}\n  Vacuum Volume: {:>18.4}\n  Partitioned Charge: {:>13.4}",
-                            analysis.vacuum_charge[0],
-                            analysis.vacuum_volume,
-                            analysis.total_charge[0],
-                        ),
-                        std::cmp::Ordering::Equal =>  format!(
-                            "\n  Vacuum Charge: {:>18.4}\n  Vacuum Spin: {:>20.4}\n  Vacuum Volume: {:>18.4}\n  Partitioned Charge: {:>13.4}\n  Partitioned Spin: {:>15.4}",
-                            analysis.vacuum_charge[0],
-                            analysis.vacuum_charge[1],
-                            analysis.vacuum_volume,
-                            analysis.total_charge[0], analysis.total_charge[1]
-                        ),
-                        std::cmp::Ordering::Greater =>  format!(
-                            "\n  Vacuum Charge: {:>18.4}\n  Vacuum Spin X: {:>18.4}\n  Vacuum Spin Y: {:>18.4}\n  Vacuum Spin Z: {:>18.4}\n  Vacuum Volume: {:>18.4}\n  Partitioned Charge: {:>13.4}\n  Partitioned Spin X: {:>13.4}\n  Partitioned Spin Y: {:>13.4}\n  Partitioned Spin Z: {:>13.4}",
-                            analysis.vacuum_charge[0],
-                            analysis.vacuum_charge[1],
-                            analysis.vacuum_charge[2],
-                            analysis.vacuum_charge[3],
-                            analysis.vacuum_volume,
-                            analysis.total_charge[0],
-                            analysis.total_charge[1],
-                            analysis.total_charge[2],
-                            analysis.total_charge[3]
-                        ),
+                let mut footer = String::new();
+                let mut push_line = |label: String, value: f64| {
+                    let width = 33usize.saturating_sub(label.len()).max(1);
+                    footer.push_str(&format!("\n  {}{:>width$.4}",
+                                             label,
+                                             value,
+                                             width = width));
                 };
+                for (name, charge) in
+                    self.density_names.iter().zip(&analysis.vacuum_charge)
+                {
+                    push_line(format!("Vacuum {}:", name), *charge);
+                }
+                push_line(String::from("Vacuum Volume:"),
+                          analysis.vacuum_volume);
+                for (name, charge) in
+                    self.density_names.iter().zip(&analysis.total_charge)
+                {
+                    push_line(format!("Partitioned {}:", name), *charge);
+                }
+                for (symbol, charge) in self.species_net_charge.iter() {
+                    push_line(format!("Average Net Charge ({}):", symbol),
+                              *charge);
+                }
                 separator.push_str(&footer);
                 separator
             }
@@ -130,6 +198,11 @@ impl Table {
         header.push_str(&format!(" {:^width$} |",
                                  "#",
                                  width = iter.next().unwrap()));
+        if let TableType::AtomsCharge = self.table_type {
+            header.push_str(&format!(" {:^width$} |",
+                                     "Element",
+                                     width = iter.next().unwrap()));
+        }
         header.push_str(&format!(" {:^width$} |",
                                  "X",
                                  width = iter.next().unwrap()));
@@ -139,31 +212,15 @@ impl Table {
         header.push_str(&format!(" {:^width$} |",
                                  "Z",
                                  width = iter.next().unwrap()));
-        header.push_str(&format!(" {:^width$} |",
-                                 "Charge",
-                                 width = iter.next().unwrap()));
-        match self.table_type {
-            TableType::AtomsCharge | TableType::BaderCharge => {
-                match self.density_num.cmp(&2) {
-                    std::cmp::Ordering::Equal => {
-                        header.push_str(&format!(" {:^width$} |",
-                                                 "Spin",
-                                                 width = iter.next().unwrap()));
-                    }
-                    std::cmp::Ordering::Greater => {
-                        header.push_str(&format!(" {:^width$} |",
-                                                 "Spin X",
-                                                 width = iter.next().unwrap()));
-                        header.push_str(&format!(" {:^width$} |",
-                                                 "Spin Y",
-                                                 width = iter.next().unwrap()));
-                        header.push_str(&format!(" {:^width$} |",
-                                                 "Spin Z",
-                                                 width = iter.next().unwrap()));
-                    }
-                    std::cmp::Ordering::Less => (),
-                }
-            }
+        for name in self.density_names.iter() {
+            header.push_str(&format!(" {:^width$} |",
+                                     name,
+                                     width = iter.next().unwrap()));
+        }
+        if self.net_charge {
+            header.push_str(&format!(" {:^width$} |",
+                                     "Net Charge",
+                                     width = iter.next().unwrap()));
         }
         header.push_str(&format!(" {:^width$} |",
                                  "Volume",
@@ -235,21 +292,41 @@ pub fn charge_files(analysis: &Analysis,
                     grid: &Grid,
                     file_type: &Box<dyn FileFormat>)
                     -> (String, String) {
+    let density_names =
+        file_type.density_names(atoms, analysis.bader_charge.len());
+    let net_charge = !analysis.atoms_net_charge.is_empty();
     let mut bader_table =
-        Table::new(TableType::BaderCharge, analysis.bader_charge.len());
+        Table::new(TableType::BaderCharge, density_names.clone(), false);
     let mut atoms_table =
-        Table::new(TableType::AtomsCharge, analysis.bader_charge.len());
+        Table::new(TableType::AtomsCharge, density_names, net_charge);
+    // average the net charge over each species in order of appearance
+    for (symbol, charge) in atoms.symbols.iter().zip(&analysis.atoms_net_charge)
+    {
+        match atoms_table.species_net_charge
+                         .iter()
+                         .position(|(s, _)| s == symbol)
+        {
+            Some(i) => atoms_table.species_net_charge[i].1 += charge,
+            None => atoms_table.species_net_charge
+                               .push((symbol.clone(), *charge)),
+        }
+    }
+    for (symbol, charge) in atoms_table.species_net_charge.iter_mut() {
+        *charge /= atoms.symbols.iter().filter(|s| *s == symbol).count() as f64;
+    }
     let mut index: Vec<usize> = (0..analysis.bader_maxima.len()).collect();
     index.sort_by(|a, b| {
              analysis.assigned_atom[*a].cmp(&analysis.assigned_atom[*b])
          });
     let mut atom_num = 0;
     atoms_table.add_row(atom_num + 1,
+                        Some(&atoms.symbols[atom_num]),
                         atoms.positions[atom_num],
                         &analysis.atoms_charge
                                  .iter()
                                  .map(|charge| charge[atom_num])
                                  .collect::<Vec<f64>>(),
+                        analysis.atoms_net_charge.get(atom_num).copied(),
                         analysis.atoms_volume[atom_num],
                         analysis.surface_distance[atom_num],
                         file_type);
@@ -259,11 +336,13 @@ pub fn charge_files(analysis: &Analysis,
             if a != atom_num {
                 bader_table.add_separator();
                 atoms_table.add_row(a + 1,
+                                    Some(&atoms.symbols[a]),
                                     atoms.positions[a],
                                     &analysis.atoms_charge
                                              .iter()
                                              .map(|charge| charge[a])
                                              .collect::<Vec<f64>>(),
+                                    analysis.atoms_net_charge.get(a).copied(),
                                     analysis.atoms_volume[a],
                                     analysis.surface_distance[a],
                                     file_type);
@@ -276,11 +355,13 @@ pub fn charge_files(analysis: &Analysis,
             utils::dot(maxima_cartesian, grid.voxel_lattice.to_cartesian);
         if analysis.bader_charge[0][i] >= grid.maxima_tolerance {
             bader_table.add_row(i + 1,
+                                None,
                                 maxima_cartesian,
                                 &analysis.bader_charge
                                          .iter()
                                          .map(|charge| charge[i])
                                          .collect::<Vec<f64>>(),
+                                None,
                                 analysis.bader_volume[i],
                                 analysis.minimum_distance[i],
                                 file_type);
@@ -295,103 +376,319 @@ pub fn charge_files(analysis: &Analysis,
 ///
 /// * `atoms_charge_file`: The contents, as a String, of the ACF.dat file.
 /// * `bader_charge_file`: The contents, as a String, of the BCF.dat file.
+/// * `path`: The [`OutputPath`] to write the files to.
 pub fn write(atoms_charge_file: String,
-             bader_charge_file: String)
+             bader_charge_file: String,
+             path: &OutputPath)
              -> std::io::Result<()> {
-    let mut bader_file = File::create("BCF.dat")?;
+    let mut bader_file = path.create("BCF.dat")?;
     bader_file.write_all(bader_charge_file.as_bytes())?;
-    let mut atoms_file = File::create("ACF.dat")?;
+    let mut atoms_file = path.create("ACF.dat")?;
     atoms_file.write_all(atoms_charge_file.as_bytes())?;
     Ok(())
 }
 
-/// Write the densities of either Bader atoms or volumes.
-#[allow(clippy::borrowed_box)]
-pub fn write_densities(atoms: &Atoms,
-                       analysis: &Analysis,
-                       densities: Vec<Vec<f64>>,
-                       grid: &Grid,
-                       output: WriteType,
-                       voxel_map: &VoxelMap,
-                       file_type: &Box<dyn FileFormat>)
-                       -> std::io::Result<()> {
-    let filename = match densities.len().cmp(&2) {
-        std::cmp::Ordering::Less => vec![String::from("charge")],
+/// The names of the charge and spin densities for labelling output.
+pub fn density_names(densities_len: usize) -> Vec<String> {
+    match densities_len.cmp(&2) {
+        std::cmp::Ordering::Less => vec![String::from("Charge")],
         std::cmp::Ordering::Equal => {
-            vec![String::from("charge"), String::from("spin")]
+            vec![String::from("Charge"), String::from("Spin")]
+        }
+        std::cmp::Ordering::Greater => vec![String::from("Charge"),
+                                            String::from("Spin X"),
+                                            String::from("Spin Y"),
+                                            String::from("Spin Z")],
+    }
+}
+
+/// Write the densities of either Bader atoms or volumes, or the VTK files,
+/// to the [`OutputPath`], reporting the progress to `reporter`. An index of an
+/// atom or volume that doesn't exist is an [`Error::Argument`], returned
+/// before anything is written.
+#[allow(clippy::borrowed_box, clippy::too_many_arguments)]
+pub fn write_densities<T, D>(atoms: &Atoms,
+                             analysis: &Analysis,
+                             densities: Vec<D>,
+                             grid: &Grid,
+                             output: WriteType,
+                             voxel_map: &VoxelMap,
+                             file_type: &Box<dyn FileFormat>,
+                             path: &OutputPath,
+                             reporter: &dyn Reporter)
+                             -> Result<(), Error>
+    where T: Real,
+          D: AsRef<[T]> {
+    // check the indices against the atoms and volumes that exist
+    let in_range = |indices: &[usize], len: usize, name: &str| {
+        match indices.iter().find(|i| **i >= len) {
+            Some(i) => Err(Error::Argument(format!("Cannot write {} {} as \
+                                                    there are only {}.",
+                                                   name,
+                                                   i + 1,
+                                                   len))),
+            None => Ok(()),
         }
-        std::cmp::Ordering::Greater => vec![String::from("charge"),
-                                            String::from("spin_x"),
-                                            String::from("spin_y"),
-                                            String::from("spin_z")],
+    };
+    match &output {
+        WriteType::Atom(a) | WriteType::SumAtoms(a) => {
+            in_range(a, atoms.positions.len(), "atom")?
+        }
+        WriteType::Volume(v) | WriteType::SumVolumes(v) => {
+            in_range(v, analysis.bader_maxima.len(), "volume")?
+        }
+        _ => (),
+    }
+    let filename = file_type.density_names(atoms, densities.len())
+                            .iter()
+                            .map(|name| name.to_lowercase().replace(' ', "_"))
+                            .collect::<Vec<String>>();
+    // sums the volumes into one density, name is either atoms or volumes
+    let write_sum = |name: &str, volumes: &[usize]| -> Result<(), Error> {
+        reporter.message(&format!("Writing out the summed charge density \
+                                   of the {}:",
+                                  name));
+        let pbar = reporter.stage("Building map", grid.size.total);
+        let map = analysis.output_sum_map(grid, voxel_map, volumes, pbar)?;
+        for (i, den) in densities.iter().enumerate() {
+            let fname = format!("{}_sum_{}", name, filename[i]);
+            let pbar = reporter.stage(&format!("Writing {}", fname), 1);
+            let den = den.as_ref()
+                         .iter()
+                         .zip(&map)
+                         .map(|(d, weight)| weight.map(|w| d.to_f64() * w))
+                         .collect::<Vec<Option<f64>>>();
+            let fname = path.join(&fname).display().to_string();
+            file_type.write(atoms, den, fname, pbar)?;
+        }
+        Ok(())
     };
     match output {
         WriteType::Atom(a) => {
-            println!("Writing out charge densities for atoms:");
+            reporter.message("Writing out charge densities for atoms:");
             let atom_iter = if a.is_empty() {
                 (0..atoms.positions.len()).collect()
             } else {
                 a
             };
             for atom in atom_iter {
-                println!("Atom {}:", atom + 1);
-                let pbar = Bar::visible(grid.size.total as u64,
-                                        100,
-                                        String::from("Building map:"));
-                let map = analysis.output_atom_map(grid, voxel_map, atom, pbar);
+                reporter.message(&format!("Atom {}:", atom + 1));
+                let pbar = reporter.stage("Building map", grid.size.total);
+                let map =
+                    analysis.output_atom_map(grid, voxel_map, atom, pbar)?;
                 for (i, den) in densities.iter().enumerate() {
                     let fname = format!("atom_{}_{}", atom + 1, filename[i]);
-                    let pbar =
-                        Bar::visible(1, 100, format!("Writing {}:", fname));
-                    let den = den.iter()
-                                 .zip(&map)
-                                 .map(|(d, weight)| {
-                                     if let Some(w) = weight {
-                                         Some(d * w)
-                                     } else {
-                                         None
-                                     }
-                                 })
-                                 .collect::<Vec<Option<f64>>>();
+                    let pbar = reporter.stage(&format!("Writing {}", fname), 1);
+                    let den =
+                        den.as_ref()
+                           .iter()
+                           .zip(&map)
+                           .map(|(d, weight)| weight.map(|w| d.to_f64() * w))
+                           .collect::<Vec<Option<f64>>>();
+                    let fname = path.join(&fname).display().to_string();
                     file_type.write(atoms, den, fname, pbar)?;
                 }
             }
         }
         WriteType::Volume(v) => {
-            println!("Writing out charge densities for atoms:");
+            reporter.message("Writing out charge densities for volumes:");
             let volume_iter = if v.is_empty() {
                 (0..analysis.bader_maxima.len()).collect()
             } else {
                 v
             };
             for volume in volume_iter {
-                print!("Atom: {}.", volume + 1);
-                let pbar = Bar::visible(grid.size.total as u64,
-                                        100,
-                                        String::from("Building map:"));
+                reporter.message(&format!("Volume {}:", volume + 1));
+                let pbar = reporter.stage("Building map", grid.size.total);
                 let map =
                     analysis.output_volume_map(grid, voxel_map, volume, pbar);
                 for (i, den) in densities.iter().enumerate() {
                     let fname =
                         format!("volume_{}_{}", volume + 1, filename[i]);
-                    let pbar =
-                        Bar::visible(1, 100, format!("Writing {}:", fname));
-                    let den = den.iter()
-                                 .zip(&map)
-                                 .map(|(d, weight)| {
-                                     if let Some(w) = weight {
-                                         Some(d * w)
-                                     } else {
-                                         None
-                                     }
-                                 })
-                                 .collect::<Vec<Option<f64>>>();
+                    let pbar = reporter.stage(&format!("Writing {}", fname), 1);
+                    let den =
+                        den.as_ref()
+                           .iter()
+                           .zip(&map)
+                           .map(|(d, weight)| weight.map(|w| d.to_f64() * w))
+                           .collect::<Vec<Option<f64>>>();
+                    let fname = path.join(&fname).display().to_string();
                     file_type.write(atoms, den, fname, pbar)?;
                 }
-                println!(" Done.");
             }
         }
+        WriteType::SumAtoms(a) => {
+            let a = if a.is_empty() {
+                (0..atoms.positions.len()).collect()
+            } else {
+                a
+            };
+            write_sum("atoms", &analysis.atoms_volumes(&a))?;
+        }
+        WriteType::SumVolumes(v) => {
+            let v = if v.is_empty() {
+                (0..analysis.bader_maxima.len()).collect()
+            } else {
+                v
+            };
+            write_sum("volumes", &v)?;
+        }
+        WriteType::Labels => {
+            reporter.message("Writing out the labels of each voxel:");
+            let pbar = reporter.stage("Building labels", grid.size.total);
+            let labels = analysis.output_label_map(grid, voxel_map, pbar)?;
+            // written as they are, without the units of a density
+            let volume = labels.iter()
+                               .map(|l| l.map(|(v, _)| (v + 1) as f64))
+                               .collect::<Vec<Option<f64>>>();
+            let atom = labels.iter()
+                             .map(|l| {
+                                 l.map(|(v, _)| {
+                                      (analysis.assigned_atom[v] + 1) as f64
+                                  })
+                             })
+                             .collect::<Vec<Option<f64>>>();
+            let weight = labels.iter()
+                               .map(|l| l.map(|(_, w)| w))
+                               .collect::<Vec<Option<f64>>>();
+            for (fname, data) in vec![("volume_labels", volume),
+                                      ("atom_labels", atom),
+                                      ("boundary_weight", weight)]
+            {
+                let pbar = reporter.stage(&format!("Writing {}", fname), 1);
+                let fname = path.join(fname).display().to_string();
+                file_type.write_values(atoms, data, fname, pbar)?;
+            }
+        }
+        WriteType::LabelsVtk => {
+            reporter.message("Writing out the labels of each voxel:");
+            vtk::write_labels(analysis,
+                              grid,
+                              voxel_map,
+                              path.join("bader_labels").display().to_string(),
+                              file_type,
+                              reporter.stage("Building labels",
+                                             grid.size.total))?;
+            reporter.message(&format!("{} written successfully.",
+                                      path.join("bader_labels.vts")
+                                          .display()));
+        }
+        WriteType::Vtk => {
+            reporter.message("Writing out VTK files:");
+            vtk::write(atoms,
+                       analysis,
+                       &densities,
+                       grid,
+                       voxel_map,
+                       path.join("bader").display().to_string(),
+                       file_type,
+                       reporter.stage("Building labels", grid.size.total))?;
+            reporter.message(&format!("{} and {} written successfully.",
+                                      path.join("bader.vts").display(),
+                                      path.join("bader_atoms.vtp").display()));
+        }
         WriteType::None => (),
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atoms::Lattice;
+    use crate::io::vasp::Vasp;
+    use crate::progress::Silent;
+
+    /// Writes `output` for two atoms, each with a volume, on a 2x2x2 grid to
+    /// `directory` in the temporary directory.
+    fn write(output: WriteType, directory: &str) -> Result<(), Error> {
+        let lattice =
+            Lattice::new([[2., 0., 0.], [0., 2., 0.], [0., 0., 2.]]);
+        let atoms = Atoms::new(lattice,
+                               vec![[0.; 3], [1.; 3]],
+                               vec![1, 1],
+                               String::new());
+        let grid = Grid::new([2, 2, 2],
+                             atoms.lattice.to_cartesian,
+                             1E-6,
+                             1E-6,
+                             None,
+                             [0.; 3]);
+        let voxel_map = VoxelMap::new(8);
+        voxel_map.maxima_store(0, 0);
+        voxel_map.maxima_store(7, 7);
+        let mut analysis = Analysis::new(&voxel_map, 1, 2);
+        analysis.assigned_atom = vec![0, 1];
+        let file_type: Box<dyn FileFormat> = Box::new(Vasp {});
+        let directory = std::env::temp_dir().join(directory);
+        let path = OutputPath::new(&directory, String::new());
+        write_densities(&atoms,
+                        &analysis,
+                        vec![vec![1f64; 8]],
+                        &grid,
+                        output,
+                        &voxel_map,
+                        &file_type,
+                        &path,
+                        &Silent)
+    }
+
+    /// Writes `output` with an index that doesn't exist, returning the error.
+    fn write_error(output: WriteType) -> Error {
+        match write(output, "bca-output-index") {
+            Ok(_) => panic!("Wrote an index that doesn't exist."),
+            Err(e) => {
+                let directory =
+                    std::env::temp_dir().join("bca-output-index");
+                assert!(!directory.exists());
+                e
+            }
+        }
+    }
+
+    #[test]
+    fn output_sum_volumes_out_of_range() {
+        let e = write_error(WriteType::SumVolumes(vec![1, 998]));
+        assert_eq!(e.to_string(),
+                   "Error: Cannot write volume 999 as there are only 2.");
+    }
+
+    #[test]
+    fn output_sum_atoms_out_of_range() {
+        let e = write_error(WriteType::SumAtoms(vec![998]));
+        assert_eq!(e.to_string(),
+                   "Error: Cannot write atom 999 as there are only 2.");
+    }
+
+    #[test]
+    fn output_atoms_out_of_range() {
+        let e = write_error(WriteType::Atom(vec![0, 2]));
+        assert_eq!(e.to_string(),
+                   "Error: Cannot write atom 3 as there are only 2.");
+    }
+
+    #[test]
+    fn output_labels_unscaled() {
+        let directory = std::env::temp_dir().join("bca-output-labels");
+        std::fs::create_dir_all(&directory).unwrap();
+        if let Err(e) = write(WriteType::Labels, "bca-output-labels") {
+            panic!("{}", e);
+        }
+        // the cell has a volume of 8 that a density would be multiplied by
+        let read = |name: &str| {
+            std::fs::read_to_string(directory.join(name)).unwrap()
+                                                         .split_whitespace()
+                                                         .map(String::from)
+                                                         .collect::<Vec<_>>()
+        };
+        let volume = read("volume_labels_CHGCAR");
+        let atom = read("atom_labels_CHGCAR");
+        let weight = read("boundary_weight_CHGCAR");
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(volume[0], "0.10000000000E+01");
+        assert_eq!(volume[7], "0.20000000000E+01");
+        assert_eq!(volume[1], "0.00000000000E+00");
+        assert_eq!(atom[7], "0.20000000000E+01");
+        assert_eq!(weight[0], "0.10000000000E+01");
+    }
+}
@@ -0,0 +1,352 @@
+use crate::atoms::{Atoms, Lattice};
+use crate::elements;
+use crate::errors::{parse_line, parse_value, Error};
+use crate::io::reader::read_values;
+use crate::io::{Dimensions, FileFormat, FortranFormat, ReadFunction};
+use crate::progress::Progress;
+use crate::storage::{Buffer, Storage};
+use crate::utils;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+
+/// Convert from Bohr.
+const LENGTH_UNITS: f64 = 0.52917721067;
+/// Convert from Bohr^3.
+const VOLUME_UNITS: f64 = LENGTH_UNITS * LENGTH_UNITS * LENGTH_UNITS;
+
+/// The Quantum ESPRESSO pp.x intermediate (filplot) file format.
+///
+/// The header contains the grid, the Bravais lattice index (ibrav) and celldm
+/// (or the explicit cell when ibrav = 0), the atomic species and the positions
+/// in units of alat. The density follows with x running fastest. Lengths are
+/// converted from Bohr to Angstrom and the density from e/Bohr^3 to
+/// e/Angstrom^3 to match the other readers.
+pub struct Qe {}
+
+impl FileFormat for Qe {
+    /// Read a pp.x filplot file.
+    fn read(&self, filename: String, storage: &Storage) -> ReadFunction {
+        // the voxel origin in pp.x is (0, 0, 0)
+        let voxel_origin = [0f64; 3];
+        // name the file in any error
+        let named = |e: Error| e.in_file(&filename);
+        let mut file = File::open(&filename).map(io::BufReader::new)
+                                            .map_err(|e| named(e.into()))?;
+        // read the header a line at a time, the density follows it
+        let mut header = Vec::new();
+        let mut next_line = |line: usize| -> Result<String, Error> {
+            let mut text = String::new();
+            match file.read_line(&mut text) {
+                Ok(0) => Err(Error::parse(line, "Incomplete header.")),
+                Ok(_) => {
+                    header.push(text.trim_end_matches(&['\n', '\r'][..])
+                                    .to_string());
+                    Ok(text)
+                }
+                Err(e) => Err(e.into()),
+            }
+        };
+        let _ = next_line(1).map_err(named)?;
+        // nr1x, nr2x, nr3x, nr1, nr2, nr3, nat, ntyp
+        let dims = parse_line::<usize>(Some(&next_line(2).map_err(named)?),
+                                       2,
+                                       8,
+                                       "grid").map_err(named)?;
+        let (nat, ntyp) = (dims[6], dims[7]);
+        let ibrav_line = next_line(3).map_err(named)?;
+        let ibrav = parse_value::<isize>(ibrav_line.split_whitespace().next(),
+                                         3,
+                                         "ibrav").map_err(named)?;
+        // title, grid, ibrav, (cell), cut-offs, species and atoms
+        let header_lines = 4 + if ibrav == 0 { 3 } else { 0 } + ntyp + nat;
+        for i in 3..header_lines {
+            let _ = next_line(i + 1).map_err(named)?;
+        }
+        // the x and y dimensions can be padded so we write it back unpadded
+        let mut text = format!("{}\n {} {} {} {} {} {} {} {}\n",
+                               header[0],
+                               dims[3],
+                               dims[4],
+                               dims[5],
+                               dims[3],
+                               dims[4],
+                               dims[5],
+                               nat,
+                               ntyp);
+        for line in header.iter().skip(2) {
+            text.push_str(line);
+            text.push('\n');
+        }
+        let atoms = self.to_atoms(text).map_err(named)?;
+        // drop any padding and convert out of Bohr as each value is parsed
+        let mut density = Buffer::zeroed(dims[3] * dims[4] * dims[5], storage)
+            .map_err(|e| named(e.into()))?;
+        read_values(&mut file,
+                    header_lines + 1,
+                    dims[0] * dims[1] * dims[5],
+                    "density",
+                    |p, x| {
+                        let (i, j) = (p % dims[0], p / dims[0] % dims[1]);
+                        let k = p / (dims[0] * dims[1]);
+                        if i < dims[3] && j < dims[4] {
+                            density[(k * dims[4] + j) * dims[3] + i] =
+                                x / VOLUME_UNITS;
+                        }
+                    }).map_err(named)?;
+        // flip the grid points as pp.x outputs density[z, y, x]
+        let grid_pts = [dims[5], dims[4], dims[3]];
+        Ok((voxel_origin, grid_pts, atoms, vec![density]))
+    }
+
+    /// Reads the grid and the number of atoms from the second line.
+    fn dimensions(&self, filename: String) -> Result<Dimensions, Error> {
+        let named = |e: Error| e.in_file(&filename);
+        let file = File::open(&filename).map_err(|e| named(e.into()))?;
+        let mut lines = io::BufReader::new(file).lines().skip(1);
+        let line = lines.next().transpose().map_err(|e| named(e.into()))?;
+        let dims = parse_line::<usize>(line.as_deref(), 2, 8, "grid")
+            .map_err(named)?;
+        Ok(Dimensions { grid: [dims[5], dims[4], dims[3]],
+                        densities: 1,
+                        atoms: dims[6] })
+    }
+
+    /// Read atom information from the header.
+    fn to_atoms(&self, atoms_text: String) -> Result<Atoms, Error> {
+        let mut lines = atoms_text.lines().skip(1);
+        let dims = parse_line::<usize>(lines.next(), 2, 8, "grid")?;
+        let (nat, ntyp) = (dims[6], dims[7]);
+        let ibrav_line = lines.next();
+        let ibrav = parse_value::<isize>(ibrav_line.and_then(|l| {
+                                                       l.split_whitespace()
+                                                        .next()
+                                                   }),
+                                         3,
+                                         "ibrav")?;
+        let mut celldm = [0f64; 6];
+        let values = parse_line::<f64>(ibrav_line, 3, 1, "celldm")?;
+        for (c, x) in celldm.iter_mut().zip(values.iter().skip(1)) {
+            *c = *x;
+        }
+        let alat = celldm[0];
+        let cell = if ibrav == 0 {
+            let mut cell = [[0f64; 3]; 3];
+            for (i, v) in cell.iter_mut().enumerate() {
+                let line = parse_line::<f64>(lines.next(), 4 + i, 3, "cell")?;
+                for (x, y) in v.iter_mut().zip(line) {
+                    *x = y * alat;
+                }
+            }
+            cell
+        } else {
+            Qe::lattice_from_ibrav(ibrav, celldm)?
+        };
+        // skip the cut-offs and read the species, "nt name zv"
+        let first = if ibrav == 0 { 8 } else { 5 };
+        let mut lines = lines.skip(1);
+        let species = (0..ntyp).map(|i| {
+                                   match lines.next()
+                                              .and_then(|l| {
+                                                  l.split_whitespace().nth(1)
+                                              }) {
+                                       Some(name) => {
+                                           Ok(elements::atomic_number(name))
+                                       }
+                                       None => Err(Error::parse(first + i,
+                                                                "Missing \
+                                                                 species.")),
+                                   }
+                               })
+                               .collect::<Result<Vec<usize>, Error>>()?;
+        let [a, b, c] = cell;
+        // density[z, y, x] so lets swap the c and a
+        let mut lattice = [[c[2], c[1], c[0]],
+                           [b[2], b[1], b[0]],
+                           [a[2], a[1], a[0]]];
+        for v in lattice.iter_mut() {
+            for x in v.iter_mut() {
+                *x *= LENGTH_UNITS;
+            }
+        }
+        // the cell follows ibrav on line 3 or is generated from celldm on it
+        let cell_line = if ibrav == 0 { 4 } else { 3 };
+        let lattice =
+            Lattice::try_new(lattice).map_err(|e| Error::parse(cell_line, e))?;
+        let mut positions: Vec<[f64; 3]> = Vec::with_capacity(nat);
+        let mut atomic_numbers: Vec<usize> = Vec::with_capacity(nat);
+        for i in 0..nat {
+            // na, tau(1:3) in units of alat, ityp
+            let line_number = first + ntyp + i;
+            let line = lines.next().unwrap_or("");
+            let ityp = line.split_whitespace()
+                           .nth(4)
+                           .and_then(|x| x.parse::<usize>().ok())
+                           .unwrap_or(0);
+            atomic_numbers.push(ityp.checked_sub(1)
+                                    .and_then(|i| species.get(i).copied())
+                                    .unwrap_or(0));
+            let pos = parse_line::<f64>(Some(line), line_number, 4, "atom")?
+                          .into_iter()
+                          .skip(1)
+                          .take(3)
+                          .map(|x| x * alat * LENGTH_UNITS)
+                          .collect::<Vec<f64>>();
+            let p = utils::dot([pos[2], pos[1], pos[0]], lattice.to_fractional);
+            positions.push(utils::dot([p[0].rem_euclid(1f64),
+                                       p[1].rem_euclid(1f64),
+                                       p[2].rem_euclid(1f64)],
+                                      lattice.to_cartesian));
+        }
+        Ok(Atoms::new(lattice, positions, atomic_numbers, atoms_text))
+    }
+
+    /// The density is stored per cubic Bohr.
+    fn scale(&self, _atoms: &Atoms) -> f64 {
+        VOLUME_UNITS
+    }
+
+    /// Write a filplot file from a vector of options where None will be
+    /// written as zero.
+    fn write_values(&self,
+                    atoms: &Atoms,
+                    data: Vec<Option<f64>>,
+                    filename: String,
+                    pbar: Box<dyn Progress>)
+                    -> std::io::Result<()> {
+        let filename = format!("{}.pp", filename);
+        let mut buffer = BufWriter::new(File::create(filename)?);
+        pbar.set_length(data.len() / 5 + (data.len() % 5 != 0) as usize);
+        buffer.write_all(atoms.text.as_bytes())?;
+        for line in data.chunks(5) {
+            for f in line {
+                write!(buffer,
+                       " {:.9}",
+                       FortranFormat { float: *f,
+                                       mult: 1. })?;
+            }
+            writeln!(buffer)?;
+            pbar.tick();
+        }
+        Ok(())
+    }
+
+    /// Deals with fortran indexing.
+    fn coordinate_format(&self, coords: [f64; 3]) -> (String, String, String) {
+        let z = format!("{:.6}", coords[0]);
+        let y = format!("{:.6}", coords[1]);
+        let x = format!("{:.6}", coords[2]);
+        (x, y, z)
+    }
+}
+
+impl Qe {
+    /// Generates the lattice vectors, in Bohr, from ibrav and celldm following
+    /// the conventions of Quantum ESPRESSO's latgen.
+    ///
+    /// # Examples
+    /// ```
+    /// use bader::io::qe::Qe;
+    ///
+    /// let fcc = Qe::lattice_from_ibrav(2, [2., 0., 0., 0., 0., 0.]).unwrap();
+    /// assert_eq!(fcc, [[-1., 0., 1.], [0., 1., 1.], [-1., 1., 0.]]);
+    /// ```
+    pub fn lattice_from_ibrav(ibrav: isize,
+                              celldm: [f64; 6])
+                              -> Result<[[f64; 3]; 3], Error> {
+        let a = celldm[0];
+        let b = a * celldm[1];
+        let c = a * celldm[2];
+        let lattice = match ibrav {
+            1 => [[a, 0., 0.], [0., a, 0.], [0., 0., a]],
+            2 => {
+                let h = a / 2.;
+                [[-h, 0., h], [0., h, h], [-h, h, 0.]]
+            }
+            3 => {
+                let h = a / 2.;
+                [[h, h, h], [-h, h, h], [-h, -h, h]]
+            }
+            -3 => {
+                let h = a / 2.;
+                [[-h, h, h], [h, -h, h], [h, h, -h]]
+            }
+            4 => [[a, 0., 0.],
+                  [-a / 2., a * 3f64.sqrt() / 2., 0.],
+                  [0., 0., c]],
+            5 | -5 => {
+                let cos_alpha = celldm[3];
+                let tx = ((1. - cos_alpha) / 2.).sqrt();
+                let ty = ((1. - cos_alpha) / 6.).sqrt();
+                let tz = ((1. + 2. * cos_alpha) / 3.).sqrt();
+                if ibrav == 5 {
+                    [[a * tx, -a * ty, a * tz],
+                     [0., 2. * a * ty, a * tz],
+                     [-a * tx, -a * ty, a * tz]]
+                } else {
+                    let a_prime = a / 3f64.sqrt();
+                    let u = a_prime * (tz - 2. * 2f64.sqrt() * ty);
+                    let v = a_prime * (tz + 2f64.sqrt() * ty);
+                    [[u, v, v], [v, u, v], [v, v, u]]
+                }
+            }
+            6 => [[a, 0., 0.], [0., a, 0.], [0., 0., c]],
+            7 => {
+                let (h, hc) = (a / 2., c / 2.);
+                [[h, -h, hc], [h, h, hc], [-h, -h, hc]]
+            }
+            8 => [[a, 0., 0.], [0., b, 0.], [0., 0., c]],
+            9 => [[a / 2., b / 2., 0.], [-a / 2., b / 2., 0.], [0., 0., c]],
+            -9 => [[a / 2., -b / 2., 0.], [a / 2., b / 2., 0.], [0., 0., c]],
+            91 => [[a, 0., 0.], [0., b / 2., -c / 2.], [0., b / 2., c / 2.]],
+            10 => [[a / 2., 0., c / 2.],
+                   [a / 2., b / 2., 0.],
+                   [0., b / 2., c / 2.]],
+            11 => [[a / 2., b / 2., c / 2.],
+                   [-a / 2., b / 2., c / 2.],
+                   [-a / 2., -b / 2., c / 2.]],
+            12 => {
+                let cos_g = celldm[3];
+                let sin_g = (1. - cos_g.powi(2)).sqrt();
+                [[a, 0., 0.], [b * cos_g, b * sin_g, 0.], [0., 0., c]]
+            }
+            -12 => {
+                let cos_b = celldm[4];
+                let sin_b = (1. - cos_b.powi(2)).sqrt();
+                [[a, 0., 0.], [0., b, 0.], [c * cos_b, 0., c * sin_b]]
+            }
+            13 => {
+                let cos_g = celldm[3];
+                let sin_g = (1. - cos_g.powi(2)).sqrt();
+                [[a / 2., 0., -c / 2.],
+                 [b * cos_g, b * sin_g, 0.],
+                 [a / 2., 0., c / 2.]]
+            }
+            -13 => {
+                let cos_b = celldm[4];
+                let sin_b = (1. - cos_b.powi(2)).sqrt();
+                [[a / 2., b / 2., 0.],
+                 [-a / 2., b / 2., 0.],
+                 [c * cos_b, 0., c * sin_b]]
+            }
+            14 => {
+                let (cos_a, cos_b, cos_g) = (celldm[3], celldm[4], celldm[5]);
+                let sin_g = (1. - cos_g.powi(2)).sqrt();
+                let z = (1. + 2. * cos_a * cos_b * cos_g
+                         - cos_a.powi(2)
+                         - cos_b.powi(2)
+                         - cos_g.powi(2)).sqrt();
+                [[a, 0., 0.],
+                 [b * cos_g, b * sin_g, 0.],
+                 [c * cos_b,
+                  c * (cos_a - cos_b * cos_g) / sin_g,
+                  c * z / sin_g]]
+            }
+            _ => {
+                return Err(Error::parse(3,
+                                        format!("Unsupported ibrav ({}).",
+                                                ibrav)))
+            }
+        };
+        Ok(lattice)
+    }
+}
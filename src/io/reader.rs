@@ -1,3 +1,4 @@
+use crate::errors::{parse_value, Error};
 use std::fs::File;
 use std::io::{self, prelude::*};
 
@@ -28,3 +29,434 @@ impl BufReader {
             .transpose()
     }
 }
+
+/// Reads a Fortran unformatted sequential file record by record.
+///
+/// Each record is bounded by a 4 byte length marker at both ends. Records
+/// larger than 2GB are split into subrecords with negative markers (gfortran)
+/// and these are joined back together. The byte order is detected from the
+/// first marker.
+pub struct FortranReader {
+    reader: io::BufReader<File>,
+    big_endian: bool,
+}
+
+impl FortranReader {
+    /// Opens the file from the path and detects the byte order.
+    pub fn open(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut marker = [0u8; 4];
+        file.read_exact(&mut marker)?;
+        let little = i32::from_le_bytes(marker).abs() as u64;
+        let big_endian = !(little > 0 && little < size);
+        file.seek(io::SeekFrom::Start(0))?;
+        let reader = io::BufReader::new(file);
+        Ok(Self { reader, big_endian })
+    }
+
+    /// Is the file big-endian.
+    pub fn big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    /// Reads a single length marker.
+    fn marker(&mut self) -> io::Result<i32> {
+        let mut marker = [0u8; 4];
+        self.reader.read_exact(&mut marker)?;
+        Ok(if self.big_endian {
+               i32::from_be_bytes(marker)
+           } else {
+               i32::from_le_bytes(marker)
+           })
+    }
+
+    /// Reads the next record a subrecord at a time, handing the reader and
+    /// the length of each subrecord to `part`, which must consume exactly
+    /// that many bytes. Returns None at the end of the file.
+    fn record<F>(&mut self, mut part: F) -> Option<io::Result<()>>
+        where F: FnMut(&mut io::BufReader<File>, usize) -> io::Result<()> {
+        let mut marker = match self.marker() {
+            Ok(m) => m,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return None
+            }
+            Err(e) => return Some(Err(e)),
+        };
+        loop {
+            if let Err(e) = part(&mut self.reader, marker.abs() as usize) {
+                return Some(Err(e));
+            }
+            if let Err(e) = self.marker() {
+                return Some(Err(e));
+            }
+            // a negative leading marker means another subrecord follows
+            if marker >= 0 {
+                break;
+            }
+            marker = match self.marker() {
+                Ok(m) => m,
+                Err(e) => return Some(Err(e)),
+            };
+        }
+        Some(Ok(()))
+    }
+
+    /// Reads the next record, returning None at the end of the file.
+    pub fn read_record(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let mut record = Vec::new();
+        let result = self.record(|reader, length| {
+                             let start = record.len();
+                             record.resize(start + length, 0);
+                             reader.read_exact(&mut record[start..])
+                         });
+        result.map(|r| r.map(|_| record))
+    }
+
+    /// Skips the next record, returning its length in bytes or None at the
+    /// end of the file.
+    pub fn skip_record(&mut self) -> Option<io::Result<usize>> {
+        let mut total = 0;
+        let result = self.record(|reader, length| {
+                             total += length;
+                             reader.seek(io::SeekFrom::Current(length as i64))
+                                   .map(|_| ())
+                         });
+        result.map(|r| r.map(|_| total))
+    }
+
+    /// Reads the next record as floats of `width` bytes, 4 for f32 and 8 for
+    /// f64, handing each to `f` with its position in the record as it is
+    /// read, so that a density record never has to be held in memory. Returns
+    /// the number of floats read or None at the end of the file.
+    pub fn read_floats<F>(&mut self,
+                          width: usize,
+                          mut f: F)
+                          -> Option<io::Result<usize>>
+        where F: FnMut(usize, f64) {
+        const CHUNK: usize = 1 << 16;
+        let big_endian = self.big_endian;
+        let mut count = 0;
+        // a float can be split between subrecords so carry the remainder
+        let mut bytes = Vec::with_capacity(CHUNK + width);
+        let result = self.record(|reader, length| {
+            let mut remaining = length;
+            while remaining > 0 {
+                let start = bytes.len();
+                let n = remaining.min(CHUNK);
+                bytes.resize(start + n, 0);
+                reader.read_exact(&mut bytes[start..])?;
+                remaining -= n;
+                let whole = bytes.len() / width * width;
+                for b in bytes[..whole].chunks_exact(width) {
+                    f(count, float(b, big_endian));
+                    count += 1;
+                }
+                bytes.drain(..whole);
+            }
+            Ok(())
+        });
+        match result {
+            Some(Ok(())) if !bytes.is_empty() => {
+                Some(Err(io::Error::new(io::ErrorKind::InvalidData,
+                                        "Record is not a whole number of \
+                                         floats.")))
+            }
+            r => r.map(|r| r.map(|_| count)),
+        }
+    }
+
+    /// Converts the bytes of a record to i32.
+    pub fn to_i32(&self, bytes: &[u8]) -> Vec<i32> {
+        bytes.chunks_exact(4)
+             .map(|b| {
+                 let b = [b[0], b[1], b[2], b[3]];
+                 if self.big_endian {
+                     i32::from_be_bytes(b)
+                 } else {
+                     i32::from_le_bytes(b)
+                 }
+             })
+             .collect()
+    }
+
+    /// Converts the bytes of a record to f32, returned as f64.
+    pub fn to_f32(&self, bytes: &[u8]) -> Vec<f64> {
+        bytes.chunks_exact(4)
+             .map(|b| float(b, self.big_endian))
+             .collect()
+    }
+
+    /// Converts the bytes of a record to f64.
+    pub fn to_f64(&self, bytes: &[u8]) -> Vec<f64> {
+        bytes.chunks_exact(8)
+             .map(|b| float(b, self.big_endian))
+             .collect()
+    }
+}
+
+/// Converts the 4 or 8 bytes of a float to f64.
+fn float(b: &[u8], big_endian: bool) -> f64 {
+    if b.len() == 4 {
+        let b = [b[0], b[1], b[2], b[3]];
+        if big_endian {
+            f32::from_be_bytes(b) as f64
+        } else {
+            f32::from_le_bytes(b) as f64
+        }
+    } else {
+        let b = [b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]];
+        if big_endian {
+            f64::from_be_bytes(b)
+        } else {
+            f64::from_le_bytes(b)
+        }
+    }
+}
+
+/// Parses the next `len` whitespace separated values of `reader` line by
+/// line, handing each to `f` with its position as it is parsed, so that a
+/// density can be placed straight into its
+/// [`Buffer`](crate::storage::Buffer) rather than being held as text first.
+///
+/// * `reader`: The reader, positioned at the start of the values.
+/// * `first_line`: The line, counting from 1, the values start on.
+/// * `len`: How many values to read, the last of them must end a line.
+/// * `name`: What the values are, used in the error message.
+/// * `f`: Called with the position and value of each value in turn.
+///
+/// ### Returns:
+/// `Result<usize, Error>`: The number of lines read.
+///
+/// ### Examples
+/// ```
+/// use bader::io::reader::read_values;
+///
+/// let mut values = vec![0.; 3];
+/// let mut text = "1 2\n3\nEND\n".as_bytes();
+/// let lines = read_values(&mut text, 10, 3, "density", |i, x| values[i] = x)
+///     .unwrap();
+/// assert_eq!((lines, values), (2, vec![1., 2., 3.]));
+/// let e = read_values(&mut "1 2\n".as_bytes(), 10, 3, "density", |_, _| ())
+///     .unwrap_err();
+/// assert_eq!(e.to_string(),
+///            "Error: Cannot read file, line 11: Expected 3 values for \
+///             density, found 2.");
+/// ```
+pub fn read_values<R, F>(reader: &mut R,
+                         first_line: usize,
+                         len: usize,
+                         name: &str,
+                         mut f: F)
+                         -> Result<usize, Error>
+    where R: BufRead,
+          F: FnMut(usize, f64) {
+    let mut text = String::new();
+    let mut count = 0;
+    let mut lines = 0;
+    while count < len {
+        let line = first_line + lines;
+        text.clear();
+        if reader.read_line(&mut text)? == 0 {
+            return Err(Error::parse(line,
+                                    format!("Expected {} values for {}, \
+                                             found {}.",
+                                            len, name, count)));
+        }
+        for x in text.split_whitespace() {
+            if count == len {
+                return Err(Error::parse(line,
+                                        format!("More than {} values for \
+                                                 {}.",
+                                                len, name)));
+            }
+            f(count, parse_value(Some(x), line, name)?);
+            count += 1;
+        }
+        lines += 1;
+    }
+    Ok(lines)
+}
+
+/// The longest subrecord gfortran writes, longer records are split.
+const MAX_SUBRECORD: usize = 2_147_483_639;
+
+/// Writes a Fortran unformatted sequential file record by record.
+///
+/// Records too long for the 4 byte length markers are split into subrecords
+/// as gfortran does: the leading marker of each subrecord but the last is
+/// negated, as is the trailing marker of each but the first.
+pub struct FortranWriter<W: Write> {
+    writer: W,
+    big_endian: bool,
+    max_subrecord: usize,
+}
+
+impl<W: Write> FortranWriter<W> {
+    /// Wraps a writer, writing records in the given byte order.
+    pub fn new(writer: W, big_endian: bool) -> Self {
+        Self { writer,
+               big_endian,
+               max_subrecord: MAX_SUBRECORD }
+    }
+
+    /// Writes a single record, bytes, bounded by length markers.
+    pub fn write_record(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write_record_with(bytes.len(), |record| record.write_all(bytes))
+    }
+
+    /// Writes a single record of `len` bytes, handed to `f` to write a piece
+    /// at a time so that a long record never has to be held in memory.
+    /// Returns an error of kind `InvalidInput` if `f` doesn't write exactly
+    /// `len` bytes.
+    pub fn write_record_with<F>(&mut self, len: usize, f: F) -> io::Result<()>
+        where F: FnOnce(&mut Record<'_, W>) -> io::Result<()> {
+        if len == 0 {
+            self.marker(0)?;
+            return self.marker(0);
+        }
+        let mut record = Record { fortran: self,
+                                  len,
+                                  written: 0 };
+        f(&mut record)?;
+        if record.written != len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Record is shorter than its length."));
+        }
+        Ok(())
+    }
+
+    /// Writes a single length marker.
+    fn marker(&mut self, marker: i32) -> io::Result<()> {
+        let marker = if self.big_endian {
+            marker.to_be_bytes()
+        } else {
+            marker.to_le_bytes()
+        };
+        self.writer.write_all(&marker)
+    }
+
+    /// Converts i32 to bytes in the byte order of the file.
+    pub fn i32_bytes(&self, values: &[i32]) -> Vec<u8> {
+        values.iter()
+              .flat_map(|v| {
+                  if self.big_endian {
+                      v.to_be_bytes()
+                  } else {
+                      v.to_le_bytes()
+                  }
+              })
+              .collect()
+    }
+
+    /// Converts f64 to f32 bytes in the byte order of the file.
+    pub fn f32_bytes(&self, values: &[f64]) -> Vec<u8> {
+        values.iter()
+              .flat_map(|v| {
+                  if self.big_endian {
+                      (*v as f32).to_be_bytes()
+                  } else {
+                      (*v as f32).to_le_bytes()
+                  }
+              })
+              .collect()
+    }
+
+    /// Converts f64 to bytes in the byte order of the file.
+    pub fn f64_bytes(&self, values: &[f64]) -> Vec<u8> {
+        values.iter()
+              .flat_map(|v| {
+                  if self.big_endian {
+                      v.to_be_bytes().to_vec()
+                  } else {
+                      v.to_le_bytes().to_vec()
+                  }
+              })
+              .collect()
+    }
+}
+
+/// A record being written by [`FortranWriter::write_record_with`], which
+/// adds the markers of each subrecord as its bytes are written.
+pub struct Record<'a, W: Write> {
+    fortran: &'a mut FortranWriter<W>,
+    len: usize,
+    written: usize,
+}
+
+impl<'a, W: Write> Record<'a, W> {
+    /// Writes f64 in the byte order of the file.
+    pub fn write_f64(&mut self, values: &[f64]) -> io::Result<()> {
+        let bytes = self.fortran.f64_bytes(values);
+        self.write_all(&bytes)
+    }
+}
+
+impl<'a, W: Write> Write for Record<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.written == self.len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Record is longer than its length."));
+        }
+        let max = self.fortran.max_subrecord;
+        let start = self.written / max * max;
+        let end = (start + max).min(self.len);
+        // safe to cast as a subrecord is never longer than i32::MAX
+        let length = (end - start) as i32;
+        if self.written == start {
+            let more = end < self.len;
+            self.fortran.marker(if more { -length } else { length })?;
+        }
+        let n = buf.len().min(end - self.written);
+        self.fortran.writer.write_all(&buf[..n])?;
+        self.written += n;
+        if self.written == end {
+            let first = start == 0;
+            self.fortran.marker(if first { length } else { -length })?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.fortran.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fortran_writer_subrecords() {
+        let filename = std::env::temp_dir().join("bca-subrecords-test");
+        let mut bytes = Vec::new();
+        let mut writer = FortranWriter::new(&mut bytes, false);
+        writer.max_subrecord = 10;
+        let record = (0..25u8).collect::<Vec<u8>>();
+        writer.write_record(&record).unwrap();
+        writer.write_record(&[25, 26]).unwrap();
+        // subrecords of 10, 10 and 5 bytes, each with two markers
+        assert_eq!(bytes.len(), 25 + 2 + 8 * 4);
+        let markers = [bytes[0], bytes[14], bytes[18], bytes[32], bytes[36]];
+        assert_eq!(markers, [246, 10, 246, 246, 5]);
+        assert_eq!(&bytes[45..49], &[251, 255, 255, 255]);
+        std::fs::write(&filename, &bytes).unwrap();
+        let mut reader = FortranReader::open(&filename).unwrap();
+        assert_eq!(reader.read_record().unwrap().unwrap(), record);
+        assert_eq!(reader.read_record().unwrap().unwrap(), vec![25, 26]);
+        assert!(reader.read_record().is_none());
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn fortran_writer_wrong_length() {
+        let mut writer = FortranWriter::new(Vec::new(), false);
+        let short = writer.write_record_with(8, |r| r.write_all(&[0; 4]));
+        let long = writer.write_record_with(4, |r| r.write_all(&[0; 8]));
+        assert_eq!(short.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(long.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,258 @@
+use crate::atoms::{Atoms, Header, Lattice};
+use crate::errors::{parse_line, parse_value, Error};
+use crate::io::reader::{FortranReader, FortranWriter};
+use crate::io::{Dimensions, FileFormat, ReadFunction};
+use crate::progress::Progress;
+use crate::storage::{Buffer, Storage};
+use crate::utils;
+use std::fs::File;
+use std::io::{BufWriter, Read};
+use std::path::Path;
+
+/// Convert from Bohr.
+const LENGTH_UNITS: f64 = 0.52917721067;
+/// Convert from Bohr^3.
+const VOLUME_UNITS: f64 = LENGTH_UNITS * LENGTH_UNITS * LENGTH_UNITS;
+
+/// The SIESTA binary density (.RHO) file format.
+///
+/// The file is Fortran unformatted: a record holding the cell in Bohr, a
+/// record holding the mesh and number of spins and then a single precision
+/// record for each (y, z) line of the mesh per spin. The density is in
+/// electrons per Bohr^3. The atoms are read from the .XV file with the same
+/// system label, which is the [`Atoms`] text, and the cell and mesh records
+/// are kept in the [`Header`] of the atoms. Spin-polarised densities are
+/// stored as up and down and so are converted to charge and spin,
+/// non-collinear densities are stored as the spin matrix (D11, D22, Re D12,
+/// Im D12) and are converted to charge and the x, y and z spin.
+pub struct Siesta {}
+
+/// Reads and checks the cell and mesh records, returning them and the mesh
+/// and number of spins.
+fn read_header(file: &mut FortranReader)
+               -> Result<(Vec<Vec<u8>>, Vec<i32>), Error> {
+    let invalid = |msg: &str| Error::parse(0, msg);
+    let mut next_record = || match file.read_record() {
+        Some(record) => record.map_err(Error::from),
+        None => Err(invalid("Unexpected end of file.")),
+    };
+    let cell = next_record()?;
+    if cell.len() != 72 {
+        return Err(invalid("Bad cell record."));
+    }
+    let mesh_record = next_record()?;
+    if mesh_record.len() != 16 {
+        return Err(invalid("Bad mesh record."));
+    }
+    let mesh = file.to_i32(&mesh_record);
+    if mesh.iter().any(|m| *m < 1) {
+        return Err(invalid("Bad mesh record."));
+    }
+    if mesh[3] != 1 && mesh[3] != 2 && mesh[3] != 4 {
+        return Err(invalid("Unsupported number of spins."));
+    }
+    Ok((vec![cell, mesh_record], mesh))
+}
+
+impl FileFormat for Siesta {
+    /// Read a .RHO and its .XV file.
+    fn read(&self, filename: String, storage: &Storage) -> ReadFunction {
+        // the voxel origin in SIESTA is (0, 0, 0)
+        let voxel_origin = [0f64; 3];
+        // name the file in any error
+        let named = |e: Error| e.in_file(&filename);
+        let invalid = |msg: &str| named(Error::parse(0, msg));
+        let mut file =
+            FortranReader::open(&filename).map_err(|e| named(e.into()))?;
+        let (records, mesh) = read_header(&mut file).map_err(named)?;
+        let grid = [mesh[0] as usize, mesh[1] as usize, mesh[2] as usize];
+        let nspin = mesh[3] as usize;
+        let mut atoms = self.xv_atoms(&filename)?;
+        atoms.header = Header { big_endian: file.big_endian(),
+                                records };
+        let total = grid.iter().product::<usize>();
+        let mut spins: Vec<Buffer<f64>> = Vec::with_capacity(nspin);
+        for _ in 0..nspin {
+            let mut density =
+                Buffer::zeroed(total, storage).map_err(|e| named(e.into()))?;
+            for row in 0..(grid[1] * grid[2]) {
+                let start = row * grid[0];
+                let len = match file.read_floats(4, |i, x| {
+                                        if i < grid[0] {
+                                            density[start + i] =
+                                                x / VOLUME_UNITS
+                                        }
+                                    }) {
+                    Some(len) => len.map_err(|e| named(e.into()))?,
+                    None => return Err(invalid("Unexpected end of file.")),
+                };
+                if len != grid[0] {
+                    return Err(invalid("Bad density record."));
+                }
+            }
+            spins.push(density);
+        }
+        // convert up and down, in place, to charge and spin
+        let densities = match nspin {
+            1 => spins,
+            2 => {
+                let (up, down) = spins.split_at_mut(1);
+                for (u, d) in up[0].iter_mut().zip(down[0].iter_mut()) {
+                    let (a, b) = (*u, *d);
+                    *u = a + b;
+                    *d = a - b;
+                }
+                spins
+            }
+            _ => {
+                let (diagonal, off) = spins.split_at_mut(2);
+                let (up, down) = diagonal.split_at_mut(1);
+                for (u, d) in up[0].iter_mut().zip(down[0].iter_mut()) {
+                    let (a, b) = (*u, *d);
+                    *u = a + b;
+                    *d = a - b;
+                }
+                off[0].iter_mut().for_each(|x| *x *= 2.);
+                off[1].iter_mut().for_each(|y| *y *= -2.);
+                // charge, x, y, z
+                let z = spins.remove(1);
+                spins.push(z);
+                spins
+            }
+        };
+        let grid_pts = [grid[2], grid[1], grid[0]];
+        Ok((voxel_origin, grid_pts, atoms, densities))
+    }
+
+    /// Reads the grid and number of spins from the mesh record and the atoms
+    /// from the .XV file.
+    fn dimensions(&self, filename: String) -> Result<Dimensions, Error> {
+        let named = |e: Error| e.in_file(&filename);
+        let mut file =
+            FortranReader::open(&filename).map_err(|e| named(e.into()))?;
+        let (_, mesh) = read_header(&mut file).map_err(named)?;
+        let atoms = self.xv_atoms(&filename)?;
+        Ok(Dimensions { grid: [mesh[2] as usize,
+                               mesh[1] as usize,
+                               mesh[0] as usize],
+                        densities: mesh[3] as usize,
+                        atoms: atoms.positions.len() })
+    }
+
+    /// Read the lattice and atoms from the .XV text.
+    fn to_atoms(&self, atoms_text: String) -> Result<Atoms, Error> {
+        let mut lines = atoms_text.lines();
+        // the cell vectors are the first three columns of the first 3 lines
+        let mut cell = [[0f64; 3]; 3];
+        for (i, v) in cell.iter_mut().enumerate() {
+            let line = parse_line::<f64>(lines.next(), i + 1, 3, "cell")?;
+            for (x, y) in v.iter_mut().zip(line) {
+                *x = y * LENGTH_UNITS;
+            }
+        }
+        let [a, b, c] = cell;
+        // density[c, b, a] so lets swap the c and a
+        let lattice = Lattice::try_new([[c[2], c[1], c[0]],
+                                        [b[2], b[1], b[0]],
+                                        [a[2], a[1], a[0]]])
+            .map_err(|e| Error::parse(1, e))?;
+        let natoms = parse_value::<usize>(lines.next()
+                                               .and_then(|l| {
+                                                   l.split_whitespace().next()
+                                               }),
+                                          4,
+                                          "number of atoms")?;
+        // each atom is "species Z x y z vx vy vz" with cartesian Bohr
+        let mut positions: Vec<[f64; 3]> = Vec::with_capacity(natoms);
+        let mut atomic_numbers: Vec<usize> = Vec::with_capacity(natoms);
+        for i in 0..natoms {
+            let line = lines.next();
+            let mut split = line.unwrap_or("").split_whitespace().skip(1);
+            atomic_numbers.push(parse_value(split.next(), 5 + i, "atom")?);
+            let mut pos = Vec::with_capacity(3);
+            for _ in 0..3 {
+                pos.push(parse_value::<f64>(split.next(), 5 + i, "position")?
+                         * LENGTH_UNITS);
+            }
+            let p = utils::dot([pos[2], pos[1], pos[0]], lattice.to_fractional);
+            positions.push(utils::dot([p[0].rem_euclid(1f64),
+                                       p[1].rem_euclid(1f64),
+                                       p[2].rem_euclid(1f64)],
+                                      lattice.to_cartesian));
+        }
+        Ok(Atoms::new(lattice, positions, atomic_numbers, atoms_text))
+    }
+
+    /// The density is stored per cubic Bohr.
+    fn scale(&self, _atoms: &Atoms) -> f64 {
+        VOLUME_UNITS
+    }
+
+    /// Write a .RHO, and the .XV that was read alongside it, from a vector of
+    /// options where None will be written as zero. The cell and mesh records
+    /// that were read are copied with the number of spins set to 1.
+    fn write_values(&self,
+                    atoms: &Atoms,
+                    data: Vec<Option<f64>>,
+                    filename: String,
+                    pbar: Box<dyn Progress>)
+                    -> std::io::Result<()> {
+        let header = &atoms.header;
+        if header.records.len() != 2 || header.records[1].len() != 16 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                                           "No SIESTA header to write."));
+        }
+        std::fs::write(format!("{}.XV", filename), &atoms.text)?;
+        let filename = format!("{}.RHO", filename);
+        let mut buffer =
+            FortranWriter::new(BufWriter::new(File::create(filename)?),
+                               header.big_endian);
+        buffer.write_record(&header.records[0])?;
+        let mut mesh = header.records[1].clone();
+        mesh[12..].copy_from_slice(&buffer.i32_bytes(&[1]));
+        buffer.write_record(&mesh)?;
+        // each record is a line of the first mesh dimension
+        let nx = {
+            let b = [mesh[0], mesh[1], mesh[2], mesh[3]];
+            if header.big_endian {
+                i32::from_be_bytes(b)
+            } else {
+                i32::from_le_bytes(b)
+            }
+        };
+        let nx = nx.max(1) as usize;
+        pbar.set_length(data.len() / nx);
+        for line in data.chunks(nx) {
+            let line = line.iter()
+                           .map(|d| d.unwrap_or(0.))
+                           .collect::<Vec<f64>>();
+            let record = buffer.f32_bytes(&line);
+            buffer.write_record(&record)?;
+            pbar.tick();
+        }
+        Ok(())
+    }
+
+    /// Deals with the flipped grid.
+    fn coordinate_format(&self, coords: [f64; 3]) -> (String, String, String) {
+        let z = format!("{:.6}", coords[0]);
+        let y = format!("{:.6}", coords[1]);
+        let x = format!("{:.6}", coords[2]);
+        (x, y, z)
+    }
+}
+
+impl Siesta {
+    /// Reads the atoms from the .XV file with the same system label as
+    /// filename, naming the .XV file in any error.
+    ///
+    /// * `filename`: The path to the .RHO.
+    fn xv_atoms(&self, filename: &str) -> Result<Atoms, Error> {
+        let xv_file = Path::new(filename).with_extension("XV");
+        let xv_name = xv_file.display().to_string();
+        let mut xv = String::new();
+        File::open(&xv_file).and_then(|mut f| f.read_to_string(&mut xv))
+                            .map_err(|e| Error::from(e).in_file(&xv_name))?;
+        self.to_atoms(xv).map_err(|e| e.in_file(&xv_name))
+    }
+}
@@ -0,0 +1,284 @@
+use crate::analysis::Analysis;
+use crate::atoms::Atoms;
+use crate::io::FileFormat;
+use crate::utils;
+use std::io::Write;
+
+/// Puts three stored values, such as the components of a position, in the
+/// order of the density file, see [`FileFormat::reversed`].
+#[allow(clippy::borrowed_box)]
+fn file_order<T: Copy>(v: [T; 3], file_type: &Box<dyn FileFormat>) -> [T; 3] {
+    if file_type.reversed() {
+        [v[2], v[1], v[0]]
+    } else {
+        v
+    }
+}
+
+/// The lattice vectors, a, b and c, in the order of the density file.
+#[allow(clippy::borrowed_box)]
+fn lattice_vectors(atoms: &Atoms,
+                   file_type: &Box<dyn FileFormat>)
+                   -> [[f64; 3]; 3] {
+    let [a, b, c] = file_order(atoms.lattice.to_cartesian, file_type);
+    [file_order(a, file_type),
+     file_order(b, file_type),
+     file_order(c, file_type)]
+}
+
+/// The per-atom properties as pairs of keys and values: the population of
+/// each density, keyed by the lowercase name of the density, the net charge if
+/// known and the volume.
+#[allow(clippy::borrowed_box)]
+fn properties(atoms: &Atoms,
+              analysis: &Analysis,
+              file_type: &Box<dyn FileFormat>)
+              -> Vec<(String, Vec<f64>)> {
+    let mut properties = file_type.density_names(atoms,
+                                                 analysis.atoms_charge.len())
+                                  .iter()
+                                  .zip(&analysis.atoms_charge)
+                                  .map(|(name, charge)| {
+                                      (name.to_lowercase().replace(' ', "_"),
+                                       charge.clone())
+                                  })
+                                  .collect::<Vec<(String, Vec<f64>)>>();
+    if !analysis.atoms_net_charge.is_empty() {
+        properties.push((String::from("net_charge"),
+                         analysis.atoms_net_charge.clone()));
+    }
+    properties.push((String::from("volume"),
+                     analysis.atoms_volume.clone()));
+    properties
+}
+
+/// Writes the structure as extended XYZ with the Bader properties of each
+/// atom as columns.
+///
+/// The comment line holds the lattice and the Properties key describing the
+/// columns: the species, the cartesian position and the population of each
+/// density (bader_charge, bader_spin), the net charge if known
+/// (bader_net_charge) and the volume (bader_volume).
+///
+/// * `atoms`: The [`Atoms`] of the density file.
+/// * `analysis`: The [`Analysis`] of the partitioned density.
+/// * `file_type`: The [`FileFormat`] of the density file.
+/// * `buffer`: Where to write the structure.
+#[allow(clippy::borrowed_box)]
+pub fn write_extxyz<W: Write>(atoms: &Atoms,
+                              analysis: &Analysis,
+                              file_type: &Box<dyn FileFormat>,
+                              buffer: &mut W)
+                              -> std::io::Result<()> {
+    let properties = properties(atoms, analysis, file_type);
+    let lattice = lattice_vectors(atoms, file_type)
+        .iter()
+        .flat_map(|v| v.iter().map(|x| format!("{:.8}", x)))
+        .collect::<Vec<String>>();
+    let columns = properties.iter()
+                            .map(|(name, _)| format!(":bader_{}:R:1", name))
+                            .collect::<String>();
+    writeln!(buffer, "{}", atoms.positions.len())?;
+    writeln!(buffer,
+             "Lattice=\"{}\" Properties=species:S:1:pos:R:3{} \
+              pbc=\"T T T\"",
+             lattice.join(" "),
+             columns)?;
+    for (i, p) in atoms.positions.iter().enumerate() {
+        let p = file_order(*p, file_type);
+        write!(buffer,
+               "{:<3} {:>14.8} {:>14.8} {:>14.8}",
+               atoms.symbols[i],
+               p[0],
+               p[1],
+               p[2])?;
+        for (_, values) in properties.iter() {
+            write!(buffer, " {:>14.8}", values[i])?;
+        }
+        writeln!(buffer)?;
+    }
+    buffer.flush()
+}
+
+/// Writes the structure as a CIF, in P1, with the Bader properties of each
+/// atom in a custom loop.
+///
+/// The atoms are labelled by their symbol and index, ie. Ti1, which is used
+/// to link the _atom_site loop to the _bader loop holding the population of
+/// each density (_bader_charge, _bader_spin), the net charge if known
+/// (_bader_net_charge) and the volume (_bader_volume).
+///
+/// * `atoms`: The [`Atoms`] of the density file.
+/// * `analysis`: The [`Analysis`] of the partitioned density.
+/// * `file_type`: The [`FileFormat`] of the density file.
+/// * `buffer`: Where to write the structure.
+#[allow(clippy::borrowed_box)]
+pub fn write_cif<W: Write>(atoms: &Atoms,
+                           analysis: &Analysis,
+                           file_type: &Box<dyn FileFormat>,
+                           buffer: &mut W)
+                           -> std::io::Result<()> {
+    let properties = properties(atoms, analysis, file_type);
+    let [a, b, c] = lattice_vectors(atoms, file_type);
+    let angle = |u: [f64; 3], v: [f64; 3]| {
+        (utils::vdot(u, v) / (utils::norm(u) * utils::norm(v))).acos()
+                                                               .to_degrees()
+    };
+    let labels = atoms.symbols
+                      .iter()
+                      .enumerate()
+                      .map(|(i, s)| format!("{}{}", s, i + 1))
+                      .collect::<Vec<String>>();
+    writeln!(buffer, "data_bader")?;
+    writeln!(buffer, "_symmetry_space_group_name_H-M   'P 1'")?;
+    writeln!(buffer, "_symmetry_Int_Tables_number      1")?;
+    let cell = [("length_a", utils::norm(a)),
+                ("length_b", utils::norm(b)),
+                ("length_c", utils::norm(c)),
+                ("angle_alpha", angle(b, c)),
+                ("angle_beta", angle(a, c)),
+                ("angle_gamma", angle(a, b)),
+                ("volume", atoms.lattice.volume)];
+    for (key, value) in cell.iter() {
+        writeln!(buffer, "_cell_{:<26} {:.8}", key, value)?;
+    }
+    writeln!(buffer)?;
+    writeln!(buffer, "loop_")?;
+    writeln!(buffer, "_symmetry_equiv_pos_as_xyz")?;
+    writeln!(buffer, "'x, y, z'")?;
+    writeln!(buffer)?;
+    writeln!(buffer, "loop_")?;
+    writeln!(buffer, "_atom_site_label")?;
+    writeln!(buffer, "_atom_site_type_symbol")?;
+    writeln!(buffer, "_atom_site_fract_x")?;
+    writeln!(buffer, "_atom_site_fract_y")?;
+    writeln!(buffer, "_atom_site_fract_z")?;
+    for (i, p) in atoms.positions.iter().enumerate() {
+        let f = file_order(utils::dot(*p, atoms.lattice.to_fractional),
+                           file_type);
+        writeln!(buffer,
+                 "{:<6} {:<3} {:>12.8} {:>12.8} {:>12.8}",
+                 labels[i],
+                 atoms.symbols[i],
+                 f[0],
+                 f[1],
+                 f[2])?;
+    }
+    writeln!(buffer)?;
+    writeln!(buffer, "loop_")?;
+    writeln!(buffer, "_bader_atom_site_label")?;
+    for (name, _) in properties.iter() {
+        writeln!(buffer, "_bader_{}", name)?;
+    }
+    for (i, label) in labels.iter().enumerate() {
+        write!(buffer, "{:<6}", label)?;
+        for (_, values) in properties.iter() {
+            write!(buffer, " {:>14.8}", values[i])?;
+        }
+        writeln!(buffer)?;
+    }
+    buffer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atoms::Lattice;
+    use crate::io::cube::Cube;
+    use crate::io::vasp::Vasp;
+    use crate::storage::Storage;
+    use crate::voxel_map::VoxelMap;
+
+    fn setup() -> (Atoms, Analysis, Box<dyn FileFormat>) {
+        // flipped, this is a = 2 along x, b = 3 along y and c = 4 along z
+        let lattice =
+            Lattice::new([[4., 0., 0.], [0., 3., 0.], [0., 0., 2.]]);
+        let atoms = Atoms::new(lattice,
+                               vec![[0.; 3], [2., 1.5, 1.]],
+                               vec![8, 1],
+                               String::new());
+        let voxel_map = VoxelMap::new(8);
+        let mut analysis = Analysis::new(&voxel_map, 1, 2);
+        analysis.atoms_charge = vec![vec![7.5, 0.5]];
+        analysis.atoms_volume = vec![20., 4.];
+        analysis.net_charge(&[6., 1.]);
+        (atoms, analysis, Box::new(Vasp {}))
+    }
+
+    #[test]
+    fn structure_write_extxyz() {
+        let (atoms, analysis, file_type) = setup();
+        let mut buffer = Vec::new();
+        write_extxyz(&atoms, &analysis, &file_type, &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("2"));
+        let comment = lines.next().unwrap();
+        assert!(comment.contains("Properties=species:S:1:pos:R:3:\
+                                  bader_charge:R:1:bader_net_charge:R:1:\
+                                  bader_volume:R:1"));
+        assert!(comment.starts_with("Lattice=\"2.00000000 0.00000000"));
+        let h = lines.nth(1)
+                     .unwrap()
+                     .split_whitespace()
+                     .collect::<Vec<&str>>();
+        assert_eq!(h[0], "H");
+        let values = h[1..].iter()
+                           .map(|x| x.parse::<f64>().unwrap())
+                           .collect::<Vec<f64>>();
+        assert_eq!(values, vec![1., 1.5, 2., 0.5, 0.5, 4.]);
+    }
+
+    #[test]
+    fn structure_write_cif() {
+        let (atoms, analysis, file_type) = setup();
+        let mut buffer = Vec::new();
+        write_cif(&atoms, &analysis, &file_type, &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains("_cell_length_a                   2.00000000"));
+        assert!(text.contains("_cell_angle_gamma                90.00000000"));
+        assert!(text.contains("H2     H     0.50000000   0.50000000   \
+                               0.50000000"));
+        assert!(text.contains("_bader_net_charge"));
+        let last = text.lines()
+                       .last()
+                       .unwrap()
+                       .split_whitespace()
+                       .collect::<Vec<&str>>();
+        assert_eq!(last, vec!["H2", "0.50000000", "0.50000000", "4.00000000"]);
+    }
+
+    #[test]
+    fn structure_write_cube_unflipped() {
+        // the H atom of values.cube is at (1, 2, 3) Bohr in a 6 Bohr cube
+        let file_type: Box<dyn FileFormat> = Box::new(Cube {});
+        let (_, _, atoms, _) =
+            file_type.read(String::from("tests/cube/values.cube"),
+                           &Storage::Memory)
+                     .unwrap();
+        let voxel_map = VoxelMap::new(27);
+        let mut analysis = Analysis::new(&voxel_map, 2, 2);
+        analysis.atoms_charge = vec![vec![7.5, 0.5], vec![7.5, 0.5]];
+        analysis.atoms_volume = vec![20., 4.];
+        let mut buffer = Vec::new();
+        write_extxyz(&atoms, &analysis, &file_type, &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        let h = text.lines()
+                    .nth(3)
+                    .unwrap()
+                    .split_whitespace()
+                    .skip(1)
+                    .take(3)
+                    .map(|x| x.parse::<f64>().unwrap())
+                    .collect::<Vec<f64>>();
+        let bohr = 0.52917721067;
+        for (x, expected) in h.iter().zip(&[bohr, 2. * bohr, 3. * bohr]) {
+            assert!((x - expected).abs() < 1E-7);
+        }
+        let mut buffer = Vec::new();
+        write_cif(&atoms, &analysis, &file_type, &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains("H2     H     0.16666667   0.33333333   \
+                               0.50000000"));
+    }
+}
@@ -1,11 +1,14 @@
 use crate::atoms::{Atoms, Lattice};
-use crate::io::reader::BufReader;
-use crate::io::{FileFormat, FortranFormat, ReadFunction};
-use crate::progress::Bar;
+use crate::elements;
+use crate::errors::{parse_line, Error};
+use crate::io::reader::{read_values, BufReader};
+use crate::io::{Dimensions, FileFormat, FortranFormat, ReadFunction};
+use crate::progress::Progress;
+use crate::storage::{Buffer, Storage};
 use crate::utils;
 use regex::{Regex, RegexSet};
 use std::fs::File;
-use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
 
 /// The coordinate system.
 enum Coord {
@@ -15,64 +18,92 @@ enum Coord {
     Cartesian,
 }
 
+/// Where the sections of a VASP density file are.
+struct Sections {
+    /// The start and end of each grid line.
+    grid: Vec<[usize; 2]>,
+    /// The line each density starts on for reporting parse errors.
+    lines: Vec<usize>,
+    /// The start of each augmentation line.
+    aug: Vec<usize>,
+    /// The total file size.
+    total: usize,
+}
+
+/// Searches the file, filename, for the grid lines or augmentation that
+/// bound the densities.
+fn sections(filename: &str) -> Result<Sections, Error> {
+    // open the file in a buffer reader
+    let mut reader = BufReader::open(filename)?;
+    let mut buffer = String::new();
+    let mut grid: Vec<[usize; 2]> = vec![];
+    let mut lines: Vec<usize> = vec![];
+    let mut aug: Vec<usize> = vec![];
+    let mut pos = 0;
+    let mut line_number = 0;
+    let regex =
+        RegexSet::new([r"^\s*\d+\s+\d+\s+\d+\s*$", r"^\s*aug"]).unwrap();
+    // the first 7 lines are useless to us
+    for _ in 0..8 {
+        let size = match reader.read_line(&mut buffer) {
+            Some(line) => {
+                let (_, size) = line?;
+                size
+            }
+            None => 0,
+        };
+        pos += size;
+        line_number += 1;
+    }
+    // lets start trying to match
+    while let Some(line) = reader.read_line(&mut buffer) {
+        let (text, size) = line?;
+        line_number += 1;
+        if regex.is_match(text) {
+            let matches: Vec<usize> = regex.matches(text).into_iter().collect();
+            match matches[0] {
+                0 => {
+                    let start = pos;
+                    pos += size;
+                    grid.push([start, pos]);
+                    lines.push(line_number + 1);
+                }
+                1 => {
+                    aug.push(pos);
+                    pos += size;
+                }
+                _ => {}
+            }
+        } else {
+            pos += size;
+        }
+    }
+    if grid.is_empty() {
+        return Err(Error::parse(0, "No grid line found."));
+    }
+    Ok(Sections { grid,
+                  lines,
+                  aug,
+                  total: pos })
+}
+
 /// The VASP file format for reading/writing CHG, PARCHG and CHGCARs.
 pub struct Vasp {}
 
 impl FileFormat for Vasp {
     /// Read a VASP density.
-    fn read(&self, filename: String) -> ReadFunction {
+    fn read(&self, filename: String, storage: &Storage) -> ReadFunction {
         // the voxel origin in VASP is (0, 0, 0)
         let voxel_origin = [0f64; 3];
-        println!("Reading {} as VASP format:", filename);
+        // name the file in any error
+        let named = |e: Error| e.in_file(&filename);
         // find the start and end points of the density as well as the total file size
-        let (grid, aug, total) = {
-            // open the file in a buffer reader
-            let mut reader = BufReader::open(filename.clone())?;
-
-            let mut buffer = String::new();
-            let mut grid: Vec<[usize; 2]> = vec![];
-            let mut aug: Vec<usize> = vec![];
-            let mut pos = 0;
-            // search for the grid lines or augmentation that bound the densities
-            let regex = RegexSet::new(&[r"^\s*\d+\s+\d+\s+\d+\s*$",
-                                        r"^\s*aug"]).unwrap();
-            // the first 7 lines are useless to us
-            for _ in 0..8 {
-                let size = match reader.read_line(&mut buffer) {
-                    Some(line) => {
-                        let (_, size) = line?;
-                        size
-                    }
-                    None => 0,
-                };
-                pos += size;
-            }
-            // lets start trying to match
-            while let Some(line) = reader.read_line(&mut buffer) {
-                let (text, size) = line?;
-                if regex.is_match(text) {
-                    let matches: Vec<usize> =
-                        regex.matches(text).into_iter().collect();
-                    let matches = matches[0];
-                    match matches {
-                        0 => {
-                            let start = pos;
-                            pos += size;
-                            let end = pos;
-                            grid.push([start, end]);
-                        }
-                        1 => {
-                            aug.push(pos);
-                            pos += size;
-                        }
-                        _ => {}
-                    }
-                } else {
-                    pos += size;
-                }
-            }
-            (grid, aug, pos)
-        };
+        let Sections { grid,
+                       lines,
+                       aug,
+                       total, } = sections(&filename).map_err(named)?;
+        let (grid_vec, atoms) =
+            self.header(&filename, &grid, &lines).map_err(named)?;
         // Now we know where everything is so let's work out what to do
         // Start by making vector of start and end points of the densities
         let mut start = Vec::with_capacity(4);
@@ -88,70 +119,52 @@ impl FileFormat for Vasp {
             };
             stop.push(s);
         }
-        let mut file = File::open(filename)?;
-        // assign Vectos with the capacity of what it is to hold
-        let mut poscar_b = Vec::with_capacity(grid[0][0]);
-        let mut grid_pts_b = Vec::with_capacity(grid[0][1] - grid[0][0]);
-        let mut density_b = Vec::with_capacity(stop[0] - start[0]);
+        let mut file = File::open(&filename).map(io::BufReader::new)
+                                            .map_err(|e| named(e.into()))?;
         // there could be a maximum of 4 densities 1 total and then 1 or 3 spin
-        let mut density: Vec<Vec<f64>> = Vec::with_capacity(4);
-        // read the poscar information poscar_b
-        let _ = <File as Read>::by_ref(&mut file).take(grid[0][1] as u64)
-                                                 .read_to_end(&mut poscar_b)?;
-        file.seek(SeekFrom::Current((grid[0][0] as i64 - grid[0][1] as i64)
-                                    as i64))?;
-        // read the grid line into grid_pts_b
-        let _ =
-            <File as Read>::by_ref(&mut file).take((grid[0][1] - grid[0][0])
-                                                   as u64)
-                                             .read_to_end(&mut grid_pts_b)?;
-        // read the total charge density into density_b
-        let _ = <File as Read>::by_ref(&mut file).take((stop[0] - start[0])
-                                                       as u64)
-                                                 .read_to_end(&mut density_b)?;
-        // convert the bytes we have read into a String and an Atoms struct
-        let poscar = String::from_utf8(poscar_b).unwrap();
-        let grid_vec: Vec<usize> = {
-            String::from_utf8(grid_pts_b).unwrap()
-                                         .split_whitespace()
-                                         .map(|x| x.parse::<usize>().unwrap())
-                                         .collect()
-        };
-        let atoms = self.to_atoms(poscar);
-        // convert out of VASP's strange units
-        density.push(String::from_utf8(density_b).unwrap()
-                                                 .split_whitespace()
-                                                 .map(|x| {
-                                                     x.parse::<f64>().unwrap()
-                                                     / atoms.lattice.volume
-                                                 })
-                                                 .collect());
-        for i in 1..start.len() {
-            let mut spin_b = Vec::with_capacity(stop[i] - start[i]);
-            let _ = file.seek(SeekFrom::Start(start[i] as u64));
-            let _ = <File as Read>::by_ref(&mut file).take((stop[i] - start[i])
-                                                           as u64)
-                                                     .read_to_end(&mut spin_b)?;
-            density.push(String::from_utf8(spin_b).unwrap()
-                                                  .split_whitespace()
-                                                  .map(|x| {
-                                                      x.parse::<f64>().unwrap()
-                                                      / atoms.lattice.volume
-                                                  })
-                                                  .collect());
+        let total = grid_vec.iter().take(3).product::<usize>();
+        let mut density: Vec<Buffer<f64>> = Vec::with_capacity(4);
+        for (i, s) in start.iter().enumerate() {
+            file.seek(SeekFrom::Start(*s as u64))?;
+            let mut section = file.by_ref().take((stop[i] - s) as u64);
+            // convert out of VASP's strange units as each value is parsed
+            let mut buffer =
+                Buffer::zeroed(total, storage).map_err(|e| named(e.into()))?;
+            read_values(&mut section, lines[i], total, "density", |p, x| {
+                buffer[p] = x / atoms.lattice.volume
+            }).map_err(named)?;
+            density.push(buffer);
         }
         // flip the grid points as VASP outputs density[z, y, x]
         let grid_pts: [usize; 3] = [grid_vec[2], grid_vec[1], grid_vec[0]];
-        println!("File read successfully.");
         Ok((voxel_origin, grid_pts, atoms, density))
     }
 
+    /// Finds the grid lines to count the densities and reads the atoms.
+    fn dimensions(&self, filename: String) -> Result<Dimensions, Error> {
+        let named = |e: Error| e.in_file(&filename);
+        let sections = sections(&filename).map_err(named)?;
+        let (grid, atoms) = self.header(&filename,
+                                        &sections.grid,
+                                        &sections.lines)
+                                .map_err(named)?;
+        Ok(Dimensions { grid: [grid[2], grid[1], grid[0]],
+                        densities: sections.grid.len(),
+                        atoms: atoms.positions.len() })
+    }
+
     /// Read atom information.
-    fn to_atoms(&self, atoms_text: String) -> Atoms {
+    fn to_atoms(&self, atoms_text: String) -> Result<Atoms, Error> {
         // create regex for matching the (C|K)artesian | Direct line
         let coord_regex = Regex::new(r"(?m)^\s*(c|C|k|K|d|D)\w*").unwrap();
         // the last match is the one we want so we don't match carbon or the comment line
-        let matches = coord_regex.find_iter(&atoms_text).last().unwrap();
+        let matches = match coord_regex.find_iter(&atoms_text).last() {
+            Some(m) => m,
+            None => {
+                return Err(Error::parse(0,
+                                        "No Direct or Cartesian line found."))
+            }
+        };
         let coord_text =
             &atoms_text[matches.start()..matches.end()].trim_start();
         let coord = if coord_text.starts_with('d') | coord_text.starts_with('D')
@@ -178,39 +191,11 @@ impl FileFormat for Vasp {
         let mut lines = atoms_text.lines();
         // skip the comment line  and then read the lattice information
         let _ = lines.next();
-        let mut scale = {
-            lines.next()
-                 .unwrap()
-                 .to_string()
-                 .split_whitespace()
-                 .map(|x| x.parse::<f64>().unwrap())
-                 .collect::<Vec<f64>>()
-        };
+        let mut scale = parse_line::<f64>(lines.next(), 2, 1, "scale")?;
         // density[z, y, x] so lets swap the c and a
-        let mut c = {
-            lines.next()
-                 .unwrap()
-                 .to_string()
-                 .split_whitespace()
-                 .map(|x| x.parse::<f64>().unwrap())
-                 .collect::<Vec<f64>>()
-        };
-        let mut b = {
-            lines.next()
-                 .unwrap()
-                 .to_string()
-                 .split_whitespace()
-                 .map(|x| x.parse::<f64>().unwrap())
-                 .collect::<Vec<f64>>()
-        };
-        let mut a = {
-            lines.next()
-                 .unwrap()
-                 .to_string()
-                 .split_whitespace()
-                 .map(|x| x.parse::<f64>().unwrap())
-                 .collect::<Vec<f64>>()
-        };
+        let mut c = parse_line::<f64>(lines.next(), 3, 3, "lattice")?;
+        let mut b = parse_line::<f64>(lines.next(), 4, 3, "lattice")?;
+        let mut a = parse_line::<f64>(lines.next(), 5, 3, "lattice")?;
         let volume = {
             (c[0] * (a[1] * b[2] - a[2] * b[1])
              + c[1] * (a[2] * b[0] - a[0] * b[2])
@@ -219,6 +204,9 @@ impl FileFormat for Vasp {
         };
         // the scale can be negative and this means that it is the volume of the cell
         // it can also be 3 values which is a multiplier for each lattice
+        if scale.len() != 1 && scale.len() != 3 {
+            return Err(Error::parse(2, "Expected 1 or 3 values for scale."));
+        }
         if scale.len() == 1 {
             if scale[0] < 0f64 {
                 scale[0] /= -volume;
@@ -231,9 +219,45 @@ impl FileFormat for Vasp {
             b[i] *= scale[2 - i];
             a[i] *= scale[2 - i];
         }
-        let lattice = Lattice::new([[a[2], a[1], a[0]],
-                                    [b[2], b[1], b[0]],
-                                    [c[2], c[1], c[0]]]);
+        let lattice = Lattice::try_new([[a[2], a[1], a[0]],
+                                        [b[2], b[1], b[0]],
+                                        [c[2], c[1], c[0]]])
+            .map_err(|e| Error::parse(3, e))?;
+        // VASP 5 has a line of species before the counts whereas VASP 4 does
+        // not, in which case the species are often in the comment line
+        let line = lines.next().unwrap_or("");
+        let (species, counts, line_number) = if line.split_whitespace()
+                                       .all(|x| x.parse::<usize>().is_ok())
+        {
+            let comment = atoms_text.lines()
+                                    .next()
+                                    .unwrap_or("")
+                                    .split_whitespace()
+                                    .collect::<Vec<&str>>();
+            let species = if comment.iter().all(|x| elements::is_symbol(x)) {
+                comment
+            } else {
+                vec![]
+            };
+            (species, Some(line), 6)
+        } else {
+            (line.split_whitespace().collect(), lines.next(), 7)
+        };
+        let counts = parse_line::<usize>(counts, line_number, 1, "counts")?;
+        if pos.len() < 3 * counts.iter().sum::<usize>() {
+            return Err(Error::parse(0,
+                                    format!("Expected {} positions, found {}.",
+                                            counts.iter().sum::<usize>(),
+                                            pos.len() / 3)));
+        }
+        let atomic_numbers = if species.len() == counts.len() {
+            species.iter()
+                   .zip(&counts)
+                   .flat_map(|(s, n)| vec![elements::atomic_number(s); *n])
+                   .collect::<Vec<usize>>()
+        } else {
+            vec![]
+        };
         let mut positions: Vec<[f64; 3]> = vec![];
         // make the positions fractional and swap c and a
         match coord {
@@ -256,31 +280,50 @@ impl FileFormat for Vasp {
                 }
             }
         }
-        Atoms::new(lattice, positions, atoms_text)
+        Ok(Atoms::new(lattice, positions, atomic_numbers, atoms_text))
+    }
+
+    /// A CHGCAR stores the density multiplied by the volume of the cell.
+    fn scale(&self, atoms: &Atoms) -> f64 {
+        atoms.lattice.volume
     }
 
     /// Write a CHGCAR from a vector of options where None will be written as zero.
-    fn write(&self,
-             atoms: &Atoms,
-             data: Vec<Option<f64>>,
-             filename: String,
-             pbar: Bar)
-             -> std::io::Result<()> {
+    fn write_values(&self,
+                    atoms: &Atoms,
+                    data: Vec<Option<f64>>,
+                    filename: String,
+                    pbar: Box<dyn Progress>)
+                    -> std::io::Result<()> {
         let filename = format!("{}_CHGCAR", filename);
         let mut buffer = BufWriter::new(File::create(filename)?);
         pbar.set_length(data.len() / 5 + (data.len() % 5 != 0) as usize);
         buffer.write_all(atoms.text.as_bytes())?;
-        data.chunks(5).for_each(|line| {
-            if let Err(e) = line.iter()
-                                .try_for_each(|f| write!(buffer, " {:.11}", FortranFormat{ float: *f, mult: atoms.lattice.volume })) {
-                panic!("Error occured during write: {}", e)
-            };
-            if let Err(e) = writeln!(buffer) {
-                panic!("Error occured during write: {}", e)
-            };
+        data.chunks(5).try_for_each(|line| {
+            line.iter()
+                .try_for_each(|f| write!(buffer, " {:.11}", FortranFormat{ float: *f, mult: 1. }))?;
+            writeln!(buffer)?;
             pbar.tick();
-        });
-        Ok(())
+            Ok(())
+        })
+    }
+
+    /// The counts line of the POSCAR, which follows the lattice in VASP 4 and
+    /// the species line in VASP 5, so that species of the same element, such
+    /// as Fe and Fe_pv, stay apart.
+    fn species_counts(&self, atoms: &Atoms) -> Vec<usize> {
+        let mut lines = atoms.text.lines().skip(5);
+        let line = lines.next().unwrap_or("");
+        let counts = if line.split_whitespace()
+                            .all(|x| x.parse::<usize>().is_ok())
+        {
+            line
+        } else {
+            lines.next().unwrap_or("")
+        };
+        counts.split_whitespace()
+              .filter_map(|x| x.parse::<usize>().ok())
+              .collect()
     }
 
     /// Deals with fortran indexing.
@@ -291,3 +334,72 @@ impl FileFormat for Vasp {
         (x, y, z)
     }
 }
+
+impl Vasp {
+    /// Reads the POSCAR information, up to and including the first grid
+    /// line, returning the grid and the atoms.
+    ///
+    /// * `filename`: The path to the density file.
+    /// * `grid`: The start and end of each grid line.
+    /// * `lines`: The line each density starts on.
+    fn header(&self,
+              filename: &str,
+              grid: &[[usize; 2]],
+              lines: &[usize])
+              -> Result<(Vec<usize>, Atoms), Error> {
+        let file = File::open(filename).map(io::BufReader::new)?;
+        let mut poscar_b = Vec::with_capacity(grid[0][1]);
+        let _ = file.take(grid[0][1] as u64).read_to_end(&mut poscar_b)?;
+        let grid_pts_b = poscar_b[grid[0][0].min(poscar_b.len())..].to_vec();
+        // convert the bytes we have read into a String and an Atoms struct
+        let poscar = String::from_utf8(poscar_b)?;
+        let grid_pts = String::from_utf8(grid_pts_b)?;
+        let grid_vec =
+            parse_line::<usize>(Some(&grid_pts), lines[0] - 1, 3, "grid")?;
+        let atoms = self.to_atoms(poscar)?;
+        Ok((grid_vec, atoms))
+    }
+
+    /// Reads the valence charge (ZVAL) of each species from a POTCAR, in the
+    /// order they appear, alongside the atomic number from the TITEL line.
+    ///
+    /// * `filename`: The path to the POTCAR.
+    pub fn read_potcar(filename: &str) -> Result<Vec<(usize, f64)>, Error> {
+        let named = |e: Error| e.in_file(filename);
+        let mut text = String::new();
+        File::open(filename).and_then(|mut f| f.read_to_string(&mut text))
+                            .map_err(|e| named(e.into()))?;
+        let mut atomic_numbers = Vec::new();
+        let mut zval = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            // TITEL  = PAW_PBE Ti_pv 07Sep2000
+            if let Some(j) = line.find("TITEL") {
+                let label = line[j..].split('=')
+                                     .nth(1)
+                                     .and_then(|t| t.split_whitespace().nth(1))
+                                     .unwrap_or("");
+                atomic_numbers.push(elements::atomic_number(label));
+            }
+            // POMASS =   47.880; ZVAL   =   10.000    mass and valenz
+            if let Some(j) = line.find("ZVAL") {
+                let value = line[j..].split('=')
+                                     .nth(1)
+                                     .and_then(|t| t.split_whitespace().next())
+                                     .and_then(|t| t.parse::<f64>().ok());
+                match value {
+                    Some(z) => zval.push(z),
+                    None => {
+                        return Err(named(Error::parse(
+                            i + 1,
+                            format!("Unable to parse ZVAL, ({}).", line.trim()),
+                        )))
+                    }
+                }
+            }
+        }
+        if zval.is_empty() || zval.len() != atomic_numbers.len() {
+            return Err(named(Error::parse(0, "No species found.")));
+        }
+        Ok(atomic_numbers.into_iter().zip(zval).collect())
+    }
+}
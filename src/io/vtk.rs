@@ -0,0 +1,282 @@
+use crate::analysis::Analysis;
+use crate::atoms::Atoms;
+use crate::errors::Error;
+use crate::grid::Grid;
+use crate::io::FileFormat;
+use crate::precision::Real;
+use crate::progress::Progress;
+use crate::utils;
+use crate::voxel_map::VoxelMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// How many values to write on each line of a DataArray.
+const LINE_LENGTH: usize = 6;
+
+/// Writes the values of a DataArray, LINE_LENGTH to a line. Values with
+/// multiple components should be formatted together.
+fn write_array<W, T>(buffer: &mut W,
+                     data_type: &str,
+                     name: &str,
+                     components: usize,
+                     values: &[T])
+                     -> std::io::Result<()>
+    where W: Write,
+          T: std::fmt::Display
+{
+    writeln!(buffer,
+             "        <DataArray type=\"{}\" Name=\"{}\" \
+              NumberOfComponents=\"{}\" format=\"ascii\">",
+             data_type,
+             name,
+             components)?;
+    for line in values.chunks(LINE_LENGTH) {
+        let line = line.iter()
+                       .map(|v| format!("{}", v))
+                       .collect::<Vec<String>>();
+        writeln!(buffer, "          {}", line.join(" "))?;
+    }
+    writeln!(buffer, "        </DataArray>")
+}
+
+/// Formats a position, as written by the [`FileFormat`], for a Points array.
+#[allow(clippy::borrowed_box)]
+fn coordinates(p: [f64; 3], file_type: &Box<dyn FileFormat>) -> String {
+    let (x, y, z) = file_type.coordinate_format(p);
+    format!("{} {} {}", x, y, z)
+}
+
+/// The labels of each voxel, from [`Analysis::output_label_map`], and the
+/// [`Analysis`] for the atom of each volume.
+type Labels<'a> = (&'a Analysis, &'a [Option<(usize, f64)>]);
+
+/// Writes a StructuredGrid of the densities, which can be empty, and the
+/// labels of each voxel, if partitioned, with the atom of each volume.
+#[allow(clippy::borrowed_box)]
+fn write_grid<T, D>(densities: &[D],
+                    names: &[String],
+                    labels: Option<Labels>,
+                    grid: &Grid,
+                    filename: String,
+                    file_type: &Box<dyn FileFormat>)
+                    -> std::io::Result<()>
+    where T: Real,
+          D: AsRef<[T]> {
+    // the fastest index in the grid is the first index of the extent
+    let mut buffer = BufWriter::new(File::create(filename)?);
+    let extent = format!("0 {} 0 {} 0 {}",
+                         grid.size.z - 1,
+                         grid.size.y - 1,
+                         grid.size.x - 1);
+    writeln!(buffer, "<?xml version=\"1.0\"?>")?;
+    writeln!(buffer,
+             "<VTKFile type=\"StructuredGrid\" version=\"0.1\" \
+              byte_order=\"LittleEndian\">")?;
+    writeln!(buffer, "  <StructuredGrid WholeExtent=\"{}\">", extent)?;
+    writeln!(buffer, "    <Piece Extent=\"{}\">", extent)?;
+    writeln!(buffer,
+             "      <PointData Scalars=\"{}\">",
+             names.first().map_or("volume", |name| name))?;
+    for (density, name) in densities.iter().zip(names) {
+        let density = density.as_ref()
+                             .iter()
+                             .map(|d| format!("{:.6E}", d.to_f64()))
+                             .collect::<Vec<String>>();
+        write_array(&mut buffer, "Float64", name, 1, &density)?;
+    }
+    if let Some((analysis, labels)) = labels {
+        let volume = labels.iter()
+                           .map(|l| l.map_or(0, |(v, _)| v + 1))
+                           .collect::<Vec<usize>>();
+        write_array(&mut buffer, "Int32", "volume", 1, &volume)?;
+        let atom = labels.iter()
+                         .map(|l| {
+                             l.map_or(0, |(v, _)| {
+                                  analysis.assigned_atom[v] + 1
+                              })
+                         })
+                         .collect::<Vec<usize>>();
+        write_array(&mut buffer, "Int32", "atom", 1, &atom)?;
+        let weight = labels.iter()
+                           .map(|l| {
+                               format!("{:.6}", l.map_or(0., |(_, w)| w))
+                           })
+                           .collect::<Vec<String>>();
+        write_array(&mut buffer, "Float64", "boundary_weight", 1, &weight)?;
+    }
+    writeln!(buffer, "      </PointData>")?;
+    writeln!(buffer, "      <Points>")?;
+    let points = (0..grid.size.total).map(|p| {
+                                         let p = grid.to_cartesian(p as isize);
+                                         let p = utils::dot(p,
+                                                            grid.voxel_lattice
+                                                                .to_cartesian);
+                                         coordinates(p, file_type)
+                                     })
+                                     .collect::<Vec<String>>();
+    write_array(&mut buffer, "Float64", "Points", 3, &points)?;
+    writeln!(buffer, "      </Points>")?;
+    writeln!(buffer, "    </Piece>")?;
+    writeln!(buffer, "  </StructuredGrid>")?;
+    writeln!(buffer, "</VTKFile>")
+}
+
+/// Writes the Bader volume and atom of each voxel, starting at 1 with 0 being
+/// vacuum, and the fraction of the voxel that belongs to that volume to a VTK
+/// XML StructuredGrid (.vts), without any density.
+///
+/// * `analysis`: The [`Analysis`] of the partitioned density.
+/// * `grid`: The [`Grid`] of the density.
+/// * `voxel_map`: The partitioned [`VoxelMap`].
+/// * `filename`: Where to save the file, minus the suffix.
+/// * `file_type`: [`FileFormat`] for writing the correct coordinates.
+/// * `pbar`: A progress bar for building the labels.
+///
+/// Returns an [`Error::Analysis`] if a voxel belongs to an unknown maxima.
+#[allow(clippy::borrowed_box)]
+pub fn write_labels(analysis: &Analysis,
+                    grid: &Grid,
+                    voxel_map: &VoxelMap,
+                    filename: String,
+                    file_type: &Box<dyn FileFormat>,
+                    pbar: Box<dyn Progress>)
+                    -> Result<(), Error> {
+    let labels = analysis.output_label_map(grid, voxel_map, pbar)?;
+    write_grid::<f64, Vec<f64>>(&[],
+                                &[],
+                                Some((analysis, &labels)),
+                                grid,
+                                format!("{}.vts", filename),
+                                file_type)?;
+    Ok(())
+}
+
+/// Writes densities, such as those upsampled before partitioning, to a VTK XML
+/// StructuredGrid (.vts) without any partitioning.
+///
+/// * `densities`: The densities to write, on the points of `grid`.
+/// * `names`: The name of each density's DataArray.
+/// * `grid`: The [`Grid`] of the densities.
+/// * `filename`: Where to save the file, minus the suffix.
+/// * `file_type`: [`FileFormat`] for writing the correct coordinates.
+#[allow(clippy::borrowed_box)]
+pub fn write_densities<T, D>(densities: &[D],
+                             names: &[String],
+                             grid: &Grid,
+                             filename: String,
+                             file_type: &Box<dyn FileFormat>)
+                             -> std::io::Result<()>
+    where T: Real,
+          D: AsRef<[T]> {
+    write_grid(densities,
+               names,
+               None,
+               grid,
+               format!("{}.vts", filename),
+               file_type)
+}
+
+/// Writes the densities and partitioning to a VTK XML StructuredGrid (.vts)
+/// and the atoms to a VTK XML PolyData (.vtp) file for viewing in ParaView.
+///
+/// A StructuredGrid is used over ImageData as the points are written
+/// explicitly and so non-orthogonal lattices are supported. Each point has the
+/// density (charge and any spin), the Bader volume and atom it is assigned to,
+/// starting at 1 with 0 being vacuum, and the fraction of the voxel that
+/// belongs to that volume, which is less than 1 for boundary voxels. The atoms
+/// carry their index, atomic number, charge and volume.
+///
+/// * `atoms`: The associated &[`Atoms`] object for the density file.
+/// * `analysis`: The [`Analysis`] of the partitioned density.
+/// * `densities`: The densities read from the file.
+/// * `grid`: The [`Grid`] of the density.
+/// * `voxel_map`: The partitioned [`VoxelMap`].
+/// * `filename`: Where to save the files, minus the suffix.
+/// * `file_type`: [`FileFormat`] for writing the correct coordinates.
+/// * `pbar`: A progress bar for building the labels.
+///
+/// Returns an [`Error::Analysis`] if a voxel belongs to an unknown maxima.
+#[allow(clippy::borrowed_box, clippy::too_many_arguments)]
+pub fn write<T, D>(atoms: &Atoms,
+                   analysis: &Analysis,
+                   densities: &[D],
+                   grid: &Grid,
+                   voxel_map: &VoxelMap,
+                   filename: String,
+                   file_type: &Box<dyn FileFormat>,
+                   pbar: Box<dyn Progress>)
+                   -> Result<(), Error>
+    where T: Real,
+          D: AsRef<[T]> {
+    let labels = analysis.output_label_map(grid, voxel_map, pbar)?;
+    let names = file_type.density_names(atoms, densities.len())
+                         .iter()
+                         .map(|name| name.to_lowercase().replace(' ', "_"))
+                         .collect::<Vec<String>>();
+    write_grid(densities,
+               &names,
+               Some((analysis, &labels)),
+               grid,
+               format!("{}.vts", filename),
+               file_type)?;
+    // the atoms as vertices
+    let n = atoms.positions.len();
+    let mut buffer =
+        BufWriter::new(File::create(format!("{}_atoms.vtp", filename))?);
+    writeln!(buffer, "<?xml version=\"1.0\"?>")?;
+    writeln!(buffer,
+             "<VTKFile type=\"PolyData\" version=\"0.1\" \
+              byte_order=\"LittleEndian\">")?;
+    writeln!(buffer, "  <PolyData>")?;
+    writeln!(buffer,
+             "    <Piece NumberOfPoints=\"{}\" NumberOfVerts=\"{}\" \
+              NumberOfLines=\"0\" NumberOfStrips=\"0\" NumberOfPolys=\"0\">",
+             n,
+             n)?;
+    writeln!(buffer, "      <PointData Scalars=\"atom\">")?;
+    write_array(&mut buffer,
+                "Int32",
+                "atom",
+                1,
+                &(1..=n).collect::<Vec<usize>>())?;
+    write_array(&mut buffer,
+                "Int32",
+                "atomic_number",
+                1,
+                &atoms.atomic_numbers)?;
+    for (charge, name) in analysis.atoms_charge.iter().zip(&names) {
+        let charge = charge.iter()
+                           .map(|c| format!("{:.6}", c))
+                           .collect::<Vec<String>>();
+        write_array(&mut buffer, "Float64", name, 1, &charge)?;
+    }
+    let volume = analysis.atoms_volume
+                         .iter()
+                         .map(|v| format!("{:.6}", v))
+                         .collect::<Vec<String>>();
+    write_array(&mut buffer, "Float64", "volume", 1, &volume)?;
+    writeln!(buffer, "      </PointData>")?;
+    writeln!(buffer, "      <Points>")?;
+    let points = atoms.positions
+                      .iter()
+                      .map(|p| coordinates(*p, file_type))
+                      .collect::<Vec<String>>();
+    write_array(&mut buffer, "Float64", "Points", 3, &points)?;
+    writeln!(buffer, "      </Points>")?;
+    writeln!(buffer, "      <Verts>")?;
+    write_array(&mut buffer,
+                "Int32",
+                "connectivity",
+                1,
+                &(0..n).collect::<Vec<usize>>())?;
+    write_array(&mut buffer,
+                "Int32",
+                "offsets",
+                1,
+                &(1..=n).collect::<Vec<usize>>())?;
+    writeln!(buffer, "      </Verts>")?;
+    writeln!(buffer, "    </Piece>")?;
+    writeln!(buffer, "  </PolyData>")?;
+    writeln!(buffer, "</VTKFile>")?;
+    Ok(())
+}
@@ -0,0 +1,572 @@
+use crate::atoms::{Atoms, Lattice};
+use crate::elements;
+use crate::errors::{parse_line, parse_value, Error};
+use crate::io::{Dimensions, FileFormat, FortranFormat, ReadFunction};
+use crate::progress::Progress;
+use crate::storage::{Buffer, Storage};
+use crate::utils;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+
+/// The type of 3D grid block in the file.
+enum GridBlock {
+    /// BEGIN_BLOCK_DATAGRID_3D found in XSF files.
+    Data,
+    /// BEGIN_BLOCK_BANDGRID_3D found in BXSF files.
+    Band,
+}
+
+impl GridBlock {
+    /// Matches the name of a grid within the block.
+    fn is_grid(&self, token: &str) -> bool {
+        let token = token.to_uppercase();
+        match self {
+            Self::Data => {
+                token.starts_with("BEGIN_DATAGRID_3D")
+                || token.starts_with("DATAGRID_3D_")
+            }
+            Self::Band => token.starts_with("BEGIN_BANDGRID_3D"),
+        }
+    }
+
+    /// Matches the end of a grid within the block.
+    fn is_end(&self, token: &str) -> bool {
+        let token = token.to_uppercase();
+        match self {
+            Self::Data => token.starts_with("END_DATAGRID_3D"),
+            Self::Band => token.starts_with("END_BANDGRID_3D"),
+        }
+    }
+}
+
+/// The whitespace separated tokens of a file, read a line at a time so that
+/// a grid is never held as text.
+struct Tokens<R: BufRead> {
+    /// The file being read.
+    reader: R,
+    /// The line being split.
+    text: String,
+    /// The byte offset of the next token within the line.
+    offset: usize,
+    /// The number, counting from 1, of the line being split.
+    line: usize,
+}
+
+impl<R: BufRead> Tokens<R> {
+    /// Moves to the start of the next token, reading lines as needed, and
+    /// returns false at the end of the file.
+    fn fill(&mut self) -> Result<bool, Error> {
+        loop {
+            let rest = &self.text[self.offset..];
+            if let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+                self.offset += start;
+                return Ok(true);
+            }
+            self.text.clear();
+            self.offset = 0;
+            if self.reader.read_line(&mut self.text)? == 0 {
+                return Ok(false);
+            }
+            self.line += 1;
+        }
+    }
+
+    /// The next token and its line without moving past it.
+    fn peek(&mut self) -> Result<Option<(&str, usize)>, Error> {
+        if !self.fill()? {
+            return Ok(None);
+        }
+        let rest = &self.text[self.offset..];
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        Ok(Some((&rest[..end], self.line)))
+    }
+
+    /// Moves past the token returned by [`Tokens::peek`].
+    fn advance(&mut self) {
+        let rest = &self.text[self.offset..];
+        self.offset += rest.find(char::is_whitespace).unwrap_or(rest.len());
+    }
+
+    /// The next token and its line.
+    fn next(&mut self) -> Result<Option<(String, usize)>, Error> {
+        let token = self.peek()?.map(|(t, line)| (t.to_string(), line));
+        self.advance();
+        Ok(token)
+    }
+}
+
+/// Reads up to the start of the grid block, everything before this is the
+/// structure and is kept verbatim, returning the structure, the type of block
+/// and the tokens that follow the block keyword.
+fn find_block<R: BufRead>(mut file: R)
+                          -> Result<(String, GridBlock, Tokens<R>), Error> {
+    let mut structure = String::new();
+    let mut block = None;
+    let mut line = String::new();
+    let mut line_number = 0;
+    loop {
+        line.clear();
+        if file.read_line(&mut line)? == 0 {
+            break;
+        }
+        line_number += 1;
+        let keyword = line.trim().to_uppercase();
+        if keyword.starts_with("BEGIN_BLOCK_DATAGRID_3D") {
+            block = Some(GridBlock::Data);
+        } else if keyword.starts_with("BEGIN_BLOCK_BANDGRID_3D") {
+            block = Some(GridBlock::Band);
+        } else {
+            structure.push_str(&line);
+            continue;
+        }
+        break;
+    }
+    let block = match block {
+        Some(b) => b,
+        None => return Err(Error::parse(0, "No 3D grid block found.")),
+    };
+    // the rest of the block keyword line is the first of the tokens
+    let first = line.trim_start()
+                    .find(char::is_whitespace)
+                    .map_or(String::new(), |i| {
+                        line.trim_start()[i..].to_string()
+                    });
+    let tokens = Tokens { reader: file,
+                          text: first,
+                          offset: 0,
+                          line: line_number };
+    Ok((structure, block, tokens))
+}
+
+/// Builds a single grid header after the structure so that the file can be
+/// written back out.
+fn grid_text(structure: String,
+             block: &GridBlock,
+             grid_header: &[String])
+             -> String {
+    let mut text = structure;
+    match block {
+        GridBlock::Data => {
+            text.push_str("BEGIN_BLOCK_DATAGRID_3D\n bader\n");
+            text.push_str(" BEGIN_DATAGRID_3D_bader\n");
+        }
+        GridBlock::Band => {
+            text.push_str("BEGIN_BLOCK_BANDGRID_3D\n bader\n");
+            text.push_str(" BEGIN_BANDGRID_3D_bader\n 1\n");
+        }
+    }
+    for line in grid_header.chunks(3) {
+        text.push_str(&format!(" {}\n", line.join(" ")));
+    }
+    if let GridBlock::Band = block {
+        text.push_str(" BAND: 1\n");
+    }
+    text
+}
+
+/// The XCrySDen file format for reading/writing XSF and BXSF grids.
+///
+/// XSF grids are "general" grids, the periodic points at the cell boundary
+/// are duplicated so a file with a grid line of `nx ny nz` only contains
+/// `(nx - 1) * (ny - 1) * (nz - 1)` unique points. The duplicates are dropped
+/// on reading and reinserted on writing. Densities are assumed to be in
+/// e/Angstrom^3 and are not converted. Each DATAGRID_3D (or band of a
+/// BANDGRID_3D) in the first block is read as a separate density.
+///
+/// The lattice is taken from the spanning vectors of the first grid, which
+/// the density sits on, and PRIMVEC is ignored even when it differs from them.
+pub struct Xsf {}
+
+impl FileFormat for Xsf {
+    /// Read an XSF or BXSF file.
+    fn read(&self, filename: String, storage: &Storage) -> ReadFunction {
+        let named = |e: Error| e.in_file(&filename);
+        let invalid =
+            |line: usize, msg: &str| Error::parse(line, msg).in_file(&filename);
+        let file = File::open(&filename).map(io::BufReader::new)
+                                        .map_err(|e| named(e.into()))?;
+        let (structure, block, mut tokens) =
+            find_block(file).map_err(named)?;
+        let mut densities: Vec<Buffer<f64>> = Vec::with_capacity(4);
+        let mut header: Option<([usize; 3], Vec<String>)> = None;
+        // loop over each grid in the block
+        while let Some((token, token_line)) = tokens.next().map_err(named)? {
+            if token.to_uppercase().starts_with("END_BLOCK") {
+                break;
+            } else if !block.is_grid(&token) {
+                continue;
+            }
+            let bands = match block {
+                GridBlock::Data => 1,
+                GridBlock::Band => match tokens.next().map_err(named)? {
+                    Some((x, line)) => match x.parse::<usize>() {
+                        Ok(x) => x,
+                        Err(_) => {
+                            return Err(invalid(line, "Bad number of bands."))
+                        }
+                    },
+                    None => return Err(invalid(0, "Missing number of bands.")),
+                },
+            };
+            // grid points, origin and spanning vectors
+            let mut grid_header = Vec::with_capacity(15);
+            for _ in 0..15 {
+                match tokens.next().map_err(named)? {
+                    Some(t) => grid_header.push(t),
+                    None => {
+                        return Err(invalid(token_line,
+                                           "Incomplete grid header."))
+                    }
+                }
+            }
+            let mut grid = [0usize; 3];
+            for (g, (x, line)) in grid.iter_mut().zip(&grid_header[..3]) {
+                *g = match x.parse::<usize>() {
+                    Ok(x) if x > 1 => x,
+                    _ => return Err(invalid(*line, "Bad grid dimensions.")),
+                };
+            }
+            match &header {
+                Some((g, _)) if *g != grid => {
+                    return Err(invalid(token_line,
+                                       "Grids have different dimensions."))
+                }
+                Some(_) => (),
+                None => {
+                    header = Some((grid,
+                                   grid_header.iter()
+                                              .map(|(s, _)| s.clone())
+                                              .collect()))
+                }
+            }
+            let [nx, ny, nz] = grid;
+            let total = nx * ny * nz;
+            for _ in 0..bands {
+                // each band is preceded by "BAND: n"
+                if let GridBlock::Band = block {
+                    match tokens.next().map_err(named)? {
+                        Some((t, _))
+                            if t.to_uppercase().starts_with("BAND:") =>
+                        {
+                            let _ = tokens.next().map_err(named)?;
+                        }
+                        _ => {
+                            return Err(invalid(token_line,
+                                               "Missing BAND: line."))
+                        }
+                    }
+                }
+                // drop the periodic images as each value is parsed
+                let mut density =
+                    Buffer::zeroed((nx - 1) * (ny - 1) * (nz - 1), storage)
+                        .map_err(|e| named(e.into()))?;
+                let mut count = 0;
+                while let Some((token, line)) = tokens.peek().map_err(named)? {
+                    if block.is_end(token)
+                       || token.to_uppercase().starts_with("BAND:")
+                    {
+                        break;
+                    }
+                    if count == total {
+                        return Err(invalid(token_line,
+                                           "Number of values does not match \
+                                            the grid."));
+                    }
+                    let x = match token.parse::<f64>() {
+                        Ok(x) => x,
+                        Err(_) => {
+                            return Err(invalid(line,
+                                               &format!("Unable to parse \
+                                                         density, ({}).",
+                                                        token)))
+                        }
+                    };
+                    let (i, j) = (count % nx, count / nx % ny);
+                    let k = count / (nx * ny);
+                    if i < nx - 1 && j < ny - 1 && k < nz - 1 {
+                        density[(k * (ny - 1) + j) * (nx - 1) + i] = x;
+                    }
+                    count += 1;
+                    tokens.advance();
+                }
+                if count != total {
+                    return Err(invalid(token_line,
+                                       "Number of values does not match the \
+                                        grid."));
+                }
+                densities.push(density);
+            }
+        }
+        let (grid, grid_header) = match header {
+            Some(h) => h,
+            None => {
+                return Err(invalid(0, "No grid found in 3D grid block."))
+            }
+        };
+        let text = grid_text(structure, &block, &grid_header);
+        let atoms = self.to_atoms(text).map_err(|e| e.in_file(&filename))?;
+        // the origin of the grid is in cartesian coordinates, convert it to
+        // voxel coordinates, swapping x and z as the grid is flipped
+        let origin = grid_header[3..6].iter()
+                                      .map(|x| x.parse::<f64>())
+                                      .collect::<Result<Vec<f64>, _>>()
+                                      .map_err(|_| invalid(0, "Bad origin."))?;
+        let grid_pts = [grid[2] - 1, grid[1] - 1, grid[0] - 1];
+        let origin = utils::dot([origin[2], origin[1], origin[0]],
+                                atoms.lattice.to_fractional);
+        let mut voxel_origin = [0f64; 3];
+        for i in 0..3 {
+            voxel_origin[i] = origin[i] * grid_pts[i] as f64;
+        }
+        Ok((voxel_origin, grid_pts, atoms, densities))
+    }
+
+    /// Counts the grids, or bands, of the first block skipping their values,
+    /// which are only checked when read, and reads the atoms.
+    fn dimensions(&self, filename: String) -> Result<Dimensions, Error> {
+        let named = |e: Error| e.in_file(&filename);
+        let invalid =
+            |line: usize, msg: &str| Error::parse(line, msg).in_file(&filename);
+        let file = File::open(&filename).map(io::BufReader::new)
+                                        .map_err(|e| named(e.into()))?;
+        let (structure, block, mut tokens) =
+            find_block(file).map_err(named)?;
+        let mut densities = 0;
+        let mut header: Option<([usize; 3], Vec<String>)> = None;
+        while let Some((token, token_line)) = tokens.next().map_err(named)? {
+            if token.to_uppercase().starts_with("END_BLOCK") {
+                break;
+            } else if !block.is_grid(&token) {
+                continue;
+            }
+            let bands = match block {
+                GridBlock::Data => 1,
+                GridBlock::Band => {
+                    match tokens.next().map_err(named)?
+                                .map(|(x, _)| x.parse::<usize>())
+                    {
+                        Some(Ok(x)) => x,
+                        _ => return Err(invalid(token_line,
+                                                "Bad number of bands.")),
+                    }
+                }
+            };
+            let mut grid_header = Vec::with_capacity(15);
+            for _ in 0..15 {
+                match tokens.next().map_err(named)? {
+                    Some((t, _)) => grid_header.push(t),
+                    None => {
+                        return Err(invalid(token_line,
+                                           "Incomplete grid header."))
+                    }
+                }
+            }
+            let mut grid = [0usize; 3];
+            for (g, x) in grid.iter_mut().zip(&grid_header[..3]) {
+                *g = match x.parse::<usize>() {
+                    Ok(x) if x > 1 => x,
+                    _ => {
+                        return Err(invalid(token_line,
+                                           "Bad grid dimensions."))
+                    }
+                };
+            }
+            // skip each "BAND: n" and the values, including the images
+            let label = match block {
+                GridBlock::Data => 0,
+                GridBlock::Band => 2,
+            };
+            let skip = bands * (grid.iter().product::<usize>() + label);
+            for _ in 0..skip {
+                if tokens.peek().map_err(named)?.is_none() {
+                    break;
+                }
+                tokens.advance();
+            }
+            densities += bands;
+            if header.is_none() {
+                header = Some((grid, grid_header));
+            }
+        }
+        let (grid, grid_header) = match header {
+            Some(h) => h,
+            None => {
+                return Err(invalid(0, "No grid found in 3D grid block."))
+            }
+        };
+        let text = grid_text(structure, &block, &grid_header);
+        let atoms = self.to_atoms(text).map_err(named)?;
+        Ok(Dimensions { grid: [grid[2] - 1, grid[1] - 1, grid[0] - 1],
+                        densities,
+                        atoms: atoms.positions.len() })
+    }
+
+    /// Read atom information from the structure section and the grid header.
+    fn to_atoms(&self, atoms_text: String) -> Result<Atoms, Error> {
+        // number the lines from 1 for reporting errors
+        let mut lines = atoms_text.lines()
+                                  .map(|l| l.trim())
+                                  .enumerate()
+                                  .map(|(i, l)| (i + 1, l));
+        let mut pos: Vec<f64> = vec![];
+        let mut atomic_numbers: Vec<usize> = vec![];
+        let mut lattice = None;
+        while let Some((i, line)) = lines.next() {
+            let keyword = line.to_uppercase();
+            if keyword.starts_with("PRIMCOORD") {
+                let natoms = parse_value::<usize>(
+                    lines.next()
+                         .and_then(|(_, l)| l.split_whitespace().next()),
+                    i + 1,
+                    "number of atoms",
+                )?;
+                for j in 0..natoms {
+                    Xsf::push_position(lines.next().map(|(_, l)| l),
+                                       i + 2 + j,
+                                       &mut pos,
+                                       &mut atomic_numbers)?;
+                }
+            } else if keyword.starts_with("ATOMS") {
+                // molecular structures have no count so read until we fail
+                for (j, line) in lines.by_ref() {
+                    if line.split_whitespace().count() < 4 {
+                        break;
+                    }
+                    Xsf::push_position(Some(line),
+                                       j,
+                                       &mut pos,
+                                       &mut atomic_numbers)?;
+                }
+            } else if keyword.starts_with("BEGIN_DATAGRID_3D")
+                      || keyword.starts_with("BEGIN_BANDGRID_3D")
+            {
+                if keyword.starts_with("BEGIN_BANDGRID_3D") {
+                    let _ = lines.next();
+                }
+                // skip the grid points and origin, the spanning vectors are
+                // the lattice the data sits on
+                let _ = lines.next();
+                let _ = lines.next();
+                let mut vectors = [[0f64; 3]; 3];
+                let mut first = 0;
+                for (k, v) in vectors.iter_mut().enumerate() {
+                    let (j, line) = lines.next().unwrap_or((0, ""));
+                    let line = parse_line::<f64>(Some(line), j, 3, "lattice")?;
+                    v.copy_from_slice(&line[..3]);
+                    if k == 0 {
+                        first = j;
+                    }
+                }
+                lattice = Some((vectors, first));
+                break;
+            }
+        }
+        let ([a, b, c], line) = match lattice {
+            Some(lattice) => lattice,
+            None => return Err(Error::parse(0, "No grid vectors found.")),
+        };
+        // density[z, y, x] so lets swap the c and a
+        let lattice = Lattice::try_new([[c[2], c[1], c[0]],
+                                        [b[2], b[1], b[0]],
+                                        [a[2], a[1], a[0]]])
+            .map_err(|e| Error::parse(line, e))?;
+        let mut positions: Vec<[f64; 3]> = vec![];
+        for i in (0..pos.len()).step_by(3) {
+            let p = utils::dot([pos[i + 2], pos[i + 1], pos[i]],
+                               lattice.to_fractional);
+            positions.push(utils::dot([p[0].rem_euclid(1f64),
+                                       p[1].rem_euclid(1f64),
+                                       p[2].rem_euclid(1f64)],
+                                      lattice.to_cartesian));
+        }
+        Ok(Atoms::new(lattice, positions, atomic_numbers, atoms_text))
+    }
+
+    /// Write an XSF file from a vector of options where None will be written as
+    /// zero.
+    fn write_values(&self,
+                    atoms: &Atoms,
+                    data: Vec<Option<f64>>,
+                    filename: String,
+                    pbar: Box<dyn Progress>)
+                    -> std::io::Result<()> {
+        let (suffix, end) = if atoms.text.contains("BEGIN_BANDGRID_3D") {
+            ("bxsf", "END_BANDGRID_3D\nEND_BLOCK_BANDGRID_3D\n")
+        } else {
+            ("xsf", "END_DATAGRID_3D\nEND_BLOCK_DATAGRID_3D\n")
+        };
+        let filename = format!("{}.{}", filename, suffix);
+        // the general grid size is the line after the grid name (and bands)
+        let grid = {
+            let mut lines = atoms.text.lines().skip_while(|l| {
+                                                  !l.contains("GRID_3D_bader")
+                                              });
+            let _ = lines.next();
+            if suffix == "bxsf" {
+                let _ = lines.next();
+            }
+            lines.next()
+                 .unwrap_or("")
+                 .split_whitespace()
+                 .map(|x| x.parse::<usize>().ok().filter(|n| *n > 1))
+                 .collect::<Option<Vec<usize>>>()
+                 .filter(|g| g.len() == 3)
+                 .ok_or_else(|| {
+                     io::Error::new(io::ErrorKind::InvalidData,
+                                    "Unable to read the grid size from the \
+                                     grid header.")
+                 })?
+        };
+        let mut buffer = BufWriter::new(File::create(filename)?);
+        pbar.set_length(grid[2] * grid[1]);
+        buffer.write_all(atoms.text.as_bytes())?;
+        let (nx, ny, nz) = (grid[0] - 1, grid[1] - 1, grid[2] - 1);
+        for k in 0..grid[2] {
+            for j in 0..grid[1] {
+                let row = ((k % nz) * ny + j % ny) * nx;
+                for i in 0..grid[0] {
+                    write!(buffer,
+                           " {:.11}",
+                           FortranFormat { float: data[row + i % nx],
+                                           mult: 1. })?;
+                }
+                writeln!(buffer)?;
+                pbar.tick();
+            }
+        }
+        buffer.write_all(end.as_bytes())?;
+        Ok(())
+    }
+
+    /// Deals with the flipped grid.
+    fn coordinate_format(&self, coords: [f64; 3]) -> (String, String, String) {
+        let z = format!("{:.6}", coords[0]);
+        let y = format!("{:.6}", coords[1]);
+        let x = format!("{:.6}", coords[2]);
+        (x, y, z)
+    }
+}
+
+impl Xsf {
+    /// Pushes the atomic number and cartesian position from an atom line,
+    /// "Z x y z [fx fy fz]", where Z can also be the element symbol.
+    fn push_position(line: Option<&str>,
+                     line_number: usize,
+                     pos: &mut Vec<f64>,
+                     numbers: &mut Vec<usize>)
+                     -> Result<(), Error> {
+        let mut split = line.unwrap_or("").split_whitespace();
+        let z = match split.next() {
+            Some(z) => z,
+            None => return Err(Error::parse(line_number, "Missing atom.")),
+        };
+        numbers.push(match z.parse::<usize>() {
+                         Ok(z) => z,
+                         Err(_) => elements::atomic_number(z),
+                     });
+        for _ in 0..3 {
+            pos.push(parse_value(split.next(), line_number, "position")?);
+        }
+        Ok(())
+    }
+}
@@ -31,16 +31,36 @@
 //! Add the following to your Cargo.toml:
 //! `bader = "0.3.2"`
 //!
+//! The whole calculation, from densities and [Atoms](atoms::Atoms) already in
+//! memory to an [Analysis](analysis::Analysis), is run by [run] with the
+//! threads and tolerances set in a [Config], with the densities stored as any
+//! [Real](precision::Real) (f64 or f32), reporting its progress to a
+//! [Reporter](progress::Reporter) such as [Silent](progress::Silent). Nothing
+//! in the library prints, the bca binary is a wrapper that reads the files,
+//! calls [run] and writes the results. Malformed files, densities that don't
+//! fit the grid and bad arguments are returned as an [Error](errors::Error),
+//! naming the file and line where known, rather than panicking. A long
+//! calculation can be stopped from another thread, or given a time limit,
+//! through the [CancellationToken](cancel::CancellationToken) of its [Config].
+//!
+//! Programs written in C, C++ or Fortran can partition densities they hold in
+//! memory through the C interface in [ffi], declared in include/bader.h and
+//! exported by the cdylib.
+//!
 //! ### Minimum Supported Rust Version (MSRV)
 //! This crate is guaranteed to compile on stable Rust 1.43.0 and up. It *might*
 //! compile with older versions but that may change in any new patch release.
 //! ## Usage
 //! The program takes a charge density file as input and performs Bader analysis
-//! of the data. Currently it supports density in [VASP] or [cube] formats. It
-//! is recommended to run VASP calculations with [LAECHG] = .TRUE. to print the
-//! core density and self-consistent valence density. These can then be passed
-//! as reference files to the program using the -r, --reference flag where they
-//! will be summed.
+//! of the data. Currently it supports density in [VASP], [cube], [XSF],
+//! Quantum ESPRESSO pp.x (filplot, `-t qe`), CASTEP .den_fmt, ABINIT _DEN or
+//! SIESTA .RHO formats, CASTEP and SIESTA reading the atoms from the .cell and
+//! .XV files of the same seed, and a written .den_fmt or .RHO is accompanied
+//! by its .cell or .XV.
+//! It is recommended to run VASP calculations with [LAECHG] = .TRUE. to print
+//! the core density and self-consistent valence density. These can then be
+//! passed as reference files to the program using the -r, --reference flag
+//! where they will be summed.
 //! ```sh
 //! $ bca CHGCAR -r AECCAR0 -r AECCAR2
 //! ```
@@ -50,16 +70,132 @@
 //! ```sh
 //! $ bca charge-density.cube -s spin-density.cube
 //! ```
+//! Progress bars are drawn on stderr and messages printed to stdout. For batch
+//! jobs `--log-format json` instead writes a line of JSON to stderr for every
+//! message and every tenth of each stage, with the fraction done and seconds
+//! elapsed, and `-q` prints nothing but errors.
+//! Settings shared between runs can be kept in a TOML (or JSON, if the name
+//! ends in .json) file and passed with `--config`, each flag being set by its
+//! name with underscores for spaces or by its long form. Flags on the
+//! command-line replace the values in the file, except that a switch set to
+//! true in the file can't be turned off from the command-line, and
+//! `--dump-config` prints the settings that would be used, as TOML, without
+//! running.
+//! ```sh
+//! $ cat bader.toml
+//! file = "CHGCAR"
+//! reference = ["AECCAR0", "AECCAR2"]
+//! format = ["dat", "json"]
+//! vacuum_tolerance = 1E-4
+//! $ bca --config bader.toml -J 4
+//! ```
+//! The partition can be saved to a binary checkpoint with `--checkpoint` and
+//! loaded with `--load` to write other outputs, or use a different maxima
+//! tolerance, without partitioning again. The input files are still read, to
+//! sum their charge, and a checkpoint made from a different input, or with a
+//! different weight (`-w`) or vacuum (`-v`) tolerance, is refused. With
+//! `--scratch` the loaded partition is memory-mapped like a new one.
+//! ```sh
+//! $ bca CHGCAR --checkpoint bader.chk
+//! $ bca CHGCAR --load bader.chk -o atoms -i 1 -f json
+//! ```
+//! For the largest grids `--precision single` stores the densities as f32,
+//! 4 rather than 8 bytes per voxel, and the weights of the boundary voxels as
+//! f32, whilst the gradients, weights and charges are still calculated and
+//! summed in f64. In double precision the whole part of each weight is the
+//! index of its maxima, which f32 cannot hold exactly, so each f32 weight is
+//! kept beside the index of its maxima, 12 bytes a weight in one store,
+//! rather than in a [`Vec`] of f64 for each boundary voxel. The sorted index
+//! and voxel map stay at 8 bytes per voxel each, so partitioning a single
+//! density drops from about 29 to about 22 bytes per voxel. For a
+//! 160x160x160 CHGCAR the peak resident memory fell from 123 MiB to 97 MiB.
+//! On the test files the charge of an atom changed by at most 3.8E-9 of the
+//! total charge.
+//! Grids larger than the memory available can be partitioned by
+//! memory-mapping the densities, the sorted index and the voxel map to
+//! scratch files with `--scratch`, ideally on a fast local disk. Each density
+//! is parsed straight into its scratch file as it is read. The voxels are
+//! partitioned in bands of similar density, each a 1024th of the grid, and
+//! each band is swept through in order of position, so the index, reference
+//! and voxel map are read forwards through their files rather than at
+//! random. A voxel whose neighbours of higher density aren't finished yet is
+//! put off to the end of its band, about 3% of them for a 160x160x160
+//! CHGCAR. `--max-resident` caps the resident memory, writing back and
+//! releasing the mapped pages whenever it is passed. The files are deleted
+//! at the end of the run.
+//! ```sh
+//! $ bca CHGCAR --scratch /scratch/$USER --max-resident 16G
+//! ```
+//! The expected peak memory, from the size of the grid and the number of
+//! densities in the header of the input, the precision, the storage, any
+//! upsampling and the output, is printed before any density is read. The
+//! calculation stops with an error if this is more than the memory available
+//! or the limit set with `--max-memory` (eg. `--max-memory 8G`).
+//! For large grids `--coarse 2` partitions a grid downsampled by 2 along each
+//! lattice vector first. Voxels whose coarse neighbours all belong to one
+//! maxima inherit it, and the weight method is only re-run at full
+//! resolution for the voxels near the coarse boundaries, so the boundary
+//! weights are still those of the full grid. The factor must divide every
+//! dimension of the grid and maxima smaller than a coarse voxel can be missed
+//! unless they lie near a boundary, so check the charges against a full run
+//! for new systems. On a 160x160x160 rock salt cell the partitioning was 3.4
+//! times faster with `--coarse 2`, re-running 23% of the voxels, and the
+//! charges were identical to a full run.
+//! ```sh
+//! $ bca CHGCAR --coarse 2
+//! ```
+//! Coarse densities can be upsampled onto a finer grid before partitioning
+//! with `--upsample 3`, interpolating the reference and every density by 3
+//! along each lattice vector of the periodic cell, either by zero-padding
+//! the Fourier transform (`--interpolation fourier`, the default) or with a
+//! periodic cubic (`--interpolation tricubic`). Both keep the original voxels
+//! and conserve the charge. `--write-upsampled` writes the upsampled
+//! densities to upsampled.vts and the output densities can only be written
+//! as VTK, as the header of the density file describes the original grid.
+//! ```sh
+//! $ bca CHGCAR --upsample 3 --write-upsampled -o vtk
+//! ```
 //! For a detailed list of usage options run
 //! ```sh
 //! $ bca --help
 //! ```
 //! ## Output
 //! The program outputs two files, ACF.dat & BCF.dat. The Atomic Charge File
-//! (ACF.dat) contians the charge (and spin) information for each atom and the
-//! Bader Charge File (BCF.dat) contains the information about each Bader volume.
-//! The BCF file also includes the atom number in the number column formatted as
-//! 'atom number: bader volume'.
+//! (ACF.dat) contians the element, charge (and spin) information for each atom
+//! and the Bader Charge File (BCF.dat) contains the information about each
+//! Bader volume. The BCF file also includes the atom number in the number
+//! column formatted as 'atom number: bader volume'. The density of a fragment,
+//! such as an adsorbed molecule, can be written with
+//! `-o sum-atoms -i 1 -i 2 -i 3`, summing the selected atoms (or volumes with
+//! `-o sum-volumes`) into a single file. So that other tools can integrate
+//! over the Bader volumes without repeating the partitioning, `-o labels`
+//! writes grids of the volume and atom each voxel belongs to (volume_labels,
+//! atom_labels), starting at 1 with 0 being vacuum, and the fraction of each
+//! voxel in that volume (boundary_weight) in the format of the input file,
+//! with `-o labels-vtk` writing the same to bader_labels.vts. Passing
+//! `-o vtk` also writes the density with the Bader volume, atom and boundary
+//! weight of each voxel to bader.vts and the atoms to bader_atoms.vtp for
+//! viewing in ParaView.
+//! Passing a POTCAR with `-p` or valence charges with `-z`
+//! (`bca CHGCAR -z Ti=10 -z O=6`) adds the net charge of each atom, and the
+//! average of each species, to ACF.dat; cube files use the nuclear charges
+//! they contain. There is no built-in table of valence charges, as the
+//! valence charge of an element depends on the pseudopotential used (Ti has
+//! 4, 10 or 12) and a guess would silently give wrong net charges. The
+//! POTCAR is matched to the species of the POSCAR in order, so two POTCARs
+//! of one element (Fe and Fe_pv) and VASP 4 files without a species line
+//! work, and every atom needs a valence charge once any is known. The POTCAR
+//! and valence charges are checked before the densities are partitioned.
+//! For scripting, `-f json` writes every result along with the input files,
+//! tolerances, grid and version to bader.json and `-f csv` writes ACF.csv and
+//! BCF.csv, with the metadata as comment lines starting with '#'. The
+//! structure, with the charge (and spin), net charge and volume of each atom,
+//! can be written to bader.xyz as extended XYZ with `-f xyz` or to bader.cif
+//! with `-f cif` for visualisation tools such as OVITO or VESTA. These
+//! replace the default ACF.dat and BCF.dat unless `-f dat` is also passed.
+//! Every output file can be written to another directory with `-d` and have a
+//! prefix added to its name with `--prefix`, so that runs in the same
+//! directory don't overwrite each other.
 //! ## License
 //! MIT
 //!
@@ -67,6 +203,7 @@
 //!
 //! [VASP]: <https://www.vasp.at/>
 //! [cube]: <https://gaussian.com/>
+//! [XSF]: <http://www.xcrysden.org/doc/XSF.html>
 //! [LAECHG]: <https://www.vasp.at/wiki/index.php/LAECHG>
 //! [Yu Min  and Trinkle Dallas R. 2011  J. Che.m Phys. 134 064111]: <https://doi.org/10.1063/1.3553716>
 //! [cargo]: <https://doc.rust-lang.org/cargo/getting-started/installation.html>
@@ -81,6 +218,21 @@ pub mod arguments;
 /// [ReducedLattice](atoms::ReducedLattice) for storing information about the
 /// cell in which the density is stored.
 pub mod atoms;
+/// Contains [CancellationToken](cancel::CancellationToken) for stopping a
+/// calculation from another thread or once a deadline has passed.
+pub mod cancel;
+/// Partitions a [downsampled](coarse::downsample) grid first and re-runs
+/// the weight method at full resolution only near its boundaries.
+pub mod coarse;
+/// The periodic table for converting between element symbols and atomic
+/// numbers.
+pub mod elements;
+/// The crate [Error](errors::Error) type, naming the file and line of any
+/// malformed input, and helpers for parsing text files.
+pub mod errors;
+/// A C interface for partitioning densities held in memory by C, C++ or
+/// Fortran programs, declared in include/bader.h.
+pub mod ffi;
 /// Contains [Grid](grid::Grid) for managing the movement around the grid on
 /// which the density is stored.
 pub mod grid;
@@ -88,12 +240,31 @@ pub mod grid;
 /// Provides a [FileFormat](io::FileFormat) trait to be implemented by modules designed to
 /// cover a specific file format of a density file.
 pub mod io;
+/// Estimates the peak memory of a calculation with
+/// [Estimate](memory::Estimate) before it is allocated.
+pub mod memory;
 /// Contains the three methods for partioning the density, ([Ongrid](methods::ongrid),
 /// [Neargrid](methods::neargrid), and [Weight](methods::weight)), and functions for
 /// performing a step for in each.
 pub mod methods;
-/// Provides [Bar](progress::Bar): A quicker thread-safe version of the [indicatif::ProgressBar].
+/// Runs the whole calculation, from the densities and [Atoms](atoms::Atoms)
+/// to the [Analysis](analysis::Analysis), with [run](pipeline::run).
+pub mod pipeline;
+/// Contains the [Real](precision::Real) trait for storing densities in
+/// single or double precision.
+pub mod precision;
+/// Provides the [Reporter](progress::Reporter) and
+/// [Progress](progress::Progress) traits taken by library functions in place
+/// of printing, with indicatif, silent and JSON lines implementations, and
+/// [Bar](progress::Bar): A quicker thread-safe version of the [indicatif::ProgressBar].
 pub mod progress;
+/// Provides [Storage](storage::Storage), where the large arrays of a
+/// calculation are kept, and [Buffer](storage::Buffer), an array on the heap
+/// or memory-mapped to a scratch file.
+pub mod storage;
+/// Upsamples a density onto a finer grid with [upsample](upsample::upsample),
+/// by Fourier or tricubic [Interpolation](upsample::Interpolation).
+pub mod upsample;
 /// Misc functions mainly for vector and matrix manipulation.
 pub mod utils;
 /// Calculates the Voronoi vectors, and their alpha values for the weight method,
@@ -102,3 +273,5 @@ pub mod voronoi;
 /// Provides the [VoxelMap](voxel_map::VoxelMap) for storing the maxima and weights of
 /// partioned voxels.
 pub mod voxel_map;
+
+pub use pipeline::{run, Config, Results};
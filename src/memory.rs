@@ -0,0 +1,303 @@
+use crate::arguments::{Args, Precision, Reference};
+use crate::errors::Error;
+use crate::grid::Size;
+use crate::io::WriteType;
+use crate::storage::Storage;
+use crate::upsample::Interpolation;
+
+/// The bytes kept on the heap for each boundary voxel: the [`Vec`] of its
+/// weights and, on average, three weights.
+const BOUNDARY_BYTES: u64 = 48;
+
+/// The bytes kept for each boundary voxel in single precision: on average
+/// three weights, each a f32 beside the maxima it belongs to.
+const SINGLE_BOUNDARY_BYTES: u64 = 36;
+
+/// The expected memory of a calculation, in bytes, at the peak of each stage.
+///
+/// The number of boundary voxels is only known once partitioned so is taken
+/// as two voxel-thick walls between as many equal cubes as there are atoms,
+/// which tends to overestimate it for molecules in vacuum.
+///
+/// # Examples
+/// ```
+/// use bader::arguments::{Args, ClapApp};
+/// use bader::grid::Size;
+/// use bader::memory::Estimate;
+///
+/// let matches = ClapApp::get().get_matches_from(vec!["bca", "CHGCAR"]);
+/// let args = Args::new(matches).unwrap();
+/// let estimate = Estimate::new(&args, &Size::new(100, 100, 100), 1, 8);
+/// // the density, the sorted index and the voxel map
+/// assert!(estimate.partitioning > 24_000_000);
+/// assert_eq!(estimate.peak(), estimate.partitioning);
+/// assert!(estimate.check(Some(1 << 20), None).is_err());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Estimate {
+    /// Reading the input, where every density is held as f64.
+    pub reading: u64,
+    /// Upsampling with `--upsample`, where the last density is interpolated
+    /// alongside the finer copies of the others.
+    pub upsampling: u64,
+    /// Partitioning, with the sorted index, voxel map and weights.
+    pub partitioning: u64,
+    /// Writing the densities of the output files.
+    pub writing: u64,
+    /// Kept in scratch files with `--scratch` rather than on the heap.
+    pub mapped: u64,
+}
+
+impl Estimate {
+    /// Estimates the memory of a calculation from the settings, the [`Size`]
+    /// of the grid and the number of densities and atoms in the file.
+    ///
+    /// With `--upsample` the densities are read on the original grid and
+    /// then upsampled one at a time, so every later stage is estimated on the
+    /// finer grid.
+    pub fn new(args: &Args, size: &Size, densities: usize, atoms: usize)
+               -> Self {
+        let factor = args.upsample.unwrap_or(1) as u64;
+        let coarse = size.total as u64;
+        let n = coarse * factor.pow(3);
+        let densities = densities as u64;
+        // the densities read as f64, then the reference, held and transient
+        let (reference, transient) = match args.reference {
+            Reference::None => (0, 0),
+            Reference::Column(_) => (1, 0),
+            Reference::One(_) => (1, 1),
+            Reference::Two(..) => (1, 2),
+        };
+        let transient = transient.max(args.spin.is_some() as u64);
+        let reading = 8 * coarse * (densities + reference + transient);
+        let (value, boundary_bytes) = match args.precision {
+            Precision::Single => (4, SINGLE_BOUNDARY_BYTES),
+            Precision::Double => (8, BOUNDARY_BYTES),
+        };
+        let (heap, mapped) = match args.storage {
+            Storage::Memory => (1, 0),
+            Storage::Mapped { .. } => (0, 1),
+        };
+        let stored = value * n * (densities + reference);
+        // the last density is still held alongside the finer copies of the
+        // others, it is copied and then upsampled an axis at a time with the
+        // last two axes alive together, through a line of each grid that is
+        // complex for a Fourier transform
+        let upsampling = match args.upsample {
+            Some(_) => {
+                let longest = size.x.max(size.y).max(size.z) as u64;
+                let line = match args.interpolation {
+                    Interpolation::Fourier => 48,
+                    Interpolation::Tricubic => 8,
+                };
+                let others = (densities + reference).saturating_sub(1);
+                heap * 8 * (coarse + n * others)
+                + 8 * coarse * (factor.pow(2) + factor.pow(3))
+                + line * (1 + factor) * longest
+            }
+            None => 0,
+        };
+        let index_and_map = 16 * n;
+        let boundary = (6. * (atoms.max(1) as f64).cbrt()
+                        * (n as f64).powf(2. / 3.))
+                        .min(n as f64) as u64;
+        let weights = boundary_bytes * boundary;
+        // the voxels to refine are listed on the heap before being sorted
+        let refine = if args.coarse.is_some() { 8 * n } else { 0 };
+        let partitioning =
+            heap * (stored + index_and_map) + weights + refine;
+        // the voxel map stays until the end but the index is dropped
+        let output = match args.output {
+            WriteType::None => 0,
+            WriteType::Atom(_)
+            | WriteType::Volume(_)
+            | WriteType::SumAtoms(_)
+            | WriteType::SumVolumes(_) => 32 * n,
+            WriteType::Labels => 72 * n,
+            WriteType::LabelsVtk => 32 * n,
+            WriteType::Vtk => 64 * n,
+        };
+        let writing = heap * (stored + index_and_map / 2) + weights + output;
+        Self { reading,
+               upsampling,
+               partitioning,
+               writing,
+               mapped: mapped * (stored + index_and_map) }
+    }
+
+    /// The largest memory of any stage.
+    pub fn peak(&self) -> u64 {
+        self.reading
+            .max(self.upsampling)
+            .max(self.partitioning)
+            .max(self.writing)
+    }
+
+    /// Returns an [`Error::Input`] if the peak is above `max_memory`, set by
+    /// the user, or the `available` memory detected, which should include
+    /// any memory the process already holds.
+    pub fn check(&self,
+                 max_memory: Option<u64>,
+                 available: Option<u64>)
+                 -> Result<(), Error> {
+        let peak = self.peak();
+        let limit = match (max_memory, available) {
+            (Some(max), _) if peak > max => {
+                Some(format!("the {} allowed by --max-memory", bytes(max)))
+            }
+            (_, Some(free)) if peak > free => {
+                Some(format!("the {} of memory available", bytes(free)))
+            }
+            _ => None,
+        };
+        match limit {
+            Some(limit) => Err(Error::Input(format!(
+                "The calculation is expected to need {} at its peak, more \
+                 than {}. Use --precision single or --scratch to reduce it.",
+                bytes(peak),
+                limit
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Display for Estimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f,
+               "Expected peak memory: {} (reading {}, ",
+               bytes(self.peak()),
+               bytes(self.reading))?;
+        if self.upsampling > 0 {
+            write!(f, "upsampling {}, ", bytes(self.upsampling))?;
+        }
+        write!(f,
+               "partitioning {}, writing {})",
+               bytes(self.partitioning),
+               bytes(self.writing))?;
+        if self.mapped > 0 {
+            write!(f, " with {} in scratch files", bytes(self.mapped))?;
+        }
+        write!(f, ".")
+    }
+}
+
+/// Formats a number of bytes in the largest unit, in powers of 1024, that
+/// keeps it above one.
+///
+/// # Examples
+/// ```
+/// use bader::memory::bytes;
+///
+/// assert_eq!(bytes(512), "512 B");
+/// assert_eq!(bytes(3 << 29), "1.5 GiB");
+/// ```
+pub fn bytes(bytes: u64) -> String {
+    let units = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.;
+    let mut unit = 0;
+    while value >= 1024. && unit < units.len() - 1 {
+        value /= 1024.;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+/// The memory available for starting new processes in bytes, without
+/// swapping, where the operating system reports it in /proc (Linux).
+pub fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kilobytes = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arguments::ClapApp;
+
+    fn args(v: Vec<&str>) -> Args {
+        Args::new(ClapApp::get().get_matches_from(v)).unwrap()
+    }
+
+    #[test]
+    fn memory_estimate_single() {
+        let size = Size::new(60, 60, 60);
+        let double = Estimate::new(&args(vec!["bca", "CHGCAR"]), &size, 2, 4);
+        let single = args(vec!["bca", "CHGCAR", "--precision", "single"]);
+        let single = Estimate::new(&single, &size, 2, 4);
+        assert_eq!(double.reading, single.reading);
+        // the densities and the index and map, then the weights
+        let double = double.partitioning - (2 * 8 + 16) * 216000;
+        let single = single.partitioning - (2 * 4 + 16) * 216000;
+        assert_eq!(double * SINGLE_BOUNDARY_BYTES, single * BOUNDARY_BYTES)
+    }
+
+    #[test]
+    fn memory_estimate_scratch() {
+        let size = Size::new(60, 60, 60);
+        let scratch = args(vec!["bca", "CHGCAR", "--scratch", "."]);
+        let estimate = Estimate::new(&scratch, &size, 1, 1);
+        assert_eq!(estimate.mapped, 24 * 216000);
+        assert!(estimate.partitioning < 216000 * 8)
+    }
+
+    #[test]
+    fn memory_estimate_output() {
+        let size = Size::new(60, 60, 60);
+        let none = Estimate::new(&args(vec!["bca", "CHGCAR"]), &size, 1, 1);
+        let vtk = args(vec!["bca", "CHGCAR", "-o", "vtk"]);
+        let vtk = Estimate::new(&vtk, &size, 1, 1);
+        assert_eq!(vtk.writing - none.writing, 64 * 216000)
+    }
+
+    #[test]
+    fn memory_estimate_upsample() {
+        let size = Size::new(30, 30, 30);
+        let fine = Size::new(60, 60, 60);
+        let upsample = args(vec!["bca", "CHGCAR", "--upsample", "2"]);
+        let upsample = Estimate::new(&upsample, &size, 1, 1);
+        let full = Estimate::new(&args(vec!["bca", "CHGCAR"]), &fine, 1, 1);
+        assert_eq!(upsample.reading, 8 * 27000);
+        assert_eq!(upsample.partitioning, full.partitioning);
+        assert_eq!(upsample.writing, full.writing)
+    }
+
+    #[test]
+    fn memory_estimate_upsampling() {
+        let size = Size::new(30, 30, 30);
+        let upsample = args(vec!["bca",
+                                 "CHGCAR",
+                                 "--upsample",
+                                 "2",
+                                 "--interpolation",
+                                 "tricubic"]);
+        let estimate = Estimate::new(&upsample, &size, 2, 1);
+        // the coarse last density, the fine first and the last two axes
+        let grids = 8 * 27000 + 8 * 216000 + 8 * 27000 * (4 + 8);
+        assert_eq!(estimate.upsampling, grids + 8 * 3 * 30);
+        let fourier = args(vec!["bca", "CHGCAR", "--upsample", "2"]);
+        let fourier = Estimate::new(&fourier, &size, 2, 1);
+        assert_eq!(fourier.upsampling, grids + 48 * 3 * 30);
+        assert_eq!(Estimate::new(&args(vec!["bca", "CHGCAR"]), &size, 2, 1)
+                       .upsampling,
+                   0)
+    }
+
+    #[test]
+    fn memory_check() {
+        let estimate = Estimate { reading: 10,
+                                  upsampling: 0,
+                                  partitioning: 30,
+                                  writing: 20,
+                                  mapped: 0 };
+        assert!(estimate.check(None, None).is_ok());
+        assert!(estimate.check(Some(30), Some(30)).is_ok());
+        assert!(estimate.check(Some(29), None).is_err());
+        assert!(estimate.check(None, Some(29)).is_err())
+    }
+}
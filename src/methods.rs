@@ -1,4 +1,5 @@
 use crate::grid::Grid;
+use crate::precision::Real;
 use crate::voxel_map::VoxelMap;
 use std::collections::HashMap;
 
@@ -55,19 +56,19 @@ pub enum WeightResult {
 /// };
 /// assert_eq!(weight, vec![62.625, 61.375])
 /// ```
-pub fn weight_step(p: isize,
-                   grid: &Grid,
-                   density: &[f64],
-                   voxel_map: &VoxelMap)
-                   -> WeightResult {
-    let control = density[p as usize];
+pub fn weight_step<T: Real>(p: isize,
+                            grid: &Grid,
+                            density: &[T],
+                            voxel_map: &VoxelMap)
+                            -> WeightResult {
+    let control = density[p as usize].to_f64();
     let mut t_sum = 0.;
     let mut weights = HashMap::<usize, f64>::new();
     // colllect the shift and distances and iterate over them.
     for (shift, alpha) in grid.voronoi.vectors.iter().zip(&grid.voronoi.alphas)
     {
         let pt = grid.voronoi_shift(p, shift);
-        let charge_diff = density[pt as usize] - control;
+        let charge_diff = density[pt as usize].to_f64() - control;
         if charge_diff > 0. {
             // calculate the gradient and add any weights to the HashMap.
             let rho = charge_diff * alpha;
@@ -75,9 +76,7 @@ pub fn weight_step(p: isize,
             match maxima.cmp(&-1) {
                 std::cmp::Ordering::Less => {
                     let point_weights = voxel_map.weight_get(maxima);
-                    for maxima_weight in point_weights.iter() {
-                        let maxima = *maxima_weight as usize;
-                        let w = maxima_weight - maxima as f64;
+                    for (maxima, w) in point_weights.iter() {
                         let weight = weights.entry(maxima).or_insert(0.);
                         *weight += w * rho;
                     }
@@ -166,22 +165,21 @@ pub fn weight_step(p: isize,
 ///     voxel_map.maxima_store(*p, 62 - (i as isize) % 2);
 /// }
 /// weight(33, &grid, &density, &voxel_map);
-/// assert_eq!(voxel_map.weight_get(-2), &vec![62.625, 61.375]);
+/// assert_eq!(voxel_map.weight_get(-2).iter().collect::<Vec<_>>(),
+///            vec![(62, 0.625), (61, 0.375)]);
 /// ```
-pub fn weight(p: usize, grid: &Grid, density: &[f64], voxel_map: &VoxelMap) {
+pub fn weight<T: Real>(p: usize,
+                       grid: &Grid,
+                       density: &[T],
+                       voxel_map: &VoxelMap) {
     let pt = p as isize;
-    match weight_step(pt, grid, density, &voxel_map) {
+    match weight_step(pt, grid, density, voxel_map) {
         WeightResult::Maxima => voxel_map.maxima_store(pt, pt),
         WeightResult::Interier(maxima) => {
             voxel_map.maxima_store(pt, maxima as isize);
         }
         WeightResult::Boundary(weights) => {
-            let i = {
-                let mut weight = voxel_map.lock();
-                let i = weight.len();
-                (*weight).push(weights);
-                i
-            };
+            let i = voxel_map.lock().push(weights);
             voxel_map.weight_store(pt, i);
         }
     }
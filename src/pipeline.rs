@@ -0,0 +1,679 @@
+use crate::analysis::Analysis;
+use crate::arguments::Args;
+use crate::atoms::Atoms;
+use crate::cancel::CancellationToken;
+use crate::coarse;
+use crate::errors::Error;
+use crate::grid::Grid;
+use crate::methods::weight;
+use crate::precision::Real;
+use crate::progress::Reporter;
+use crate::storage::{resident_memory, Buffer, Storage};
+use crate::utils::vacuum_tolerance;
+use crate::voxel_map::VoxelMap;
+use atomic_counter::{AtomicCounter, RelaxedCounter};
+use crossbeam_utils::thread;
+
+/// The settings of a calculation run with [`run`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// How many threads to partition the density over.
+    pub threads: usize,
+    /// Tolerance to disregard weights at.
+    pub weight_tolerance: f64,
+    /// Tolerance to disregard maxima at.
+    pub maxima_tolerance: f64,
+    /// The density below which a voxel is considered vacuum, if any.
+    pub vacuum_tolerance: Option<f64>,
+    /// Stops the calculation when cancelled or past its deadline.
+    pub cancel: CancellationToken,
+    /// Where the sorted index and the voxel map are kept.
+    pub storage: Storage,
+    /// Partitions a grid downsampled by this factor first and re-runs the
+    /// weight method at full resolution only near its boundaries, if set.
+    pub coarse: Option<usize>,
+}
+
+impl Default for Config {
+    /// The defaults of the command-line.
+    fn default() -> Self {
+        Self { threads: num_cpus::get().min(12),
+               weight_tolerance: 1E-6,
+               maxima_tolerance: 1E-6,
+               vacuum_tolerance: None,
+               cancel: CancellationToken::new(),
+               storage: Storage::Memory,
+               coarse: None }
+    }
+}
+
+impl From<&Args> for Config {
+    /// Takes the settings parsed from the command-line.
+    fn from(args: &Args) -> Self {
+        Self { threads: args.threads,
+               weight_tolerance: args.weight_tolerance,
+               maxima_tolerance: args.maxima_tolerance,
+               vacuum_tolerance: args.vacuum_tolerance,
+               cancel: CancellationToken::new(),
+               storage: args.storage.clone(),
+               coarse: args.coarse }
+    }
+}
+
+/// The results of [`run`]: the analysis along with the grid and partitioned
+/// voxels needed for writing densities of the Bader volumes.
+pub struct Results {
+    /// The charges, volumes and distances of each Bader volume and atom.
+    pub analysis: Analysis,
+    /// The [`Grid`] built from the density and lattice.
+    pub grid: Grid,
+    /// The maxima, or weights, of every voxel.
+    pub voxel_map: VoxelMap,
+}
+
+/// Partitions the densities into Bader volumes, assigns each volume to an
+/// atom and sums the charge of each volume and atom.
+///
+/// With [`Storage::Mapped`] the sorted index and the voxel map are kept in
+/// scratch files, as can the densities by passing them as
+/// [`Buffer`]s. The voxels are then partitioned in bands of similar density,
+/// each swept through in order of position as described in [`partition`], so
+/// the index, reference and voxel map are read forwards through their files.
+/// Whenever the resident memory passes the cap the index and voxel map are
+/// written back and released, the densities are only read and so their pages
+/// are dropped by the kernel as needed.
+///
+/// * `config`: The [`Config`] of the calculation.
+/// * `densities`: The densities to sum, ie. the charge and spin, as any
+///   slice of values such as a [`Vec`] or a [`Buffer`].
+/// * `reference`: The density to partition, if empty the first of
+///   `densities` is used.
+/// * `atoms`: The [`Atoms`] the densities belong to.
+/// * `grid`: The number of voxels along each lattice vector, in the flipped
+///   order of the densities.
+/// * `voxel_origin`: The origin of each voxel.
+/// * `reporter`: The [`Reporter`] of the progress of each stage, use
+///   [`Silent`](crate::progress::Silent) to report nothing.
+///
+/// ### Returns:
+/// `Result<Results, Error>`: The [`Results`] of the calculation, an
+/// [`Error::Input`] if the densities don't fit the grid or contain NaN, an
+/// [`Error::Io`] if a scratch file can't be made, an
+/// [`Error::Analysis`] if the partitioning produced an unknown maxima, or
+/// [`Error::Cancelled`] if `config.cancel` was cancelled before the end.
+///
+/// ### Examples
+/// ```
+/// use bader::atoms::{Atoms, Lattice};
+/// use bader::pipeline::{run, Config};
+/// use bader::progress::Silent;
+///
+/// // a 4x4x4 cubic cell with a peak of density at the only atom
+/// let lattice = Lattice::new([[4., 0., 0.], [0., 4., 0.], [0., 0., 4.]]);
+/// let atoms = Atoms::new(lattice, vec![[0.; 3]], vec![1], String::new());
+/// let density = (0..64usize).map(|p| {
+///                               let d = [p / 16, p / 4 % 4, p % 4];
+///                               let r = d.iter()
+///                                        .map(|&x| x.min(4 - x).pow(2))
+///                                        .sum::<usize>();
+///                               (-(r as f64)).exp()
+///                           })
+///                           .collect::<Vec<f64>>();
+/// let config = Config { threads: 1,
+///                       ..Config::default() };
+/// let results =
+///     run(&config, &[density], &[], &atoms, [4, 4, 4], [0.; 3], &Silent)
+///     .unwrap();
+/// let analysis = results.analysis;
+/// assert_eq!(analysis.bader_maxima, vec![0]);
+/// assert!((analysis.atoms_charge[0][0] - analysis.total_charge[0]).abs()
+///         < 1E-12);
+/// ```
+pub fn run<T, D>(config: &Config,
+                 densities: &[D],
+                 reference: &[T],
+                 atoms: &Atoms,
+                 grid: [usize; 3],
+                 voxel_origin: [f64; 3],
+                 reporter: &dyn Reporter)
+                 -> Result<Results, Error>
+    where T: Real,
+          D: AsRef<[T]> + Sync {
+    let total = grid.iter().try_fold(1usize, |t, g| t.checked_mul(*g));
+    let total = match total {
+        Some(t) if t > 0 && t < isize::MAX as usize - 1 => t,
+        _ => {
+            return Err(Error::Input(format!("Grid size, {:?}, is invalid.",
+                                            grid)))
+        }
+    };
+    if densities.is_empty() {
+        return Err(Error::Input(String::from("No densities to partition.")));
+    }
+    let given_reference = Some(reference).filter(|r| !r.is_empty());
+    for density in densities.iter().map(|d| d.as_ref()).chain(given_reference)
+    {
+        if density.len() != total {
+            return Err(Error::Input(format!("Density has {} values but the \
+                                             grid has {}.",
+                                            density.len(),
+                                            total)));
+        }
+    }
+    let reference = if reference.is_empty() {
+        densities[0].as_ref()
+    } else {
+        reference
+    };
+    if reference.iter().any(|x| x.to_f64().is_nan()) {
+        return Err(Error::Input(String::from("Density contains NaN.")));
+    }
+    let grid = Grid::new(grid,
+                         atoms.lattice.to_cartesian,
+                         config.weight_tolerance,
+                         config.maxima_tolerance,
+                         config.vacuum_tolerance,
+                         voxel_origin);
+    let voxel_map = VoxelMap::with_precision(grid.size.total,
+                                             &config.storage,
+                                             T::PRECISION)?;
+    match config.coarse {
+        Some(factor) => {
+            coarse::partition(config,
+                              factor,
+                              reference,
+                              atoms.lattice.to_cartesian,
+                              &grid,
+                              &voxel_map,
+                              reporter)?;
+        }
+        None => {
+            let index = sorted_index(reference, 0..total, &config.storage)?;
+            partition(config,
+                      &grid,
+                      reference,
+                      &voxel_map,
+                      &index,
+                      "Bader Partitioning",
+                      reporter)?;
+        }
+    }
+    analyse(config, densities, atoms, grid, voxel_map, reporter)
+}
+
+/// Sorts the voxels, `voxels`, from the highest to the lowest value of
+/// `reference`, in the given [`Storage`], ready for [`partition`].
+///
+/// Returns an [`Error::Io`] if a scratch file can't be made. `reference`
+/// must not contain NaN.
+pub fn sorted_index<T, I>(reference: &[T],
+                          voxels: I,
+                          storage: &Storage)
+                          -> Result<Buffer<usize>, Error>
+    where T: Real,
+          I: ExactSizeIterator<Item = usize> {
+    let mut voxels = voxels;
+    let mut index =
+        Buffer::from_fn(voxels.len(), storage, |_| voxels.next().unwrap())?;
+    // sort the reference density from highest to lowest, safe to unwrap
+    // as NaN has been rejected
+    index.sort_unstable_by(|a, b| {
+             reference[*b].partial_cmp(&reference[*a]).unwrap()
+         });
+    Ok(index)
+}
+
+/// The fraction of the grid in each band of a [`sorted_index`] swept through
+/// in order of position when the voxel map is memory-mapped. Thinner bands
+/// put off fewer voxels, as the neighbours of higher density of a voxel are
+/// then mostly in an earlier band, but each sweep touches fewer voxels.
+const BANDS: usize = 1024;
+
+/// Runs the weight method on the voxels of a [`sorted_index`], above the
+/// vacuum tolerance of the `grid`, over the threads of `config`, storing the
+/// result of each in `voxel_map` and reporting the progress as the `stage`.
+/// Every voxel of higher density that neighbours one in `index` must either
+/// be in `index` or already stored.
+///
+/// In memory the voxels are visited in order of density. With
+/// [`Storage::Mapped`] the index is split into bands of similar density, each
+/// a 1024th of the grid, and each band is swept through in order of
+/// position, so the reference and voxel map are read forwards through their
+/// files rather than at random. A voxel whose neighbours of higher density
+/// aren't all stored yet is put off until the end of the sweep, when those
+/// left are visited in order of density.
+///
+/// Returns [`Error::Cancelled`] if `config.cancel` was cancelled before every
+/// voxel was partitioned.
+pub fn partition<T: Real>(config: &Config,
+                          grid: &Grid,
+                          reference: &[T],
+                          voxel_map: &VoxelMap,
+                          index: &Buffer<usize>,
+                          stage: &str,
+                          reporter: &dyn Reporter)
+                          -> Result<(), Error> {
+    let max_resident = config.storage.max_resident();
+    let counter = RelaxedCounter::new(0);
+    let vacuum_index =
+        vacuum_tolerance(reference, index, grid.vacuum_tolerance);
+    let pbar = reporter.stage(stage, vacuum_index);
+    let weigh = |p: usize| {
+        // reading /proc for every voxel would be slow
+        if counter.inc() % 65536 == 0
+           && max_resident.map_or(false, |max| {
+                  resident_memory().map_or(false, |r| r > max)
+              })
+        {
+            // a failed release leaves the pages resident, which is slower
+            // but still correct
+            let _ = index.release();
+            let _ = voxel_map.release();
+        }
+        weight(p, grid, reference, voxel_map);
+        pbar.tick();
+    };
+    match config.storage {
+        Storage::Memory => {
+            in_order(config, vacuum_index, |i| index[i], &weigh);
+        }
+        Storage::Mapped { .. } => {
+            index.advise_sequential(true);
+            voxel_map.advise_sequential(true);
+            let size = (grid.size.total / BANDS).max(4096);
+            for start in (0..vacuum_index).step_by(size) {
+                if config.cancel.is_cancelled() {
+                    break;
+                }
+                let end = (start + size).min(vacuum_index);
+                let mut band = (start..end).collect::<Vec<usize>>();
+                band.sort_unstable_by_key(|i| index[*i]);
+                let mut deferred = sweep(config, &band, |i| {
+                    let p = index[i];
+                    let ready = is_ready(p, grid, reference, voxel_map);
+                    if ready {
+                        weigh(p);
+                    }
+                    ready
+                });
+                drop(band);
+                // the densest voxel put off is ready, and so on down
+                deferred.sort_unstable();
+                in_order(config,
+                         deferred.len(),
+                         |i| index[deferred[i]],
+                         &weigh);
+            }
+        }
+    }
+    if counter.get() < vacuum_index {
+        return Err(Error::Cancelled);
+    }
+    Ok(())
+}
+
+/// Passes the `len` voxels given by `voxel` to `weigh`, in order, over the
+/// threads of `config`. Each voxel waits on its neighbours of higher density
+/// so these must come before it.
+fn in_order<F, W>(config: &Config, len: usize, voxel: F, weigh: &W)
+    where F: Fn(usize) -> usize + Sync,
+          W: Fn(usize) + Sync {
+    let counter = RelaxedCounter::new(0);
+    thread::scope(|s| {
+        for _ in 0..config.threads.max(1) {
+            s.spawn(|_| loop {
+                 // only stop between voxels so that no thread is left
+                 // waiting on one that won't be finished
+                 if config.cancel.is_cancelled() {
+                     break;
+                 }
+                 let i = counter.inc();
+                 if i >= len {
+                     break;
+                 };
+                 weigh(voxel(i));
+             });
+        }
+    }).unwrap();
+}
+
+/// Passes each of `band` to `try_weigh`, over the threads of `config`, and
+/// returns those it put off.
+fn sweep<F>(config: &Config, band: &[usize], try_weigh: F) -> Vec<usize>
+    where F: Fn(usize) -> bool + Sync {
+    let counter = RelaxedCounter::new(0);
+    thread::scope(|s| {
+        let handles = (0..config.threads.max(1))
+            .map(|_| {
+                s.spawn(|_| {
+                     let mut deferred = Vec::new();
+                     while !config.cancel.is_cancelled() {
+                         let i = counter.inc();
+                         if i >= band.len() {
+                             break;
+                         }
+                         if !try_weigh(band[i]) {
+                             deferred.push(band[i]);
+                         }
+                     }
+                     deferred
+                 })
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter()
+               .flat_map(|handle| handle.join().unwrap())
+               .collect()
+    }).unwrap()
+}
+
+/// Whether every neighbour of voxel `p` of higher density has been stored in
+/// `voxel_map`, so that weighting `p` won't wait.
+fn is_ready<T: Real>(p: usize,
+                     grid: &Grid,
+                     reference: &[T],
+                     voxel_map: &VoxelMap)
+                     -> bool {
+    let control = reference[p].to_f64();
+    grid.voronoi.vectors.iter().all(|shift| {
+        let pt = grid.voronoi_shift(p as isize, shift);
+        reference[pt as usize].to_f64() <= control
+        || voxel_map.maxima_non_block_get(pt) != -1
+    })
+}
+
+/// Assigns the Bader volumes of an already partitioned [`VoxelMap`], such as
+/// one restored from a [checkpoint](crate::io::checkpoint), to atoms and sums
+/// the charge of each volume and atom. This is the second half of [`run`].
+///
+/// * `config`: The [`Config`] of the calculation, only its token is used.
+/// * `densities`: The densities to sum, ie. the charge and spin.
+/// * `atoms`: The [`Atoms`] the densities belong to.
+/// * `grid`: The [`Grid`] the density was partitioned on.
+/// * `voxel_map`: The fully partitioned [`VoxelMap`].
+/// * `reporter`: The [`Reporter`] of the progress of each stage.
+///
+/// ### Returns:
+/// `Result<Results, Error>`: The [`Results`] of the calculation, an
+/// [`Error::Input`] if the densities don't fit the grid, an
+/// [`Error::Analysis`] if the map holds an unknown maxima, or
+/// [`Error::Cancelled`] if `config.cancel` was cancelled before the end.
+pub fn analyse<T, D>(config: &Config,
+                     densities: &[D],
+                     atoms: &Atoms,
+                     grid: Grid,
+                     voxel_map: VoxelMap,
+                     reporter: &dyn Reporter)
+                     -> Result<Results, Error>
+    where T: Real,
+          D: AsRef<[T]> {
+    if let Some(density) =
+        densities.iter().find(|d| d.as_ref().len() != grid.size.total)
+    {
+        return Err(Error::Input(format!("Density has {} values but the \
+                                         grid has {}.",
+                                        density.as_ref().len(),
+                                        grid.size.total)));
+    }
+    let mut analysis =
+        Analysis::new(&voxel_map, densities.len(), atoms.positions.len());
+    // find the nearest atom to each Bader maxima
+    let pbar =
+        reporter.stage("Assigning to Atoms", analysis.bader_maxima.len());
+    analysis.assign_atoms(atoms, &grid, pbar);
+    // sum the charge in each volume and then each atom
+    let pbar = reporter.stage("Summing Charge", grid.size.total);
+    analysis.charge_sum(atoms,
+                        densities,
+                        &grid,
+                        &voxel_map,
+                        &config.cancel,
+                        pbar)?;
+    analysis.atoms_charge_sum();
+    Ok(Results { analysis,
+                 grid,
+                 voxel_map })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atoms::Lattice;
+    use crate::progress::{Progress, Silent};
+    use std::time::Instant;
+
+    fn atoms() -> Atoms {
+        let lattice =
+            Lattice::new([[2., 0., 0.], [0., 2., 0.], [0., 0., 2.]]);
+        Atoms::new(lattice, vec![[0.; 3]], vec![1], String::new())
+    }
+
+    #[test]
+    fn pipeline_run_wrong_length() {
+        let config = Config { threads: 1,
+                              ..Config::default() };
+        let result = run(&config,
+                         &[vec![1.; 7]],
+                         &[],
+                         &atoms(),
+                         [2, 2, 2],
+                         [0.; 3],
+                         &Silent);
+        let flag = matches!(result, Err(Error::Input(_)));
+        assert!(flag)
+    }
+
+    #[test]
+    fn pipeline_run_nan() {
+        let config = Config { threads: 1,
+                              ..Config::default() };
+        let mut density = vec![1.; 8];
+        density[3] = f64::NAN;
+        let result = run(&config,
+                         &[density],
+                         &[],
+                         &atoms(),
+                         [2, 2, 2],
+                         [0.; 3],
+                         &Silent);
+        let flag = matches!(result, Err(Error::Input(_)));
+        assert!(flag)
+    }
+
+    #[test]
+    fn pipeline_run_cancelled() {
+        let config = Config { threads: 1,
+                              ..Config::default() };
+        config.cancel.cancel();
+        let result = run(&config,
+                         &[vec![1.; 8]],
+                         &[],
+                         &atoms(),
+                         [2, 2, 2],
+                         [0.; 3],
+                         &Silent);
+        let flag = matches!(result, Err(Error::Cancelled));
+        assert!(flag)
+    }
+
+    #[test]
+    fn pipeline_run_deadline() {
+        let cancel = CancellationToken::with_deadline(Instant::now());
+        let config = Config { threads: 1,
+                              cancel,
+                              ..Config::default() };
+        let result = run(&config,
+                         &[vec![1.; 8]],
+                         &[],
+                         &atoms(),
+                         [2, 2, 2],
+                         [0.; 3],
+                         &Silent);
+        let flag = matches!(result, Err(Error::Cancelled));
+        assert!(flag)
+    }
+
+    /// Cancels the token once the partitioning has started.
+    struct CancelOnTick(CancellationToken);
+
+    impl Progress for CancelOnTick {
+        fn set_length(&self, _length: usize) {}
+
+        fn tick(&self) {
+            self.0.cancel()
+        }
+    }
+
+    impl Reporter for CancelOnTick {
+        fn stage(&self, _stage: &str, _length: usize) -> Box<dyn Progress> {
+            Box::new(CancelOnTick(self.0.clone()))
+        }
+
+        fn message(&self, _text: &str) {}
+    }
+
+    #[test]
+    fn pipeline_run_cancelled_while_running() {
+        let config = Config { threads: 4,
+                              ..Config::default() };
+        let reporter = CancelOnTick(config.cancel.clone());
+        let density = (0..4096).map(|p| ((p * 7919) % 4096) as f64)
+                               .collect::<Vec<f64>>();
+        let result = run(&config,
+                         &[density],
+                         &[],
+                         &atoms(),
+                         [16, 16, 16],
+                         [0.; 3],
+                         &reporter);
+        let flag = matches!(result, Err(Error::Cancelled));
+        assert!(flag)
+    }
+
+    /// Two gaussians of different heights on a 24x24x24 grid.
+    fn two_gaussians() -> (Atoms, Vec<f64>) {
+        let lattice =
+            Lattice::new([[6., 0., 0.], [0., 6., 0.], [0., 0., 6.]]);
+        let atoms = Atoms::new(lattice,
+                               vec![[1.5, 1.5, 1.5], [4., 3.5, 4.5]],
+                               vec![1, 8],
+                               String::new());
+        let density =
+            (0..13824usize).map(|p| {
+                               let r = [(p / 576) as f64 / 4.,
+                                        (p / 24 % 24) as f64 / 4.,
+                                        (p % 24) as f64 / 4.];
+                               atoms.positions
+                                    .iter()
+                                    .zip(&[1., 3.])
+                                    .map(|(a, h)| {
+                                        let d2 = r.iter()
+                                                  .zip(a)
+                                                  .map(|(x, y)| {
+                                                      let d = (x - y).abs();
+                                                      d.min(6. - d).powi(2)
+                                                  })
+                                                  .sum::<f64>();
+                                        h * (-d2).exp()
+                                    })
+                                    .sum::<f64>()
+                           })
+                           .collect::<Vec<f64>>();
+        (atoms, density)
+    }
+
+    #[test]
+    fn pipeline_run_single() {
+        let (atoms, density) = two_gaussians();
+        let config = Config { threads: 2,
+                              ..Config::default() };
+        let single = vec![density.iter().map(|x| *x as f32).collect()];
+        let double = run(&config,
+                         &[density],
+                         &[],
+                         &atoms,
+                         [24, 24, 24],
+                         [0.; 3],
+                         &Silent).unwrap();
+        let single = run::<f32, Vec<f32>>(&config,
+                                          &single,
+                                          &[],
+                                          &atoms,
+                                          [24, 24, 24],
+                                          [0.; 3],
+                                          &Silent).unwrap();
+        let total = double.analysis.total_charge[0];
+        for (d, s) in double.analysis.atoms_charge[0]
+                            .iter()
+                            .zip(&single.analysis.atoms_charge[0])
+        {
+            assert!((d - s).abs() / total < 1E-7);
+        }
+    }
+
+    #[test]
+    fn pipeline_run_mapped() {
+        let (atoms, density) = two_gaussians();
+        let directory = std::env::temp_dir().join("bca-pipeline-test");
+        std::fs::create_dir_all(&directory).unwrap();
+        // a cap of zero releases the mapped pages at every check
+        let storage = Storage::Mapped { directory: directory.clone(),
+                                        max_resident: Some(0) };
+        let memory = Config { threads: 2,
+                              ..Config::default() };
+        let mapped = Config { storage: storage.clone(),
+                              ..memory.clone() };
+        let buffer = Buffer::from_vec(density.clone(), &storage).unwrap();
+        let memory = run(&memory,
+                         &[density],
+                         &[],
+                         &atoms,
+                         [24, 24, 24],
+                         [0.; 3],
+                         &Silent).unwrap();
+        let mapped = run(&mapped,
+                         &[buffer],
+                         &[],
+                         &atoms,
+                         [24, 24, 24],
+                         [0.; 3],
+                         &Silent).unwrap();
+        assert_eq!(memory.analysis.atoms_charge,
+                   mapped.analysis.atoms_charge);
+        assert_eq!(memory.analysis.bader_maxima,
+                   mapped.analysis.bader_maxima);
+        drop(mapped);
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+        std::fs::remove_dir(&directory).unwrap();
+    }
+
+    #[test]
+    fn pipeline_run_coarse() {
+        let (atoms, density) = two_gaussians();
+        let full = Config { threads: 2,
+                            ..Config::default() };
+        let coarse = Config { coarse: Some(2),
+                              ..full.clone() };
+        let full = run(&full,
+                       std::slice::from_ref(&density),
+                       &[],
+                       &atoms,
+                       [24, 24, 24],
+                       [0.; 3],
+                       &Silent).unwrap();
+        let coarse = run(&coarse,
+                         &[density],
+                         &[],
+                         &atoms,
+                         [24, 24, 24],
+                         [0.; 3],
+                         &Silent).unwrap();
+        let total = full.analysis.total_charge[0];
+        for (f, c) in full.analysis.atoms_charge[0]
+                          .iter()
+                          .zip(&coarse.analysis.atoms_charge[0])
+        {
+            assert!((f - c).abs() / total < 1E-12);
+        }
+        assert_eq!(full.analysis.bader_maxima, coarse.analysis.bader_maxima);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::arguments::Precision;
+use crate::storage::{Buffer, Storage};
+
+/// The type a density is stored as: f64 or, to halve its memory, f32.
+///
+/// Every value is converted to f64 before any arithmetic, so gradients,
+/// weights and the summed charges are always calculated in double precision
+/// and only the rounding of the stored density, and of the stored weights of
+/// the boundary voxels, differs between the two.
+pub trait Real: Copy + PartialOrd + Send + Sync + 'static {
+    /// The precision the weights of the boundary voxels are kept in.
+    const PRECISION: Precision;
+
+    /// Rounds a double to the stored type.
+    fn from_f64(x: f64) -> Self;
+
+    /// Widens the stored value to a double.
+    fn to_f64(self) -> f64;
+}
+
+impl Real for f64 {
+    const PRECISION: Precision = Precision::Double;
+
+    fn from_f64(x: f64) -> Self {
+        x
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+impl Real for f32 {
+    const PRECISION: Precision = Precision::Single;
+
+    fn from_f64(x: f64) -> Self {
+        x as f32
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+/// Converts densities to single precision one at a time, into the same
+/// storage, freeing each double precision density as it goes so that the peak
+/// memory is only one density above the total.
+///
+/// ### Examples
+/// ```
+/// use bader::precision::single;
+/// use bader::storage::{Buffer, Storage};
+///
+/// let densities = vec![Buffer::from_vec(vec![0.1, 1E-50], &Storage::Memory),
+///                      Buffer::from_vec(vec![2.], &Storage::Memory)];
+/// let densities = densities.into_iter()
+///                          .collect::<std::io::Result<Vec<_>>>()
+///                          .unwrap();
+/// let densities = single(densities, &Storage::Memory).unwrap();
+/// assert_eq!(&densities[0][..], &[0.1f32, 0.]);
+/// assert_eq!(&densities[1][..], &[2f32]);
+/// ```
+pub fn single(densities: Vec<Buffer<f64>>,
+              storage: &Storage)
+              -> std::io::Result<Vec<Buffer<f32>>> {
+    densities.into_iter()
+             .map(|density| {
+                 Buffer::from_fn(density.len(), storage, |i| {
+                     f32::from_f64(density[i])
+                 })
+             })
+             .collect()
+}
@@ -1,13 +1,42 @@
+use crate::io::json;
 use atomic_counter::{AtomicCounter, RelaxedCounter};
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
-use std::sync::Arc;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// The progress of a single stage of the calculation, such as partitioning or
+/// writing a file. Ticked from many threads at once so should be cheap.
+pub trait Progress: Send + Sync {
+    /// Sets how many steps the stage has.
+    fn set_length(&self, length: usize);
+
+    /// Marks a step of the stage as done.
+    fn tick(&self);
+}
+
+/// Receives the events of a calculation: the start of each stage, whose
+/// [`Progress`] is then ticked, and messages such as which file is being
+/// read. Library functions take one of these rather than printing.
+pub trait Reporter: Send + Sync {
+    /// Starts a stage, returning the [`Progress`] to tick.
+    ///
+    /// * `stage`: The name of the stage.
+    /// * `length`: How many steps the stage has.
+    fn stage(&self, stage: &str, length: usize) -> Box<dyn Progress>;
+
+    /// Reports a message.
+    fn message(&self, text: &str);
+}
 
 /// Contains the indicatif progress bar and an atomic_counter RelaxedCounter.
 /// The counter is used to update the bar for use with a rayon iterator
 pub struct Bar {
     counter: Arc<RelaxedCounter>,
+    /// How often, in milliseconds, to redraw a displayed bar.
+    refresh_rate: u64,
     /// An Arc wrapped ProgressBar from indicatif.
     pub pbar: Arc<ProgressBar>,
 }
@@ -27,21 +56,24 @@ impl Bar {
                 .progress_chars("=>-"),
         );
         let pb = Arc::new(progress_bar);
-        let pb2 = pb.clone();
         let counter = Arc::new(RelaxedCounter::new(0));
-        let counter2 = counter.clone();
+        Self { counter,
+               refresh_rate,
+               pbar: pb }
+    }
+
+    /// Displays a hidden Bar, starting the thread that redraws it.
+    pub fn display(&self) {
+        self.pbar.set_draw_target(ProgressDrawTarget::stderr());
+        let pb = self.pbar.clone();
+        let counter = self.counter.clone();
+        let refresh_rate = self.refresh_rate;
         thread::spawn(move || {
-            while Arc::strong_count(&counter2) > 1 && !pb2.is_finished() {
-                pb2.set_position(counter2.get() as u64);
+            while Arc::strong_count(&counter) > 1 && !pb.is_finished() {
+                pb.set_position(counter.get() as u64);
                 thread::sleep(Duration::from_millis(refresh_rate));
             }
         });
-        Self { counter, pbar: pb }
-    }
-
-    /// Displays a hidden Bar.
-    pub fn display(&self) {
-        self.pbar.set_draw_target(ProgressDrawTarget::stderr())
     }
 
     /// Creates a Bar and sets it to be displayed.
@@ -62,6 +94,16 @@ impl Bar {
     }
 }
 
+impl Progress for Bar {
+    fn set_length(&self, length: usize) {
+        Bar::set_length(self, length)
+    }
+
+    fn tick(&self) {
+        Bar::tick(self)
+    }
+}
+
 impl Drop for Bar {
     /// make sure we clear bars when the object is dropped
     fn drop(&mut self) {
@@ -73,6 +115,143 @@ impl Drop for Bar {
     }
 }
 
+/// Draws each stage as an indicatif [`Bar`] on stderr and prints messages to
+/// stdout.
+pub struct Indicatif;
+
+impl Reporter for Indicatif {
+    fn stage(&self, stage: &str, length: usize) -> Box<dyn Progress> {
+        Box::new(Bar::visible(length as u64, 100, format!("{}: ", stage)))
+    }
+
+    fn message(&self, text: &str) {
+        println!("{}", text);
+    }
+}
+
+/// Reports nothing, without spawning any threads.
+pub struct Silent;
+
+impl Progress for Silent {
+    fn set_length(&self, _length: usize) {}
+
+    fn tick(&self) {}
+}
+
+impl Reporter for Silent {
+    fn stage(&self, _stage: &str, _length: usize) -> Box<dyn Progress> {
+        Box::new(Silent)
+    }
+
+    fn message(&self, _text: &str) {}
+}
+
+/// Writes every event as a line of JSON, with the seconds elapsed since the
+/// reporter was created. Each stage writes its fraction done when it starts,
+/// at every tenth of its length and when it ends:
+/// ```text
+/// {"stage": "Bader Partitioning", "fraction": 0.1, "elapsed": 0.52}
+/// {"message": "Reading CHGCAR as VASP format:", "elapsed": 0.0}
+/// ```
+#[derive(Clone)]
+pub struct JsonLines {
+    /// Where to write the events.
+    sink: Arc<Mutex<Box<dyn Write + Send>>>,
+    /// When the reporter was created.
+    start: Instant,
+}
+
+impl JsonLines {
+    /// Creates the reporter, writing the events to `sink`.
+    ///
+    /// ### Examples
+    /// ```
+    /// use bader::progress::{JsonLines, Reporter};
+    ///
+    /// let reporter = JsonLines::new(Box::new(std::io::stderr()));
+    /// let pbar = reporter.stage("Summing Charge", 20);
+    /// (0..20).for_each(|_| pbar.tick());
+    /// ```
+    pub fn new(sink: Box<dyn Write + Send>) -> Self {
+        Self { sink: Arc::new(Mutex::new(sink)),
+               start: Instant::now() }
+    }
+
+    /// Writes a single event, ignoring failures as they shouldn't stop the
+    /// calculation.
+    fn event(&self, fields: &str) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if let Ok(mut sink) = self.sink.lock() {
+            let _ = writeln!(sink,
+                             "{{{}, \"elapsed\": {}}}",
+                             fields,
+                             json::number(elapsed));
+            let _ = sink.flush();
+        }
+    }
+}
+
+impl Reporter for JsonLines {
+    fn stage(&self, stage: &str, length: usize) -> Box<dyn Progress> {
+        let stage = JsonStage { reporter: self.clone(),
+                                stage: json::string(stage),
+                                length: AtomicUsize::new(length),
+                                counter: RelaxedCounter::new(0) };
+        stage.event(0);
+        Box::new(stage)
+    }
+
+    fn message(&self, text: &str) {
+        self.event(&format!("\"message\": {}", json::string(text)));
+    }
+}
+
+/// The [`Progress`] of a stage reported by [`JsonLines`].
+struct JsonStage {
+    reporter: JsonLines,
+    /// The name of the stage, already escaped.
+    stage: String,
+    length: AtomicUsize,
+    counter: RelaxedCounter,
+}
+
+impl JsonStage {
+    /// Writes the fraction of the stage done after `position` steps.
+    fn event(&self, position: usize) {
+        let length = self.length.load(Ordering::Relaxed);
+        // rounded as the tenths rarely divide the length exactly
+        let fraction = if length == 0 {
+            1.
+        } else {
+            (position as f64 * 1E3 / length as f64).round().min(1E3) / 1E3
+        };
+        self.reporter.event(&format!("\"stage\": {}, \"fraction\": {}",
+                                     self.stage,
+                                     json::number(fraction)));
+    }
+}
+
+impl Progress for JsonStage {
+    fn set_length(&self, length: usize) {
+        self.length.store(length, Ordering::Relaxed);
+    }
+
+    fn tick(&self) {
+        let position = self.counter.inc() + 1;
+        let step = self.length.load(Ordering::Relaxed) / 10;
+        if step > 0 && position % step == 0 && position < step * 10 {
+            self.event(position);
+        }
+    }
+}
+
+impl Drop for JsonStage {
+    /// report the end of the stage
+    fn drop(&mut self) {
+        self.event(self.counter.get());
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -89,4 +268,41 @@ mod tests {
         bar.tick();
         assert_eq!(bar.counter.get(), 1)
     }
+
+    /// A sink that can be read after the reporter has written to it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn progress_json_lines() {
+        let sink = Shared::default();
+        let reporter = JsonLines::new(Box::new(sink.clone()));
+        reporter.message("Reading \"CHGCAR\":");
+        {
+            let pbar = reporter.stage("Summing Charge", 20);
+            (0..20).for_each(|_| pbar.tick());
+        }
+        let text = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        let lines = text.lines()
+                        .map(|l| &l[..l.find(", \"elapsed\"").unwrap()])
+                        .collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 12);
+        assert_eq!(lines[0], "{\"message\": \"Reading \\\"CHGCAR\\\":\"");
+        assert_eq!(lines[1],
+                   "{\"stage\": \"Summing Charge\", \"fraction\": 0.0");
+        assert_eq!(lines[2],
+                   "{\"stage\": \"Summing Charge\", \"fraction\": 0.1");
+        assert_eq!(lines[11],
+                   "{\"stage\": \"Summing Charge\", \"fraction\": 1.0");
+    }
 }
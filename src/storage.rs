@@ -0,0 +1,301 @@
+use memmap2::MmapMut;
+use std::fs::{File, OpenOptions};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+
+/// Where the large arrays of a calculation, the densities, the sorted index
+/// and the voxel map, are kept.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Storage {
+    /// On the heap.
+    Memory,
+    /// Memory-mapped to files in a scratch directory, so that the kernel can
+    /// page them out when the grid is larger than the memory available.
+    Mapped {
+        /// The directory to create the files in, they are deleted when the
+        /// arrays are dropped.
+        directory: PathBuf,
+        /// The resident memory, in bytes, above which the mapped pages are
+        /// written back and released, if any.
+        max_resident: Option<u64>,
+    },
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self::Memory
+    }
+}
+
+impl Storage {
+    /// The cap on the resident memory, if any.
+    pub fn max_resident(&self) -> Option<u64> {
+        match self {
+            Self::Memory => None,
+            Self::Mapped { max_resident, .. } => *max_resident,
+        }
+    }
+}
+
+/// Types that are safe to store as their raw bytes in a file: plain values
+/// without padding or pointers.
+///
+/// # Safety
+/// Any bit pattern written by the type must be a valid value of it.
+pub unsafe trait Plain: Sized + Send + Sync {}
+
+unsafe impl Plain for f32 {}
+unsafe impl Plain for f64 {}
+unsafe impl Plain for usize {}
+unsafe impl Plain for isize {}
+unsafe impl Plain for AtomicIsize {}
+
+/// A fixed length array held either on the heap or in a memory-mapped
+/// scratch file, depending on its [`Storage`].
+///
+/// # Examples
+/// ```
+/// use bader::storage::{Buffer, Storage};
+///
+/// let storage = Storage::Mapped { directory: std::env::temp_dir(),
+///                                 max_resident: None };
+/// let mut buffer = Buffer::from_vec(vec![3usize, 1, 2], &storage).unwrap();
+/// buffer.sort_unstable();
+/// assert_eq!(&buffer[..], &[1, 2, 3]);
+/// ```
+pub struct Buffer<T: Plain> {
+    data: Data<T>,
+}
+
+/// The backing of a [`Buffer`].
+enum Data<T> {
+    Memory(Vec<T>),
+    Mapped {
+        map: MmapMut,
+        len: usize,
+        path: PathBuf,
+    },
+}
+
+/// Counts the scratch files created so that each has a unique name.
+static SCRATCH_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates a scratch file of `bytes` length in `directory`.
+fn scratch_file(directory: &Path,
+                bytes: usize)
+                -> std::io::Result<(File, PathBuf)> {
+    let i = SCRATCH_FILES.fetch_add(1, Ordering::Relaxed);
+    let path =
+        directory.join(format!("bca-{}-{}.scratch", std::process::id(), i));
+    let file = OpenOptions::new().read(true)
+                                 .write(true)
+                                 .create_new(true)
+                                 .open(&path)
+                                 .map_err(|e| {
+                                     std::io::Error::new(e.kind(),
+                                                         format!("{}: {}",
+                                                                 path.display(),
+                                                                 e))
+                                 })?;
+    file.set_len(bytes as u64)?;
+    Ok((file, path))
+}
+
+impl<T: Plain> Buffer<T> {
+    /// Maps a zeroed scratch file of `len` values in `directory`.
+    fn mapped(len: usize, directory: &Path) -> std::io::Result<Self> {
+        let bytes = len * std::mem::size_of::<T>();
+        let (file, path) = scratch_file(directory, bytes)?;
+        // mapping an empty file fails so always map at least one byte
+        if bytes == 0 {
+            file.set_len(1)?;
+        }
+        match unsafe { MmapMut::map_mut(&file) } {
+            Ok(map) => Ok(Self { data: Data::Mapped { map, len, path } }),
+            Err(e) => {
+                let _ = std::fs::remove_file(&path);
+                Err(e)
+            }
+        }
+    }
+
+    /// Creates a buffer of `len` values, each made by `f` from its index in
+    /// order.
+    pub fn from_fn<F>(len: usize,
+                      storage: &Storage,
+                      mut f: F)
+                      -> std::io::Result<Self>
+        where F: FnMut(usize) -> T {
+        let mut buffer = match storage {
+            Storage::Memory => {
+                let data = Data::Memory((0..len).map(f).collect());
+                return Ok(Self { data });
+            }
+            Storage::Mapped { directory, .. } => Self::mapped(len, directory)?,
+        };
+        // the file is zeroed so write each value in place
+        for (i, v) in buffer.iter_mut().enumerate() {
+            unsafe { std::ptr::write(v, f(i)) };
+        }
+        Ok(buffer)
+    }
+
+    /// Creates a buffer of `len` zeros, the default of every [`Plain`] type,
+    /// for filling in place. A scratch file is already zero so none of its
+    /// pages are touched until they are written.
+    pub fn zeroed(len: usize, storage: &Storage) -> std::io::Result<Self>
+        where T: Copy + Default {
+        match storage {
+            Storage::Memory => {
+                Ok(Self { data: Data::Memory(vec![T::default(); len]) })
+            }
+            Storage::Mapped { directory, .. } => Self::mapped(len, directory),
+        }
+    }
+
+    /// Moves a vector into a buffer, freeing the vector once it is copied to
+    /// a scratch file.
+    pub fn from_vec(vec: Vec<T>, storage: &Storage) -> std::io::Result<Self>
+        where T: Copy {
+        match storage {
+            Storage::Memory => Ok(Self { data: Data::Memory(vec) }),
+            Storage::Mapped { .. } => {
+                Self::from_fn(vec.len(), storage, |i| vec[i])
+            }
+        }
+    }
+
+    /// Writes back any modified pages of a mapped buffer and drops them from
+    /// the resident memory, they are read back from the file when next used.
+    /// Does nothing for a buffer on the heap.
+    pub fn release(&self) -> std::io::Result<()> {
+        if let Data::Mapped { map, .. } = &self.data {
+            map.flush()?;
+            #[cfg(unix)]
+            map.advise(memmap2::Advice::DontNeed)?;
+        }
+        Ok(())
+    }
+
+    /// Tells the kernel that the buffer is read in order, so that it reads
+    /// ahead, or at random, so that it doesn't. Does nothing for a buffer on
+    /// the heap.
+    pub fn advise_sequential(&self, sequential: bool) {
+        #[cfg(unix)]
+        {
+            if let Data::Mapped { map, .. } = &self.data {
+                let advice = if sequential {
+                    memmap2::Advice::Sequential
+                } else {
+                    memmap2::Advice::Random
+                };
+                // only a hint so failing doesn't matter
+                let _ = map.advise(advice);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = sequential;
+    }
+}
+
+impl<T: Plain> Deref for Buffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match &self.data {
+            Data::Memory(vec) => vec,
+            Data::Mapped { map, len, .. } => unsafe {
+                std::slice::from_raw_parts(map.as_ptr() as *const T, *len)
+            },
+        }
+    }
+}
+
+impl<T: Plain> DerefMut for Buffer<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match &mut self.data {
+            Data::Memory(vec) => vec,
+            Data::Mapped { map, len, .. } => unsafe {
+                std::slice::from_raw_parts_mut(map.as_mut_ptr() as *mut T, *len)
+            },
+        }
+    }
+}
+
+impl<T: Plain> AsRef<[T]> for Buffer<T> {
+    fn as_ref(&self) -> &[T] {
+        self
+    }
+}
+
+impl<'a, T: Plain> IntoIterator for &'a Buffer<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T: Plain> Drop for Buffer<T> {
+    /// Removes the scratch file once the map is dropped.
+    fn drop(&mut self) {
+        let data = std::mem::replace(&mut self.data, Data::Memory(Vec::new()));
+        if let Data::Mapped { map, path, .. } = data {
+            drop(map);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// The resident memory of the process in bytes, where the operating system
+/// reports it in /proc (Linux).
+pub fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kilobytes = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn storage_mapped_removes_file() {
+        let directory = std::env::temp_dir().join("bca-storage-test");
+        std::fs::create_dir_all(&directory).unwrap();
+        let storage = Storage::Mapped { directory: directory.clone(),
+                                        max_resident: Some(0) };
+        {
+            let buffer = Buffer::from_fn(1000, &storage, |i| i as f64).unwrap();
+            assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+            buffer.release().unwrap();
+            assert_eq!(buffer[999], 999.);
+        }
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+        std::fs::remove_dir(&directory).unwrap();
+    }
+
+    #[test]
+    fn storage_mapped_zeroed() {
+        let directory = std::env::temp_dir().join("bca-storage-zeroed");
+        std::fs::create_dir_all(&directory).unwrap();
+        let storage = Storage::Mapped { directory: directory.clone(),
+                                        max_resident: None };
+        {
+            let mut buffer = Buffer::<f64>::zeroed(1000, &storage).unwrap();
+            assert!(buffer.iter().all(|x| *x == 0.));
+            buffer[999] = 1.;
+            assert_eq!(buffer.iter().sum::<f64>(), 1.);
+        }
+        std::fs::remove_dir(&directory).unwrap();
+    }
+
+    #[test]
+    fn storage_memory() {
+        let buffer = Buffer::from_vec(vec![-1isize; 4], &Storage::Memory);
+        assert_eq!(&buffer.unwrap()[..], &[-1; 4]);
+    }
+}
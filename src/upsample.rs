@@ -0,0 +1,202 @@
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+/// How the density is interpolated between the voxels of the original grid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    /// Zero-padding the Fourier transform, exact for a band-limited density.
+    Fourier,
+    /// A periodic Catmull-Rom cubic along each lattice vector, which only
+    /// reaches two voxels either side so can't ring across the cell.
+    Tricubic,
+}
+
+/// Interpolates every line of `density` that runs along `axis`, in the
+/// flipped order of the grid, onto `factor` times as many voxels with
+/// `line`, returning the density and its new grid.
+fn along_axis<F>(density: &[f64],
+                 grid: [usize; 3],
+                 axis: usize,
+                 factor: usize,
+                 mut line: F)
+                 -> (Vec<f64>, [usize; 3])
+    where F: FnMut(&[f64], &mut [f64]) {
+    let n = grid[axis];
+    let m = n * factor;
+    let outer = grid[..axis].iter().product::<usize>();
+    let inner = grid[axis + 1..].iter().product::<usize>();
+    let mut fine = grid;
+    fine[axis] = m;
+    let mut upsampled = vec![0f64; outer * m * inner];
+    let mut values = vec![0f64; n];
+    let mut interpolated = vec![0f64; m];
+    for o in 0..outer {
+        for k in 0..inner {
+            for (i, v) in values.iter_mut().enumerate() {
+                *v = density[(o * n + i) * inner + k];
+            }
+            line(&values, &mut interpolated);
+            for (i, v) in interpolated.iter().enumerate() {
+                upsampled[(o * m + i) * inner + k] = *v;
+            }
+        }
+    }
+    (upsampled, fine)
+}
+
+/// Upsamples `density`, in the flipped order of the grid, onto a grid
+/// `factor` times finer along each lattice vector of the periodic cell,
+/// returning the density and its grid.
+///
+/// Every `factor`th voxel of the finer grid sits on, and keeps the value of,
+/// a voxel of the original grid, so the voxel origin scales with the grid.
+/// Both interpolations keep the mean of the density and so the charge, the
+/// sum of the density times the volume of a voxel, is conserved to rounding.
+///
+/// # Examples
+/// ```
+/// use bader::upsample::{upsample, Interpolation};
+///
+/// let density = (0..8).map(|p| p as f64).collect::<Vec<f64>>();
+/// let (fine, grid) =
+///     upsample(&density, [2, 2, 2], 2, Interpolation::Tricubic);
+/// assert_eq!(grid, [4, 4, 4]);
+/// assert_eq!(fine[((2 * 4 + 2) * 4) + 2], 7.);
+/// assert!((fine.iter().sum::<f64>() / 64. - 3.5).abs() < 1E-12);
+/// ```
+pub fn upsample(density: &[f64],
+                grid: [usize; 3],
+                factor: usize,
+                interpolation: Interpolation)
+                -> (Vec<f64>, [usize; 3]) {
+    let mut upsampled = (density.to_vec(), grid);
+    for axis in 0..3 {
+        let (density, grid) = upsampled;
+        upsampled = match interpolation {
+            Interpolation::Fourier => {
+                let n = grid[axis];
+                let m = n * factor;
+                let mut planner = FftPlanner::<f64>::new();
+                let forward = planner.plan_fft_forward(n);
+                let inverse = planner.plan_fft_inverse(m);
+                let mut spectrum = vec![Complex::new(0., 0.); n];
+                let mut padded = vec![Complex::new(0., 0.); m];
+                along_axis(&density, grid, axis, factor, |values, fine| {
+                    for (s, v) in spectrum.iter_mut().zip(values) {
+                        *s = Complex::new(*v, 0.);
+                    }
+                    forward.process(&mut spectrum);
+                    pad(&spectrum, &mut padded);
+                    inverse.process(&mut padded);
+                    // rustfft doesn't normalise so divide by the forward size
+                    for (f, p) in fine.iter_mut().zip(&padded) {
+                        *f = p.re / n as f64;
+                    }
+                })
+            }
+            Interpolation::Tricubic => {
+                along_axis(&density, grid, axis, factor, |values, fine| {
+                    cubic(values, factor, fine)
+                })
+            }
+        };
+    }
+    upsampled
+}
+
+/// Copies the frequencies of `spectrum` into the larger `padded`, leaving the
+/// new frequencies zero. The Nyquist frequency of an even length is split
+/// between its positive and negative frequency to keep the result real.
+fn pad(spectrum: &[Complex<f64>], padded: &mut [Complex<f64>]) {
+    let n = spectrum.len();
+    let m = padded.len();
+    for p in padded.iter_mut() {
+        *p = Complex::new(0., 0.);
+    }
+    for (k, s) in spectrum.iter().enumerate() {
+        if 2 * k < n {
+            padded[k] = *s;
+        } else if 2 * k > n {
+            padded[m - n + k] = *s;
+        } else {
+            padded[k] = s / 2.;
+            padded[m - k] = s / 2.;
+        }
+    }
+}
+
+/// Interpolates the periodic `values` onto `factor` times as many points with
+/// a Catmull-Rom cubic, whose weights sum to one at every point.
+fn cubic(values: &[f64], factor: usize, fine: &mut [f64]) {
+    let n = values.len();
+    for (j, f) in fine.iter_mut().enumerate() {
+        let i = j / factor;
+        let t = (j % factor) as f64 / factor as f64;
+        let (t2, t3) = (t * t, t * t * t);
+        let weights = [(-t3 + 2. * t2 - t) / 2.,
+                       (3. * t3 - 5. * t2 + 2.) / 2.,
+                       (-3. * t3 + 4. * t2 + t) / 2.,
+                       (t3 - t2) / 2.];
+        *f = weights.iter()
+                    .enumerate()
+                    .map(|(w, weight)| weight * values[(i + n + w - 1) % n])
+                    .sum();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A smooth periodic density on a 6 x 4 x 5 grid.
+    fn density() -> (Vec<f64>, [usize; 3]) {
+        let grid = [6, 4, 5];
+        let tau = 2. * std::f64::consts::PI;
+        let density = (0..120).map(|p| {
+                                  let x = (p / 20) as f64 / 6.;
+                                  let y = (p / 5 % 4) as f64 / 4.;
+                                  let z = (p % 5) as f64 / 5.;
+                                  2. + (tau * x).cos() * (tau * y).sin()
+                                  + 0.5 * (tau * z).cos()
+                              })
+                              .collect::<Vec<f64>>();
+        (density, grid)
+    }
+
+    fn conserved(interpolation: Interpolation) {
+        let (density, grid) = density();
+        let (fine, fine_grid) = upsample(&density, grid, 3, interpolation);
+        assert_eq!(fine_grid, [18, 12, 15]);
+        let mean = density.iter().sum::<f64>() / 120.;
+        let fine_mean = fine.iter().sum::<f64>() / 3240.;
+        assert!((mean - fine_mean).abs() < 1E-12);
+        // the original voxels are kept
+        for (p, d) in density.iter().enumerate() {
+            let f = ((p / 20 * 12 + p / 5 % 4) * 15 + p % 5) * 3;
+            assert!((fine[f] - d).abs() < 1E-12);
+        }
+    }
+
+    #[test]
+    fn upsample_fourier_conserved() {
+        conserved(Interpolation::Fourier)
+    }
+
+    #[test]
+    fn upsample_tricubic_conserved() {
+        conserved(Interpolation::Tricubic)
+    }
+
+    #[test]
+    fn upsample_fourier_band_limited() {
+        // a single cosine is reproduced exactly between the voxels
+        let tau = 2. * std::f64::consts::PI;
+        let density = (0..4).map(|z| (tau * z as f64 / 4.).cos())
+                            .collect::<Vec<f64>>();
+        let (fine, _) =
+            upsample(&density, [1, 1, 4], 4, Interpolation::Fourier);
+        for (z, f) in fine.iter().enumerate() {
+            assert!((f - (tau * z as f64 / 16.).cos()).abs() < 1E-12);
+        }
+    }
+}
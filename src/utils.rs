@@ -1,3 +1,5 @@
+use crate::precision::Real;
+
 /// compute the dot product between a vector and a matrix
 pub fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
     [a[1] * b[2] - a[2] * b[1],
@@ -70,14 +72,14 @@ pub fn invert_lattice(lattice: &[[f64; 3]; 3])
 }
 
 /// returns the first index that is not vacuum from a sorted index list
-pub fn vacuum_tolerance(density: &[f64],
-                        index: &[usize],
-                        tolerance: Option<f64>)
-                        -> usize {
+pub fn vacuum_tolerance<T: Real>(density: &[T],
+                                 index: &[usize],
+                                 tolerance: Option<f64>)
+                                 -> usize {
     match tolerance {
         Some(tol) => {
             for (i, p) in index.iter().enumerate() {
-                if density[*p] < tol {
+                if density[*p].to_f64() < tol {
                     return i.saturating_sub(1);
                 }
             }
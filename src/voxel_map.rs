@@ -1,19 +1,213 @@
+use crate::arguments::Precision;
+use crate::storage::{Buffer, Storage};
 use std::cell::UnsafeCell;
 use std::collections::BTreeSet;
-use std::ops::{Deref, DerefMut};
-use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicPtr, Ordering};
+
+/// The most maxima a voxel can contribute to, one for each of its neighbours.
+pub const MAX_WEIGHTS: usize = 26;
+
+/// The number of values in each segment of a [`Segments`].
+const SEGMENT: usize = 1 << 16;
 
 /// Describes the state of the voxel.
 pub enum Voxel<'a> {
     /// Contians the position of the voxel's maxima.
     Maxima(usize),
-    /// Contians a vector of the maxima the current voxel contributes to and
-    /// their weights.
-    Weight(&'a Vec<f64>),
+    /// Contians the maxima the current voxel contributes to and their
+    /// weights.
+    Weight(Weights<'a>),
     /// A voxel beneath the vacuum tolerance and not contributing to any maxima.
     Vacuum,
 }
 
+/// The weights of a boundary voxel, the most probable first.
+#[derive(Clone, Copy)]
+pub enum Weights<'a> {
+    /// Each weight added to the maxima it belongs to, as the whole part.
+    Double(&'a [f64]),
+    /// The weights stored in single precision from this index onwards.
+    Single(&'a SingleWeights, usize),
+}
+
+impl<'a> Weights<'a> {
+    /// The maxima and the weight of each, widened to f64.
+    pub fn iter(&self) -> WeightsIter<'a> {
+        WeightsIter { weights: *self,
+                      i: 0,
+                      done: false }
+    }
+
+    /// The maxima the voxel contributes the most to.
+    pub fn maxima(&self) -> usize {
+        match self {
+            Weights::Double(w) => w[0] as usize,
+            Weights::Single(w, start) => w.get(*start).0,
+        }
+    }
+}
+
+impl<'a> PartialEq for Weights<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+/// Iterates over the maxima and weights of a boundary voxel.
+pub struct WeightsIter<'a> {
+    weights: Weights<'a>,
+    i: usize,
+    done: bool,
+}
+
+impl<'a> Iterator for WeightsIter<'a> {
+    type Item = (usize, f64);
+
+    fn next(&mut self) -> Option<(usize, f64)> {
+        if self.done {
+            return None;
+        }
+        let next = match self.weights {
+            Weights::Double(w) => {
+                w.get(self.i).map(|w| (*w as usize, w - w.trunc()))
+            }
+            Weights::Single(w, start) => {
+                let (maxima, weight) = w.get(start + self.i);
+                // the last weight of each voxel is negated
+                self.done = weight.is_sign_negative();
+                Some((maxima, weight.abs() as f64))
+            }
+        };
+        self.i += 1;
+        self.done |= next.is_none();
+        next
+    }
+}
+
+/// Values kept in segments that are never moved once allocated, so that
+/// those stored can be read whilst more are stored.
+struct Segments<T> {
+    segments: Vec<AtomicPtr<T>>,
+}
+
+impl<T: Default> Segments<T> {
+    /// Room for `len` values.
+    fn new(len: usize) -> Self {
+        let segments = len / SEGMENT + 1;
+        Self { segments: (0..segments).map(|_| AtomicPtr::default())
+                                      .collect() }
+    }
+
+    /// The value at index `i`, which has to have been stored.
+    fn get(&self, i: usize) -> &T {
+        let segment = self.segments[i / SEGMENT].load(Ordering::Acquire);
+        unsafe { &*segment.add(i % SEGMENT) }
+    }
+
+    /// The value at index `i` for storing, allocating its segment if needed.
+    fn get_mut(&mut self, i: usize) -> &mut T {
+        let segment = &self.segments[i / SEGMENT];
+        if segment.load(Ordering::Acquire).is_null() {
+            let values = (0..SEGMENT).map(|_| T::default())
+                                     .collect::<Box<[T]>>();
+            segment.store(Box::into_raw(values) as *mut T, Ordering::Release);
+        }
+        let segment = segment.load(Ordering::Acquire);
+        unsafe { &mut *segment.add(i % SEGMENT) }
+    }
+}
+
+impl<T> Drop for Segments<T> {
+    fn drop(&mut self) {
+        for segment in self.segments.iter() {
+            let segment = segment.load(Ordering::Acquire);
+            if !segment.is_null() {
+                let values =
+                    std::ptr::slice_from_raw_parts_mut(segment, SEGMENT);
+                unsafe { drop(Box::from_raw(values)) };
+            }
+        }
+    }
+}
+
+/// The weights of the boundary voxels in double precision, a [`Vec`] for
+/// each voxel.
+struct DoubleWeights {
+    weights: Segments<Vec<f64>>,
+    voxels: usize,
+}
+
+impl DoubleWeights {
+    /// Room for the weights of a grid of `size` voxels.
+    fn new(size: usize) -> Self {
+        Self { weights: Segments::new(size),
+               voxels: 0 }
+    }
+
+    /// Stores the weights of a voxel and returns their index.
+    fn push(&mut self, weights: Vec<f64>) -> usize {
+        *self.weights.get_mut(self.voxels) = weights;
+        self.voxels += 1;
+        self.voxels - 1
+    }
+}
+
+/// The weights of the boundary voxels in single precision, each a f32 and
+/// the maxima it belongs to. The weights of a voxel follow one another with
+/// the last negated.
+pub struct SingleWeights {
+    maxima: Segments<usize>,
+    weights: Segments<f32>,
+    len: usize,
+    voxels: usize,
+}
+
+impl SingleWeights {
+    /// Room for the weights of a grid of `size` voxels.
+    fn new(size: usize) -> Self {
+        // a voxel is never split across segments, so may skip the end of one
+        let len = MAX_WEIGHTS * size / (SEGMENT - MAX_WEIGHTS) * SEGMENT;
+        Self { maxima: Segments::new(len),
+               weights: Segments::new(len),
+               len: 0,
+               voxels: 0 }
+    }
+
+    /// The maxima and weight at index `i`, which has to have been stored.
+    fn get(&self, i: usize) -> (usize, f32) {
+        (*self.maxima.get(i), *self.weights.get(i))
+    }
+
+    /// Stores the weights of a voxel, each added to its maxima, and returns
+    /// the index of the first.
+    fn push(&mut self, weights: &[f64]) -> usize {
+        // the reads rely on every voxel ending in a negated weight
+        assert!(!weights.is_empty() && weights.len() <= MAX_WEIGHTS);
+        let mut start = self.len;
+        if start % SEGMENT + weights.len() > SEGMENT {
+            start += SEGMENT - start % SEGMENT;
+        }
+        for (j, w) in weights.iter().enumerate() {
+            let m = *w as usize;
+            let mut w = (w - m as f64) as f32;
+            if j + 1 == weights.len() {
+                w = -w;
+            }
+            *self.maxima.get_mut(start + j) = m;
+            *self.weights.get_mut(start + j) = w;
+        }
+        self.len = start + weights.len();
+        self.voxels += 1;
+        start
+    }
+}
+
+/// The weights of every boundary voxel, in the precision they are kept in.
+enum WeightMap {
+    Double(DoubleWeights),
+    Single(SingleWeights),
+}
+
 /// A lock guard for write access to [`VoxelMap.weight_map`]
 pub struct Lock<'a> {
     data: &'a VoxelMap,
@@ -21,18 +215,15 @@ pub struct Lock<'a> {
 
 unsafe impl<'a> Sync for Lock<'a> {}
 
-/// Deref only exposes the weight_map field of a [`VoxelMap`].
-impl<'a> Deref for Lock<'a> {
-    type Target = Vec<Vec<f64>>;
-    fn deref(&self) -> &Vec<Vec<f64>> {
-        unsafe { &*self.data.weight_map.get() }
-    }
-}
-
-/// DerefMut only exposes the weight_map field of a [`VoxelMap`].
-impl<'a> DerefMut for Lock<'a> {
-    fn deref_mut(&mut self) -> &mut Vec<Vec<f64>> {
-        unsafe { &mut *self.data.weight_map.get() }
+impl<'a> Lock<'a> {
+    /// Stores the weights of a voxel, each added to the maxima it belongs to
+    /// and the most probable first, and returns the index to pass to
+    /// [`VoxelMap::weight_store`]. `weights` can't be empty.
+    pub fn push(&mut self, weights: Vec<f64>) -> usize {
+        match unsafe { &mut *self.data.weight_map.get() } {
+            WeightMap::Double(map) => map.push(weights),
+            WeightMap::Single(map) => map.push(&weights),
+        }
     }
 }
 
@@ -48,10 +239,9 @@ impl<'a> Drop for Lock<'a> {
 /// stored in the weight_map. The weight_map is only written to once by each
 /// point and so once a value has been written it is safe to read by any thread.
 /// To check it has been written to `weight_get` monitors the state of corresponding
-/// voxel_map value. Writing to the map is acheived by acquiring the lock, noting
-/// the length of the weight_map, pushing the weight vector for voxel p to the
-/// weight_map, droping the write lock and then storing the index of the inserted
-/// vector using `weight_store`.
+/// voxel_map value. Writing to the map is acheived by acquiring the lock,
+/// pushing the weights for voxel p to the weight_map, droping the write lock
+/// and then storing the index returned by the push using `weight_store`.
 ///
 /// # Examples
 /// ```
@@ -59,17 +249,13 @@ impl<'a> Drop for Lock<'a> {
 ///
 /// for p in 0..1isize {
 ///     let voxel_map = VoxelMap::new(10);
-///     let i = {
-///         let mut weight = voxel_map.lock();
-///         (*weight).push(Vec::with_capacity(0));
-///         weight.len() - 1
-///     };
+///     let i = voxel_map.lock().push(vec![3.75, 5.25]);
 ///     voxel_map.weight_store(p, i)
 /// }
 /// ```
 pub struct VoxelMap {
-    weight_map: UnsafeCell<Vec<Vec<f64>>>,
-    voxel_map: Vec<AtomicIsize>,
+    weight_map: UnsafeCell<WeightMap>,
+    voxel_map: Buffer<AtomicIsize>,
     lock: AtomicBool,
 }
 
@@ -78,32 +264,81 @@ unsafe impl Sync for VoxelMap {}
 impl VoxelMap {
     /// Initialises a VoxelMap of dimensions, size.
     pub fn new(size: usize) -> Self {
+        // safe to unwrap as the heap can't fail with an I/O error
+        Self::with_storage(size, &Storage::Memory).unwrap()
+    }
+
+    /// Initialises a VoxelMap of dimensions, size, with the maxima of each
+    /// voxel kept in `storage`. The weights of the boundary voxels are always
+    /// kept on the heap.
+    pub fn with_storage(size: usize,
+                        storage: &Storage)
+                        -> std::io::Result<Self> {
+        Self::with_precision(size, storage, Precision::Double)
+    }
+
+    /// Initialises a VoxelMap as [`VoxelMap::with_storage`] with the weights
+    /// of the boundary voxels kept in `precision`. In single precision each
+    /// weight is a f32 beside the maxima it belongs to, in one store rather
+    /// than a [`Vec`] for each voxel.
+    pub fn with_precision(size: usize,
+                          storage: &Storage,
+                          precision: Precision)
+                          -> std::io::Result<Self> {
         // For mapping the the voxels
-        let weight_map = UnsafeCell::new(Vec::<Vec<f64>>::with_capacity(size));
-        let mut voxel_map = Vec::with_capacity(size);
-        voxel_map.resize_with(size, || AtomicIsize::new(-1));
+        let weight_map = match precision {
+            Precision::Double => {
+                WeightMap::Double(DoubleWeights::new(size))
+            }
+            Precision::Single => WeightMap::Single(SingleWeights::new(size)),
+        };
+        let weight_map = UnsafeCell::new(weight_map);
+        let voxel_map =
+            Buffer::from_fn(size, storage, |_| AtomicIsize::new(-1))?;
         let lock = AtomicBool::new(false);
         // For post processing
-        Self { weight_map,
-               voxel_map,
-               lock }
+        Ok(Self { weight_map,
+                  voxel_map,
+                  lock })
+    }
+
+    /// Writes back and releases the resident pages of the maxima of each
+    /// voxel if they are memory-mapped, see [`Buffer::release`].
+    pub fn release(&self) -> std::io::Result<()> {
+        self.voxel_map.release()
+    }
+
+    /// Hints whether the maxima of each voxel are about to be read in order,
+    /// see [`Buffer::advise_sequential`].
+    pub fn advise_sequential(&self, sequential: bool) {
+        self.voxel_map.advise_sequential(sequential)
     }
 
     /// How many voxels are boundary voxels?
     pub fn boundary_voxels(&self) -> usize {
-        (unsafe { &*self.weight_map.get() }).len()
+        match unsafe { &*self.weight_map.get() } {
+            WeightMap::Double(map) => map.voxels,
+            WeightMap::Single(map) => map.voxels,
+        }
     }
 
     /// Retrieves the state of the voxel, p. This will lock until p has been stored
     /// in the VoxelMap and then return either a `Voxel::Maxima` or `Voxel::Weight`.
     /// Calling this on a voxel, p, that is below the vacuum_tolerance will deadlock
     /// as a voxel is considered stored once voxel_map\[p\] > -1.
-    pub fn weight_get(&self, i: isize) -> &Vec<f64> {
-        let i = -2 - i;
-        &(unsafe { &*self.weight_map.get() })[i as usize]
+    pub fn weight_get(&self, i: isize) -> Weights<'_> {
+        let i = (-2 - i) as usize;
+        match unsafe { &*self.weight_map.get() } {
+            WeightMap::Double(map) => Weights::Double(map.weights.get(i)),
+            WeightMap::Single(map) => Weights::Single(map, i),
+        }
     }
 
     /// Atomic loading of voxel, p, from voxel_map blocks if maxima == -1
+    ///
+    /// Only voxels of higher density are waited on and these are always
+    /// claimed by a thread before p is. A cancelled partition stops claiming
+    /// voxels but finishes those already claimed, so this never spins forever.
     pub fn maxima_get(&self, p: isize) -> isize {
         loop {
             match self.voxel_map[p as usize].load(Ordering::Relaxed) {
@@ -120,23 +355,19 @@ impl VoxelMap {
             std::cmp::Ordering::Equal => -1,
             std::cmp::Ordering::Greater => maxima,
             std::cmp::Ordering::Less => {
-                let weight = self.weight_get(maxima);
-                weight[0] as isize
+                self.weight_get(maxima).maxima() as isize
             }
         }
     }
 
     /// A none locking retrieval of the state of voxel, p. This should only be
     /// used once the VoxelMap has been fully populated.
-    pub fn voxel_get(&self, p: isize) -> Voxel {
+    pub fn voxel_get(&self, p: isize) -> Voxel<'_> {
         let maxima = self.voxel_map[p as usize].load(Ordering::Relaxed);
         match maxima.cmp(&-1) {
             std::cmp::Ordering::Equal => Voxel::Vacuum,
             std::cmp::Ordering::Greater => Voxel::Maxima(maxima as usize),
-            std::cmp::Ordering::Less => {
-                let weight = self.weight_get(maxima);
-                Voxel::Weight(weight)
-            }
+            std::cmp::Ordering::Less => Voxel::Weight(self.weight_get(maxima)),
         }
     }
 
@@ -144,14 +375,14 @@ impl VoxelMap {
     /// value.
     pub fn maxima_list(&self) -> Vec<usize> {
         let len = self.voxel_map.len() as isize;
-        let maximas = (0..len).filter_map(|x| {
-                                  if let Voxel::Maxima(m) = self.voxel_get(x) {
-                                      Some(m)
-                                  } else {
-                                      None
-                                  }
-                              })
-                              .collect::<BTreeSet<usize>>();
+        // insert each in turn, collecting into the set would first collect
+        // the maxima of every voxel into a Vec as large as the grid
+        let mut maximas = BTreeSet::new();
+        for x in 0..len {
+            if let Voxel::Maxima(m) = self.voxel_get(x) {
+                maximas.insert(m);
+            }
+        }
         maximas.into_iter().collect()
     }
 
@@ -166,9 +397,24 @@ impl VoxelMap {
         self.maxima_store(p, -2 - (i as isize));
     }
 
+    /// Renumbers the weights of every boundary voxel, stored with
+    /// `weight_store` as the order they were pushed in, to the index each push
+    /// returned, `indices`, which differ in single precision.
+    pub fn renumber_weights(&self, indices: &[usize]) {
+        if indices.iter().enumerate().all(|(k, i)| k == *i) {
+            return;
+        }
+        for p in 0..self.voxel_map.len() {
+            let maxima = self.voxel_map[p].load(Ordering::Relaxed);
+            if maxima < -1 {
+                self.weight_store(p as isize, indices[(-2 - maxima) as usize]);
+            }
+        }
+    }
+
     /// Locks the structure for write access unlock occurs when the returned
     /// Lock is dropped.
-    pub fn lock(&self) -> Lock {
+    pub fn lock(&self) -> Lock<'_> {
         while self.lock.swap(true, Ordering::SeqCst) {}
         Lock { data: self }
     }
@@ -189,5 +435,63 @@ mod tests {
     }
 
     #[test]
-    fn voxel_map_weight_store() {}
+    fn voxel_map_weight_store() {
+        let voxel_map = VoxelMap::new(10);
+        let i = voxel_map.lock().push(vec![3.75, 5.25]);
+        voxel_map.weight_store(0, i);
+        assert_eq!(voxel_map.maxima_non_block_get(0), 3);
+        match voxel_map.voxel_get(0) {
+            Voxel::Weight(w) => {
+                assert_eq!(w.iter().collect::<Vec<_>>(),
+                           vec![(3, 0.75), (5, 0.25)])
+            }
+            _ => panic!("voxel 0 isn't a boundary voxel"),
+        }
+    }
+
+    #[test]
+    fn voxel_map_weight_store_stays() {
+        let size = 2 * SEGMENT;
+        let voxel_map = VoxelMap::new(size);
+        let i = voxel_map.lock().push(vec![3.75, 5.25]);
+        voxel_map.weight_store(0, i);
+        // the weights read by other threads mustn't move as more are stored
+        let first = voxel_map.weight_get(-2);
+        for p in 1..size as isize {
+            let i = voxel_map.lock().push(vec![p as f64 + 0.5, 1.5]);
+            voxel_map.weight_store(p, i);
+        }
+        assert_eq!(voxel_map.boundary_voxels(), size);
+        assert_eq!(first.iter().collect::<Vec<_>>(),
+                   vec![(3, 0.75), (5, 0.25)]);
+        assert_eq!(voxel_map.weight_get(-2 - size as isize + 1).maxima(),
+                   size - 1);
+    }
+
+    #[test]
+    fn voxel_map_weight_store_single() {
+        let size = 2 * SEGMENT;
+        let voxel_map =
+            VoxelMap::with_precision(size, &Storage::Memory, Precision::Single)
+            .unwrap();
+        // enough weights to fill the first segment and start the next
+        for p in 0..SEGMENT as isize {
+            let i = voxel_map.lock()
+                             .push(vec![p as f64 + 0.5, 8589934592.375, 1.125]);
+            voxel_map.weight_store(p, i);
+        }
+        assert_eq!(voxel_map.boundary_voxels(), SEGMENT);
+        for p in 0..SEGMENT as isize {
+            match voxel_map.voxel_get(p) {
+                Voxel::Weight(w) => {
+                    assert_eq!(w.maxima(), p as usize);
+                    assert_eq!(w.iter().collect::<Vec<_>>(),
+                               vec![(p as usize, 0.5),
+                                    (8589934592, 0.375),
+                                    (1, 0.125)])
+                }
+                _ => panic!("voxel {} isn't a boundary voxel", p),
+            }
+        }
+    }
 }
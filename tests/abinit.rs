@@ -0,0 +1,92 @@
+#[cfg(test)]
+mod tests {
+    use bader::io::abinit::Abinit;
+    use bader::io::{Dimensions, FileFormat};
+    use bader::progress::Silent;
+    use bader::storage::Storage;
+
+    const VOLUME_UNITS: f64 = 0.52917721067 * 0.52917721067 * 0.52917721067;
+
+    #[test]
+    fn abinit_read() {
+        let filename = String::from("tests/abinit/density_DEN");
+        let abinit = Abinit {};
+        let (voxel_origin, grid, atoms, densities) =
+            match abinit.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        let length = 8. * 0.52917721067;
+        assert_eq!(voxel_origin, [0.; 3]);
+        assert_eq!(grid, [4, 4, 4]);
+        assert_eq!(atoms.positions.len(), 2);
+        assert_eq!(atoms.atomic_numbers, vec![8, 8]);
+        atoms.positions[1].iter()
+                          .zip(&[0.75 * length, 0.5 * length, 0.25 * length])
+                          .for_each(|(p, e)| assert!((p - e).abs() < 1e-10));
+        assert_eq!(densities.len(), 2);
+        assert!((densities[0][1] * VOLUME_UNITS - 1.).abs() < 1e-10);
+        assert!((densities[0][4] * VOLUME_UNITS - 10.).abs() < 1e-10);
+        assert!((densities[0][16] * VOLUME_UNITS - 100.).abs() < 1e-10);
+        assert!((densities[0][63] * VOLUME_UNITS - 333.).abs() < 1e-10);
+        assert!((densities[1][63] * VOLUME_UNITS - 166.5).abs() < 1e-10);
+    }
+
+    #[test]
+    fn abinit_write_read() {
+        let abinit = Abinit {};
+        // the header is kept from reading so the input can be removed
+        let input = std::env::temp_dir().join("bader_abinit_input_DEN");
+        if let Err(e) = std::fs::copy("tests/abinit/density_DEN", &input) {
+            panic!("{}", e)
+        }
+        let (_, grid, atoms, densities) =
+            match abinit.read(input.to_string_lossy().to_string(),
+                              &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        if let Err(e) = std::fs::remove_file(&input) {
+            panic!("{}", e)
+        }
+        let filename = std::env::temp_dir().join("bader_abinit_write_read");
+        let filename = filename.to_string_lossy().to_string();
+        let data = densities[0].iter()
+                               .map(|d| if *d == 0. { None } else { Some(*d) })
+                               .collect::<Vec<Option<f64>>>();
+        let pbar = Box::new(Silent);
+        if let Err(e) = abinit.write(&atoms, data, filename.clone(), pbar) {
+            panic!("{}", e)
+        }
+        let (_, grid_2, atoms_2, densities_2) =
+            match abinit.read(format!("{}_DEN", filename), &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(grid, grid_2);
+        assert_eq!(atoms.positions, atoms_2.positions);
+        assert_eq!(densities_2.len(), 1);
+        densities[0].iter()
+                    .zip(&densities_2[0])
+                    .for_each(|(a, b)| assert!((a - b).abs() < 1e-10));
+    }
+
+    #[test]
+    fn abinit_dimensions() {
+        let filename = String::from("tests/abinit/density_DEN");
+        let abinit = Abinit {};
+        let dimensions = match abinit.dimensions(filename.clone()) {
+            Ok(d) => d,
+            Err(e) => panic!("{}", e),
+        };
+        let (_, grid, atoms, densities) =
+            match abinit.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(dimensions,
+                   Dimensions { grid,
+                                densities: densities.len(),
+                                atoms: atoms.positions.len() });
+    }
+}
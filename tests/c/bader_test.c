@@ -0,0 +1,104 @@
+/*
+ * Partitions two gaussians in a cubic cell through the C interface.
+ *
+ * Build and run from the root of the repository with:
+ *   cargo build --release
+ *   cc tests/c/bader_test.c -Iinclude -Ltarget/release -lbader -lm \
+ *      -o target/bader_test
+ *   LD_LIBRARY_PATH=target/release target/bader_test
+ */
+#include <math.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#include "bader.h"
+
+#define N 24
+#define LENGTH 6.0
+
+/* The periodic distance squared between two points of the cubic cell. */
+static double distance2(const double *a, const double *b) {
+    double r2 = 0.0;
+    for (int i = 0; i < 3; i++) {
+        double d = fabs(a[i] - b[i]);
+        d = fmin(d, LENGTH - d);
+        r2 += d * d;
+    }
+    return r2;
+}
+
+int main(void) {
+    const double lattice[9] = {LENGTH, 0.0, 0.0,
+                               0.0, LENGTH, 0.0,
+                               0.0, 0.0, LENGTH};
+    const size_t dims[3] = {N, N, N};
+    const double positions[6] = {1.5, 1.5, 1.5,
+                                 4.5, 3.0, 3.0};
+    const double weights[2] = {1.0, 2.0};
+    double *density = malloc(N * N * N * sizeof(double));
+    int64_t *atom_labels = malloc(N * N * N * sizeof(int64_t));
+    double total = 0.0;
+    double charge[2], volume[2];
+    int failed = 0;
+
+    /* a varies fastest, so index the density as rho[c][b][a] */
+    for (size_t c = 0; c < N; c++) {
+        for (size_t b = 0; b < N; b++) {
+            for (size_t a = 0; a < N; a++) {
+                const double r[3] = {a * LENGTH / N,
+                                     b * LENGTH / N,
+                                     c * LENGTH / N};
+                double rho = 0.0;
+                for (int i = 0; i < 2; i++) {
+                    const double r2 = distance2(r, positions + 3 * i);
+                    rho += weights[i] * exp(-2.0 * r2);
+                }
+                density[(c * N + b) * N + a] = rho;
+                total += rho;
+            }
+        }
+    }
+    total *= LENGTH * LENGTH * LENGTH / (N * N * N);
+
+    BaderGrid *grid = bader_grid_new(lattice, dims, positions, 2);
+    if (grid == NULL) {
+        fprintf(stderr, "bader_grid_new returned NULL\n");
+        return 1;
+    }
+    if (bader_atoms_charge(grid, charge) != BADER_ERROR_NOT_RUN) {
+        fprintf(stderr, "charges were returned before partitioning\n");
+        failed = 1;
+    }
+    if (bader_partition(grid, density, 2) != BADER_OK
+        || bader_atoms_charge(grid, charge) != BADER_OK
+        || bader_atoms_volume(grid, volume) != BADER_OK
+        || bader_voxel_labels(grid, atom_labels, NULL) != BADER_OK) {
+        fprintf(stderr, "%s\n", bader_error_message(grid));
+        bader_grid_free(grid);
+        return 1;
+    }
+    printf("volumes: %zu\n", bader_volumes_len(grid));
+    for (size_t i = 0; i < bader_atoms_len(grid); i++) {
+        printf("atom %zu: charge %.6f volume %.6f\n",
+               i + 1, charge[i], volume[i]);
+    }
+    if (fabs(charge[0] + charge[1] - total) > 1e-9) {
+        fprintf(stderr, "charge is not conserved\n");
+        failed = 1;
+    }
+    if (fabs(volume[0] + volume[1] - LENGTH * LENGTH * LENGTH) > 1e-9) {
+        fprintf(stderr, "volume is not conserved\n");
+        failed = 1;
+    }
+    if (atom_labels[0] != 0 || atom_labels[(12 * N + 12) * N + 18] != 1) {
+        fprintf(stderr, "voxels are labelled with the wrong atoms\n");
+        failed = 1;
+    }
+    bader_grid_free(grid);
+    free(density);
+    free(atom_labels);
+    if (!failed) {
+        printf("passed\n");
+    }
+    return failed;
+}
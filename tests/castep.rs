@@ -0,0 +1,125 @@
+#[cfg(test)]
+mod tests {
+    use bader::io::castep::Castep;
+    use bader::io::{Dimensions, FileFormat};
+    use bader::progress::Silent;
+    use bader::storage::Storage;
+
+    #[test]
+    fn castep_read() {
+        let filename = String::from("tests/castep/density.den_fmt");
+        let castep = Castep {};
+        let (voxel_origin, grid, atoms, densities) =
+            match castep.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(voxel_origin, [0.; 3]);
+        assert_eq!(grid, [4, 4, 4]);
+        assert_eq!(atoms.positions, vec![[0., 0., 0.], [3., 2., 1.]]);
+        assert_eq!(atoms.atomic_numbers, vec![8, 1]);
+        assert_eq!(densities.len(), 2);
+        assert_eq!(densities[0][1], 1. / atoms.lattice.volume);
+        assert_eq!(densities[0][4], 10. / atoms.lattice.volume);
+        assert_eq!(densities[0][16], 100. / atoms.lattice.volume);
+        assert_eq!(densities[0][63], 333. / atoms.lattice.volume);
+        assert_eq!(densities[1][63], -333. / atoms.lattice.volume);
+    }
+
+    #[test]
+    fn castep_dimensions() {
+        let filename = String::from("tests/castep/density.den_fmt");
+        let castep = Castep {};
+        let dimensions = match castep.dimensions(filename.clone()) {
+            Ok(d) => d,
+            Err(e) => panic!("{}", e),
+        };
+        let (_, grid, atoms, densities) =
+            match castep.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(dimensions,
+                   Dimensions { grid,
+                                densities: densities.len(),
+                                atoms: atoms.positions.len() });
+    }
+
+    #[test]
+    fn castep_write_read() {
+        let castep = Castep {};
+        let (_, grid, atoms, densities) =
+            match castep.read(String::from("tests/castep/density.den_fmt"),
+                              &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        let filename = std::env::temp_dir().join("bader_castep_write_read");
+        let filename = filename.to_string_lossy().to_string();
+        let _ = std::fs::remove_file(format!("{}.cell", filename));
+        let data = densities[1].iter()
+                               .map(|d| if *d == 0. { None } else { Some(*d) })
+                               .collect::<Vec<Option<f64>>>();
+        let pbar = Box::new(Silent);
+        if let Err(e) = castep.write(&atoms, data, filename.clone(), pbar) {
+            panic!("{}", e)
+        }
+        // the .cell is written alongside so the atoms can be read back
+        let (_, grid_2, atoms_2, densities_2) =
+            match castep.read(format!("{}.den_fmt", filename),
+                              &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(grid, grid_2);
+        assert_eq!(atoms.positions, atoms_2.positions);
+        assert_eq!(atoms.atomic_numbers, atoms_2.atomic_numbers);
+        assert_eq!(densities_2.len(), 1);
+        // the values are written to 10 decimal places
+        densities[1].iter().zip(densities_2[0].iter()).for_each(|(a, b)| {
+            assert!((a - b).abs() * atoms.lattice.volume < 1E-10)
+        });
+    }
+
+    #[test]
+    fn castep_cell_line() {
+        let seed = std::env::temp_dir().join("bader_castep_cell_line");
+        let seed = seed.to_string_lossy().to_string();
+        let cell = std::fs::read_to_string("tests/castep/density.cell")
+            .unwrap()
+            .replace("0.25 0.5  0.75", "0.25 half 0.75");
+        std::fs::write(format!("{}.cell", seed), cell).unwrap();
+        std::fs::copy("tests/castep/density.den_fmt",
+                      format!("{}.den_fmt", seed)).unwrap();
+        let castep = Castep {};
+        match castep.read(format!("{}.den_fmt", seed), &Storage::Memory) {
+            Err(e) => {
+                assert!(e.to_string()
+                         .starts_with(&format!("Error: Cannot read {}.cell, \
+                                                line 11:",
+                                               seed)))
+            }
+            Ok(_) => panic!("Read a position that isn't a number."),
+        }
+    }
+
+    #[test]
+    fn castep_write_bad_grid() {
+        let castep = Castep {};
+        let (_, _, mut atoms, densities) =
+            match castep.read(String::from("tests/castep/density.den_fmt"),
+                              &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        atoms.text = atoms.text.replace("4     4     4", "4     four  4");
+        let filename = std::env::temp_dir().join("bader_castep_write_bad_grid");
+        let filename = filename.to_string_lossy().to_string();
+        let data = vec![None; densities[0].len()];
+        let pbar = Box::new(Silent);
+        match castep.write(&atoms, data, filename, pbar) {
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            Ok(_) => panic!("Wrote a grid with an unreadable size."),
+        }
+    }
+}
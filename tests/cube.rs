@@ -1,7 +1,10 @@
 #[cfg(test)]
 mod tests {
+    use bader::errors::Error;
     use bader::io::cube::Cube;
-    use bader::io::FileFormat;
+    use bader::io::{Dimensions, FileFormat};
+    use bader::progress::Silent;
+    use bader::storage::Storage;
 
     const LENGTH_UNITS: f64 = 0.52917721067;
     const VOLUME_UNITS: f64 = LENGTH_UNITS * LENGTH_UNITS * LENGTH_UNITS;
@@ -10,14 +13,125 @@ mod tests {
     fn cube_read() {
         let filename = String::from("tests/cube/anatase.cube");
         let cube = Cube {};
-        let (voxel_origin, grid, atoms, densities) = match cube.read(filename) {
-            Ok(r) => r,
-            Err(e) => panic!("{}", e),
-        };
+        let (voxel_origin, grid, atoms, densities) =
+            match cube.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
         assert_eq!(voxel_origin, [0.5; 3]);
         assert_eq!(grid, [96, 96, 180]);
         assert_eq!(atoms.positions.len(), 576);
         assert_eq!(densities[0][0], 0.13387E-02 / VOLUME_UNITS);
         assert_eq!(densities[0][1658879], 0.11782E+01 / VOLUME_UNITS);
     }
+
+    #[test]
+    fn cube_read_orbitals() {
+        let filename = String::from("tests/cube/orbitals.cube");
+        let cube = Cube {};
+        let (_, grid, atoms, densities) =
+            match cube.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(grid, [3, 3, 3]);
+        assert_eq!(atoms.positions.len(), 2);
+        assert_eq!(atoms.symbols, vec![String::from("O"), String::from("H")]);
+        assert_eq!(cube.valence(&atoms), vec![Some(8.), Some(1.)]);
+        atoms.positions[1].iter()
+                          .zip(&[1., 2., 3.])
+                          .for_each(|(p, e)| {
+                              assert!((p - e * LENGTH_UNITS).abs() < 1e-10)
+                          });
+        assert_eq!(densities.len(), 2);
+        assert_eq!(densities[0][5], 12. / VOLUME_UNITS);
+        assert_eq!(densities[1][5], -12. / VOLUME_UNITS);
+        assert_eq!(densities[0][26], 222. / VOLUME_UNITS);
+        assert_eq!(cube.density_names(&atoms, 2),
+                   vec![String::from("MO 5"), String::from("MO 6")]);
+    }
+
+    #[test]
+    fn cube_read_bad_atom() {
+        let filename = String::from("tests/cube/bad.cube");
+        let cube = Cube {};
+        let flag = match cube.read(filename, &Storage::Memory) {
+            Err(Error::Parse { file, line, .. }) => {
+                file == "tests/cube/bad.cube" && line == 8
+            }
+            _ => false,
+        };
+        assert!(flag)
+    }
+
+    #[test]
+    fn cube_read_values() {
+        let filename = String::from("tests/cube/values.cube");
+        let cube = Cube {};
+        let (_, grid, atoms, densities) =
+            match cube.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(grid, [3, 3, 3]);
+        assert_eq!(atoms.positions.len(), 2);
+        assert_eq!(densities.len(), 2);
+        assert_eq!(densities[1][26], -222. / VOLUME_UNITS);
+        assert_eq!(cube.density_names(&atoms, 2),
+                   vec![String::from("Value 1"), String::from("Value 2")]);
+    }
+
+    #[test]
+    fn cube_orbitals_write_read() {
+        let cube = Cube {};
+        let (_, grid, atoms, densities) =
+            match cube.read(String::from("tests/cube/orbitals.cube"),
+                            &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        let filename = std::env::temp_dir().join("bader_cube_write_read");
+        let filename = filename.to_string_lossy().to_string();
+        let data = densities[1].iter()
+                               .map(|d| if *d == 0. { None } else { Some(*d) })
+                               .collect::<Vec<Option<f64>>>();
+        let pbar = Box::new(Silent);
+        if let Err(e) = cube.write(&atoms, data, filename.clone(), pbar) {
+            panic!("{}", e)
+        }
+        let (_, grid_2, atoms_2, densities_2) =
+            match cube.read(format!("{}.cube", filename), &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(grid, grid_2);
+        assert_eq!(atoms.positions, atoms_2.positions);
+        assert_eq!(densities_2.len(), 1);
+        densities[1].iter()
+                    .zip(&densities_2[0])
+                    .for_each(|(a, b)| assert!((a - b).abs() < 1e-10));
+    }
+
+    #[test]
+    fn cube_dimensions() {
+        let cube = Cube {};
+        for filename in &["tests/cube/orbitals.cube",
+                          "tests/cube/values.cube"]
+        {
+            let filename = String::from(*filename);
+            let dimensions = match cube.dimensions(filename.clone()) {
+                Ok(d) => d,
+                Err(e) => panic!("{}", e),
+            };
+            let (_, grid, atoms, densities) =
+                match cube.read(filename, &Storage::Memory) {
+                    Ok(r) => r,
+                    Err(e) => panic!("{}", e),
+                };
+            assert_eq!(dimensions,
+                       Dimensions { grid,
+                                    densities: densities.len(),
+                                    atoms: atoms.positions.len() });
+        }
+    }
 }
@@ -0,0 +1,97 @@
+#[cfg(test)]
+mod tests {
+    use bader::ffi::*;
+    use std::ffi::CStr;
+
+    const LATTICE: [f64; 9] = [4., 0., 0., 0., 4., 0., 0., 0., 4.];
+    const DIMS: [usize; 3] = [8, 4, 4];
+    const POSITIONS: [f64; 6] = [0., 0., 0., 2., 0., 0.];
+
+    // peaks of density at the atoms, which are 4 voxels apart along a
+    fn density() -> Vec<f64> {
+        (0..128usize).map(|p| {
+                          let d = [p % 8, p / 8 % 4, p / 32];
+                          let r = [(d[0] % 4).min(4 - d[0] % 4),
+                                   d[1].min(4 - d[1]),
+                                   d[2].min(4 - d[2])];
+                          (-((r[0].pow(2) + r[1].pow(2) + r[2].pow(2)) as f64))
+                              .exp()
+                      })
+                      .collect()
+    }
+
+    #[test]
+    fn ffi_partition() {
+        let density = density();
+        let mut charge = [0.; 2];
+        let mut volume = [0.; 2];
+        let mut atom_labels = vec![0i64; 128];
+        let mut volume_labels = vec![0i64; 128];
+        unsafe {
+            let grid = bader_grid_new(LATTICE.as_ptr(),
+                                      DIMS.as_ptr(),
+                                      POSITIONS.as_ptr(),
+                                      2);
+            assert!(!grid.is_null());
+            assert_eq!(bader_atoms_len(grid), 2);
+            assert_eq!(bader_partition(grid, density.as_ptr(), 1), BADER_OK);
+            assert_eq!(bader_volumes_len(grid), 2);
+            assert_eq!(bader_atoms_charge(grid, charge.as_mut_ptr()), BADER_OK);
+            assert_eq!(bader_atoms_volume(grid, volume.as_mut_ptr()), BADER_OK);
+            assert_eq!(bader_voxel_labels(grid,
+                                          atom_labels.as_mut_ptr(),
+                                          volume_labels.as_mut_ptr()),
+                       BADER_OK);
+            bader_grid_free(grid);
+        }
+        let total = density.iter().sum::<f64>() * 0.5;
+        assert!((charge[0] - charge[1]).abs() < 1E-12);
+        assert!((charge[0] + charge[1] - total).abs() < 1E-12);
+        assert!((volume[0] + volume[1] - 64.).abs() < 1E-12);
+        assert_eq!(atom_labels[0], 0);
+        assert_eq!(atom_labels[4], 1);
+        assert_eq!(atom_labels[1], 0);
+        assert_eq!(atom_labels[5], 1);
+        assert_ne!(volume_labels[0], volume_labels[4]);
+    }
+
+    #[test]
+    fn ffi_errors() {
+        let mut density = density();
+        density[3] = f64::NAN;
+        let mut charge = [0.; 2];
+        unsafe {
+            assert!(bader_grid_new(LATTICE.as_ptr(),
+                                   [0, 4, 4].as_ptr(),
+                                   POSITIONS.as_ptr(),
+                                   2).is_null());
+            assert!(bader_grid_new(LATTICE.as_ptr(),
+                                   [usize::MAX / 2, 4, 4].as_ptr(),
+                                   POSITIONS.as_ptr(),
+                                   2).is_null());
+            assert!(bader_grid_new(LATTICE.as_ptr(),
+                                   DIMS.as_ptr(),
+                                   POSITIONS.as_ptr(),
+                                   usize::MAX / 2).is_null());
+            assert!(bader_grid_new([0.; 9].as_ptr(),
+                                   DIMS.as_ptr(),
+                                   POSITIONS.as_ptr(),
+                                   2).is_null());
+            let grid = bader_grid_new(LATTICE.as_ptr(),
+                                      DIMS.as_ptr(),
+                                      POSITIONS.as_ptr(),
+                                      2);
+            assert_eq!(bader_atoms_charge(grid, charge.as_mut_ptr()),
+                       BADER_ERROR_NOT_RUN);
+            assert_eq!(bader_partition(grid, std::ptr::null(), 1),
+                       BADER_ERROR_NULL);
+            assert_eq!(bader_partition(grid, density.as_ptr(), 1),
+                       BADER_ERROR_INPUT);
+            let message = CStr::from_ptr(bader_error_message(grid));
+            assert_eq!(message.to_str().unwrap(),
+                       "Error: Density contains NaN.");
+            assert_eq!(bader_volumes_len(grid), 0);
+            bader_grid_free(grid);
+        }
+    }
+}
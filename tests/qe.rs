@@ -0,0 +1,90 @@
+#[cfg(test)]
+mod tests {
+    use bader::io::qe::Qe;
+    use bader::io::{Dimensions, FileFormat};
+    use bader::progress::Silent;
+    use bader::utils::dot;
+    use bader::storage::Storage;
+
+    const LENGTH_UNITS: f64 = 0.52917721067;
+    const VOLUME_UNITS: f64 = LENGTH_UNITS * LENGTH_UNITS * LENGTH_UNITS;
+
+    #[test]
+    fn qe_read() {
+        let filename = String::from("tests/qe/density.pp");
+        let qe = Qe {};
+        let (voxel_origin, grid, atoms, densities) =
+            match qe.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(voxel_origin, [0.; 3]);
+        assert_eq!(grid, [4, 4, 4]);
+        assert_eq!(atoms.positions.len(), 2);
+        assert_eq!(atoms.positions[0], [0.; 3]);
+        assert_eq!(atoms.atomic_numbers, vec![28, 28]);
+        // tau = (0.25, 0.25, 0.25) alat is (0.75, 0.75, 0.75) once wrapped
+        dot(atoms.positions[1], atoms.lattice.to_fractional)
+            .iter()
+            .for_each(|x| assert!((x - 0.75).abs() < 1E-12));
+        assert!((atoms.lattice.volume - 54. * VOLUME_UNITS).abs() < 1E-12);
+        assert_eq!(densities.len(), 1);
+        assert_eq!(densities[0].len(), 64);
+        assert_eq!(densities[0][1], 1. / VOLUME_UNITS);
+        assert_eq!(densities[0][4], 10. / VOLUME_UNITS);
+        assert_eq!(densities[0][16], 100. / VOLUME_UNITS);
+        assert_eq!(densities[0][63], 333. / VOLUME_UNITS);
+    }
+
+    #[test]
+    fn qe_dimensions() {
+        let filename = String::from("tests/qe/density.pp");
+        let qe = Qe {};
+        let dimensions = match qe.dimensions(filename.clone()) {
+            Ok(d) => d,
+            Err(e) => panic!("{}", e),
+        };
+        let (_, grid, atoms, densities) =
+            match qe.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(dimensions,
+                   Dimensions { grid,
+                                densities: densities.len(),
+                                atoms: atoms.positions.len() });
+    }
+
+    #[test]
+    fn qe_write_read() {
+        let qe = Qe {};
+        let (_, grid, atoms, densities) =
+            match qe.read(String::from("tests/qe/density.pp"),
+                          &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        let filename = std::env::temp_dir().join("bader_qe_write_read");
+        let filename = filename.to_string_lossy().to_string();
+        let data = densities[0].iter()
+                               .map(|d| if *d == 0. { None } else { Some(*d) })
+                               .collect::<Vec<Option<f64>>>();
+        let pbar = Box::new(Silent);
+        if let Err(e) = qe.write(&atoms, data, filename.clone(), pbar) {
+            panic!("{}", e)
+        }
+        let (_, grid_2, atoms_2, densities_2) =
+            match qe.read(format!("{}.pp", filename), &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(grid, grid_2);
+        assert_eq!(atoms.positions, atoms_2.positions);
+        assert_eq!(atoms.atomic_numbers, atoms_2.atomic_numbers);
+        assert_eq!(densities_2.len(), 1);
+        // the values are written to 9 significant figures
+        densities[0].iter().zip(densities_2[0].iter()).for_each(|(a, b)| {
+            assert!((a - b).abs() <= 1E-9 * a.abs())
+        });
+    }
+}
@@ -0,0 +1,23 @@
+  fcc test
+    5    4    4    4    4    4    2    1
+   2     6.00000000     0.00000000     0.00000000     0.00000000     0.00000000     0.00000000
+     25.0000000000      4.0000000000     25.0000000000    0
+   1  Ni     10.00000000
+   1       0.000000000    0.000000000    0.000000000    1
+   2       0.250000000    0.250000000    0.250000000    1
+  0.000000000E+00  1.000000000E+00  2.000000000E+00  3.000000000E+00 -1.000000000E+00
+  1.000000000E+01  1.100000000E+01  1.200000000E+01  1.300000000E+01 -1.000000000E+00
+  2.000000000E+01  2.100000000E+01  2.200000000E+01  2.300000000E+01 -1.000000000E+00
+  3.000000000E+01  3.100000000E+01  3.200000000E+01  3.300000000E+01 -1.000000000E+00
+  1.000000000E+02  1.010000000E+02  1.020000000E+02  1.030000000E+02 -1.000000000E+00
+  1.100000000E+02  1.110000000E+02  1.120000000E+02  1.130000000E+02 -1.000000000E+00
+  1.200000000E+02  1.210000000E+02  1.220000000E+02  1.230000000E+02 -1.000000000E+00
+  1.300000000E+02  1.310000000E+02  1.320000000E+02  1.330000000E+02 -1.000000000E+00
+  2.000000000E+02  2.010000000E+02  2.020000000E+02  2.030000000E+02 -1.000000000E+00
+  2.100000000E+02  2.110000000E+02  2.120000000E+02  2.130000000E+02 -1.000000000E+00
+  2.200000000E+02  2.210000000E+02  2.220000000E+02  2.230000000E+02 -1.000000000E+00
+  2.300000000E+02  2.310000000E+02  2.320000000E+02  2.330000000E+02 -1.000000000E+00
+  3.000000000E+02  3.010000000E+02  3.020000000E+02  3.030000000E+02 -1.000000000E+00
+  3.100000000E+02  3.110000000E+02  3.120000000E+02  3.130000000E+02 -1.000000000E+00
+  3.200000000E+02  3.210000000E+02  3.220000000E+02  3.230000000E+02 -1.000000000E+00
+  3.300000000E+02  3.310000000E+02  3.320000000E+02  3.330000000E+02 -1.000000000E+00
@@ -0,0 +1,87 @@
+#[cfg(test)]
+mod tests {
+    use bader::io::siesta::Siesta;
+    use bader::io::{Dimensions, FileFormat};
+    use bader::progress::Silent;
+    use bader::storage::Storage;
+
+    const VOLUME_UNITS: f64 = 0.52917721067 * 0.52917721067 * 0.52917721067;
+
+    #[test]
+    fn siesta_read() {
+        let filename = String::from("tests/siesta/density.RHO");
+        let siesta = Siesta {};
+        let (voxel_origin, grid, atoms, densities) =
+            match siesta.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        let length = 8. * 0.52917721067;
+        assert_eq!(voxel_origin, [0.; 3]);
+        assert_eq!(grid, [4, 4, 4]);
+        assert_eq!(atoms.positions.len(), 2);
+        assert_eq!(atoms.atomic_numbers, vec![8, 1]);
+        atoms.positions[1].iter()
+                          .zip(&[0.75 * length, 0.5 * length, 0.25 * length])
+                          .for_each(|(p, e)| assert!((p - e).abs() < 1e-10));
+        assert_eq!(densities.len(), 2);
+        assert!((densities[0][1] * VOLUME_UNITS - 1.).abs() < 1e-5);
+        assert!((densities[0][4] * VOLUME_UNITS - 10.).abs() < 1e-5);
+        assert!((densities[0][16] * VOLUME_UNITS - 100.).abs() < 1e-5);
+        assert!((densities[0][63] * VOLUME_UNITS - 333.).abs() < 1e-5);
+        assert!((densities[1][63] * VOLUME_UNITS - 166.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn siesta_write_read() {
+        let siesta = Siesta {};
+        let (_, grid, atoms, densities) =
+            match siesta.read(String::from("tests/siesta/density.RHO"),
+                              &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        let filename = std::env::temp_dir().join("bader_siesta_write_read");
+        // the .XV is written alongside the density
+        let _ = std::fs::remove_file(filename.with_extension("XV"));
+        let filename = filename.to_string_lossy().to_string();
+        let data = densities[0].iter()
+                               .map(|d| if *d == 0. { None } else { Some(*d) })
+                               .collect::<Vec<Option<f64>>>();
+        let pbar = Box::new(Silent);
+        if let Err(e) = siesta.write(&atoms, data, filename.clone(), pbar) {
+            panic!("{}", e)
+        }
+        let (_, grid_2, atoms_2, densities_2) =
+            match siesta.read(format!("{}.RHO", filename), &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(grid, grid_2);
+        assert_eq!(atoms.text, atoms_2.text);
+        assert_eq!(atoms.positions, atoms_2.positions);
+        assert_eq!(densities_2.len(), 1);
+        densities[0].iter()
+                    .zip(&densities_2[0])
+                    .for_each(|(a, b)| assert!((a - b).abs() < 1e-5));
+    }
+
+    #[test]
+    fn siesta_dimensions() {
+        let filename = String::from("tests/siesta/density.RHO");
+        let siesta = Siesta {};
+        let dimensions = match siesta.dimensions(filename.clone()) {
+            Ok(d) => d,
+            Err(e) => panic!("{}", e),
+        };
+        let (_, grid, atoms, densities) =
+            match siesta.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(dimensions,
+                   Dimensions { grid,
+                                densities: densities.len(),
+                                atoms: atoms.positions.len() });
+    }
+}
@@ -1,32 +1,86 @@
 #[cfg(test)]
 mod tests {
+    use bader::arguments::{Args, ClapApp};
+    use bader::errors::Error;
     use bader::io::vasp::Vasp;
-    use bader::io::FileFormat;
+    use bader::io::{valence, Dimensions, FileFormat};
+    use bader::storage::Storage;
 
     #[test]
     fn vasp_read_no_spin() {
         let filename = String::from("tests/vasp/CHGCAR_no_spin");
         let vasp = Vasp {};
-        let (voxel_origin, grid, atoms, densities) = match vasp.read(filename) {
-            Ok(r) => r,
-            Err(e) => panic!("{}", e),
-        };
+        let (voxel_origin, grid, atoms, densities) =
+            match vasp.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
         assert_eq!(voxel_origin, [0.; 3]);
         assert_eq!(grid, [32, 32, 32]);
         assert_eq!(atoms.positions, vec![[0., 0., 0.]]);
+        assert_eq!(atoms.atomic_numbers, vec![28]);
+        assert_eq!(atoms.symbols, vec![String::from("Ni")]);
         assert_eq!(densities[0][0], 0.15246059033E+03 / atoms.lattice.volume);
         assert_eq!(densities[0][32767],
                    0.13036296982E+03 / atoms.lattice.volume);
     }
 
+    #[test]
+    fn vasp_read_bad_value() {
+        let filename = String::from("tests/vasp/CHGCAR_bad");
+        let vasp = Vasp {};
+        let e = match vasp.read(filename, &Storage::Memory) {
+            Ok(_) => panic!("Read a malformed file."),
+            Err(e) => e,
+        };
+        assert_eq!(e.to_string(),
+                   "Error: Cannot read tests/vasp/CHGCAR_bad, line 13: \
+                    Unable to parse density, (0.1000000x000E+01).");
+    }
+
+    #[test]
+    fn vasp_to_atoms_singular_lattice() {
+        let text = "fcc Ni
+   3.53000000000000
+     0.000000    0.000000    0.000000
+     0.000000    0.500000    0.500000
+     0.500000    0.000000    0.500000
+   Ni
+     1
+Direct
+  0.000000  0.000000  0.000000
+";
+        let vasp = Vasp {};
+        let e = match vasp.to_atoms(String::from(text)) {
+            Ok(_) => panic!("Read a singular lattice."),
+            Err(e) => e,
+        };
+        assert_eq!(e.to_string(),
+                   "Error: Cannot read file, line 3: Lattice doesn't span \
+                    3D space");
+    }
+
+    #[test]
+    fn vasp_read_missing_file() {
+        let vasp = Vasp {};
+        let flag =
+            match vasp.read(String::from("tests/vasp/missing"),
+                            &Storage::Memory) {
+                Err(Error::Io { file, .. }) => file == "tests/vasp/missing",
+                _ => false,
+            };
+        assert!(flag)
+    }
+
     #[test]
     fn vasp_read_no_spin_chg() {
         let filename = String::from("tests/vasp/CHG_no_spin");
         let vasp = Vasp {};
-        let (voxel_origin, grid, atoms, densities) = match vasp.read(filename) {
-            Ok(r) => r,
-            Err(e) => panic!("{}", e),
-        };
+        let (voxel_origin, grid, atoms, densities) =
+            match vasp.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
         assert_eq!(voxel_origin, [0.; 3]);
         assert_eq!(grid, [32, 32, 32]);
         assert_eq!(atoms.positions, vec![[0., 0., 0.]]);
@@ -38,10 +92,11 @@ mod tests {
     fn vasp_read_spin() {
         let filename = String::from("tests/vasp/CHGCAR_spin");
         let vasp = Vasp {};
-        let (voxel_origin, grid, atoms, densities) = match vasp.read(filename) {
-            Ok(r) => r,
-            Err(e) => panic!("{}", e),
-        };
+        let (voxel_origin, grid, atoms, densities) =
+            match vasp.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
         assert_eq!(voxel_origin, [0.; 3]);
         assert_eq!(grid, [32, 32, 32]);
         assert_eq!(atoms.positions, vec![[0., 0., 0.]]);
@@ -57,10 +112,11 @@ mod tests {
     fn vasp_read_spin_chg() {
         let filename = String::from("tests/vasp/CHG_spin");
         let vasp = Vasp {};
-        let (voxel_origin, grid, atoms, densities) = match vasp.read(filename) {
-            Ok(r) => r,
-            Err(e) => panic!("{}", e),
-        };
+        let (voxel_origin, grid, atoms, densities) =
+            match vasp.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
         assert_eq!(voxel_origin, [0.; 3]);
         assert_eq!(grid, [32, 32, 32]);
         assert_eq!(atoms.positions, vec![[0., 0., 0.]]);
@@ -74,10 +130,11 @@ mod tests {
     fn vasp_read_ncl() {
         let filename = String::from("tests/vasp/CHGCAR_ncl");
         let vasp = Vasp {};
-        let (voxel_origin, grid, atoms, densities) = match vasp.read(filename) {
-            Ok(r) => r,
-            Err(e) => panic!("{}", e),
-        };
+        let (voxel_origin, grid, atoms, densities) =
+            match vasp.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
         assert_eq!(voxel_origin, [0.; 3]);
         assert_eq!(grid, [32, 32, 32]);
         assert_eq!(atoms.positions, vec![[0., 0., 0.]]);
@@ -99,10 +156,11 @@ mod tests {
     fn vasp_read_ncl_chg() {
         let filename = String::from("tests/vasp/CHG_ncl");
         let vasp = Vasp {};
-        let (voxel_origin, grid, atoms, densities) = match vasp.read(filename) {
-            Ok(r) => r,
-            Err(e) => panic!("{}", e),
-        };
+        let (voxel_origin, grid, atoms, densities) =
+            match vasp.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
         assert_eq!(voxel_origin, [0.; 3]);
         assert_eq!(grid, [32, 32, 32]);
         assert_eq!(atoms.positions, vec![[0., 0., 0.]]);
@@ -115,4 +173,117 @@ mod tests {
         assert_eq!(densities[3][0], 16.140 / atoms.lattice.volume);
         assert_eq!(densities[3][32767], 13.834 / atoms.lattice.volume);
     }
+
+    #[test]
+    fn vasp_read_potcar() {
+        let zval = match Vasp::read_potcar("tests/vasp/POTCAR") {
+            Ok(r) => r,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(zval, vec![(28, 10.), (8, 6.)]);
+    }
+
+    #[test]
+    fn vasp_read_potcar_no_species() {
+        assert!(Vasp::read_potcar("tests/vasp/CHGCAR_spin").is_err());
+    }
+
+    /// The valence charges of a bcc Fe cell, with the species line `species`
+    /// (VASP 4 if empty) and a count of one for each of `counts`.
+    fn fe_valence(species: &str,
+                  counts: &str,
+                  flags: Vec<&str>)
+                  -> Result<Option<Vec<f64>>, Error> {
+        let text = format!("Fe\n1.0\n2.8 0.0 0.0\n0.0 2.8 0.0\n0.0 0.0 \
+                            2.8\n{}{}\nDirect\n0.0 0.0 0.0\n0.5 0.5 \
+                            0.5\n\n 2 2 2\n",
+                           species,
+                           counts);
+        let vasp: Box<dyn FileFormat> = Box::new(Vasp {});
+        let atoms = vasp.to_atoms(text)?;
+        let mut v = vec!["bca", "CHGCAR"];
+        v.extend(flags);
+        let args = Args::new(ClapApp::get().get_matches_from(v))?;
+        let potcar = args.potcar
+                         .as_ref()
+                         .map(|f| Vasp::read_potcar(f))
+                         .transpose()?;
+        valence(&args, potcar.as_deref(), &atoms, &vasp)
+    }
+
+    #[test]
+    fn vasp_valence_same_element() {
+        let valence = fe_valence("Fe Fe\n",
+                                 "1 1",
+                                 vec!["-p", "tests/vasp/POTCAR_Fe"]);
+        assert_eq!(valence.unwrap(), Some(vec![8., 14.]));
+    }
+
+    #[test]
+    fn vasp_valence_vasp4() {
+        let valence =
+            fe_valence("", "1 1", vec!["-p", "tests/vasp/POTCAR_Fe"]);
+        assert_eq!(valence.unwrap(), Some(vec![8., 14.]));
+    }
+
+    #[test]
+    fn vasp_valence_zval_first() {
+        let valence = fe_valence("Fe Fe\n",
+                                 "1 1",
+                                 vec!["-p", "tests/vasp/POTCAR_Fe", "-z",
+                                      "Fe=16"]);
+        assert_eq!(valence.unwrap(), Some(vec![16., 16.]));
+    }
+
+    #[test]
+    fn vasp_valence_species_mismatch() {
+        let e = fe_valence("Fe\n", "2", vec!["-p", "tests/vasp/POTCAR_Fe"]);
+        assert_eq!(e.unwrap_err().to_string(),
+                   "Error: The POTCAR has 2 species but the structure has 1.");
+    }
+
+    #[test]
+    fn vasp_valence_element_mismatch() {
+        let e = fe_valence("Fe O\n", "1 1", vec!["-p", "tests/vasp/POTCAR"]);
+        assert_eq!(e.unwrap_err().to_string(),
+                   "Error: The POTCAR species Ni doesn't match the \
+                    structure's Fe.");
+    }
+
+    #[test]
+    fn vasp_valence_missing() {
+        let e = fe_valence("Fe Co\n", "1 1", vec!["-z", "Fe=8"]);
+        assert_eq!(e.unwrap_err().to_string(),
+                   "Error: No valence charge for atom 2 (Co), pass one with \
+                    -z Co=VALUE.");
+    }
+
+    #[test]
+    fn vasp_valence_none() {
+        assert_eq!(fe_valence("Fe Co\n", "1 1", vec![]).unwrap(), None);
+    }
+
+    #[test]
+    fn vasp_dimensions() {
+        let vasp = Vasp {};
+        for filename in &["tests/vasp/CHGCAR_no_spin",
+                          "tests/vasp/CHGCAR_ncl",
+                          "tests/vasp/CHG_spin"]
+        {
+            let filename = String::from(*filename);
+            let dimensions = match vasp.dimensions(filename.clone()) {
+                Ok(d) => d,
+                Err(e) => panic!("{}", e),
+            };
+            let (_, grid, atoms, densities) =
+                match vasp.read(filename, &Storage::Memory) {
+                    Ok(r) => r,
+                    Err(e) => panic!("{}", e),
+                };
+            assert_eq!(dimensions,
+                       Dimensions { grid,
+                                    densities: densities.len(),
+                                    atoms: atoms.positions.len() });
+        }
+    }
 }
@@ -0,0 +1,100 @@
+#[cfg(test)]
+mod tests {
+    use bader::io::xsf::Xsf;
+    use bader::io::{Dimensions, FileFormat};
+    use bader::progress::Silent;
+    use bader::storage::Storage;
+
+    #[test]
+    fn xsf_read() {
+        let filename = String::from("tests/xsf/density.xsf");
+        let xsf = Xsf {};
+        let (voxel_origin, grid, atoms, densities) =
+            match xsf.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(voxel_origin, [0.; 3]);
+        assert_eq!(grid, [4, 4, 4]);
+        assert_eq!(atoms.positions, vec![[0., 0., 0.], [3., 2., 1.]]);
+        assert_eq!(atoms.atomic_numbers, vec![8, 1]);
+        assert_eq!(densities.len(), 2);
+        assert_eq!(densities[0].len(), 64);
+        assert_eq!(densities[0][0], 0.);
+        assert_eq!(densities[0][1], 1.);
+        assert_eq!(densities[0][4], 10.);
+        assert_eq!(densities[0][16], 100.);
+        assert_eq!(densities[0][63], 333.);
+        assert_eq!(densities[1][63], -333.);
+    }
+
+    #[test]
+    fn xsf_write_read() {
+        let xsf = Xsf {};
+        let (_, grid, atoms, densities) =
+            match xsf.read(String::from("tests/xsf/density.xsf"),
+                           &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        let filename = std::env::temp_dir().join("bader_xsf_write_read");
+        let filename = filename.to_string_lossy().to_string();
+        let data = densities[0].iter()
+                               .map(|d| if *d == 0. { None } else { Some(*d) })
+                               .collect::<Vec<Option<f64>>>();
+        let pbar = Box::new(Silent);
+        if let Err(e) = xsf.write(&atoms, data, filename.clone(), pbar) {
+            panic!("{}", e)
+        }
+        let (_, grid_2, atoms_2, densities_2) =
+            match xsf.read(format!("{}.xsf", filename), &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(grid, grid_2);
+        assert_eq!(atoms.positions, atoms_2.positions);
+        assert_eq!(densities_2.len(), 1);
+        assert_eq!(&densities[0][..], &densities_2[0][..]);
+    }
+
+    #[test]
+    fn xsf_write_bad_grid() {
+        let xsf = Xsf {};
+        let (_, _, mut atoms, densities) =
+            match xsf.read(String::from("tests/xsf/density.xsf"),
+                           &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        let start = atoms.text.find("DATAGRID_3D_bader").unwrap();
+        atoms.text.truncate(start);
+        atoms.text.push_str("DATAGRID_3D_bader\n 5 five 5\n");
+        let filename = std::env::temp_dir().join("bader_xsf_write_bad_grid");
+        let filename = filename.to_string_lossy().to_string();
+        let data = vec![None; densities[0].len()];
+        let pbar = Box::new(Silent);
+        match xsf.write(&atoms, data, filename, pbar) {
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            Ok(_) => panic!("Wrote a grid with an unreadable size."),
+        }
+    }
+
+    #[test]
+    fn xsf_dimensions() {
+        let filename = String::from("tests/xsf/density.xsf");
+        let xsf = Xsf {};
+        let dimensions = match xsf.dimensions(filename.clone()) {
+            Ok(d) => d,
+            Err(e) => panic!("{}", e),
+        };
+        let (_, grid, atoms, densities) =
+            match xsf.read(filename, &Storage::Memory) {
+                Ok(r) => r,
+                Err(e) => panic!("{}", e),
+            };
+        assert_eq!(dimensions,
+                   Dimensions { grid,
+                                densities: densities.len(),
+                                atoms: atoms.positions.len() });
+    }
+}